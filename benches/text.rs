@@ -0,0 +1,56 @@
+use rust_web_browser_from_scratch::css::{self, Origin};
+use rust_web_browser_from_scratch::engine::{self, RenderOptions};
+use rust_web_browser_from_scratch::html;
+use rust_web_browser_from_scratch::painting;
+use rust_web_browser_from_scratch::stats;
+use rust_web_browser_from_scratch::style;
+use std::time::Instant;
+
+// How long rendering a document that is one very long run of text takes, stage by
+// stage, and with the `count-allocations` feature what each stage allocates. Text
+// is the input whose size no node count bounds: a line of megabytes, like minified
+// JSON pasted into a `<pre>`, or a paragraph of a million words must still lay out
+// in time linear in its length. That layout doesn't copy the line is tested in
+// `layout`.
+
+const BYTES: usize = 5_000_000; // of text in each case
+
+fn main() {
+    let line = "x".repeat(BYTES);
+    let paragraph = "word ".repeat(BYTES / 5);
+    let cases = [
+        ("unbreakable line", format!("<html><body><pre>{}</pre></body></html>", line), "html, body, pre { display: block; } pre { white-space: pre; }"),
+        ("paragraph", format!("<html><body><p>{}</p></body></html>", paragraph), "html, body, p { display: block; }"),
+    ];
+    let options = RenderOptions::default();
+    for (name, source, css_source) in cases {
+        let mut stages = Vec::new();
+        let mut started = (Instant::now(), stats::allocated_bytes());
+        let mut end_stage = |stage| {
+            let now = (Instant::now(), stats::allocated_bytes());
+            let bytes = match (started.1, now.1) {
+                (Some(before), Some(after)) => Some(after - before),
+                _ => None
+            };
+            stages.push((stage, now.0 - started.0, bytes));
+            started = (Instant::now(), stats::allocated_bytes());
+        };
+        let root = html::parse(source);
+        let stylesheets = [css::default_user_agent_stylesheet(), css::parse_with_limits(css_source.to_string(), Origin::Author, &options.limits).unwrap()];
+        end_stage("parse");
+        let style_root = style::style_tree_with_context(&root, &stylesheets, &options.style_context());
+        end_stage("style");
+        let layout_root = engine::layout(&style_root, &options).unwrap();
+        end_stage("layout");
+        drop(painting::build_display_list(&layout_root));
+        end_stage("paint");
+
+        println!("{}: {} bytes", name, BYTES);
+        for (stage, time, bytes) in stages {
+            match bytes {
+                Some(bytes) => println!("  {}: {:.2?}, {} bytes allocated", stage, time, bytes),
+                None => println!("  {}: {:.2?}", stage, time),
+            }
+        }
+    }
+}
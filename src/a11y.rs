@@ -0,0 +1,189 @@
+use crate::dom::NodeType;
+use crate::style::{Display, StyledNode};
+use std::fmt::Write;
+
+// A simplified accessibility tree: the document outline with the content that
+// matters for structure (headings, paragraphs, links, images and lists).
+// Elements without a role of their own (div, span, ...) are transparent and
+// their children are hoisted into the nearest ancestor that has one.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Role {
+    Document,
+    Section, // section, article, nav, main, header, footer, aside
+    Heading(u8),
+    Paragraph,
+    Link,
+    Image,
+    List,
+    ListItem,
+}
+
+#[derive(Debug, Clone)]
+pub struct A11yNode {
+    pub role: Role,
+    pub name: String, // visible text content, or alt text for images
+    pub href: Option<String>,
+    pub children: Vec<A11yNode>,
+}
+
+// build the outline of a styled document, leaving out hidden content
+pub fn build_tree(root: &StyledNode) -> A11yNode {
+    let mut document = A11yNode { role: Role::Document, name: String::new(), href: None, children: Vec::new() };
+    collect(root, &mut document.children);
+    document
+}
+
+fn role_of(tag_name: &str) -> Option<Role> {
+    match tag_name {
+        "section" | "article" | "nav" | "main" | "header" | "footer" | "aside" => Some(Role::Section),
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => Some(Role::Heading(tag_name.as_bytes()[1] - b'0')),
+        "p" => Some(Role::Paragraph),
+        "a" => Some(Role::Link),
+        "img" => Some(Role::Image),
+        "ul" | "ol" => Some(Role::List),
+        "li" => Some(Role::ListItem),
+        _ => None
+    }
+}
+
+fn is_hidden(node: &StyledNode) -> bool {
+    !node.is_visible()
+}
+
+// append the outline nodes for the descendants of `node`
+fn collect_children(node: &StyledNode, out: &mut Vec<A11yNode>) {
+    for child in &node.children {
+        collect(child, out);
+    }
+}
+
+fn collect(node: &StyledNode, out: &mut Vec<A11yNode>) {
+    let elem = match node.node.node_type {
+        NodeType::Element(ref elem) => elem,
+        _ => return
+    };
+    if let Display::None = node.display() {
+        return;
+    }
+    // a hidden element has no node of its own, but visible descendants still count
+    let role = match role_of(&elem.tag_name) {
+        Some(role) if !is_hidden(node) => role,
+        _ => return collect_children(node, out)
+    };
+
+    let (name, href) = match role {
+        Role::Image => (elem.attrs.get("alt").cloned().unwrap_or_default(), None),
+        Role::Link => (text_content(node), elem.attrs.get("href").cloned()),
+        Role::Section | Role::List => (String::new(), None),
+        _ => (text_content(node), None)
+    };
+    let mut a11y_node = A11yNode { role, name, href, children: Vec::new() };
+    collect_children(node, &mut a11y_node.children);
+    out.push(a11y_node);
+}
+
+// the visible text of a subtree, with whitespace collapsed
+fn text_content(node: &StyledNode) -> String {
+    fn walk(node: &StyledNode, words: &mut Vec<String>) {
+        match node.node.node_type {
+            NodeType::Text(ref text) if !is_hidden(node) => {
+                words.extend(text.split_whitespace().map(str::to_string));
+            }
+            NodeType::Element(_) if !matches!(node.display(), Display::None) => {
+                for child in &node.children {
+                    walk(child, words);
+                }
+            }
+            _ => {}
+        }
+    }
+    let mut words = Vec::new();
+    walk(node, &mut words);
+    words.join(" ")
+}
+
+impl Role {
+    fn name(&self) -> &'static str {
+        match self {
+            Role::Document => "document",
+            Role::Section => "section",
+            Role::Heading(_) => "heading",
+            Role::Paragraph => "paragraph",
+            Role::Link => "link",
+            Role::Image => "image",
+            Role::List => "list",
+            Role::ListItem => "listitem",
+        }
+    }
+}
+
+impl A11yNode {
+    /// Render the tree as indented text, one node per line.
+    pub fn to_outline(&self) -> String {
+        let mut out = String::new();
+        self.write_outline(&mut out, 0);
+        out
+    }
+
+    fn write_outline(&self, out: &mut String, depth: usize) {
+        let _ = write!(out, "{}{}", "  ".repeat(depth), self.role.name());
+        if let Role::Heading(level) = self.role {
+            let _ = write!(out, "({})", level);
+        }
+        if !self.name.is_empty() {
+            let _ = write!(out, " {:?}", self.name);
+        }
+        if let Some(ref href) = self.href {
+            let _ = write!(out, " -> {}", href);
+        }
+        out.push('\n');
+        for child in &self.children {
+            child.write_outline(out, depth + 1);
+        }
+    }
+
+    /// Render the tree as a JSON object with `role`, `level` (headings only), `name`,
+    /// `href` (links only) and `children` keys.
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        self.write_json(&mut out);
+        out
+    }
+
+    fn write_json(&self, out: &mut String) {
+        let _ = write!(out, "{{\"role\":{}", json_string(self.role.name()));
+        if let Role::Heading(level) = self.role {
+            let _ = write!(out, ",\"level\":{}", level);
+        }
+        let _ = write!(out, ",\"name\":{}", json_string(&self.name));
+        if let Some(ref href) = self.href {
+            let _ = write!(out, ",\"href\":{}", json_string(href));
+        }
+        out.push_str(",\"children\":[");
+        for (i, child) in self.children.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            child.write_json(out);
+        }
+        out.push_str("]}");
+    }
+}
+
+// quote and escape a string as a JSON string literal
+pub fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => { let _ = write!(out, "\\u{:04x}", c as u32); }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,298 @@
+use crate::css::{self, Color, Unit, Value};
+use crate::image_decode::{DecodedImage, ImageCache, ImageError};
+use crate::layout::{LayoutBox, Rect};
+use crate::painting::{DisplayCommand, DisplayList};
+use crate::replaced;
+use crate::resource::{ResolvedUrl, ResourceLoader};
+use crate::style::StyledNode;
+use std::sync::Arc;
+
+// Background layers: the images and gradients `background-image` stacks over a box's
+// background color, each placed by its own `background-position`, tiled as its
+// `background-repeat` says, and scrolling with the box or fixed to the viewport as
+// its `background-attachment` says. There's no `background-size`, so images keep
+// their intrinsic size and gradients cover the positioning area.
+
+/// One layer of a box's background.
+///
+/// https://www.w3.org/TR/css-backgrounds-3/#layering
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layer {
+    pub image: Value, // `url(...)` or `linear-gradient(...)`
+    pub position: Value,
+    pub repeat_x: bool,
+    pub repeat_y: bool,
+    // positioned in the viewport rather than the box; `local` behaves as `scroll`,
+    // since nothing scrolls
+    pub fixed: bool,
+}
+
+/// The background layers of a node with images, topmost first. The number of
+/// images decides how many layers there are; the other properties' lists are
+/// repeated as needed to cover them, as the spec says.
+///
+/// A layer whose image is `none` is left out, keeping the index of the others, so
+/// that a layer's index is its position in `background-image`.
+pub fn layers(style: &StyledNode) -> Vec<(usize, Layer)> {
+    let images = items(style.value("background-image"));
+    let positions = items(style.value("background-position"));
+    let repeats = items(style.value("background-repeat"));
+    let attachments = items(style.value("background-attachment"));
+    // the item for layer `i` of a list, or `None` if the property isn't set
+    let nth = |list: &[Value], i: usize| match list.is_empty() {
+        true => None,
+        false => Some(list[i % list.len()].clone()),
+    };
+    images.iter().enumerate()
+        .filter(|(_, image)| !matches!(image, Value::Keyword(_)))
+        .map(|(i, image)| {
+            let repeat = nth(&repeats, i);
+            let is_repeat = |keyword: &str| matches!(repeat, Some(Value::Keyword(ref k)) if k == keyword);
+            (i, Layer {
+                image: image.clone(),
+                position: nth(&positions, i).unwrap_or_else(|| {
+                    Value::List(vec![Value::Length(0.0, Unit::Percent), Value::Length(0.0, Unit::Percent)])
+                }),
+                repeat_x: !is_repeat("repeat-y") && !is_repeat("no-repeat"),
+                repeat_y: !is_repeat("repeat-x") && !is_repeat("no-repeat"),
+                fixed: matches!(nth(&attachments, i), Some(Value::Keyword(ref k)) if k == "fixed"),
+            })
+        })
+        .collect()
+}
+
+// the comma-separated items of a value, or just the value
+fn items(value: Option<Value>) -> Vec<Value> {
+    match value {
+        Some(Value::CommaList(items)) => items,
+        Some(value) => vec![value],
+        None => Vec::new(),
+    }
+}
+
+/// Decode the `url()` images of a box's background layers, by layer, with `None`
+/// for layers without one and those that couldn't be loaded, which paint nothing.
+/// URLs are resolved against the document's, `base`, as `<img>` sources are.
+pub fn load_images(style: &StyledNode, base: &ResolvedUrl, loader: &dyn ResourceLoader, cache: &mut ImageCache, warnings: &mut Vec<String>) -> Vec<Option<Arc<DecodedImage>>> {
+    let images = items(style.value("background-image"));
+    if !images.iter().any(|image| url_of(image).is_some()) {
+        return Vec::new();
+    }
+    images.iter().map(|image| {
+        let url = url_of(image)?;
+        match base.join(url).map_err(ImageError::from).and_then(|resolved| cache.get_or_decode(&resolved, loader)) {
+            Ok(image) => Some(image),
+            Err(error) => {
+                warnings.push(format!("can't load background image {}: {}", url, error));
+                None
+            }
+        }
+    }).collect()
+}
+
+fn url_of(image: &Value) -> Option<&str> {
+    match image {
+        Value::Function(name, arguments) if name == "url" => match arguments.as_slice() {
+            [Value::Str(url)] => Some(url.as_str()).filter(|url| !url.is_empty()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Paint one layer of `layout_box`'s background, cut off outside `painted`, leaving
+/// out tiles that don't reach the `visible` part of it.
+///
+/// https://www.w3.org/TR/css-backgrounds-3/#background-position
+///
+/// The layer is placed in `origin`, the box's padding box, or the viewport for a
+/// fixed layer, and then repeated from there across `painted` along the axes it
+/// repeats in. `space` and `round` repeat as `repeat` does.
+pub fn render_layer(list: &mut DisplayList, layout_box: &LayoutBox, index: usize, layer: &Layer, origin: Rect, painted: Rect, visible: Rect) {
+    let image = match layer.image {
+        Value::Function(ref name, _) if name == "url" => match layout_box.background_images.get(index) {
+            Some(Some(image)) => image.clone(),
+            _ => return,
+        },
+        ref gradient => match linear_gradient(gradient, origin.width, origin.height) {
+            Some(image) => Arc::new(image),
+            None => return,
+        },
+    };
+    // an image keeps its intrinsic size, and a gradient fills the positioning area
+    let (width, height) = match layer.image {
+        Value::Function(ref name, _) if name == "url" => (image.width as f32, image.height as f32),
+        _ => (origin.width, origin.height),
+    };
+    if !(width >= 1.0 && height >= 1.0) {
+        return;
+    }
+    let (x, y) = replaced::resolve_position(&layer.position, origin.width - width, origin.height - height);
+    let (x, y) = (origin.x + x, origin.y + y);
+    // on an axis that repeats, from the first tile at or before the visible area's edge
+    let columns = match layer.repeat_x {
+        true => tiles(x, width, visible.x, visible.x + visible.width),
+        false => vec![x],
+    };
+    let rows = match layer.repeat_y {
+        true => tiles(y, height, visible.y, visible.y + visible.height),
+        false => vec![y],
+    };
+    for &tile_y in &rows {
+        for &tile_x in &columns {
+            let tile = Rect { x: tile_x, y: tile_y, width, height };
+            if tile.intersection(visible).is_some() {
+                list.push(DisplayCommand::Image(image.clone(), tile, painted));
+            }
+        }
+    }
+}
+
+// where the tiles of a layer placed at `start`, each `size` long, begin in the
+// span from `from` to `to`
+fn tiles(start: f32, size: f32, from: f32, to: f32) -> Vec<f32> {
+    let first = start - ((start - from) / size).ceil() * size;
+    (0..).map(|i| first + i as f32 * size).take_while(|&at| at < to).collect()
+}
+
+// gradients are drawn at most this many pixels along each axis, and stretched to
+// their size beyond that
+const MAX_GRADIENT_PIXELS: f32 = 256.0;
+
+/// Draw a `linear-gradient()` `width` by `height` px, or `None` if it's not one.
+///
+/// https://www.w3.org/TR/css-images-3/#linear-gradients
+///
+/// Colors are interpolated in premultiplied sRGB. A gradient along either axis is
+/// drawn one pixel across, since it doesn't change across.
+pub fn linear_gradient(value: &Value, width: f32, height: f32) -> Option<DecodedImage> {
+    let arguments = match value {
+        Value::Function(name, arguments) if name == "linear-gradient" => arguments.as_slice(),
+        _ => return None,
+    };
+    if !(width > 0.0 && height > 0.0) {
+        return None;
+    }
+    let (angle, stops) = match arguments.split_first().and_then(|(first, rest)| Some((direction(first, width, height)?, rest))) {
+        Some((angle, stops)) => (angle, stops),
+        None => (180.0, arguments), // to bottom
+    };
+    let (sin, cos) = angle.to_radians().sin_cos();
+    // the gradient line passes through the center, long enough for the corners
+    // perpendicular to it at either end to get its first and last colors
+    let length = (width * sin).abs() + (height * cos).abs();
+    let stops = color_stops(stops, length)?;
+    let along_x = (angle % 180.0).abs() > 0.01;
+    let along_y = ((angle - 90.0) % 180.0).abs() > 0.01;
+    let pixels = |size: f32, varies: bool| match varies {
+        true => size.min(MAX_GRADIENT_PIXELS).ceil().max(1.0) as u32,
+        false => 1,
+    };
+    let (columns, rows) = (pixels(width, along_x), pixels(height, along_y));
+    let mut rgba = Vec::with_capacity((columns * rows * 4) as usize);
+    for row in 0..rows {
+        for column in 0..columns {
+            // the pixel's center, from the center of the gradient box
+            let x = (column as f32 + 0.5) / columns as f32 * width - width / 2.0;
+            let y = (row as f32 + 0.5) / rows as f32 * height - height / 2.0;
+            let t = (x * sin - y * cos) / length + 0.5;
+            let color = color_at(&stops, t);
+            rgba.extend_from_slice(&[color.r, color.g, color.b, color.a]);
+        }
+    }
+    Some(DecodedImage { width: columns, height: rows, rgba })
+}
+
+// the angle of a gradient's direction argument in degrees, clockwise from up, or
+// `None` if the argument isn't a direction
+fn direction(argument: &Value, width: f32, height: f32) -> Option<f32> {
+    let sides = match argument {
+        Value::Keyword(angle) => return css::angle_degrees(angle),
+        Value::List(words) if matches!(words.first(), Some(Value::Keyword(to)) if to == "to") => &words[1..],
+        _ => return None,
+    };
+    let has = |side: &str| sides.iter().any(|word| matches!(word, Value::Keyword(k) if k == side));
+    // toward a corner, the line is perpendicular to the diagonal between the
+    // other two corners
+    let corner = height.atan2(width).to_degrees();
+    Some(match (has("top"), has("right"), has("bottom"), has("left")) {
+        (true, true, _, _) => corner,
+        (_, true, true, _) => 180.0 - corner,
+        (_, _, true, true) => 180.0 + corner,
+        (true, _, _, true) => 360.0 - corner,
+        (true, ..) => 0.0,
+        (_, true, ..) => 90.0,
+        (_, _, true, _) => 180.0,
+        _ => 270.0,
+    })
+}
+
+// the colors of a gradient and where they are along its line, from 0 to 1: the
+// first at 0 and the last at 1 unless placed, those between spread evenly between
+// their placed neighbors, and none before the one ahead of it
+fn color_stops(arguments: &[Value], length: f32) -> Option<Vec<(Color, f32)>> {
+    let mut stops: Vec<(Color, Option<f32>)> = Vec::new();
+    for argument in arguments {
+        let (color, position) = match argument {
+            Value::ColorValue(color) => (*color, None),
+            Value::List(parts) => match parts.as_slice() {
+                [Value::ColorValue(color), position] => (*color, Some(position.to_px_of(length) / length)),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        stops.push((color, position));
+    }
+    if stops.len() < 2 {
+        return None;
+    }
+    let last = stops.len() - 1;
+    stops[0].1.get_or_insert(0.0);
+    stops[last].1.get_or_insert(1.0);
+    let mut placed: Vec<(Color, f32)> = Vec::with_capacity(stops.len());
+    let mut i = 0;
+    while i < stops.len() {
+        match stops[i].1 {
+            Some(position) => {
+                let before = placed.last().map_or(position, |&(_, at)| at);
+                placed.push((stops[i].0, position.max(before)));
+                i += 1;
+            }
+            None => {
+                // the run of unplaced stops up to the next placed one
+                let end = (i..stops.len()).find(|&j| stops[j].1.is_some()).unwrap_or(last);
+                let from = placed.last().map_or(0.0, |&(_, at)| at);
+                let to = stops[end].1.unwrap_or(1.0).max(from);
+                let count = (end - i + 1) as f32;
+                for (k, &(color, _)) in stops[i..end].iter().enumerate() {
+                    placed.push((color, from + (to - from) * (k + 1) as f32 / count));
+                }
+                i = end;
+            }
+        }
+    }
+    Some(placed)
+}
+
+// the color at `t` along a gradient's line
+fn color_at(stops: &[(Color, f32)], t: f32) -> Color {
+    let (first, last) = (stops[0], stops[stops.len() - 1]);
+    if t <= first.1 {
+        return first.0;
+    }
+    let end = match stops.iter().position(|&(_, at)| at > t) {
+        Some(end) => end,
+        None => return last.0,
+    };
+    let ((from, start), (to, stop)) = (stops[end - 1], stops[end]);
+    let f = (t - start) / (stop - start);
+    let alpha = from.a as f32 + (to.a as f32 - from.a as f32) * f;
+    let channel = |a: u8, b: u8| {
+        let premultiplied = a as f32 * from.a as f32 + (b as f32 * to.a as f32 - a as f32 * from.a as f32) * f;
+        match alpha > 0.0 {
+            true => (premultiplied / alpha).round().clamp(0.0, 255.0) as u8,
+            false => 0,
+        }
+    };
+    Color { r: channel(from.r, to.r), g: channel(from.g, to.g), b: channel(from.b, to.b), a: alpha.round() as u8 }
+}
@@ -0,0 +1,222 @@
+use crate::css::Value;
+use crate::inline::{is_combining, text_bounds, Fragment, LineBox};
+use crate::layout::{LayoutContext, Rect};
+use crate::style::StyledNode;
+use std::borrow::Cow;
+
+// A first cut at bidirectional text: enough of the Unicode Bidirectional Algorithm to
+// show a Hebrew or Arabic word inside an English sentence, or an English one inside
+// Hebrew, in the right order. Each character on a line gets an embedding level from
+// its direction, with digits and neutral characters like punctuation taking the
+// direction of the text around them, and the runs at odd levels are then reversed.
+//
+// Only the block's `direction` sets the base direction. `unicode-bidi`, explicit
+// embeddings and isolates, and mirrored brackets are ignored, and levels are resolved
+// a line at a time rather than over the whole paragraph. Right-to-left text is
+// reversed a character at a time, with any combining marks on it, which is right for
+// Hebrew but leaves Arabic letters unjoined until text is shaped.
+//
+// https://www.unicode.org/reports/tr9/
+
+/// The base direction of the text in a block container, from `direction`.
+///
+/// https://www.w3.org/TR/css-writing-modes-3/#direction
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum Direction {
+    #[default]
+    Ltr,
+    Rtl
+}
+
+impl Direction {
+    pub(crate) fn of(style: &StyledNode) -> Direction {
+        match style.value("direction") {
+            Some(Value::Keyword(k)) if k == "rtl" => Direction::Rtl,
+            _ => Direction::Ltr
+        }
+    }
+
+    // the class that text of this direction has
+    fn class(self) -> Class {
+        match self {
+            Direction::Ltr => Class::Left,
+            Direction::Rtl => Class::Right
+        }
+    }
+}
+
+// the bidirectional character types we distinguish, the strong ones, European and
+// Arabic digits, and the rest lumped together as neutral
+#[derive(Clone, Copy, PartialEq)]
+enum Class {
+    Left,
+    Right,
+    Number,
+    Neutral
+}
+
+fn class(c: char) -> Class {
+    match c {
+        '0'..='9' | '\u{660}'..='\u{669}' | '\u{6F0}'..='\u{6F9}' => Class::Number,
+        // Hebrew, Arabic, Syriac, Thaana, N'Ko and their presentation forms, the
+        // right-to-left mark, and the historic right-to-left scripts
+        '\u{590}'..='\u{8FF}' | '\u{200F}' | '\u{FB1D}'..='\u{FDFF}' | '\u{FE70}'..='\u{FEFE}'
+            | '\u{10800}'..='\u{10FFF}' | '\u{1E800}'..='\u{1EFFF}' => Class::Right,
+        c if c.is_alphabetic() || c == '\u{200E}' => Class::Left,
+        _ => Class::Neutral
+    }
+}
+
+// the embedding level of each character of a line, given their classes in logical order
+//
+// https://www.unicode.org/reports/tr9/#Resolving_Weak_Types
+// https://www.unicode.org/reports/tr9/#Resolving_Neutral_Types
+fn resolve_levels(classes: &mut [Class], base: Direction) -> Vec<u8> {
+    // digits after left-to-right text are left-to-right (W7)
+    let mut strong = base.class();
+    for class in classes.iter_mut() {
+        match *class {
+            Class::Left | Class::Right => strong = *class,
+            Class::Number if strong == Class::Left => *class = Class::Left,
+            _ => {}
+        }
+    }
+    // neutrals between text of one direction, with digits counting as right-to-left,
+    // take that direction, and the rest the base direction (N1 and N2)
+    let as_strong = |class: Class| if class == Class::Number { Class::Right } else { class };
+    let mut start = 0;
+    while start < classes.len() {
+        if classes[start] != Class::Neutral {
+            start += 1;
+            continue;
+        }
+        let end = classes[start..].iter().position(|&class| class != Class::Neutral).map_or(classes.len(), |n| start + n);
+        let before = start.checked_sub(1).map_or(base.class(), |i| as_strong(classes[i]));
+        let after = classes.get(end).map_or(base.class(), |&class| as_strong(class));
+        classes[start..end].fill(if before == after { before } else { base.class() });
+        start = end;
+    }
+    classes.iter().map(|&class| match (class, base) {
+        (Class::Left, Direction::Ltr) => 0,
+        (Class::Right, _) => 1,
+        // left-to-right text in a right-to-left paragraph, and digits anywhere
+        _ => 2
+    }).collect()
+}
+
+/// Put the text on `line` in visual order, for a block whose base direction is
+/// `base`.
+///
+/// https://www.unicode.org/reports/tr9/#Reordering_Resolved_Levels
+///
+/// Fragments are left in logical order, and so line breaking measured, until here.
+/// A fragment is split where its level changes, and right-to-left text into single
+/// characters, and these pieces, with the spaces between them, are then moved within
+/// the space the line's text takes up. A space with an inline-block in it stays
+/// where it is, and no reversed run reaches past it. Lines with no right-to-left
+/// text in a left-to-right block are left as they are.
+pub(crate) fn reorder_line(line: &mut LineBox, base: Direction, atomics: &[Rect], ctx: &LayoutContext) {
+    let right_to_left = |c: char| class(c) == Class::Right;
+    if base == Direction::Ltr && !line.fragments.iter().any(|fragment| fragment.text.contains(right_to_left)) {
+        return;
+    }
+    // the class of each character in logical order, and of each space between
+    // fragments; combining marks have the class of the character they're on (W1)
+    let spaced = |before: &Fragment, after: &Fragment| after.rect.x > before.rect.x + before.rect.width;
+    let mut classes = Vec::new();
+    for (i, fragment) in line.fragments.iter().enumerate() {
+        if i > 0 && spaced(&line.fragments[i - 1], fragment) {
+            classes.push(Class::Neutral);
+        }
+        for c in fragment.text.chars() {
+            let previous = classes.last().copied().unwrap_or(base.class());
+            classes.push(if is_combining(c) { previous } else { class(c) });
+        }
+    }
+    let mut levels = resolve_levels(&mut classes, base).into_iter();
+
+    let on_line = |atomic: &&Rect| atomic.y < line.rect.y + line.rect.height && atomic.y + atomic.height > line.rect.y;
+    let atomics: Vec<&Rect> = atomics.iter().filter(on_line).collect();
+    let mut pieces: Vec<Piece> = Vec::new();
+    let mut right = None;
+    for fragment in std::mem::take(&mut line.fragments) {
+        if let Some(left) = right.filter(|&left| fragment.rect.x > left) {
+            let width = fragment.rect.x - left;
+            let fixed = atomics.iter().any(|atomic| atomic.x + atomic.width / 2.0 >= left && atomic.x + atomic.width / 2.0 <= fragment.rect.x);
+            pieces.push(Piece { fragment: None, x: left, width, level: levels.next().unwrap_or(0), fixed });
+        }
+        right = Some(fragment.rect.x + fragment.rect.width);
+
+        // where each piece of the fragment starts and ends, and its level
+        let mut bounds = Vec::new();
+        let (mut start, mut level) = (0, None);
+        for (i, c) in fragment.text.char_indices() {
+            let next = levels.next().unwrap_or(0);
+            if level.is_some_and(|level| next != level || (level % 2 == 1 && !is_combining(c))) {
+                bounds.push((start, i, level.unwrap_or(0)));
+                start = i;
+            }
+            level = Some(next);
+        }
+        let level = level.unwrap_or(0);
+        if bounds.is_empty() {
+            pieces.push(Piece { x: fragment.rect.x, width: fragment.rect.width, level, fixed: false, fragment: Some(fragment) });
+            continue;
+        }
+        bounds.push((start, fragment.text.len(), level));
+        let (mut x, end_x) = (fragment.rect.x, fragment.rect.x + fragment.rect.width);
+        let last = bounds.len() - 1;
+        for (i, (start, end, level)) in bounds.into_iter().enumerate() {
+            let text = match fragment.text {
+                Cow::Borrowed(text) => Cow::Borrowed(&text[start..end]),
+                Cow::Owned(ref text) => Cow::Owned(text[start..end].to_string())
+            };
+            // the last piece ends where the fragment did, whatever rounding there was
+            let width = if i == last { end_x - x } else { ctx.measurer.measure(&text, &fragment.font) };
+            let rect = Rect { x, width, ..fragment.rect };
+            let piece = Fragment { node: fragment.node, text, index: fragment.index + start, font: fragment.font, rect };
+            pieces.push(Piece { fragment: Some(piece), x, width, level, fixed: false });
+            x += width;
+        }
+    }
+
+    // from the highest level down, reverse every run of pieces at that level or above
+    let highest = pieces.iter().map(|piece| piece.level).max().unwrap_or(0);
+    for level in (1..=highest).rev() {
+        let reversed = |piece: &Piece| piece.level >= level && !piece.fixed;
+        let mut start = 0;
+        while start < pieces.len() {
+            let end = pieces[start..].iter().position(|piece| !reversed(piece)).map_or(pieces.len(), |n| start + n);
+            reverse(&mut pieces[start..end]);
+            start = end + 1;
+        }
+    }
+    line.fragments = pieces.into_iter().filter_map(|piece| {
+        let mut fragment = piece.fragment?;
+        fragment.rect.x = piece.x;
+        Some(fragment)
+    }).collect();
+    line.text_bounds = text_bounds(&line.fragments);
+}
+
+// a piece of a line being put in visual order: a fragment, part of one, or the space
+// between two, which is `fixed` in place if there's an inline-block in it
+struct Piece<'a> {
+    fragment: Option<Fragment<'a>>,
+    x: f32,
+    width: f32,
+    level: u8,
+    fixed: bool
+}
+
+// mirror a run of pieces within the space they take up, keeping them in visual order
+fn reverse(pieces: &mut [Piece]) {
+    let (left, right) = match (pieces.first(), pieces.last()) {
+        (Some(first), Some(last)) => (first.x, last.x + last.width),
+        _ => return
+    };
+    for piece in pieces.iter_mut() {
+        piece.x = left + right - piece.x - piece.width;
+    }
+    pieces.reverse();
+}
@@ -0,0 +1,158 @@
+use crate::dom::NodeType;
+use crate::layout::{AnonymousBlock, LayoutBox, Rect};
+use crate::painting::Canvas;
+use crate::css::Color;
+use std::fmt;
+
+// Comparing two renderings of a document, to check that a change to the engine or
+// to a stylesheet changes the output only where it should. Layout trees are
+// compared box by box, pairing boxes by their position in the tree, and canvases
+// pixel by pixel.
+
+/// Geometry differences no larger than this, in px, are rounding noise.
+pub const GEOMETRY_EPSILON: f32 = 0.01;
+
+/// A difference between a box in one layout tree and its counterpart in another.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayoutDiff {
+    pub path: String, // like `html > body > div[2]`, counting among the parent's children in `a`
+    pub kind: LayoutDiffKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LayoutDiffKind {
+    Geometry { old: Rect, new: Rect }, // border boxes
+    BoxChanged { old: String, new: String }, // the boxes come from different elements
+    Removed, // only in `a`
+    Added, // only in `b`
+    Text { old: String, new: String }, // the text on the box's lines
+}
+
+impl fmt::Display for LayoutDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rect = |r: &Rect| format!("{}x{} at ({}, {})", r.width, r.height, r.x, r.y);
+        match &self.kind {
+            LayoutDiffKind::Geometry { old, new } => write!(f, "{}: {} -> {}", self.path, rect(old), rect(new)),
+            LayoutDiffKind::BoxChanged { old, new } => write!(f, "{}: <{}> -> <{}>", self.path, old, new),
+            LayoutDiffKind::Removed => write!(f, "{}: removed", self.path),
+            LayoutDiffKind::Added => write!(f, "{}: added", self.path),
+            LayoutDiffKind::Text { old, new } => write!(f, "{}: text {:?} -> {:?}", self.path, old, new),
+        }
+    }
+}
+
+/// The differences between two layout trees, in tree order.
+pub fn diff_layout(a: &LayoutBox, b: &LayoutBox) -> Vec<LayoutDiff> {
+    let mut diffs = Vec::new();
+    diff_boxes(a, b, box_name(a), &mut diffs);
+    diffs
+}
+
+fn diff_boxes(a: &LayoutBox, b: &LayoutBox, path: String, diffs: &mut Vec<LayoutDiff>) {
+    let (old, new) = (box_name(a), box_name(b));
+    if old != new {
+        // different boxes, so comparing what's inside them would only add noise
+        diffs.push(LayoutDiff { path, kind: LayoutDiffKind::BoxChanged { old, new } });
+        return;
+    }
+    let (old, new) = (a.dimensions.border_box(), b.dimensions.border_box());
+    if !same_rect(old, new) {
+        diffs.push(LayoutDiff { path: path.clone(), kind: LayoutDiffKind::Geometry { old, new } });
+    }
+    let (old, new) = (line_text(a), line_text(b));
+    if old != new {
+        diffs.push(LayoutDiff { path: path.clone(), kind: LayoutDiffKind::Text { old, new } });
+    }
+    for i in 0..a.children.len().max(b.children.len()) {
+        let child = a.children.get(i).or(b.children.get(i)).unwrap();
+        let child_path = format!("{} > {}[{}]", path, box_name(child), i);
+        match (a.children.get(i), b.children.get(i)) {
+            (Some(a), Some(b)) => diff_boxes(a, b, child_path, diffs),
+            (Some(_), None) => diffs.push(LayoutDiff { path: child_path, kind: LayoutDiffKind::Removed }),
+            (None, _) => diffs.push(LayoutDiff { path: child_path, kind: LayoutDiffKind::Added }),
+        }
+    }
+}
+
+// the element a box is for, `#text` for text, or `anonymous` for an anonymous block
+fn box_name(layout_box: &LayoutBox) -> String {
+    match layout_box.style_node() {
+        Some(style) => match style.node.node_type {
+            NodeType::Element(ref elem) => elem.tag_name.clone(),
+            NodeType::Text(_) => "#text".to_string(),
+            NodeType::Comment(_) => "#comment".to_string(),
+        },
+        None if matches!(layout_box.box_type, AnonymousBlock) => "anonymous".to_string(),
+        None => String::new(),
+    }
+}
+
+fn line_text(layout_box: &LayoutBox) -> String {
+    let words: Vec<&str> = layout_box.lines.iter()
+        .flat_map(|line| line.fragments.iter().map(|fragment| &*fragment.text))
+        .collect();
+    words.join(" ")
+}
+
+fn same_rect(a: Rect, b: Rect) -> bool {
+    [(a.x, b.x), (a.y, b.y), (a.width, b.width), (a.height, b.height)]
+        .iter()
+        .all(|(a, b)| (a - b).abs() <= GEOMETRY_EPSILON)
+}
+
+/// How much two canvases differ.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PixelDiffSummary {
+    pub count: usize, // pixels that differ by more than the tolerance
+    pub bounds: Option<PixelRect>, // the smallest rectangle containing them
+    pub max_delta: u8, // the largest difference of any channel of any pixel
+}
+
+/// A rectangle of whole pixels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PixelRect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl fmt::Display for PixelDiffSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.bounds {
+            None => write!(f, "no pixels differ"),
+            Some(r) => write!(f, "{} pixels differ within {}x{} at ({}, {}), by up to {} per channel",
+                              self.count, r.width, r.height, r.x, r.y, self.max_delta),
+        }
+    }
+}
+
+/// Compare two canvases pixel by pixel, counting the pixels where some channel
+/// differs by more than `tolerance`. Canvases of different sizes are compared over
+/// the larger of each dimension, with the pixels one of them lacks transparent.
+pub fn diff_canvas(a: &Canvas, b: &Canvas, tolerance: u8) -> PixelDiffSummary {
+    let transparent = Color { r: 0, g: 0, b: 0, a: 0 };
+    let pixel = |canvas: &Canvas, x: usize, y: usize| {
+        if x < canvas.width && y < canvas.height { canvas.pixels[y * canvas.width + x] } else { transparent }
+    };
+    let mut summary = PixelDiffSummary::default();
+    // the differing pixels' extent, as (x0, y0, x1, y1) with the far edges exclusive
+    let mut extent: Option<(usize, usize, usize, usize)> = None;
+    for y in 0..a.height.max(b.height) {
+        for x in 0..a.width.max(b.width) {
+            let (p, q) = (pixel(a, x, y), pixel(b, x, y));
+            let delta = [p.r.abs_diff(q.r), p.g.abs_diff(q.g), p.b.abs_diff(q.b), p.a.abs_diff(q.a)]
+                .into_iter().max().unwrap();
+            summary.max_delta = summary.max_delta.max(delta);
+            if delta > tolerance {
+                summary.count += 1;
+                extent = Some(match extent {
+                    Some((x0, y0, x1, y1)) => (x0.min(x), y0.min(y), x1.max(x + 1), y1.max(y + 1)),
+                    None => (x, y, x + 1, y + 1),
+                });
+            }
+        }
+    }
+    summary.bounds = extent.map(|(x0, y0, x1, y1)| PixelRect { x: x0, y: y0, width: x1 - x0, height: y1 - y0 });
+    summary
+}
@@ -0,0 +1,164 @@
+use crate::a11y::json_string;
+use crate::css::{CssWarning, Selector, Stylesheet, WarningKind};
+use crate::selectors::PseudoClass;
+use std::fmt;
+use std::fmt::Write;
+
+// How much of each stylesheet the engine understood, as `--report-css` prints, so
+// someone feeding it a real-world stylesheet can see what was dropped: at-rules
+// nothing reads, properties it doesn't know, values it doesn't support, and
+// selectors that can never match. Declarations with invalid values are gone by the
+// time the cascade runs, so those are counted from the warnings `css::validate` gave.
+
+/// What the engine made of each author stylesheet, in cascade order.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CssCoverage {
+    pub sources: Vec<SourceCoverage>,
+}
+
+/// What the engine made of one stylesheet.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SourceCoverage {
+    pub source_name: Option<String>,
+    pub rules: usize,
+    pub declarations: usize, // as written, including those validation dropped
+    pub unknown_properties: usize,
+    pub invalid_values: usize, // declarations of known properties with values the engine doesn't support
+    // selectors with pseudo-classes or pseudo-elements the engine never matches
+    pub unsupported_selectors: usize,
+    pub skipped_at_rules: Vec<(String, usize)>, // by name, in order of appearance
+}
+
+impl SourceCoverage {
+    /// Count what the engine understood of `stylesheet`, after validating it gave
+    /// `warnings`.
+    pub fn of(stylesheet: &Stylesheet, warnings: &[CssWarning]) -> SourceCoverage {
+        let count = |kind| warnings.iter().filter(|warning| warning.kind == kind).count();
+        let invalid_values = count(WarningKind::InvalidValue);
+        let mut coverage = SourceCoverage {
+            source_name: stylesheet.source_name.clone(),
+            rules: stylesheet.rules.len(),
+            declarations: invalid_values + stylesheet.rules.iter().map(|rule| rule.declarations.len()).sum::<usize>(),
+            unknown_properties: count(WarningKind::UnknownProperty),
+            invalid_values,
+            unsupported_selectors: stylesheet.rules.iter()
+                .flat_map(|rule| &rule.selectors)
+                .filter(|selector| !is_supported(selector))
+                .count(),
+            skipped_at_rules: Vec::new(),
+        };
+        let skipped = stylesheet.at_rules.iter()
+            .filter(|at_rule| !is_understood(&at_rule.name, &at_rule.prelude))
+            .map(|at_rule| &at_rule.name)
+            .chain(&stylesheet.dropped_at_rules);
+        for name in skipped {
+            match coverage.skipped_at_rules.iter_mut().find(|(skipped, _)| skipped == name) {
+                Some((_, count)) => *count += 1,
+                None => coverage.skipped_at_rules.push((name.clone(), 1)),
+            }
+        }
+        coverage
+    }
+
+    pub fn skipped_at_rule_count(&self) -> usize {
+        self.skipped_at_rules.iter().map(|(_, count)| count).sum()
+    }
+
+    fn write_json(&self, out: &mut String) {
+        let name = self.source_name.as_deref().map_or("null".to_string(), json_string);
+        let _ = write!(out, "{{\"source\":{},\"rules\":{},\"declarations\":{},\"unknownProperties\":{},\
+                             \"invalidValues\":{},\"unsupportedSelectors\":{},\"skippedAtRules\":{{",
+                       name, self.rules, self.declarations, self.unknown_properties, self.invalid_values,
+                       self.unsupported_selectors);
+        for (i, (name, count)) in self.skipped_at_rules.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let _ = write!(out, "{}:{}", json_string(name), count);
+        }
+        out.push_str("}}");
+    }
+}
+
+impl CssCoverage {
+    /// Render the report as a JSON array with an object per stylesheet.
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("[");
+        for (i, source) in self.sources.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            source.write_json(&mut out);
+        }
+        out.push(']');
+        out
+    }
+}
+
+// the at-rules something reads: `@import` when loading stylesheets, `@charset` when
+// decoding them, and `@page` for PDF output, though only without page selectors
+fn is_understood(name: &str, prelude: &str) -> bool {
+    match name {
+        "import" | "charset" => true,
+        "page" => prelude.is_empty(),
+        _ => false,
+    }
+}
+
+// can the selector ever match? Pseudo-classes other than the dynamic ones and
+// `:lang()`, and pseudo-elements other than `::before`, `::after`, `::first-line` and
+// `::first-letter`, never do, and pseudo-elements only match on the subject.
+fn is_supported(selector: &Selector) -> bool {
+    let subject = selector.subject();
+    selector.simple_selectors().all(|simple| {
+        simple.pseudo_classes.iter().all(|name| PseudoClass::from_name(name).is_some() || name.starts_with("lang("))
+            && simple.pseudo_element.as_deref().is_none_or(|name| {
+                std::ptr::eq(simple, subject) && matches!(name, "before" | "after" | "first-line" | "first-letter")
+            })
+    })
+}
+
+impl fmt::Display for CssCoverage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let headings = ["Stylesheet", "Rules", "Declarations", "Unknown properties", "Invalid values",
+                        "Unsupported selectors", "Skipped at-rules"];
+        let rows: Vec<[String; 7]> = self.sources.iter().map(|source| {
+            let skipped: Vec<String> = source.skipped_at_rules.iter()
+                .map(|(name, count)| format!("@{} x{}", name, count))
+                .collect();
+            [source.source_name.clone().unwrap_or_else(|| "(unnamed)".to_string()),
+             source.rules.to_string(),
+             source.declarations.to_string(),
+             source.unknown_properties.to_string(),
+             source.invalid_values.to_string(),
+             source.unsupported_selectors.to_string(),
+             match skipped.is_empty() {
+                 true => "0".to_string(),
+                 false => format!("{} ({})", source.skipped_at_rule_count(), skipped.join(", ")),
+             }]
+        }).collect();
+        let mut widths = headings.map(str::len);
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.len());
+            }
+        }
+        // names and at-rule lists are left-aligned, and counts right-aligned
+        let write_row = |f: &mut fmt::Formatter, cells: &[&str]| {
+            let mut line = String::new();
+            for (i, (cell, width)) in cells.iter().zip(widths).enumerate() {
+                match i {
+                    0 => { let _ = write!(line, "{:<width$}", cell, width = width); }
+                    6 => { let _ = write!(line, "  {}", cell); }
+                    _ => { let _ = write!(line, "  {:>width$}", cell, width = width); }
+                }
+            }
+            writeln!(f, "{}", line.trim_end())
+        };
+        write_row(f, &headings)?;
+        for row in &rows {
+            write_row(f, &row.each_ref().map(String::as_str))?;
+        }
+        Ok(())
+    }
+}
@@ -1,10 +1,17 @@
+use crate::engine::{EngineError, Limit, Limits};
+use crate::media::MediaQueryList;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::fmt;
+use std::sync::Arc;
+
 // a simple selector can include a tag name, an ID prefixed by '#', any number of class
 // names prefixed by '.', or some combination of the above. If the tag name is empty
 // or '*' then it is a “universal selector” that can match any tag.
 
 // a selector is either a simple selector or a chain of selectors with delimiter: ' ', '+', '>'
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Origin {
     UserAgent,
     User,
@@ -14,27 +21,121 @@ pub enum Origin {
 #[derive(Debug, Clone)]
 pub struct Stylesheet {
     pub rules: Vec<Rule>,
+    pub at_rules: Vec<AtRule>,
     pub origin: Origin,
+    // a cascade layer within the origin: for normal declarations higher layers win
+    // over lower ones whatever their specificity, and for !important ones lower
+    // layers win. Stylesheets in the same layer cascade in the order they're given.
+    pub layer: i32,
+    // the file name or URL the stylesheet came from, for messages
+    pub source_name: Option<String>,
+    // the names of at-rules left out for being inside `@media` blocks, for reports
+    pub dropped_at_rules: Vec<String>,
+    pub syntax_errors: Vec<SyntaxError>, // what the parser skipped, in source order
+    line_starts: Vec<usize>, // the byte offset of each line of the source
+}
+
+/// A piece of a stylesheet that doesn't parse, which the parser skipped to carry on
+/// with the rest, as CSS's error handling has it: a declaration up to its `;` or the
+/// end of its block, or a rule along with its whole block.
+///
+/// https://www.w3.org/TR/css-syntax-3/#error-handling
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxError {
+    pub message: String, // what went wrong where the skipped part starts
+    pub span: Span, // what was skipped
 }
 
 #[derive(Debug, Clone)]
 pub struct Rule {
     pub selectors: Vec<Selector>,
     pub declarations: Vec<Declaration>,
+    pub span: Span, // from the first selector to the closing brace
+    // the conditions of the `@media` blocks the rule is in, outermost first; it
+    // applies only where all of them match
+    pub media: Vec<MediaQueryList>,
 }
 
-// ways to select an element, could be by its tag_name, id, or list of classes
+/// A range of bytes in a stylesheet's source.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize, // exclusive
+}
+
+/// A place in a stylesheet's source, as a reader would look for it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLocation {
+    pub source_name: Option<String>,
+    pub line: usize, // counting from 1
+    pub column: usize, // counting from 1, in bytes
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.source_name {
+            Some(ref name) => write!(f, "{}:{}:{}", name, self.line, self.column),
+            None => write!(f, "line {}, column {}", self.line, self.column),
+        }
+    }
+}
+
+impl Stylesheet {
+    /// Where the byte at `offset` in the source is.
+    pub fn location(&self, offset: usize) -> SourceLocation {
+        source_location(&self.line_starts, &self.source_name, offset)
+    }
+}
+
+fn source_location(line_starts: &[usize], source_name: &Option<String>, offset: usize) -> SourceLocation {
+    let line = line_starts.partition_point(|&start| start <= offset).max(1);
+    let line_start = line_starts.get(line - 1).copied().unwrap_or(0);
+    SourceLocation { source_name: source_name.clone(), line, column: offset - line_start + 1 }
+}
+
+// an at-rule such as `@page :first { margin: 20px; }`, kept for the stage that
+// understands it; statements like `@charset "utf-8";` have no declarations
 #[derive(Debug, Clone)]
+pub struct AtRule {
+    pub name: String, // without the '@'
+    pub prelude: String, // everything between the name and the block, trimmed
+    // for the at-rules in `DECLARATION_AT_RULES`, what their block declares
+    pub declarations: Vec<Declaration>,
+    pub block: Option<String>, // the block as written, braces and all, unless it's a statement
+}
+
+// the at-rules whose blocks hold declarations, like `@page { margin: 1in }`; the
+// blocks of other at-rules hold rules or other at-rules
+const DECLARATION_AT_RULES: &[&str] = &["page", "font-face", "counter-style", "property", "viewport"];
+
+// ways to select an element, could be by its tag_name, id, or list of classes
+#[derive(Debug, Clone, PartialEq)]
 pub struct SimpleSelector {
     pub tag_name: Option<String>,
     pub id: Option<String>,
-    pub class: Vec<String>
+    pub class: Vec<String>,
+    pub pseudo_classes: Vec<String>, // `hover` for `:hover`
+    pub pseudo_element: Option<String> // `before` for `::before`
 }
 
-// types of selector, for now just the atomic simple selector is implemented
-#[derive(Debug, Clone)]
+// types of selector: a simple selector on its own, or a chain of them joined by
+// combinators, which also say where the element is in the tree
+#[derive(Debug, Clone, PartialEq)]
 pub enum Selector {
-    Simple(SimpleSelector)
+    Simple(SimpleSelector),
+    // `nav > ul a`: the first simple selector, then each of the rest with the
+    // combinator joining it to the one before; the last is the subject
+    Compound(SimpleSelector, Vec<(Combinator, SimpleSelector)>)
+}
+
+/// How a selector relates the element matched by the simple selector to its right
+/// to the one matched by the simple selector to its left.
+///
+/// https://www.w3.org/TR/selectors-4/#combinators
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Combinator {
+    Descendant, // whitespace: any ancestor
+    Child, // `>`: the parent
 }
 
 // paired with a selector to specify what properties of selected DOM nodes to apply
@@ -43,23 +144,97 @@ pub struct Declaration {
     pub name: String, // name of property
     pub value: Value, // value set to this property
     pub important: bool, // !important flag
+    pub span: Span, // from the property name to the semicolon
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Keyword(String),
     Length(f32, Unit),
+    Number(f32), // unitless, e.g. `font-weight: 700`
     ColorValue(Color),
+    Str(String), // a quoted string, without the quotes
+    Function(String, Vec<Value>), // name and comma-separated arguments, e.g. `counter(item)`
+    List(Vec<Value>), // several space-separated components
+    // several comma-separated items, e.g. `box-shadow: 1px 1px red, 2px 2px blue`,
+    // each shaped like a value without commas; an item with no components (as
+    // from a trailing comma) is an empty `List`, which no property accepts
+    CommaList(Vec<Value>),
     Inherit,
     // insert more values as required
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Unit {
     Px,
+    Percent,
+    Vw, // 1% of the viewport width
+    Vh,
+    Vmin, // 1% of the smaller viewport dimension
+    Vmax,
+    Em, // the element's font size, or for `font-size` the parent's
+    Rem, // the root element's font size
+    // absolute lengths, at 96px to the inch
+    In,
+    Cm,
+    Mm,
+    Q, // a quarter of a millimeter
+    Pt, // 1/72 of an inch
+    Pc, // 12pt
+    Fr, // a share of the free space in a grid container, for track sizes only
     // insert more units as required
 }
 
+impl Unit {
+    /// The unit written after a number as `name`, like `px` or `%`, in any case.
+    pub fn from_name(name: &str) -> Option<Unit> {
+        const UNITS: [Unit; 15] = [Unit::Px, Unit::Percent, Unit::Vw, Unit::Vh, Unit::Vmin, Unit::Vmax,
+                                   Unit::Em, Unit::Rem, Unit::In, Unit::Cm, Unit::Mm, Unit::Q, Unit::Pt, Unit::Pc,
+                                   Unit::Fr];
+        UNITS.into_iter().find(|unit| unit.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Unit::Px => "px",
+            Unit::Percent => "%",
+            Unit::Vw => "vw",
+            Unit::Vh => "vh",
+            Unit::Vmin => "vmin",
+            Unit::Vmax => "vmax",
+            Unit::Em => "em",
+            Unit::Rem => "rem",
+            Unit::In => "in",
+            Unit::Cm => "cm",
+            Unit::Mm => "mm",
+            Unit::Q => "q",
+            Unit::Pt => "pt",
+            Unit::Pc => "pc",
+            Unit::Fr => "fr",
+        }
+    }
+
+    /// The length of `n` of this unit in px, or `None` if the unit is relative to
+    /// something, like the viewport or the font size, rather than absolute.
+    ///
+    /// https://www.w3.org/TR/css-values-4/#absolute-lengths
+    ///
+    /// An inch is 96px, so a 72pt box is exactly 72 points wide in a PDF.
+    pub fn absolute_px(self, n: f32) -> Option<f32> {
+        let per_inch = match self {
+            Unit::Px => return Some(n),
+            Unit::In => 1.0,
+            Unit::Cm => 2.54,
+            Unit::Mm => 25.4,
+            Unit::Q => 101.6,
+            Unit::Pt => 72.0,
+            Unit::Pc => 6.0,
+            Unit::Percent | Unit::Vw | Unit::Vh | Unit::Vmin | Unit::Vmax | Unit::Em | Unit::Rem | Unit::Fr => return None,
+        };
+        Some(n * 96.0 / per_inch)
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Color {
     pub r: u8,
@@ -72,10 +247,52 @@ impl Value {
     /// Convert a value to pixels
     pub fn to_px(&self) -> f32 {
         match *self {
-            Value::Length(f, Unit::Px) => f,
+            Value::Length(f, unit) => unit.absolute_px(f).unwrap_or(0.0),
+            Value::Function(..) => self.math_function_px(0.0).unwrap_or(0.0),
             _ => 0.0,  // Return 0 for non-length values
         }
     }
+
+    /// Convert a value to pixels, resolving percentages against `reference`
+    pub fn to_px_of(&self, reference: f32) -> f32 {
+        match *self {
+            Value::Length(f, Unit::Percent) => f * reference / 100.0,
+            Value::Function(..) => self.math_function_px(reference).unwrap_or(0.0),
+            _ => self.to_px(),
+        }
+    }
+
+    /// Evaluate `min()`, `max()` or `clamp()` of lengths in px and percentages of
+    /// `reference`, which may nest; `None` for any other value.
+    ///
+    /// https://www.w3.org/TR/css-values-4/#comp-func
+    ///
+    /// `clamp(min, preferred, max)` is `max(min, min(preferred, max))`, so a minimum
+    /// above the maximum wins.
+    pub fn math_function_px(&self, reference: f32) -> Option<f32> {
+        let (name, arguments) = match self {
+            Value::Function(name, arguments) if is_math_function(name) => (name, arguments),
+            _ => return None
+        };
+        let px = |value: &Value| match *value {
+            Value::Length(f, Unit::Percent) => Some(f * reference / 100.0),
+            Value::Length(f, unit) => unit.absolute_px(f),
+            Value::Number(0.0) => Some(0.0),
+            Value::Function(..) => value.math_function_px(reference),
+            _ => None
+        };
+        let arguments = arguments.iter().map(px).collect::<Option<Vec<f32>>>()?;
+        match (name.as_str(), arguments.as_slice()) {
+            ("min", [first, rest @ ..]) => Some(rest.iter().fold(*first, |a, &b| a.min(b))),
+            ("max", [first, rest @ ..]) => Some(rest.iter().fold(*first, |a, &b| a.max(b))),
+            ("clamp", &[min, preferred, max]) => Some(preferred.min(max).max(min)),
+            _ => None
+        }
+    }
+}
+
+fn is_math_function(name: &str) -> bool {
+    matches!(name, "min" | "max" | "clamp")
 }
 
 pub type Specificity = (usize, usize, usize);
@@ -83,40 +300,239 @@ pub type Specificity = (usize, usize, usize);
 impl Selector {
     // decides which style overrides another if conflict
     pub fn specificity(&self) -> Specificity {
-        let Selector::Simple(ref simple) = *self;
-        let a = simple.id.iter().count();
-        let b = simple.class.len();
-        let c = simple.tag_name.iter().count();
-        return (a, b, c);
+        self.simple_selectors().fold((0, 0, 0), |(a, b, c), simple| {
+            (a + simple.id.iter().count(),
+             b + simple.class.len() + simple.pseudo_classes.len(),
+             c + simple.tag_name.iter().count() + simple.pseudo_element.iter().count())
+        })
+    }
+
+    /// The simple selector the element itself must match: the last one.
+    pub fn subject(&self) -> &SimpleSelector {
+        match self {
+            Selector::Simple(simple) => simple,
+            Selector::Compound(first, rest) => rest.last().map_or(first, |(_, simple)| simple),
+        }
+    }
+
+    /// The simple selectors in the selector, from left to right.
+    pub fn simple_selectors(&self) -> impl Iterator<Item = &SimpleSelector> {
+        let (first, rest) = match self {
+            Selector::Simple(simple) => (simple, &[][..]),
+            Selector::Compound(first, rest) => (first, rest.as_slice()),
+        };
+        std::iter::once(first).chain(rest.iter().map(|(_, simple)| simple))
     }
 }
 
+/// Parse a stylesheet. Parts of it that don't parse are skipped, and listed in its
+/// `syntax_errors`.
 pub fn parse(source: String, origin: Origin) -> Stylesheet {
-    let mut parser = Parser { pos: 0, input: source };
-    return Stylesheet { rules: parser.parse_rules(), origin };
+    let mut parser = Parser::new(source);
+    parser.parse_stylesheet(usize::MAX, origin).expect("no stylesheet has more than usize::MAX rules")
+}
+
+/// Parse the declarations of a `style` attribute, such as `color: red; width: 10px`,
+/// leaving out those that don't parse.
+pub fn parse_declaration_list(source: &str) -> Vec<Declaration> {
+    let mut parser = Parser::new(source.to_string());
+    parser.parse_declaration_list()
+}
+
+/// Parse a single selector such as `nav > a.active:hover`, or `None` if that's not
+/// all the source holds.
+pub fn parse_selector(source: &str) -> Option<Selector> {
+    let mut parser = Parser::new(source.trim().to_string());
+    let selector = parser.parse_selector().ok()?;
+    (parser.eof() && !parser.input.is_empty()).then_some(selector)
+}
+
+// parse a stylesheet, giving up once it's larger or has more rules than `limits` allow
+pub fn parse_with_limits(source: String, origin: Origin, limits: &Limits) -> Result<Stylesheet, EngineError> {
+    Limits::check(Limit::InputBytes, source.len(), limits.max_input_bytes)?;
+    let mut parser = Parser::new(source);
+    parser.parse_stylesheet(limits.max_css_rules, origin)
+}
+
+/// The URLs of the stylesheet's `@import` rules, in order, from either the
+/// `@import "a.css"` or the `@import url(a.css)` form. Media queries after the URL
+/// are ignored, so every import applies.
+pub fn import_urls(stylesheet: &Stylesheet) -> Vec<String> {
+    stylesheet.at_rules.iter()
+        .filter(|rule| rule.name == "import")
+        .filter_map(|rule| {
+            let prelude = rule.prelude.as_str();
+            let inner = match prelude.get(..4) {
+                Some(url) if url.eq_ignore_ascii_case("url(") => prelude[4..].split(')').next()?.trim(),
+                _ => prelude
+            };
+            let url = match inner.chars().next()? {
+                quote @ ('"' | '\'') => inner[1..].split(quote).next()?,
+                _ => inner.split_whitespace().next()?
+            };
+            (!url.is_empty()).then(|| url.to_string())
+        })
+        .collect()
+}
+
+/// Parsed stylesheets kept for reuse across renders, keyed by a hash of their
+/// source and their origin. Holds at most `capacity` stylesheets, dropping the least
+/// recently used. Share it between threads behind a `Mutex`.
+pub struct StylesheetCache {
+    capacity: usize,
+    entries: Vec<CacheEntry>, // least recently used first
+    stats: CacheStats,
+}
+
+struct CacheEntry {
+    hash: u64,
+    origin: Origin,
+    source: String, // to tell a hash collision from a hit
+    limits: Limits, // a stylesheet within some limits may be over others
+    stylesheet: Arc<Stylesheet>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    pub evictions: usize,
+}
+
+impl StylesheetCache {
+    pub fn new(capacity: usize) -> StylesheetCache {
+        StylesheetCache { capacity, entries: Vec::new(), stats: CacheStats::default() }
+    }
+
+    /// Return the cached stylesheet for this source and origin, parsed within `limits`,
+    /// parsing it on a miss.
+    pub fn get_or_parse(&mut self, source: &str, origin: Origin, limits: &Limits) -> Result<Arc<Stylesheet>, EngineError> {
+        let mut hasher = DefaultHasher::new();
+        source.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let found = self.entries.iter()
+            .position(|e| e.hash == hash && e.origin == origin && e.source == source && e.limits == *limits);
+        if let Some(i) = found {
+            self.stats.hits += 1;
+            let entry = self.entries.remove(i);
+            let stylesheet = entry.stylesheet.clone();
+            self.entries.push(entry);
+            return Ok(stylesheet);
+        }
+
+        self.stats.misses += 1;
+        let stylesheet = Arc::new(parse_with_limits(source.to_string(), origin, limits)?);
+        if self.capacity == 0 {
+            return Ok(stylesheet);
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.remove(0);
+            self.stats.evictions += 1;
+        }
+        self.entries.push(CacheEntry { hash, origin, source: source.to_string(), limits: limits.clone(), stylesheet: stylesheet.clone() });
+        Ok(stylesheet)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
 }
 
 // Create default user agent stylesheet with basic HTML defaults
 pub fn default_user_agent_stylesheet() -> Stylesheet {
-    let css = "html, body { display: block; } head { display: none; } div, p, h1, h2, h3, h4, h5, h6 { display: block; } span, a, em, strong { display: inline; } script, style { display: none; }".to_string();
+    let css = "html, body { display: block; } head { display: none; } div, p, h1, h2, h3, h4, h5, h6, pre { display: block; } pre { white-space: pre; } span, a, em, strong, b, i { display: inline; } b, strong { font-weight: bold; } i, em { font-style: italic; } area, base, basefont, datalist, link, meta, noembed, noframes, param, rp, script, style, template, title { display: none; } ol, ul, li { display: block; } ol, ul { counter-reset: list-item; } li { counter-increment: list-item; } table { display: table; } thead, tbody, tfoot { display: table-row-group; } tr { display: table-row; } td, th { display: table-cell; } th { font-weight: bold; text-align: center; } center { display: block; text-align: center; } u, ins { text-decoration: underline; } s, strike, del { text-decoration: line-through; } big { font-size: larger; } small { font-size: smaller; } :focus { outline: 2px solid #005fcc; } input, button, select, textarea { display: inline-block; font-size: 13.333px; color: #000000; border: 1px solid #767676; padding: 1px 2px; background-color: #ffffff; } button { padding: 1px 6px; background-color: #efefef; } select { padding: 1px 20px 1px 4px; } textarea { white-space: pre-wrap; } ::placeholder { color: #757575; }".to_string();
     
     parse(css, Origin::UserAgent)
 }
 
+// Parsing fails with a message saying what was wrong where it stopped, and the
+// rule or declaration it was in is then skipped.
+type Parsed<T> = Result<T, String>;
+
+// what to skip after a parse error
+#[derive(Clone, Copy, PartialEq)]
+enum Skip {
+    Declaration, // up to and including its `;`
+    Rule, // up to the end of its block
+}
+
 struct Parser {
     pos: usize,
-    input: String
+    input: String,
+    errors: Vec<SyntaxError>
 }
 
 impl Parser {
+    fn new(input: String) -> Parser {
+        Parser { pos: 0, input, errors: Vec::new() }
+    }
+
     // return true if all chars in input consumed
     fn eof(&self) -> bool {
         self.pos >= self.input.len()
     }
 
-    fn expect_char(&mut self, c: char) {
-        if self.consume_char() != c {
-            panic!("Expected {:?} at byte {} but it was not found", c, self.pos);
+    fn expect_char(&mut self, c: char) -> Parsed<()> {
+        match !self.eof() && self.next_char() == c {
+            true => { self.consume_char(); Ok(()) }
+            false => Err(self.unexpected(&format!("{:?}", c)))
+        }
+    }
+
+    // the message for finding something other than `expected` here
+    fn unexpected(&self, expected: &str) -> String {
+        match self.eof() {
+            true => format!("expected {} before the end", expected),
+            false => format!("expected {} but found {:?}", expected, self.next_char())
+        }
+    }
+
+    // skip what's left of a declaration or rule from `start`, where it failed to
+    // parse with `message`: a `}` closes the block it's in, and brackets and strings
+    // nested in it are skipped whole
+    fn recover(&mut self, start: usize, skip: Skip, message: String) {
+        self.pos = start;
+        self.skip_balanced(skip);
+        self.errors.push(SyntaxError { message, span: Span { start, end: self.pos } });
+    }
+
+    // skip to the end of a declaration or rule, as `recover` does
+    fn skip_balanced(&mut self, skip: Skip) {
+        let mut closers = Vec::new();
+        while !self.eof() {
+            match self.next_char() {
+                '"' | '\'' => {
+                    // an unterminated string runs to the end
+                    let _ = self.parse_string();
+                    continue;
+                }
+                ';' if closers.is_empty() && skip == Skip::Declaration => {
+                    self.consume_char();
+                    break;
+                }
+                '}' if closers.is_empty() => break,
+                '{' => closers.push('}'),
+                '(' => closers.push(')'),
+                '[' => closers.push(']'),
+                c if closers.last() == Some(&c) => {
+                    closers.pop();
+                    if c == '}' && closers.is_empty() && skip == Skip::Rule {
+                        self.consume_char();
+                        break;
+                    }
+                }
+                _ => {}
+            }
+            self.consume_char();
         }
     }
 
@@ -127,7 +543,7 @@ impl Parser {
     fn consume_char(&mut self) -> char {
         let c = self.next_char();
         self.pos += c.len_utf8();
-        return c;
+        c
     }
 
     fn consume_while(&mut self, test: impl Fn(char) -> bool) -> String {
@@ -135,7 +551,7 @@ impl Parser {
         while !self.eof() && test(self.next_char()) {
             result.push(self.consume_char());
         }
-        return result;
+        result
     }
 
     fn consume_whitespace(&mut self) {
@@ -147,56 +563,198 @@ impl Parser {
         self.consume_while(valid_identifier_char)
     }
 
-    fn parse_value(&mut self) -> Value {
-        match self.next_char() {
-            '0'..'9' => self.parse_length(),
-            '#' => self.parse_color(),
+    fn parse_value(&mut self) -> Parsed<Value> {
+        Ok(match self.next_char() {
+            '0'..='9' => self.parse_length()?,
+            '-' if self.input[self.pos + 1..].starts_with(|c: char| c.is_ascii_digit()) => self.parse_length()?,
+            '#' => self.parse_color()?,
+            '"' | '\'' => Value::Str(self.parse_string()?),
+            // the separator in values like `grid-column: 1 / 3`
+            '/' => {
+                self.consume_char();
+                Value::Keyword("/".to_string())
+            }
             _ => {
                 let keyword = self.parse_identifier();
+                if !self.eof() && self.next_char() == '(' {
+                    let name = keyword.to_ascii_lowercase();
+                    if name == "url" {
+                        return Ok(Value::Function(name, vec![Value::Str(self.parse_url()?)]));
+                    }
+                    let arguments = self.parse_arguments()?;
+                    return Ok(match color_function(&name, &arguments) {
+                        Some(color) => Value::ColorValue(color),
+                        None => Value::Function(name, arguments)
+                    });
+                }
+                // a named color stays a keyword here, since it may as well be a name
+                // of something else, like the counter of `counter-reset: teal`;
+                // `resolve_named_colors` turns it into a color where it is one
                 match keyword.as_str() {
                     "inherit" => Value::Inherit,
                     _ => Value::Keyword(keyword)
                 }
             }
+        })
+    }
+
+    // parse a declaration's value: comma-separated items of space-separated components
+    fn parse_value_list(&mut self) -> Parsed<Value> {
+        let mut items = vec![self.parse_components(&[';', '!', '}'])?];
+        while !self.eof() && self.next_char() == ',' {
+            self.consume_char();
+            items.push(self.parse_components(&[';', '!', '}'])?);
         }
+        Ok(match items.len() {
+            1 => items.remove(0),
+            _ => Value::CommaList(items)
+        })
     }
 
-    fn parse_length(&mut self) -> Value {
-        Value::Length(self.parse_float(), self.parse_unit())
+    // parse space-separated components up to a comma or one of `terminators`, or the
+    // end of the source if `terminators` includes `}`, since that closes every block
+    fn parse_components(&mut self, terminators: &[char]) -> Parsed<Value> {
+        let mut values = Vec::new();
+        loop {
+            self.consume_whitespace();
+            if self.eof() {
+                if terminators.contains(&'}') {
+                    break;
+                }
+                return Err(self.unexpected(&format!("{:?}", terminators[0])));
+            }
+            match self.next_char() {
+                ',' => break,
+                c if terminators.contains(&c) => break,
+                _ => {
+                    let start = self.pos;
+                    values.push(self.parse_value()?);
+                    if self.pos == start {
+                        return Err(self.unexpected("a value"));
+                    }
+                }
+            }
+        }
+        Ok(match values.len() {
+            1 => values.remove(0),
+            _ => Value::List(values)
+        })
     }
 
-    fn parse_float(&mut self) -> f32 {
-        self.consume_while(|c| matches!(c, '0'..'9' | '.')).parse().unwrap()
+    // parse what's inside `url(...)`, quoted or not, with the parentheses
+    fn parse_url(&mut self) -> Parsed<String> {
+        self.expect_char('(')?;
+        self.consume_whitespace();
+        let url = match self.starts_with("\"") || self.starts_with("'") {
+            true => self.parse_string()?,
+            false => self.consume_while(|c| c != ')' && !c.is_whitespace()),
+        };
+        self.consume_whitespace();
+        self.expect_char(')')?;
+        Ok(url)
     }
 
-    fn parse_unit(&mut self) -> Unit {
-        match &*self.parse_identifier().to_ascii_lowercase() {
-            "px" => Unit::Px,
-            other => panic!("unit '{}' not recognized", other)
+    // parse a quoted string, with backslash escapes of the quote and of backslash itself
+    fn parse_string(&mut self) -> Parsed<String> {
+        let quote = self.consume_char();
+        let mut value = String::new();
+        loop {
+            if self.eof() {
+                return Err("expected the end of a string before the end".to_string());
+            }
+            match self.consume_char() {
+                '\\' if !self.eof() => value.push(self.consume_char()),
+                c if c == quote => break,
+                c => value.push(c)
+            }
         }
+        Ok(value)
     }
 
-    fn parse_hex_pair(&mut self) -> u8 {
-        let s = &self.input[self.pos .. self.pos+2];
-        self.pos += 2;
-        u8::from_str_radix(s, 16).unwrap()
+    // parse `(arg, arg, ...)` after a function name, where an argument may have
+    // several space-separated components, like `drop-shadow(1px 1px red)`
+    fn parse_arguments(&mut self) -> Parsed<Vec<Value>> {
+        self.expect_char('(')?;
+        let mut arguments = Vec::new();
+        self.consume_whitespace();
+        if self.starts_with(")") {
+            self.consume_char();
+            return Ok(arguments);
+        }
+        loop {
+            arguments.push(self.parse_components(&[')'])?);
+            // the components end at a comma or the closing parenthesis
+            if self.consume_char() == ')' {
+                break;
+            }
+        }
+        Ok(arguments)
     }
 
-    fn parse_color(&mut self) -> Value {
-        self.expect_char('#');
-        Value::ColorValue(
-            Color {
-                r: self.parse_hex_pair(),
-                g: self.parse_hex_pair(),
-                b: self.parse_hex_pair(),
-                a: 255
-            }
-        )
+    fn parse_length(&mut self) -> Parsed<Value> {
+        let start = self.pos;
+        let f = self.parse_float()?;
+        if self.eof() || !(self.starts_with("%") || valid_identifier_char(self.next_char())) {
+            return Ok(Value::Number(f));
+        }
+        Ok(match self.parse_unit() {
+            Some(unit) => Value::Length(f, unit),
+            // an unknown unit like `10ppx` is kept as written, for validation to reject
+            None => Value::Keyword(self.input[start..self.pos].to_string())
+        })
+    }
+
+    fn parse_float(&mut self) -> Parsed<f32> {
+        let start = self.pos;
+        let sign = if self.starts_with("-") { self.consume_char(); -1.0 } else { 1.0 };
+        match self.consume_while(|c| matches!(c, '0'..='9' | '.')).parse::<f32>() {
+            Ok(f) => Ok(sign * f),
+            Err(_) => Err(format!("invalid number {:?}", &self.input[start..self.pos]))
+        }
+    }
+
+    fn parse_unit(&mut self) -> Option<Unit> {
+        if self.starts_with("%") {
+            self.consume_char();
+            return Some(Unit::Percent);
+        }
+        Unit::from_name(&self.parse_identifier())
+    }
+
+    // parse `digits` hex digits as one channel, a single digit standing for itself
+    // twice, as the `f` of `#fff` does for `ff`
+    fn parse_hex_channel(&mut self, digits: usize) -> u8 {
+        let s = &self.input[self.pos .. self.pos + digits];
+        self.pos += digits;
+        let value = u8::from_str_radix(s, 16).expect("the digits were checked to be hex");
+        if digits == 1 { value * 17 } else { value }
+    }
+
+    // parse `#rrggbb`, or `#rrggbbaa`, which is how colors with alpha are serialized,
+    // or their short forms `#rgb` and `#rgba`
+    fn parse_color(&mut self) -> Parsed<Value> {
+        self.expect_char('#')?;
+        let length = self.input[self.pos..].chars().take_while(char::is_ascii_hexdigit).count();
+        if ![3, 4, 6, 8].contains(&length) {
+            let digits = self.consume_while(valid_identifier_char);
+            return Err(format!("invalid color #{}", digits));
+        }
+        let digits = if length == 3 || length == 4 { 1 } else { 2 };
+        let mut color = Color {
+            r: self.parse_hex_channel(digits),
+            g: self.parse_hex_channel(digits),
+            b: self.parse_hex_channel(digits),
+            a: 255
+        };
+        if length == 4 || length == 8 {
+            color.a = self.parse_hex_channel(digits);
+        }
+        Ok(Value::ColorValue(color))
     }
 
     // parse a simple selector `type#id.class1.class2.class3`
     fn parse_simple_selector(&mut self) -> SimpleSelector {
-        let mut selector = SimpleSelector { tag_name: None, id: None, class: Vec::new() };
+        let mut selector = SimpleSelector { tag_name: None, id: None, class: Vec::new(), pseudo_classes: Vec::new(), pseudo_element: None };
         while !self.eof() {
             match self.next_char() {
                 '#' => {
@@ -211,38 +769,104 @@ impl Parser {
                     // universal selector
                     self.consume_char();
                 }
+                ':' => {
+                    // `::before`, or the legacy single-colon form `:before`; any other
+                    // name after a single colon is a pseudo-class
+                    self.consume_char();
+                    let double_colon = self.starts_with(":");
+                    if double_colon {
+                        self.consume_char();
+                    }
+                    let name = self.parse_identifier().to_ascii_lowercase();
+                    let legacy = ["before", "after", "first-line", "first-letter"];
+                    if double_colon || legacy.contains(&name.as_str()) {
+                        selector.pseudo_element = Some(name);
+                    } else if !self.eof() && self.next_char() == '(' {
+                        // a functional pseudo-class like `:lang(en)`, kept with its
+                        // argument as its name, so that one we don't support never matches
+                        self.consume_char();
+                        let argument = self.consume_while(|c| c != ')');
+                        if !self.eof() {
+                            self.consume_char();
+                        }
+                        selector.pseudo_classes.push(format!("{}({})", name, argument.trim().to_ascii_lowercase()));
+                    } else {
+                        selector.pseudo_classes.push(name);
+                    }
+                }
                 c if valid_identifier_char(c) => {
                     selector.tag_name = Some(self.parse_identifier());
                 }
                 _ => break,
             }
         }
-        return selector;
+        selector
+    }
+
+    // parse simple selectors joined by combinators, like `nav > ul a`
+    fn parse_selector(&mut self) -> Parsed<Selector> {
+        let start = self.pos;
+        let first = self.parse_simple_selector();
+        if self.pos == start {
+            return Err(self.unexpected("a selector"));
+        }
+        let mut rest = Vec::new();
+        loop {
+            self.consume_whitespace();
+            if self.eof() {
+                break;
+            }
+            // anything else that can follow whitespace ends the selector
+            let combinator = match self.next_char() {
+                '>' => {
+                    self.consume_char();
+                    self.consume_whitespace();
+                    Combinator::Child
+                }
+                c if starts_simple_selector(c) => Combinator::Descendant,
+                _ => break,
+            };
+            if self.eof() || !starts_simple_selector(self.next_char()) {
+                return Err(self.unexpected("a selector after a combinator"));
+            }
+            rest.push((combinator, self.parse_simple_selector()));
+        }
+        Ok(match rest.is_empty() {
+            true => Selector::Simple(first),
+            false => Selector::Compound(first, rest),
+        })
     }
 
     // parse a comma-separated list of selectors
-    fn parse_selectors(&mut self) -> Vec<Selector> {
+    fn parse_selectors(&mut self) -> Parsed<Vec<Selector>> {
         let mut selectors = Vec::new();
         loop {
-            selectors.push(Selector::Simple(self.parse_simple_selector()));
+            selectors.push(self.parse_selector()?);
             self.consume_whitespace();
-            match self.next_char() {
-                ',' => { self.consume_char(); self.consume_whitespace(); }
-                '{' => break, // start of declarations
-                c => panic!("Unexpected char {} in selector list", c)
+            match self.eof() {
+                false if self.next_char() == ',' => { self.consume_char(); self.consume_whitespace(); }
+                false if self.next_char() == '{' => break, // start of declarations
+                _ => return Err(self.unexpected("'{' or ',' after a selector"))
             }
         }
         // return selectors with highest specificity first, used in matching
-        selectors.sort_by(|a, b| b.specificity().cmp(&a.specificity()));
-        return selectors;
+        selectors.sort_by_key(|s| std::cmp::Reverse(s.specificity()));
+        Ok(selectors)
     }
 
-    fn parse_declaration(&mut self) -> Declaration {
+    fn parse_declaration(&mut self) -> Parsed<Declaration> {
+        let start = self.pos;
         let name = self.parse_identifier();
+        if name.is_empty() {
+            return Err(self.unexpected("a property name"));
+        }
         self.consume_whitespace();
-        self.expect_char(':');
+        self.expect_char(':')?;
         self.consume_whitespace();
-        let value = self.parse_value();
+        let value = zero_lengths_as_px(&name, self.parse_value_list()?);
+        if value == Value::List(Vec::new()) {
+            return Err(self.unexpected("a value"));
+        }
         self.consume_whitespace();
         
         // Check for !important
@@ -254,50 +878,1241 @@ impl Parser {
             false
         };
         
-        self.expect_char(';');
-        return Declaration { name, value, important }
+        // the last declaration of a block needn't end with a semicolon
+        if !(self.eof() || self.starts_with("}")) {
+            self.expect_char(';')?;
+        }
+        Ok(Declaration { name, value, important, span: Span { start, end: self.pos } })
     }
 
     fn starts_with(&self, s: &str) -> bool {
         self.input[self.pos..].starts_with(s)
     }
 
-    fn parse_declarations(&mut self) -> Vec<Declaration> {
-        self.expect_char('{');
+    // parse `{ <declarations> }`; the end of the source closes the block too
+    fn parse_declarations(&mut self) -> Parsed<Vec<Declaration>> {
+        self.expect_char('{')?;
+        let declarations = self.parse_declaration_list();
+        if !self.eof() {
+            self.consume_char();
+        }
+        Ok(declarations)
+    }
+
+    // parse declarations up to the `}` ending the block they're in, skipping any
+    // that don't parse
+    fn parse_declaration_list(&mut self) -> Vec<Declaration> {
         let mut declarations = Vec::new();
         loop {
             self.consume_whitespace();
-            if self.next_char() == '}' {
-                self.consume_char();
+            if self.eof() || self.starts_with("}") {
                 break;
             }
-            declarations.push(self.parse_declaration());
+            // a stray semicolon is an empty declaration
+            if self.starts_with(";") {
+                self.consume_char();
+                continue;
+            }
+            let start = self.pos;
+            match self.parse_declaration() {
+                Ok(declaration) => declarations.push(declaration),
+                Err(message) => self.recover(start, Skip::Declaration, message)
+            }
         }
-        return declarations;
+        declarations
     }
 
     // parse a rule set: `<selectors> { <declarations> }`
-    fn parse_rule(&mut self) -> Rule {
-        Rule {
-            selectors: self.parse_selectors(),
-            declarations: self.parse_declarations()
+    fn parse_rule(&mut self) -> Parsed<Rule> {
+        let start = self.pos;
+        let selectors = self.parse_selectors()?;
+        let declarations = self.parse_declarations()?;
+        Ok(Rule { selectors, declarations, span: Span { start, end: self.pos }, media: Vec::new() })
+    }
+
+    // parse `@name prelude { <declarations> }` or `@name prelude;`; the block of an
+    // at-rule that holds something else, like the rules of `@supports`, is kept as
+    // written without being parsed
+    fn parse_at_rule(&mut self) -> Parsed<AtRule> {
+        self.expect_char('@')?;
+        let name = self.parse_identifier().to_ascii_lowercase();
+        let prelude = self.consume_while(|c| c != '{' && c != ';').trim().to_string();
+        if !self.starts_with("{") {
+            // a statement, ended by a semicolon or the end of the source
+            self.consume_while(|c| c == ';');
+            return Ok(AtRule { name, prelude, declarations: Vec::new(), block: None });
         }
+        let start = self.pos;
+        let declarations = match DECLARATION_AT_RULES.contains(&name.as_str()) {
+            true => self.parse_declarations()?,
+            false => { self.skip_balanced(Skip::Rule); Vec::new() }
+        };
+        let block = Some(self.input[start..self.pos].to_string());
+        Ok(AtRule { name, prelude, declarations, block })
     }
 
-    // parse a list of rules to create a stylesheet
-    fn parse_rules(&mut self) -> Vec<Rule> {
-        let mut rules = Vec::new();
+    // parse a list of rules and at-rules to create a stylesheet
+    fn parse_stylesheet(&mut self, max_rules: usize, origin: Origin) -> Result<Stylesheet, EngineError> {
+        let line_starts = std::iter::once(0).chain(self.input.match_indices('\n').map(|(i, _)| i + 1)).collect();
+        let mut stylesheet = Stylesheet {
+            rules: Vec::new(), at_rules: Vec::new(), origin, layer: 0, source_name: None,
+            dropped_at_rules: Vec::new(), syntax_errors: Vec::new(), line_starts
+        };
         loop {
             self.consume_whitespace();
             if self.eof() { break }
-            rules.push(self.parse_rule());
+            // a `}` without a block to close
+            if self.starts_with("}") {
+                let start = self.pos;
+                self.consume_char();
+                self.errors.push(SyntaxError { message: "unexpected '}'".to_string(), span: Span { start, end: self.pos } });
+                continue;
+            }
+            self.parse_statement(&mut stylesheet, &[], max_rules)?;
+        }
+        stylesheet.syntax_errors = std::mem::take(&mut self.errors);
+        Ok(stylesheet)
+    }
+
+    // parse a rule or at-rule into the stylesheet, inside `@media` blocks with the
+    // conditions in `media`; one that doesn't parse is skipped
+    fn parse_statement(&mut self, stylesheet: &mut Stylesheet, media: &[MediaQueryList], max_rules: usize) -> Result<(), EngineError> {
+        let start = self.pos;
+        if self.starts_with("@media") && !self.input[self.pos + 6..].starts_with(valid_identifier_char) {
+            self.pos += "@media".len();
+            let mut media = media.to_vec();
+            media.push(MediaQueryList::parse(&self.consume_while(|c| c != '{')));
+            if let Err(message) = self.expect_char('{') {
+                self.recover(start, Skip::Rule, message);
+                return Ok(());
+            }
+            loop {
+                self.consume_whitespace();
+                if self.eof() {
+                    break;
+                }
+                if self.next_char() == '}' {
+                    self.consume_char();
+                    break;
+                }
+                self.parse_statement(stylesheet, &media, max_rules)?;
+            }
+        } else if self.next_char() == '@' {
+            let at_rule = match self.parse_at_rule() {
+                Ok(at_rule) => at_rule,
+                Err(message) => {
+                    self.recover(start, Skip::Rule, message);
+                    return Ok(());
+                }
+            };
+            // other at-rules, such as `@page`, can't be made conditional
+            match media.is_empty() {
+                true => stylesheet.at_rules.push(at_rule),
+                false => stylesheet.dropped_at_rules.push(at_rule.name),
+            }
+        } else {
+            match self.parse_rule() {
+                Ok(mut rule) => {
+                    rule.media = media.to_vec();
+                    stylesheet.rules.push(rule);
+                }
+                Err(message) => self.recover(start, Skip::Rule, message)
+            }
         }
-        return rules;
+        let count = stylesheet.rules.len() + stylesheet.at_rules.len();
+        Limits::check(Limit::CssRules, count, max_rules)
     }
 }
 
 // test if current char matches the allowed chars
+// can `c` start a simple selector?
+fn starts_simple_selector(c: char) -> bool {
+    matches!(c, '#' | '.' | '*' | ':') || valid_identifier_char(c)
+}
+
 fn valid_identifier_char(c: char) -> bool {
     // TODO: Include U+00A0 and higher.
     matches!(c, 'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_')
-}
\ No newline at end of file
+}
+
+// a unitless `0` is a length of zero, for properties that take lengths but not
+// numbers, so that layout needn't tell `margin: 0` from `margin: 0px`
+fn zero_lengths_as_px(property: &str, value: Value) -> Value {
+    match value {
+        Value::Number(zero) if zero == 0.0 && takes_lengths(property) => Value::Length(0.0, Unit::Px),
+        Value::List(values) if takes_lengths(property) => {
+            Value::List(values.into_iter().map(|value| zero_lengths_as_px(property, value)).collect())
+        }
+        value => value
+    }
+}
+
+// does the property take lengths, but not numbers?
+fn takes_lengths(property: &str) -> bool {
+    matches!(property_grammar(property), Some(Grammar::Length | Grammar::LengthOr(_) | Grammar::Lengths | Grammar::Position))
+}
+
+/// Does the property take colors, alone or among other components? Only in such a
+/// property is a named color keyword a color.
+pub fn takes_colors(property: &str) -> bool {
+    matches!(property_grammar(property),
+        Some(Grammar::Color | Grammar::Border | Grammar::Outline | Grammar::Background | Grammar::Layers(Grammar::Image)))
+}
+
+/// Replace the named color keywords in `value`, at any depth, by the colors they
+/// name, for the value of a property that `takes_colors`.
+pub fn resolve_named_colors(value: &mut Value) {
+    match value {
+        Value::Keyword(keyword) => {
+            if let Some(color) = named_color(keyword) {
+                *value = Value::ColorValue(color);
+            }
+        }
+        Value::List(values) | Value::CommaList(values) | Value::Function(_, values) => {
+            values.iter_mut().for_each(resolve_named_colors);
+        }
+        _ => {}
+    }
+}
+
+// the named colors of CSS, sorted by name, as `0xRRGGBB`
+//
+// https://www.w3.org/TR/css-color-4/#named-colors
+const NAMED_COLORS: &[(&str, u32)] = &[
+    ("aliceblue", 0xf0f8ff), ("antiquewhite", 0xfaebd7), ("aqua", 0x00ffff), ("aquamarine", 0x7fffd4),
+    ("azure", 0xf0ffff), ("beige", 0xf5f5dc), ("bisque", 0xffe4c4), ("black", 0x000000),
+    ("blanchedalmond", 0xffebcd), ("blue", 0x0000ff), ("blueviolet", 0x8a2be2), ("brown", 0xa52a2a),
+    ("burlywood", 0xdeb887), ("cadetblue", 0x5f9ea0), ("chartreuse", 0x7fff00), ("chocolate", 0xd2691e),
+    ("coral", 0xff7f50), ("cornflowerblue", 0x6495ed), ("cornsilk", 0xfff8dc), ("crimson", 0xdc143c),
+    ("cyan", 0x00ffff), ("darkblue", 0x00008b), ("darkcyan", 0x008b8b), ("darkgoldenrod", 0xb8860b),
+    ("darkgray", 0xa9a9a9), ("darkgreen", 0x006400), ("darkgrey", 0xa9a9a9), ("darkkhaki", 0xbdb76b),
+    ("darkmagenta", 0x8b008b), ("darkolivegreen", 0x556b2f), ("darkorange", 0xff8c00),
+    ("darkorchid", 0x9932cc), ("darkred", 0x8b0000), ("darksalmon", 0xe9967a), ("darkseagreen", 0x8fbc8f),
+    ("darkslateblue", 0x483d8b), ("darkslategray", 0x2f4f4f), ("darkslategrey", 0x2f4f4f),
+    ("darkturquoise", 0x00ced1), ("darkviolet", 0x9400d3), ("deeppink", 0xff1493), ("deepskyblue", 0x00bfff),
+    ("dimgray", 0x696969), ("dimgrey", 0x696969), ("dodgerblue", 0x1e90ff), ("firebrick", 0xb22222),
+    ("floralwhite", 0xfffaf0), ("forestgreen", 0x228b22), ("fuchsia", 0xff00ff), ("gainsboro", 0xdcdcdc),
+    ("ghostwhite", 0xf8f8ff), ("gold", 0xffd700), ("goldenrod", 0xdaa520), ("gray", 0x808080),
+    ("green", 0x008000), ("greenyellow", 0xadff2f), ("grey", 0x808080), ("honeydew", 0xf0fff0),
+    ("hotpink", 0xff69b4), ("indianred", 0xcd5c5c), ("indigo", 0x4b0082), ("ivory", 0xfffff0),
+    ("khaki", 0xf0e68c), ("lavender", 0xe6e6fa), ("lavenderblush", 0xfff0f5), ("lawngreen", 0x7cfc00),
+    ("lemonchiffon", 0xfffacd), ("lightblue", 0xadd8e6), ("lightcoral", 0xf08080), ("lightcyan", 0xe0ffff),
+    ("lightgoldenrodyellow", 0xfafad2), ("lightgray", 0xd3d3d3), ("lightgreen", 0x90ee90),
+    ("lightgrey", 0xd3d3d3), ("lightpink", 0xffb6c1), ("lightsalmon", 0xffa07a), ("lightseagreen", 0x20b2aa),
+    ("lightskyblue", 0x87cefa), ("lightslategray", 0x778899), ("lightslategrey", 0x778899),
+    ("lightsteelblue", 0xb0c4de), ("lightyellow", 0xffffe0), ("lime", 0x00ff00), ("limegreen", 0x32cd32),
+    ("linen", 0xfaf0e6), ("magenta", 0xff00ff), ("maroon", 0x800000), ("mediumaquamarine", 0x66cdaa),
+    ("mediumblue", 0x0000cd), ("mediumorchid", 0xba55d3), ("mediumpurple", 0x9370db),
+    ("mediumseagreen", 0x3cb371), ("mediumslateblue", 0x7b68ee), ("mediumspringgreen", 0x00fa9a),
+    ("mediumturquoise", 0x48d1cc), ("mediumvioletred", 0xc71585), ("midnightblue", 0x191970),
+    ("mintcream", 0xf5fffa), ("mistyrose", 0xffe4e1), ("moccasin", 0xffe4b5), ("navajowhite", 0xffdead),
+    ("navy", 0x000080), ("oldlace", 0xfdf5e6), ("olive", 0x808000), ("olivedrab", 0x6b8e23),
+    ("orange", 0xffa500), ("orangered", 0xff4500), ("orchid", 0xda70d6), ("palegoldenrod", 0xeee8aa),
+    ("palegreen", 0x98fb98), ("paleturquoise", 0xafeeee), ("palevioletred", 0xdb7093),
+    ("papayawhip", 0xffefd5), ("peachpuff", 0xffdab9), ("peru", 0xcd853f), ("pink", 0xffc0cb),
+    ("plum", 0xdda0dd), ("powderblue", 0xb0e0e6), ("purple", 0x800080), ("rebeccapurple", 0x663399),
+    ("red", 0xff0000), ("rosybrown", 0xbc8f8f), ("royalblue", 0x4169e1), ("saddlebrown", 0x8b4513),
+    ("salmon", 0xfa8072), ("sandybrown", 0xf4a460), ("seagreen", 0x2e8b57), ("seashell", 0xfff5ee),
+    ("sienna", 0xa0522d), ("silver", 0xc0c0c0), ("skyblue", 0x87ceeb), ("slateblue", 0x6a5acd),
+    ("slategray", 0x708090), ("slategrey", 0x708090), ("snow", 0xfffafa), ("springgreen", 0x00ff7f),
+    ("steelblue", 0x4682b4), ("tan", 0xd2b48c), ("teal", 0x008080), ("thistle", 0xd8bfd8),
+    ("tomato", 0xff6347), ("turquoise", 0x40e0d0), ("violet", 0xee82ee), ("wheat", 0xf5deb3),
+    ("white", 0xffffff), ("whitesmoke", 0xf5f5f5), ("yellow", 0xffff00), ("yellowgreen", 0x9acd32),
+];
+
+/// The color a keyword names, ASCII case-insensitively: one of the named colors, like
+/// `red` or `rebeccapurple`, or `transparent`.
+pub fn named_color(name: &str) -> Option<Color> {
+    let name = name.to_ascii_lowercase();
+    if name == "transparent" {
+        return Some(Color { r: 0, g: 0, b: 0, a: 0 });
+    }
+    let i = NAMED_COLORS.binary_search_by_key(&name.as_str(), |&(name, _)| name).ok()?;
+    let rgb = NAMED_COLORS[i].1;
+    Some(Color { r: (rgb >> 16) as u8, g: (rgb >> 8) as u8, b: rgb as u8, a: 255 })
+}
+
+// the color of a comma-separated `rgb(r, g, b)` or `rgba(r, g, b, a)`, with channels as
+// numbers or percentages and alpha as a 0..1 number or a percentage
+fn color_function(name: &str, arguments: &[Value]) -> Option<Color> {
+    fn channel(value: &Value, scale: f32) -> Option<u8> {
+        let f = match *value {
+            Value::Number(n) => n * scale,
+            Value::Length(p, Unit::Percent) => p * 2.55,
+            _ => return None,
+        };
+        Some(f.clamp(0.0, 255.0).round() as u8)
+    }
+    let (r, g, b, a) = match (name, arguments) {
+        ("rgb" | "rgba", [r, g, b]) => (r, g, b, None),
+        ("rgb" | "rgba", [r, g, b, a]) => (r, g, b, Some(a)),
+        _ => return None,
+    };
+    Some(Color {
+        r: channel(r, 1.0)?,
+        g: channel(g, 1.0)?,
+        b: channel(b, 1.0)?,
+        a: match a {
+            Some(a) => channel(a, 255.0)?,
+            None => 255,
+        },
+    })
+}
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Selector::Simple(simple) => write!(f, "{}", simple),
+            Selector::Compound(first, rest) => {
+                write!(f, "{}", first)?;
+                for (combinator, simple) in rest {
+                    match combinator {
+                        Combinator::Descendant => write!(f, " {}", simple)?,
+                        Combinator::Child => write!(f, " > {}", simple)?,
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Display for SimpleSelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.tag_name.is_none() && self.id.is_none() && self.class.is_empty() && self.pseudo_classes.is_empty() {
+            write!(f, "*")?;
+        }
+        if let Some(ref tag_name) = self.tag_name {
+            write!(f, "{}", tag_name)?;
+        }
+        if let Some(ref id) = self.id {
+            write!(f, "#{}", id)?;
+        }
+        for class in &self.class {
+            write!(f, ".{}", class)?;
+        }
+        for pseudo_class in &self.pseudo_classes {
+            write!(f, ":{}", pseudo_class)?;
+        }
+        if let Some(ref pseudo_element) = self.pseudo_element {
+            write!(f, "::{}", pseudo_element)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Keyword(k) => write!(f, "{}", k),
+            Value::Length(n, unit) => write!(f, "{}{}", n, unit.name()),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::ColorValue(c) if c.a == 255 => write!(f, "#{:02x}{:02x}{:02x}", c.r, c.g, c.b),
+            Value::ColorValue(c) => write!(f, "#{:02x}{:02x}{:02x}{:02x}", c.r, c.g, c.b, c.a),
+            Value::Str(s) => write!(f, "{:?}", s),
+            Value::Function(name, args) => {
+                write!(f, "{}(", name)?;
+                for (i, arg) in args.iter().enumerate() {
+                    write!(f, "{}{}", if i > 0 { ", " } else { "" }, arg)?;
+                }
+                write!(f, ")")
+            }
+            Value::List(values) => {
+                for (i, value) in values.iter().enumerate() {
+                    write!(f, "{}{}", if i > 0 { " " } else { "" }, value)?;
+                }
+                Ok(())
+            }
+            Value::CommaList(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    write!(f, "{}{}", if i > 0 { ", " } else { "" }, item)?;
+                }
+                Ok(())
+            }
+            Value::Inherit => write!(f, "inherit"),
+        }
+    }
+}
+
+// Minification: writing a stylesheet back out as compactly as it can be read in
+// again, for using the crate as a CSS tool and for comparing stylesheets by what
+// they say rather than how they're written.
+
+/// The stylesheet as short as it can be written: no whitespace that isn't needed,
+/// colors in their shortest hex form, zero lengths without units, only the last of
+/// a rule's declarations of each property, and no empty rules.
+///
+/// Parsing the result gives the same stylesheet again, except that zero lengths
+/// come back as numbers and named colors as hex. At-rules are written before the
+/// other rules, since the two are kept apart once parsed; the block of one that
+/// doesn't hold declarations, like `@supports` or `@keyframes`, is written as it
+/// was. The stylesheet's `layer`, like its origin, is set where it's loaded rather
+/// than written in it, so the stylesheet parsed back needs the same one given.
+pub fn minify(stylesheet: &Stylesheet) -> String {
+    let mut out = String::new();
+    for at_rule in &stylesheet.at_rules {
+        out.push('@');
+        out.push_str(&at_rule.name);
+        if !at_rule.prelude.is_empty() {
+            out.push(' ');
+            out.push_str(&at_rule.prelude);
+        }
+        match at_rule.block {
+            None => out.push(';'),
+            Some(_) if DECLARATION_AT_RULES.contains(&at_rule.name.as_str()) => {
+                minify_declarations(&at_rule.declarations, &mut out);
+            }
+            // what's inside isn't parsed, so it can only be written as it was
+            Some(ref block) => out.push_str(block),
+        }
+    }
+    // the `@media` blocks the last rule written is in, still open
+    let mut open: &[MediaQueryList] = &[];
+    for rule in stylesheet.rules.iter().filter(|rule| !rule.declarations.is_empty()) {
+        let shared = open.iter().zip(&rule.media).take_while(|(a, b)| a == b).count();
+        out.push_str(&"}".repeat(open.len() - shared));
+        for media in &rule.media[shared..] {
+            out.push_str("@media ");
+            out.push_str(&media.to_string());
+            out.push('{');
+        }
+        open = &rule.media;
+        let selectors: Vec<String> = rule.selectors.iter().map(Selector::to_string).collect();
+        out.push_str(&selectors.join(","));
+        minify_declarations(&rule.declarations, &mut out);
+    }
+    out.push_str(&"}".repeat(open.len()));
+    out
+}
+
+// write `{a:b;c:d;}`, keeping only the declaration of each property that wins the
+// cascade: the last `!important` one, or else the last. Every declaration keeps
+// its `;`, which the parser requires.
+fn minify_declarations(declarations: &[Declaration], out: &mut String) {
+    out.push('{');
+    for (i, declaration) in declarations.iter().enumerate() {
+        let same_property = |other: &&Declaration| other.name == declaration.name;
+        let overridden = declarations[i + 1..].iter().filter(same_property).any(|later| later.important || !declaration.important)
+            || !declaration.important && declarations[..i].iter().filter(same_property).any(|earlier| earlier.important);
+        if overridden {
+            continue;
+        }
+        out.push_str(&declaration.name);
+        out.push(':');
+        // named colors are written as hex, which is rarely longer
+        let mut value = declaration.value.clone();
+        if takes_colors(&declaration.name) {
+            resolve_named_colors(&mut value);
+        }
+        minify_value(&value, takes_lengths(&declaration.name), out);
+        if declaration.important {
+            out.push_str("!important");
+        }
+        out.push(';');
+    }
+    out.push('}');
+}
+
+// `zero_as_number` drops the unit of zero lengths, which the property parses back
+// as lengths; inside functions units are kept, since `hsl(0, 0%, 0%)` needs them
+fn minify_value(value: &Value, zero_as_number: bool, out: &mut String) {
+    use std::fmt::Write;
+    match value {
+        Value::Length(zero, _) if *zero == 0.0 && zero_as_number => out.push('0'),
+        Value::ColorValue(color) => {
+            let channels = [color.r, color.g, color.b, color.a];
+            let channels = if color.a == 255 { &channels[..3] } else { &channels[..] };
+            out.push('#');
+            // `#abc` for `#aabbcc`, when every channel is a doubled digit
+            let short = channels.iter().all(|channel| channel % 17 == 0);
+            for channel in channels {
+                let _ = match short {
+                    true => write!(out, "{:x}", channel / 17),
+                    false => write!(out, "{:02x}", channel),
+                };
+            }
+        }
+        Value::Str(string) => {
+            out.push('"');
+            for c in string.chars() {
+                if c == '"' || c == '\\' {
+                    out.push('\\');
+                }
+                out.push(c);
+            }
+            out.push('"');
+        }
+        Value::Function(name, arguments) => {
+            out.push_str(name);
+            out.push('(');
+            for (i, argument) in arguments.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                minify_value(argument, false, out);
+            }
+            out.push(')');
+        }
+        Value::List(values) => {
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    out.push(' ');
+                }
+                minify_value(value, zero_as_number, out);
+            }
+        }
+        Value::CommaList(items) => {
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                minify_value(item, zero_as_number, out);
+            }
+        }
+        value => {
+            let _ = write!(out, "{}", value);
+        }
+    }
+}
+
+// Validation: checking declarations against the properties the engine knows and
+// the values it understands for each. Unknown properties are kept (the cascade
+// ignores what nothing reads), while invalid values for known properties are
+// dropped, as CSS requires, so that the cascade falls back to other declarations.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WarningKind {
+    UnknownProperty,
+    InvalidValue,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CssWarning {
+    pub kind: WarningKind,
+    pub property: String,
+    pub value: String,
+    pub selector: String, // the rule's selectors, comma-separated
+    pub span: Span, // the declaration's bytes in the stylesheet source
+    pub location: SourceLocation, // where the declaration starts
+}
+
+impl CssWarning {
+    /// What's wrong with the declaration, without where it is.
+    pub fn problem(&self) -> String {
+        let problem = match self.kind {
+            WarningKind::UnknownProperty => "unknown property",
+            WarningKind::InvalidValue => "invalid value for",
+        };
+        format!("{} {:?} in `{} {{ {}: {} }}`", problem, self.property, self.selector, self.property, self.value)
+    }
+}
+
+impl fmt::Display for CssWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}", self.problem(), self.location)
+    }
+}
+
+// the kinds of value each known property accepts
+enum Grammar {
+    Length, // a length or percentage
+    LengthOr(&'static [&'static str]),
+    Lengths, // one to four lengths, for box edge shorthands
+    NumberOr(&'static [&'static str]),
+    LengthOrNumber,
+    Color,
+    Keywords(&'static [&'static str]),
+    Position, // one or two lengths or edge keywords, like `right 10px`
+    Transform, // `none`, or translations and scales, like `translate(10px, 0) scale(2)`
+    Outline, // a width, style and color, each optional and in any order, like `2px solid blue`
+    Border, // the same for every side of a box, like `1px solid #ccc`
+    Image, // `none`, `url(...)` or `linear-gradient(...)`
+    Layers(&'static Grammar), // a comma-separated value for each background layer
+    Background, // comma-separated layers like `url(a.png) no-repeat top left`, the last with a color
+    Pair(&'static Grammar), // one or two values, like `gap: 10px 20px`
+    Tracks, // `none`, or grid track sizes in px, percentages or fr, like `1fr 2fr 100px`
+    GridLine, // `auto`, a line number, or `span` and a number of tracks
+    GridPlacement, // a grid line, or a start and end line separated by `/`, like `1 / span 2`
+    Any, // not checked further
+}
+
+// every property the engine reads, with the values it understands for each
+const PROPERTIES: &[(&str, Grammar)] = {
+    use Grammar::*;
+    &[
+        ("display", Keywords(&["inline", "block", "inline-block", "table", "table-row-group",
+            "table-header-group", "table-footer-group", "table-row", "table-cell", "grid", "none"])),
+        ("color", Color),
+        ("background", Background),
+        ("background-color", Color),
+        ("background-image", Layers(&Image)),
+        ("background-position", Layers(&Position)),
+        ("background-repeat", Layers(&Keywords(BACKGROUND_REPEATS))),
+        ("background-attachment", Layers(&Keywords(BACKGROUND_ATTACHMENTS))),
+        ("content-visibility", Keywords(&["visible", "auto", "hidden"])),
+        ("contain-intrinsic-height", LengthOr(&["none", "auto"])),
+        ("contain-intrinsic-size", LengthOr(&["none", "auto"])),
+        ("background-clip", Keywords(&["border-box", "padding-box", "content-box"])),
+        ("border-color", Color),
+        ("border-collapse", Keywords(&["separate", "collapse"])),
+        ("border-spacing", Lengths),
+        ("grid-template-columns", Tracks),
+        ("grid-template-rows", Tracks),
+        ("grid-column", GridPlacement),
+        ("grid-row", GridPlacement),
+        ("grid-column-start", GridLine),
+        ("grid-column-end", GridLine),
+        ("grid-row-start", GridLine),
+        ("grid-row-end", GridLine),
+        ("gap", Pair(&LengthOr(&["normal"]))),
+        ("row-gap", LengthOr(&["normal"])),
+        ("column-gap", LengthOr(&["normal"])),
+        ("width", LengthOr(&["auto", "min-content", "max-content", "fit-content"])),
+        ("height", LengthOr(&["auto"])),
+        ("object-fit", Keywords(&["fill", "contain", "cover", "none", "scale-down"])),
+        ("object-position", Position),
+        ("transform", Transform),
+        ("transform-origin", Position),
+        ("margin", Lengths),
+        ("margin-top", LengthOr(&["auto"])),
+        ("margin-right", LengthOr(&["auto"])),
+        ("margin-bottom", LengthOr(&["auto"])),
+        ("margin-left", LengthOr(&["auto"])),
+        ("padding", Lengths),
+        ("padding-top", Length),
+        ("padding-right", Length),
+        ("padding-bottom", Length),
+        ("padding-left", Length),
+        ("border", Border),
+        ("border-width", Lengths),
+        ("border-top-width", Length),
+        ("border-right-width", Length),
+        ("border-bottom-width", Length),
+        ("border-left-width", Length),
+        ("outline", Outline),
+        ("outline-width", LengthOr(&["thin", "medium", "thick"])),
+        ("outline-style", Keywords(OUTLINE_STYLES)),
+        ("outline-color", Color),
+        ("outline-offset", Length),
+        ("font-size", LengthOr(&["xx-small", "x-small", "small", "medium", "large", "x-large", "xx-large",
+            "xxx-large", "larger", "smaller"])),
+        ("font-weight", NumberOr(&["normal", "bold", "bolder", "lighter"])),
+        ("font-style", Keywords(&["normal", "italic", "oblique"])),
+        ("font-family", Any),
+        ("line-height", LengthOrNumber),
+        ("vertical-align", LengthOr(&["baseline", "top", "middle", "bottom"])),
+        ("text-align", Keywords(&["left", "right", "center", "justify", "start", "end"])),
+        ("text-decoration", Any),
+        ("text-indent", Length),
+        ("word-spacing", LengthOr(&["normal"])),
+        ("letter-spacing", LengthOr(&["normal"])),
+        ("tab-size", LengthOrNumber),
+        ("visibility", Keywords(&["visible", "hidden", "collapse"])),
+        ("direction", Keywords(&["ltr", "rtl"])),
+        ("unicode-bidi", Keywords(&["normal", "embed", "isolate", "bidi-override", "isolate-override", "plaintext"])),
+        ("white-space", Keywords(&["normal", "nowrap", "pre", "pre-wrap", "pre-line"])),
+        ("word-break", Keywords(&["normal", "break-all", "break-word"])),
+        ("overflow-wrap", Keywords(&["normal", "break-word", "anywhere"])),
+        ("word-wrap", Keywords(&["normal", "break-word", "anywhere"])),
+        ("overflow", Keywords(&["visible", "hidden", "clip", "scroll", "auto"])),
+        ("overflow-x", Keywords(&["visible", "hidden", "clip", "scroll", "auto"])),
+        ("overflow-y", Keywords(&["visible", "hidden", "clip", "scroll", "auto"])),
+        ("text-overflow", Keywords(&["clip", "ellipsis"])),
+        ("content", Any),
+        ("counter-reset", Any),
+        ("counter-increment", Any),
+        ("break-before", Keywords(&["auto", "avoid", "avoid-page", "page", "left", "right"])),
+        ("break-after", Keywords(&["auto", "avoid", "avoid-page", "page", "left", "right"])),
+        ("break-inside", Keywords(&["auto", "avoid", "avoid-page"])),
+        ("page-break-before", Keywords(&["auto", "always", "avoid", "left", "right"])),
+        ("page-break-after", Keywords(&["auto", "always", "avoid", "left", "right"])),
+        ("page-break-inside", Keywords(&["auto", "avoid"])),
+        ("all", Keywords(&[])), // only the cascade-wide keywords
+    ]
+};
+
+const BORDER_STYLES: &[&str] = &["none", "hidden", "dotted", "dashed", "solid", "double", "groove", "ridge", "inset", "outset"];
+const OUTLINE_STYLES: &[&str] = &["none", "auto", "dotted", "dashed", "solid", "double", "groove", "ridge", "inset", "outset"];
+const BACKGROUND_REPEATS: &[&str] = &["repeat", "repeat-x", "repeat-y", "no-repeat", "space", "round"];
+const BACKGROUND_ATTACHMENTS: &[&str] = &["scroll", "fixed", "local"];
+const POSITION_KEYWORDS: &[&str] = &["left", "center", "right", "top", "bottom"];
+
+fn property_grammar(property: &str) -> Option<&'static Grammar> {
+    PROPERTIES.iter().find(|(name, _)| *name == property).map(|(_, grammar)| grammar)
+}
+
+/// The names of all properties the engine understands.
+pub fn known_properties() -> impl Iterator<Item = &'static str> {
+    PROPERTIES.iter().map(|&(name, _)| name)
+}
+
+/// The longhands a shorthand property declaration sets, each with its value, or
+/// `None` if `name` isn't a shorthand or `value` doesn't fit it.
+///
+/// https://www.w3.org/TR/css-cascade-4/#shorthand
+///
+/// The cascade applies these in the shorthand's place, so whichever of a shorthand
+/// and one of its longhands comes later in cascade order sets that longhand.
+pub fn expand_shorthand(name: &str, value: &Value) -> Option<Vec<(&'static str, Value)>> {
+    if name == "outline" {
+        // the longhands the shorthand leaves out are reset to their initial values
+        let (width, style, color) = match value {
+            Value::List(components) => outline_components(components),
+            value if is_keyword_in(value, &["initial", "unset"]) || *value == Value::Inherit => {
+                (Some(value), Some(value), Some(value))
+            }
+            value => outline_components(std::slice::from_ref(value)),
+        };
+        let initial = Value::Keyword("initial".to_string());
+        return Some([("outline-width", width), ("outline-style", style), ("outline-color", color)].into_iter()
+            .map(|(longhand, value)| (longhand, value.unwrap_or(&initial).clone()))
+            .collect());
+    }
+    if name == "background" {
+        return expand_background(value);
+    }
+    if name == "border" {
+        return expand_border(value);
+    }
+    if name == "grid-column" || name == "grid-row" {
+        return expand_grid_placement(name, value);
+    }
+    let longhands: &[&'static str] = match name {
+        "margin" => &["margin-top", "margin-right", "margin-bottom", "margin-left"],
+        "padding" => &["padding-top", "padding-right", "padding-bottom", "padding-left"],
+        "border-width" => &["border-top-width", "border-right-width", "border-bottom-width", "border-left-width"],
+        "overflow" => &["overflow-x", "overflow-y"],
+        "gap" => &["row-gap", "column-gap"],
+        _ => return None
+    };
+    let components = match value {
+        Value::List(components) => components.as_slice(),
+        value => std::slice::from_ref(value)
+    };
+    // box edges go top, right, bottom, left, with missing sides copying the opposite one
+    let values: Vec<&Value> = match (longhands.len(), components) {
+        (4, [all]) => vec![all; 4],
+        (4, [vertical, horizontal]) => vec![vertical, horizontal, vertical, horizontal],
+        (4, [top, horizontal, bottom]) => vec![top, horizontal, bottom, horizontal],
+        (4, [top, right, bottom, left]) => vec![top, right, bottom, left],
+        (2, [both]) => vec![both; 2],
+        (2, [x, y]) => vec![x, y],
+        (1, _) => vec![value],
+        _ => return None
+    };
+    Some(longhands.iter().zip(values).map(|(&longhand, value)| (longhand, value.clone())).collect())
+}
+
+// the longhands of `border`: the four widths and the color, as there's no `border-style`
+// to set; the style still decides the widths, as a border of no style has none and one
+// with a style but no width is `medium`
+//
+// https://www.w3.org/TR/css-backgrounds-3/#border-shorthands
+fn expand_border(value: &Value) -> Option<Vec<(&'static str, Value)>> {
+    const WIDTHS: [&str; 4] = ["border-top-width", "border-right-width", "border-bottom-width", "border-left-width"];
+    let (width, color) = match value {
+        value if is_keyword_in(value, &["initial", "unset"]) || *value == Value::Inherit => (value.clone(), value.clone()),
+        value => {
+            let (width, style, color) = border_components(components_of(value));
+            let drawn = style.is_some_and(|style| !is_keyword_in(style, &["none", "hidden"]));
+            let width = match (width, drawn) {
+                (_, false) => Value::Length(0.0, Unit::Px),
+                (Some(width), true) => width.clone(),
+                (None, true) => Value::Length(3.0, Unit::Px),
+            };
+            (width, color.cloned().unwrap_or_else(|| Value::Keyword("initial".to_string())))
+        }
+    };
+    let mut longhands: Vec<(&'static str, Value)> = WIDTHS.iter().map(|&longhand| (longhand, width.clone())).collect();
+    longhands.push(("border-color", color));
+    Some(longhands)
+}
+
+// the longhands of `grid-column` or `grid-row`: the start line, and the end line
+// after a `/`, which is `auto` if there isn't one
+//
+// https://www.w3.org/TR/css-grid-1/#placement-shorthands
+fn expand_grid_placement(name: &str, value: &Value) -> Option<Vec<(&'static str, Value)>> {
+    let (start, end) = match name {
+        "grid-column" => ("grid-column-start", "grid-column-end"),
+        _ => ("grid-row-start", "grid-row-end"),
+    };
+    if is_keyword_in(value, &["initial", "unset"]) || *value == Value::Inherit {
+        return Some(vec![(start, value.clone()), (end, value.clone())]);
+    }
+    let (start_line, end_line) = grid_placement_lines(value)?;
+    let end_line = end_line.unwrap_or_else(|| Value::Keyword("auto".to_string()));
+    Some(vec![(start, start_line), (end, end_line)])
+}
+
+// the lines of a grid placement, before and after its `/`, each a single value or a
+// `List` like `span 2`; `None` if there's more than one `/` or nothing on a side of it
+fn grid_placement_lines(value: &Value) -> Option<(Value, Option<Value>)> {
+    let line = |components: &[Value]| match components {
+        [] => None,
+        [single] => Some(single.clone()),
+        components => Some(Value::List(components.to_vec())),
+    };
+    let components = components_of(value);
+    let mut sides = components.split(|component| is_keyword_in(component, &["/"]));
+    let start = line(sides.next()?)?;
+    let end = match sides.next() {
+        Some(end) => Some(line(end)?),
+        None => None,
+    };
+    match sides.next() {
+        Some(_) => None,
+        None => Some((start, end)),
+    }
+}
+
+// the longhands of `background`: a list of images, positions, repeats and
+// attachments with one item per layer, and the color of the last layer
+fn expand_background(value: &Value) -> Option<Vec<(&'static str, Value)>> {
+    const LONGHANDS: [&str; 5] = ["background-image", "background-position", "background-repeat",
+                                  "background-attachment", "background-color"];
+    if is_keyword_in(value, &["initial", "unset"]) || *value == Value::Inherit {
+        return Some(LONGHANDS.iter().map(|&longhand| (longhand, value.clone())).collect());
+    }
+    let items = match value {
+        Value::CommaList(items) => items.as_slice(),
+        value => std::slice::from_ref(value),
+    };
+    let layers = items.iter()
+        .map(|item| background_layer(components_of(item)))
+        .collect::<Option<Vec<BackgroundLayer>>>()?;
+    // the longhands the shorthand leaves out are reset to their initial values
+    let per_layer = |component: fn(&BackgroundLayer) -> Value| {
+        let mut values: Vec<Value> = layers.iter().map(component).collect();
+        match values.len() {
+            1 => values.remove(0),
+            _ => Value::CommaList(values),
+        }
+    };
+    let color = layers.last().and_then(|layer| layer.color).cloned().unwrap_or_else(|| Value::Keyword("initial".to_string()));
+    Some(vec![
+        ("background-image", per_layer(|layer| layer.image.cloned().unwrap_or(Value::Keyword("none".to_string())))),
+        ("background-position", per_layer(|layer| match layer.position {
+            [] => Value::List(vec![Value::Length(0.0, Unit::Percent), Value::Length(0.0, Unit::Percent)]),
+            [single] => single.clone(),
+            both => Value::List(both.to_vec()),
+        })),
+        ("background-repeat", per_layer(|layer| layer.repeat.cloned().unwrap_or(Value::Keyword("repeat".to_string())))),
+        ("background-attachment", per_layer(|layer| layer.attachment.cloned().unwrap_or(Value::Keyword("scroll".to_string())))),
+        ("background-color", color),
+    ])
+}
+
+// one layer of a `background`, each part as written if it was given
+struct BackgroundLayer<'v> {
+    image: Option<&'v Value>,
+    position: &'v [Value], // one or two components
+    repeat: Option<&'v Value>,
+    attachment: Option<&'v Value>,
+    color: Option<&'v Value>,
+}
+
+// the parts among the components of a layer, which may come in any order, or `None`
+// if a component fits none of them or a part is given twice; the position's
+// components must be next to each other
+fn background_layer(components: &[Value]) -> Option<BackgroundLayer<'_>> {
+    let mut layer = BackgroundLayer { image: None, position: &[], repeat: None, attachment: None, color: None };
+    let mut i = 0;
+    while i < components.len() {
+        let component = &components[i];
+        let part = if Grammar::Image.accepts(component) {
+            &mut layer.image
+        } else if is_keyword_in(component, BACKGROUND_REPEATS) {
+            &mut layer.repeat
+        } else if is_keyword_in(component, BACKGROUND_ATTACHMENTS) {
+            &mut layer.attachment
+        } else if is_color(component) {
+            &mut layer.color
+        } else if is_length(component) || is_keyword_in(component, POSITION_KEYWORDS) {
+            if !layer.position.is_empty() {
+                return None;
+            }
+            let two = components.get(i + 1).is_some_and(|next| is_length(next) || is_keyword_in(next, POSITION_KEYWORDS));
+            let count = 1 + two as usize;
+            layer.position = &components[i..i + count];
+            i += count;
+            continue;
+        } else {
+            return None;
+        };
+        if part.replace(component).is_some() {
+            return None;
+        }
+        i += 1;
+    }
+    Some(layer)
+}
+
+// the space-separated components of a value
+fn components_of(value: &Value) -> &[Value] {
+    match value {
+        Value::List(components) => components.as_slice(),
+        value => std::slice::from_ref(value),
+    }
+}
+
+// is the value a `linear-gradient()` the painter can draw: an optional direction,
+// as an angle or `to` a side or corner, then at least two colors, each with an
+// optional position?
+fn is_linear_gradient(value: &Value) -> bool {
+    let arguments = match value {
+        Value::Function(name, arguments) if name == "linear-gradient" => arguments.as_slice(),
+        _ => return false,
+    };
+    let is_direction = |argument: &Value| match argument {
+        Value::Keyword(angle) => angle_degrees(angle).is_some(),
+        Value::List(words) => match words.split_first() {
+            Some((to, sides)) => is_keyword_in(to, &["to"]) && !sides.is_empty() && sides.len() <= 2
+                && sides.iter().all(|side| is_keyword_in(side, &["left", "right", "top", "bottom"])),
+            None => false,
+        },
+        _ => false,
+    };
+    let is_stop = |argument: &Value| match components_of(argument) {
+        [color] => is_color(color),
+        [color, position] => is_color(color) && is_length(position),
+        _ => false,
+    };
+    let stops = match arguments.first() {
+        Some(first) if is_direction(first) => &arguments[1..],
+        _ => arguments,
+    };
+    stops.len() >= 2 && stops.iter().all(is_stop)
+}
+
+/// The size of an angle like `90deg` or `0.25turn` in degrees, or `None` if `text`
+/// isn't one; angles have no unit of their own, so they're parsed as keywords.
+pub fn angle_degrees(text: &str) -> Option<f32> {
+    let (number, per_unit) = match text {
+        _ if text.ends_with("deg") => (&text[..text.len() - 3], 1.0),
+        _ if text.ends_with("turn") => (&text[..text.len() - 4], 360.0),
+        _ => return None,
+    };
+    number.parse::<f32>().ok().filter(|n| n.is_finite()).map(|n| n * per_unit)
+}
+
+fn is_length(value: &Value) -> bool {
+    match value {
+        // a math function of lengths, which may themselves be math functions
+        Value::Function(name, arguments) if is_math_function(name) => {
+            let count = match name.as_str() {
+                "clamp" => arguments.len() == 3,
+                _ => !arguments.is_empty(),
+            };
+            count && arguments.iter().all(is_length)
+        }
+        // an fr is a share of a grid container's free space, not a length
+        Value::Length(_, Unit::Fr) => false,
+        value => matches!(value, Value::Length(..)) || *value == Value::Number(0.0)
+    }
+}
+
+// a color, or `currentcolor`, which the cascade replaces with the element's color
+fn is_color(value: &Value) -> bool {
+    match value {
+        Value::ColorValue(_) => true,
+        Value::Keyword(keyword) => keyword.eq_ignore_ascii_case("currentcolor") || named_color(keyword).is_some(),
+        _ => false,
+    }
+}
+
+fn is_keyword_in(value: &Value, keywords: &[&str]) -> bool {
+    matches!(value, Value::Keyword(k) if keywords.contains(&k.to_ascii_lowercase().as_str()))
+}
+
+impl Grammar {
+    fn accepts(&self, value: &Value) -> bool {
+        // the cascade-wide keywords apply to every property
+        if *value == Value::Inherit || is_keyword_in(value, &["initial", "unset"]) {
+            return true;
+        }
+        // a list with an empty item, like `Arial, , serif` or `Arial, serif,`
+        if let Value::CommaList(items) = value {
+            if items.iter().any(|item| *item == Value::List(Vec::new())) {
+                return false;
+            }
+        }
+        match self {
+            Grammar::Length => is_length(value),
+            Grammar::LengthOr(keywords) => is_length(value) || is_keyword_in(value, keywords),
+            Grammar::Lengths => match value {
+                Value::List(values) => values.len() <= 4 && values.iter()
+                    .all(|v| Grammar::LengthOr(&["auto"]).accepts(v)),
+                value => Grammar::LengthOr(&["auto"]).accepts(value),
+            },
+            Grammar::NumberOr(keywords) => matches!(value, Value::Number(_)) || is_keyword_in(value, keywords),
+            Grammar::LengthOrNumber => is_length(value) || matches!(value, Value::Number(_)),
+            Grammar::Color => is_color(value),
+            Grammar::Keywords(keywords) => is_keyword_in(value, keywords),
+            Grammar::Position => {
+                let component = Grammar::LengthOr(POSITION_KEYWORDS);
+                match value {
+                    Value::List(values) => values.len() == 2 && values.iter().all(|v| component.accepts(v)),
+                    value => component.accepts(value),
+                }
+            }
+            Grammar::Transform => match value {
+                Value::List(functions) => functions.iter().all(is_transform_function),
+                value => is_keyword_in(value, &["none"]) || is_transform_function(value),
+            },
+            Grammar::Outline => {
+                let components = match value {
+                    Value::List(components) => components.as_slice(),
+                    value => std::slice::from_ref(value),
+                };
+                let (width, style, color) = outline_components(components);
+                let count = [width, style, color].iter().filter(|component| component.is_some()).count();
+                !components.is_empty() && count == components.len()
+            }
+            Grammar::Border => {
+                let components = components_of(value);
+                let (width, style, color) = border_components(components);
+                let count = [width, style, color].iter().filter(|component| component.is_some()).count();
+                !components.is_empty() && count == components.len()
+            }
+            Grammar::Image => match value {
+                Value::Function(name, arguments) if name == "url" => matches!(arguments.as_slice(), [Value::Str(_)]),
+                value => is_keyword_in(value, &["none"]) || is_linear_gradient(value),
+            },
+            Grammar::Layers(layer) => match value {
+                Value::CommaList(items) => items.iter().all(|item| layer.accepts(item)),
+                value => layer.accepts(value),
+            },
+            Grammar::Background => match value {
+                Value::CommaList(items) => items.iter().enumerate().all(|(i, item)| {
+                    // only the bottom layer, the last, can have a color
+                    background_layer(components_of(item)).is_some_and(|layer| layer.color.is_none() || i == items.len() - 1)
+                }),
+                value => background_layer(components_of(value)).is_some(),
+            },
+            Grammar::Pair(component) => match value {
+                Value::List(values) => values.len() == 2 && values.iter().all(|v| component.accepts(v)),
+                value => component.accepts(value),
+            },
+            Grammar::Tracks => {
+                let is_track = |v: &Value| match *v {
+                    Value::Length(n, Unit::Fr) => n >= 0.0,
+                    Value::Length(n, _) | Value::Number(n) => n >= 0.0 && is_length(v),
+                    _ => false,
+                };
+                match value {
+                    Value::List(values) => values.iter().all(is_track),
+                    value => is_keyword_in(value, &["none"]) || is_track(value),
+                }
+            }
+            Grammar::GridLine => is_grid_line(value),
+            Grammar::GridPlacement => grid_placement_lines(value).is_some_and(|(start, end)| {
+                is_grid_line(&start) && end.as_ref().is_none_or(is_grid_line)
+            }),
+            Grammar::Any => true,
+        }
+    }
+}
+
+// `auto`, a line number other than 0, negative ones counting from the end, or `span`
+// and a positive number of tracks; named lines aren't supported
+fn is_grid_line(value: &Value) -> bool {
+    let is_integer = |n: f32| n.fract() == 0.0;
+    match value {
+        Value::Number(n) => is_integer(*n) && *n != 0.0,
+        Value::List(components) => matches!(components.as_slice(),
+            [span, Value::Number(n)] if is_keyword_in(span, &["span"]) && is_integer(*n) && *n > 0.0),
+        value => is_keyword_in(value, &["auto"]),
+    }
+}
+
+// the width, style and color among the components of an `outline`, each the first
+// that fits; a component that fits none, or a second of one kind, is left out
+fn outline_components(components: &[Value]) -> (Option<&Value>, Option<&Value>, Option<&Value>) {
+    let width = components.iter().find(|&v| Grammar::LengthOr(&["thin", "medium", "thick"]).accepts(v));
+    let style = components.iter().find(|&v| is_keyword_in(v, OUTLINE_STYLES));
+    let color = components.iter().find(|v| is_color(v));
+    (width, style, color)
+}
+
+// the width, style and color among the components of a `border`
+fn border_components(components: &[Value]) -> (Option<&Value>, Option<&Value>, Option<&Value>) {
+    let width = components.iter().find(|&v| is_length(v));
+    let style = components.iter().find(|&v| is_keyword_in(v, BORDER_STYLES));
+    let color = components.iter().find(|v| is_color(v));
+    (width, style, color)
+}
+
+// a translation by lengths or a scale by numbers or percentages; rotations, skews
+// and matrices aren't supported
+fn is_transform_function(value: &Value) -> bool {
+    let is_factor = |value: &Value| matches!(value, Value::Number(_) | Value::Length(_, Unit::Percent));
+    match value {
+        Value::Function(name, args) => match (name.as_str(), args.len()) {
+            ("translate", 1 | 2) | ("translatex" | "translatey", 1) => args.iter().all(is_length),
+            ("scale", 1 | 2) | ("scalex" | "scaley", 1) => args.iter().all(is_factor),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Check every declaration in the stylesheet, dropping those with invalid values,
+/// and return a warning for each problem found.
+pub fn validate(stylesheet: &mut Stylesheet) -> Vec<CssWarning> {
+    let mut warnings = Vec::new();
+    let (line_starts, source_name) = (&stylesheet.line_starts, &stylesheet.source_name);
+    for rule in &mut stylesheet.rules {
+        let selector = rule.selectors.iter().map(|s| s.to_string()).collect::<Vec<_>>().join(", ");
+        rule.declarations.retain(|declaration| {
+            let warning = |kind| CssWarning {
+                kind,
+                property: declaration.name.clone(),
+                value: declaration.value.to_string(),
+                selector: selector.clone(),
+                span: declaration.span,
+                location: source_location(line_starts, source_name, declaration.span.start),
+            };
+            match property_grammar(&declaration.name) {
+                None => {
+                    warnings.push(warning(WarningKind::UnknownProperty));
+                    true
+                }
+                Some(grammar) if !grammar.accepts(&declaration.value) => {
+                    warnings.push(warning(WarningKind::InvalidValue));
+                    false
+                }
+                Some(_) => true
+            }
+        });
+    }
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // what a stylesheet says, as minifying it must keep it: each non-empty rule with
+    // the declarations that win within it, and each at-rule with its block
+    fn structure(stylesheet: &Stylesheet) -> Vec<String> {
+        let winning = |declarations: &[Declaration]| -> Vec<String> {
+            let wins = |i: usize, declaration: &Declaration| declarations.iter().enumerate()
+                .filter(|&(j, other)| j != i && other.name == declaration.name)
+                .all(|(j, other)| match (declaration.important, other.important) {
+                    (true, false) => true,
+                    (false, true) => false,
+                    _ => j < i,
+                });
+            declarations.iter().enumerate().filter(|&(i, declaration)| wins(i, declaration))
+                .map(|(_, declaration)| {
+                    let mut value = declaration.value.clone();
+                    normalize(&mut value, takes_colors(&declaration.name));
+                    format!("{}:{:?}{}", declaration.name, value, if declaration.important { "!" } else { "" })
+                })
+                .collect()
+        };
+        let at_rules = stylesheet.at_rules.iter().map(|at_rule| {
+            format!("@{} {} {:?} {:?}", at_rule.name, at_rule.prelude, winning(&at_rule.declarations),
+                at_rule.block.as_ref().filter(|_| !DECLARATION_AT_RULES.contains(&at_rule.name.as_str())))
+        });
+        let rules = stylesheet.rules.iter().filter(|rule| !rule.declarations.is_empty()).map(|rule| {
+            let selectors: Vec<String> = rule.selectors.iter().map(Selector::to_string).collect();
+            let media: Vec<String> = rule.media.iter().map(MediaQueryList::to_string).collect();
+            format!("{:?} {} {:?}", media, selectors.join(","), winning(&rule.declarations))
+        });
+        at_rules.chain(rules).collect()
+    }
+
+    // zero lengths as the numbers they're written as, and named colors as colors
+    fn normalize(value: &mut Value, colors: bool) {
+        if colors {
+            resolve_named_colors(value);
+        }
+        match value {
+            Value::Length(zero, _) if *zero == 0.0 => *value = Value::Number(0.0),
+            Value::List(values) | Value::CommaList(values) => values.iter_mut().for_each(|value| normalize(value, colors)),
+            _ => {}
+        }
+    }
+
+    // stylesheets built of random pieces, with the same pieces coming up often, so that
+    // properties repeat within rules and rules are nested in the same `@media` blocks
+    fn generated_stylesheets(count: usize) -> Vec<String> {
+        const PIECES: &[&str] = &[
+            "p { margin: 0px 10px; color: red; }",
+            "a:hover, .b > i { color: #ffffff; color: rgba(0, 0, 0, 0.5) !important; color: blue; }",
+            "div { padding: 0; padding: 0em 1px; border: 1px solid navy; background: url(\"a b.png\") no-repeat, linear-gradient(white, teal); }",
+            "ol { counter-reset: teal 2; content: \"{ } ;\" counter(teal); }",
+            "h1 {}",
+            "@media print { p { margin: 0; } }",
+            "@media (min-width: 600px) { @media screen { .x { width: calc(100% - 0px); } } }",
+            "@supports (display: grid) { .grid { display: grid; } }",
+            "@keyframes spin { from { transform: scale(1); } to { transform: scale(2); } }",
+            "@layer base { p { color: red; } }",
+            "@layer base, theme;",
+            "@page { margin: 1in; size: letter; }",
+            "@page :first {}",
+            "@font-face { font-family: \"X\"; src: url(x.woff); }",
+            "@import url(\"other.css\");",
+            "@charset \"utf-8\";",
+        ];
+        let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+        (0..count).map(|_| {
+            let mut css = String::new();
+            for _ in 0..8 {
+                state = state.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+                css.push_str(PIECES[(state >> 33) as usize % PIECES.len()]);
+                css.push('\n');
+            }
+            css
+        }).collect()
+    }
+
+    #[test]
+    fn minified_stylesheets_parse_back_the_same() {
+        let fixtures = [
+            include_str!("../examples/test.css"),
+            include_str!("../examples/outline.css"),
+            include_str!("../examples/combinators.css"),
+        ];
+        let mut stylesheets: Vec<Stylesheet> = fixtures.iter().map(|css| parse(css.to_string(), Origin::Author))
+            .chain(generated_stylesheets(200).into_iter().map(|css| parse(css, Origin::Author)))
+            .collect();
+        stylesheets.push(default_user_agent_stylesheet());
+        for stylesheet in &stylesheets {
+            let minified = minify(stylesheet);
+            let reparsed = parse(minified.clone(), stylesheet.origin);
+            assert_eq!(structure(&reparsed), structure(stylesheet), "minified as {}", minified);
+            assert!(reparsed.syntax_errors.is_empty(), "{:?} in {}", reparsed.syntax_errors, minified);
+            // minifying again changes nothing
+            assert_eq!(minify(&reparsed), minified);
+        }
+    }
+
+    #[test]
+    fn blocks_of_unknown_at_rules_are_kept_as_written() {
+        let stylesheet = parse("@supports (display: grid) { .a { color: red } } @page {} .b { color: blue }".to_string(), Origin::Author);
+        assert!(stylesheet.syntax_errors.is_empty(), "{:?}", stylesheet.syntax_errors);
+        assert_eq!(stylesheet.rules.len(), 1);
+        assert_eq!(minify(&stylesheet), "@supports (display: grid){ .a { color: red } }@page{}.b{color:#00f;}");
+    }
+
+    #[test]
+    fn garbage_is_skipped_up_to_where_the_css_goes_on() {
+        let source = "h1 { color: red } %$ > { garbage: { nested } more } h2 { color: blue } p { width: 10banana; color: #00ff00 }";
+        let mut stylesheet = parse(source.to_string(), Origin::Author);
+        // the bad rule is skipped with its nested block
+        let skipped: Vec<&str> = stylesheet.syntax_errors.iter().map(|error| &source[error.span.start..error.span.end]).collect();
+        assert_eq!(skipped, ["%$ > { garbage: { nested } more }"]);
+        // and a length in no known unit is dropped by validating, with a warning
+        let warnings = validate(&mut stylesheet);
+        assert_eq!(warnings.iter().map(|warning| warning.property.as_str()).collect::<Vec<_>>(), ["width"]);
+        let rules: Vec<(String, Vec<&str>)> = stylesheet.rules.iter().map(|rule| {
+            let selectors: Vec<String> = rule.selectors.iter().map(Selector::to_string).collect();
+            (selectors.join(","), rule.declarations.iter().map(|declaration| declaration.name.as_str()).collect())
+        }).collect();
+        let expected = [("h1", vec!["color"]), ("h2", vec!["color"]), ("p", vec!["color"])];
+        assert_eq!(rules, expected.map(|(selector, names)| (selector.to_string(), names)));
+        assert_eq!(stylesheet.rules[2].declarations[0].value, Value::ColorValue(Color { r: 0, g: 255, b: 0, a: 255 }));
+    }
+}
@@ -1,4 +1,6 @@
 use std::collections::{HashMap, HashSet};
+use crate::css::Selector;
+use crate::selectors::{CompiledSelector, MatchingContext};
 
 // Data struct and type defns
 
@@ -23,7 +25,49 @@ pub struct Node {
     // data common to all Node structs
     pub children: Vec<Node>,
     // data specific to each node type
-    pub node_type: NodeType
+    pub node_type: NodeType,
+    // where the node came from, if the parser was asked to keep it
+    pub source: Option<SourceSpan>
+}
+
+/// A document's `<!DOCTYPE>`, which isn't a node of the tree, but decides the
+/// document's `QuirksMode`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Doctype {
+    pub name: String, // lowercased, like `html`
+    pub public_id: Option<String>,
+    pub system_id: Option<String>,
+    // the doctype was malformed, like `<!DOCTYPE>` with no name, which puts the
+    // document in quirks mode whatever it says
+    pub force_quirks: bool
+}
+
+/// Whether a document is rendered to today's standards, or emulates the old
+/// browsers a legacy doctype, or none at all, says it was written for.
+///
+/// https://dom.spec.whatwg.org/#concept-document-quirks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QuirksMode {
+    NoQuirks,
+    LimitedQuirks, // only the line height calculation is the old one
+    Quirks
+}
+
+/// The source a node was parsed from, kept when `ParserOptions::preserve_fidelity`
+/// is set so that `html::serialize` can write the node back exactly as it was
+/// written until it's changed. The spans of a document's nodes, concatenated in
+/// tree order, are the whole document.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SourceSpan {
+    pub before: String, // source ahead of the node the tree leaves out, like `<?xml?>`
+    pub start: String, // the start tag, text or comment as written; empty if implied
+    // any markup skipped before an element's end tag, and the end tag, which is empty if
+    // implied; then the source of any ignored end tags after the node, and for the
+    // last top-level node, the whitespace ending the document
+    pub end: String,
+    // the node as `html::serialize` writes new nodes, when it was parsed; a node that
+    // no longer serializes to this has changed
+    pub(crate) parsed: String
 }
 
 // Constructor definitions
@@ -31,21 +75,24 @@ pub struct Node {
 pub fn text(data: String) -> Node {
     Node {
         children: vec![],
-        node_type: NodeType::Text(data)
+        node_type: NodeType::Text(data),
+        source: None
     }
 }
 
 pub fn elem(tag_name: String, attrs: AttrsMap, children: Vec<Node>) -> Node {
     Node {
         children,
-        node_type: NodeType::Element(ElementData { tag_name, attrs })
+        node_type: NodeType::Element(ElementData { tag_name, attrs }),
+        source: None
     }
 }
 
 pub fn comment(comment: String) -> Node {
     Node {
         children: vec![],
-        node_type: NodeType::Comment(CommentData { comment })
+        node_type: NodeType::Comment(CommentData { comment }),
+        source: None
     }
 }
 
@@ -63,3 +110,205 @@ impl ElementData {
         }
     }
 }
+
+// Queries
+
+impl Node {
+    /// All elements in this subtree (including this node) matching the selector, in document order.
+    pub fn query_selector_all(&self, selector: &Selector) -> Vec<&Node> {
+        let compiled = CompiledSelector::new(selector);
+        let mut found = Vec::new();
+        self.collect_matches(&compiled, &mut Vec::new(), &mut found);
+        found
+    }
+
+    /// The first element in this subtree matching the selector.
+    pub fn query_selector(&self, selector: &Selector) -> Option<&Node> {
+        self.query_selector_all(selector).into_iter().next()
+    }
+
+    /// The document's title: the text of the first `title` element, with runs of
+    /// whitespace collapsed to single spaces, or `None` if there is no such element.
+    pub fn title(&self) -> Option<String> {
+        fn find_title(node: &Node) -> Option<&Node> {
+            match node.node_type {
+                NodeType::Element(ref elem) if elem.tag_name == "title" => Some(node),
+                _ => node.children.iter().find_map(find_title)
+            }
+        }
+        let title = find_title(self)?;
+        let text: String = title.children.iter()
+            .filter_map(|child| match child.node_type {
+                NodeType::Text(ref text) => Some(text.as_str()),
+                _ => None
+            })
+            .collect();
+        Some(text.split_whitespace().collect::<Vec<_>>().join(" "))
+    }
+
+    fn collect_matches<'a>(
+        &'a self,
+        selector: &CompiledSelector,
+        ancestors: &mut Vec<&'a ElementData>,
+        found: &mut Vec<&'a Node>
+    ) {
+        let elem = match self.node_type {
+            NodeType::Element(ref elem) => elem,
+            _ => return
+        };
+        let ctx = MatchingContext { ancestors, ..Default::default() };
+        if selector.matches(elem, &ctx) {
+            found.push(self);
+        }
+        ancestors.push(elem);
+        for child in &self.children {
+            child.collect_matches(selector, ancestors, found);
+        }
+        ancestors.pop();
+    }
+}
+
+// Sanitizing
+
+/// What `sanitize` keeps of an untrusted document. Names are compared
+/// case-insensitively, and given here in lowercase.
+#[derive(Debug, Clone)]
+pub struct SanitizePolicy {
+    /// Elements removed along with everything inside them.
+    pub removed_elements: HashSet<String>,
+    /// If set, any other element is replaced by its children.
+    pub allowed_elements: Option<HashSet<String>>,
+    /// If set, any other attribute is removed. Event handlers (`on*`) always are.
+    pub allowed_attributes: Option<HashSet<String>>,
+    /// Attributes holding a URL, which is removed unless it's relative or has one
+    /// of `allowed_url_schemes`.
+    pub url_attributes: HashSet<String>,
+    pub allowed_url_schemes: HashSet<String>,
+}
+
+fn name_set(names: &[&str]) -> HashSet<String> {
+    names.iter().map(|name| name.to_string()).collect()
+}
+
+impl Default for SanitizePolicy {
+    /// A conservative policy for user-submitted content: text formatting, lists,
+    /// tables, links and images, with nothing that runs code or loads other documents.
+    fn default() -> SanitizePolicy {
+        SanitizePolicy {
+            removed_elements: name_set(&[
+                "applet", "base", "embed", "frame", "frameset", "iframe", "link", "meta",
+                "noscript", "object", "script", "style", "template",
+            ]),
+            allowed_elements: Some(name_set(&[
+                "html", "head", "title", "body",
+                "a", "abbr", "b", "blockquote", "br", "caption", "cite", "code", "col", "colgroup",
+                "dd", "del", "div", "dl", "dt", "em", "h1", "h2", "h3", "h4", "h5", "h6", "hr",
+                "i", "img", "ins", "kbd", "li", "mark", "ol", "p", "pre", "q", "s", "small",
+                "span", "strong", "sub", "sup", "table", "tbody", "td", "tfoot", "th", "thead",
+                "tr", "u", "ul", "wbr",
+            ])),
+            allowed_attributes: Some(name_set(&[
+                "align", "alt", "cite", "class", "colspan", "dir", "height", "href", "id",
+                "lang", "rowspan", "src", "title", "width",
+            ])),
+            url_attributes: name_set(&[
+                "action", "background", "cite", "data", "formaction", "href", "poster", "src",
+                "xlink:href",
+            ]),
+            allowed_url_schemes: name_set(&["http", "https", "mailto", "tel"]),
+        }
+    }
+}
+
+/// What `sanitize` took out of a document, in document order.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SanitizeReport {
+    pub removed_elements: Vec<String>,
+    pub unwrapped_elements: Vec<String>, // replaced by their children
+    pub removed_attributes: Vec<(String, String)>, // element and attribute names
+}
+
+impl SanitizeReport {
+    pub fn is_empty(&self) -> bool {
+        self.removed_elements.is_empty() && self.unwrapped_elements.is_empty() && self.removed_attributes.is_empty()
+    }
+}
+
+/// Remove from `root`'s subtree whatever `policy` doesn't allow, for rendering
+/// untrusted HTML. The root itself is never removed, but its attributes are
+/// sanitized like any other element's.
+pub fn sanitize(root: &mut Node, policy: &SanitizePolicy) -> SanitizeReport {
+    let mut report = SanitizeReport::default();
+    sanitize_node(root, policy, &mut report);
+    report
+}
+
+fn sanitize_node(node: &mut Node, policy: &SanitizePolicy, report: &mut SanitizeReport) {
+    if let NodeType::Element(ref mut elem) = node.node_type {
+        sanitize_attributes(elem, policy, report);
+    }
+    let children = std::mem::take(&mut node.children);
+    for mut child in children {
+        let tag_name = match child.node_type {
+            NodeType::Element(ref elem) => elem.tag_name.to_ascii_lowercase(),
+            _ => {
+                node.children.push(child);
+                continue;
+            }
+        };
+        if policy.removed_elements.contains(&tag_name) {
+            report.removed_elements.push(tag_name);
+            continue;
+        }
+        sanitize_node(&mut child, policy, report);
+        match policy.allowed_elements {
+            Some(ref allowed) if !allowed.contains(&tag_name) => {
+                report.unwrapped_elements.push(tag_name);
+                node.children.append(&mut child.children);
+            }
+            _ => node.children.push(child),
+        }
+    }
+}
+
+fn sanitize_attributes(elem: &mut ElementData, policy: &SanitizePolicy, report: &mut SanitizeReport) {
+    let mut removed: Vec<String> = elem.attrs.iter()
+        .filter(|(name, value)| !is_allowed_attribute(name, value, policy))
+        .map(|(name, _)| name.clone())
+        .collect();
+    // attributes are stored unordered; report them in a stable order
+    removed.sort();
+    for name in removed {
+        elem.attrs.remove(&name);
+        report.removed_attributes.push((elem.tag_name.to_ascii_lowercase(), name.to_ascii_lowercase()));
+    }
+}
+
+fn is_allowed_attribute(name: &str, value: &str, policy: &SanitizePolicy) -> bool {
+    let name = name.to_ascii_lowercase();
+    if name.starts_with("on") {
+        return false;
+    }
+    if policy.allowed_attributes.as_ref().is_some_and(|allowed| !allowed.contains(&name)) {
+        return false;
+    }
+    if policy.url_attributes.contains(&name) {
+        return match url_scheme(value) {
+            Some(scheme) => policy.allowed_url_schemes.contains(&scheme),
+            None => true,
+        };
+    }
+    true
+}
+
+// the lowercased scheme of a URL, or `None` for a relative URL. Browsers ignore
+// whitespace and control characters inside a scheme, so `java\nscript:` is read as
+// `javascript:` here too.
+fn url_scheme(url: &str) -> Option<String> {
+    let url: String = url.chars().filter(|c| !c.is_ascii_whitespace() && !c.is_control()).collect();
+    let end = url.find([':', '/', '?', '#'])?;
+    if !url[end..].starts_with(':') {
+        return None;
+    }
+    Some(url[..end].to_ascii_lowercase())
+}
@@ -0,0 +1,281 @@
+use std::borrow::Cow;
+use std::fmt;
+
+// Turning the bytes of a document or stylesheet into text. Documents say what
+// encoding they're in with a byte order mark, the charset of the HTTP response
+// they came in, or a <meta> tag near the start; each of these overrides the next.
+// Only UTF-8, UTF-16 and windows-1252 (which ISO-8859-1 and ASCII are decoded
+// as, as the Encoding Standard requires) are built in.
+
+/// A character encoding the decoder supports.
+///
+/// https://encoding.spec.whatwg.org/#names-and-labels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Windows1252,
+}
+
+impl Encoding {
+    /// The encoding a label such as `"ISO-8859-1"` or `"utf8"` names, or `None`
+    /// for labels of encodings that aren't supported.
+    pub fn from_label(label: &str) -> Option<Encoding> {
+        let label = label.trim_matches(|c: char| c.is_ascii_whitespace()).to_ascii_lowercase();
+        match label.as_str() {
+            "unicode-1-1-utf-8" | "unicode11utf8" | "unicode20utf8" | "utf-8" | "utf8" | "x-unicode20utf8" => Some(Encoding::Utf8),
+            "csunicode" | "iso-10646-ucs-2" | "ucs-2" | "unicode" | "unicodefeff" | "utf-16" | "utf-16le" => Some(Encoding::Utf16Le),
+            "unicodefffe" | "utf-16be" => Some(Encoding::Utf16Be),
+            "ansi_x3.4-1968" | "ascii" | "cp1252" | "cp819" | "csisolatin1" | "ibm819" | "iso-8859-1" | "iso-ir-100"
+                | "iso8859-1" | "iso88591" | "iso_8859-1" | "iso_8859-1:1987" | "l1" | "latin1" | "us-ascii"
+                | "windows-1252" | "x-cp1252" => Some(Encoding::Windows1252),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Utf16Le => "UTF-16LE",
+            Encoding::Utf16Be => "UTF-16BE",
+            Encoding::Windows1252 => "windows-1252",
+        }
+    }
+
+    /// Decode `bytes`, replacing malformed sequences with U+FFFD.
+    pub fn decode<'b>(&self, bytes: &'b [u8]) -> Cow<'b, str> {
+        match self {
+            Encoding::Utf8 => String::from_utf8_lossy(bytes),
+            Encoding::Utf16Le => Cow::Owned(decode_utf16(bytes, u16::from_le_bytes)),
+            Encoding::Utf16Be => Cow::Owned(decode_utf16(bytes, u16::from_be_bytes)),
+            Encoding::Windows1252 => Cow::Owned(bytes.iter().map(|&byte| windows_1252_char(byte)).collect()),
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// What decided a document's encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodingSource {
+    ByteOrderMark,
+    Transport, // the charset of the Content-Type the document was served with
+    Meta, // a <meta charset> or <meta http-equiv="Content-Type">
+    Default, // nothing declared one
+}
+
+/// The encoding of an HTML document, and what decided it.
+///
+/// https://html.spec.whatwg.org/multipage/parsing.html#determining-the-character-encoding
+///
+/// A byte order mark wins, then `transport_charset`, then a <meta> tag in the
+/// first 1024 bytes. An undeclared document is windows-1252, as the spec
+/// suggests, unless it's entirely valid UTF-8: bytes that decode as UTF-8 are
+/// almost never meant as anything else, and most documents without a declaration
+/// are UTF-8 today.
+pub fn sniff_document(bytes: &[u8], transport_charset: Option<&str>) -> (Encoding, EncodingSource) {
+    if let Some((encoding, _)) = byte_order_mark(bytes) {
+        return (encoding, EncodingSource::ByteOrderMark);
+    }
+    if let Some(encoding) = transport_charset.and_then(Encoding::from_label) {
+        return (encoding, EncodingSource::Transport);
+    }
+    if let Some(encoding) = prescan_meta(&bytes[..bytes.len().min(1024)]) {
+        return (encoding, EncodingSource::Meta);
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(_) => (Encoding::Utf8, EncodingSource::Default),
+        Err(_) => (Encoding::Windows1252, EncodingSource::Default),
+    }
+}
+
+/// Decode an HTML document as `sniff_document` decides, without its byte order mark.
+pub fn decode_document(bytes: &[u8], transport_charset: Option<&str>) -> (String, Encoding) {
+    let (encoding, _) = sniff_document(bytes, transport_charset);
+    let bom_length = byte_order_mark(bytes).map_or(0, |(_, length)| length);
+    (encoding.decode(&bytes[bom_length..]).into_owned(), encoding)
+}
+
+/// Decode a stylesheet: by its byte order mark, else its `@charset` rule, else as UTF-8.
+///
+/// https://www.w3.org/TR/css-syntax-3/#input-byte-stream
+pub fn decode_stylesheet(bytes: &[u8]) -> String {
+    if let Some((encoding, length)) = byte_order_mark(bytes) {
+        return encoding.decode(&bytes[length..]).into_owned();
+    }
+    // `@charset` must be written exactly so, at the very start; UTF-16 labels are
+    // ignored, since a stylesheet that says so in ASCII can't be UTF-16
+    let declared = bytes.strip_prefix(b"@charset \"")
+        .and_then(|rest| rest.iter().position(|&b| b == b'"').map(|end| &rest[..end]))
+        .and_then(|label| Encoding::from_label(&String::from_utf8_lossy(label)))
+        .filter(|encoding| !matches!(encoding, Encoding::Utf16Le | Encoding::Utf16Be));
+    declared.unwrap_or(Encoding::Utf8).decode(bytes).into_owned()
+}
+
+// the encoding a byte order mark at the start of `bytes` declares, and its length
+fn byte_order_mark(bytes: &[u8]) -> Option<(Encoding, usize)> {
+    match bytes {
+        [0xEF, 0xBB, 0xBF, ..] => Some((Encoding::Utf8, 3)),
+        [0xFF, 0xFE, ..] => Some((Encoding::Utf16Le, 2)),
+        [0xFE, 0xFF, ..] => Some((Encoding::Utf16Be, 2)),
+        _ => None
+    }
+}
+
+fn decode_utf16(bytes: &[u8], unit: fn([u8; 2]) -> u16) -> String {
+    let units = bytes.chunks(2).map(|pair| match *pair {
+        [a, b] => unit([a, b]),
+        // a lone trailing byte is malformed
+        _ => 0xFFFD
+    });
+    char::decode_utf16(units).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)).collect()
+}
+
+// windows-1252 is Latin-1 but for 0x80 to 0x9F, which hold punctuation and a few
+// letters instead of control characters; the five bytes it leaves unassigned
+// decode to the control characters of the same values
+//
+// https://encoding.spec.whatwg.org/index-windows-1252.txt
+const WINDOWS_1252_HIGH: [char; 32] = [
+    '\u{20AC}', '\u{81}', '\u{201A}', '\u{192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{2C6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8D}', '\u{17D}', '\u{8F}',
+    '\u{90}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{2DC}', '\u{2122}', '\u{161}', '\u{203A}', '\u{153}', '\u{9D}', '\u{17E}', '\u{178}',
+];
+
+fn windows_1252_char(byte: u8) -> char {
+    match byte {
+        0x80..=0x9F => WINDOWS_1252_HIGH[(byte - 0x80) as usize],
+        _ => byte as char
+    }
+}
+
+/// The windows-1252 byte for `c`, if it has one. The control characters the five
+/// unassigned bytes decode to have none, as in PDF's WinAnsiEncoding, which is
+/// otherwise the same.
+pub fn windows_1252_byte(c: char) -> Option<u8> {
+    match c as u32 {
+        0x00..=0x7F | 0xA0..=0xFF => Some(c as u32 as u8),
+        0x80..=0x9F => None,
+        _ => WINDOWS_1252_HIGH.iter().position(|&high| high == c).map(|i| 0x80 + i as u8),
+    }
+}
+
+/// Look through the start of a document for a <meta> tag declaring its encoding.
+///
+/// https://html.spec.whatwg.org/multipage/parsing.html#prescan-a-byte-stream-to-determine-its-encoding
+///
+/// Comments are skipped, and so are other tags. A declared UTF-16 means UTF-8,
+/// since a document whose <meta> is readable as ASCII isn't UTF-16.
+fn prescan_meta(bytes: &[u8]) -> Option<Encoding> {
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let rest = &bytes[pos..];
+        if rest.starts_with(b"<!--") {
+            pos += find(&rest[4..], b"-->").map_or(rest.len(), |end| 4 + end + 3);
+        } else if rest.len() > 5 && rest[..5].eq_ignore_ascii_case(b"<meta") && is_space_or_slash(rest[5]) {
+            let (attributes, length) = tag_attributes(&rest[5..]);
+            pos += 5 + length;
+            if let Some(encoding) = meta_encoding(&attributes) {
+                return Some(match encoding {
+                    Encoding::Utf16Le | Encoding::Utf16Be => Encoding::Utf8,
+                    encoding => encoding
+                });
+            }
+        } else if rest[0] == b'<' && rest.get(1).is_some_and(|c| c.is_ascii_alphabetic() || *c == b'/') {
+            // any other tag is skipped whole, attributes and all
+            pos += tag_attributes(&rest[1..]).1 + 1;
+        } else {
+            pos += 1;
+        }
+    }
+    None
+}
+
+// the encoding a <meta> tag's attributes declare, if any
+fn meta_encoding(attributes: &[(String, String)]) -> Option<Encoding> {
+    let attribute = |name: &str| attributes.iter().find(|(n, _)| n == name).map(|(_, value)| value.as_str());
+    if let Some(charset) = attribute("charset") {
+        return Encoding::from_label(charset);
+    }
+    if attribute("http-equiv").is_some_and(|value| value.eq_ignore_ascii_case("content-type")) {
+        return attribute("content").and_then(charset_from_content_type);
+    }
+    None
+}
+
+/// The encoding named by the charset parameter of a Content-Type value like
+/// `text/html; charset=ISO-8859-1`.
+pub fn charset_from_content_type(content_type: &str) -> Option<Encoding> {
+    let lower = content_type.to_ascii_lowercase();
+    let start = lower.find("charset")? + "charset".len();
+    let value = lower[start..].trim_start().strip_prefix('=')?.trim_start();
+    let label = match value.chars().next()? {
+        quote @ ('"' | '\'') => value[1..].split(quote).next()?,
+        _ => value.split(|c: char| c == ';' || c.is_ascii_whitespace()).next()?
+    };
+    Encoding::from_label(label)
+}
+
+// the attributes of a tag whose name has been read, and how many bytes they take
+// up to and including the closing '>'; names are lowercased
+fn tag_attributes(bytes: &[u8]) -> (Vec<(String, String)>, usize) {
+    let mut attributes = Vec::new();
+    let mut pos = bytes.iter().position(|&c| is_space_or_slash(c) || c == b'>').unwrap_or(bytes.len());
+    loop {
+        while pos < bytes.len() && is_space_or_slash(bytes[pos]) {
+            pos += 1;
+        }
+        if pos >= bytes.len() {
+            return (attributes, pos);
+        }
+        if bytes[pos] == b'>' {
+            return (attributes, pos + 1);
+        }
+        let name_start = pos;
+        while pos < bytes.len() && !is_space_or_slash(bytes[pos]) && !matches!(bytes[pos], b'=' | b'>') {
+            pos += 1;
+        }
+        let name = String::from_utf8_lossy(&bytes[name_start..pos]).to_ascii_lowercase();
+        while pos < bytes.len() && bytes[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        let mut value = String::new();
+        if bytes.get(pos) == Some(&b'=') {
+            pos += 1;
+            while pos < bytes.len() && bytes[pos].is_ascii_whitespace() {
+                pos += 1;
+            }
+            let value_start;
+            match bytes.get(pos) {
+                Some(&quote @ (b'"' | b'\'')) => {
+                    value_start = pos + 1;
+                    pos = value_start + bytes[value_start..].iter().position(|&c| c == quote).unwrap_or(bytes.len() - value_start);
+                    value = String::from_utf8_lossy(&bytes[value_start..pos]).into_owned();
+                    pos += 1;
+                }
+                _ => {
+                    value_start = pos;
+                    while pos < bytes.len() && !bytes[pos].is_ascii_whitespace() && bytes[pos] != b'>' {
+                        pos += 1;
+                    }
+                    value = String::from_utf8_lossy(&bytes[value_start..pos]).into_owned();
+                }
+            }
+        }
+        attributes.push((name, value));
+    }
+}
+
+fn is_space_or_slash(c: u8) -> bool {
+    c.is_ascii_whitespace() || c == b'/'
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
@@ -0,0 +1,1011 @@
+use crate::coverage::SourceCoverage;
+use crate::css::{self, CacheStats, Origin, Stylesheet, StylesheetCache, Value};
+use crate::dom::{self, Node, QuirksMode};
+use crate::encoding;
+use crate::font::{StandardFontMeasurer, TextMeasurer};
+use crate::html::{self, ParseError, ParserOptions, StreamingParser};
+use crate::image_decode::ImageCache;
+use crate::layout::{self, Dimensions, InitialContainingBlock, LayoutBox, Rect};
+use crate::links::ScrollTarget;
+use crate::media::{ColorScheme, MediaType};
+use crate::painting::{self, Blending, DisplayList, ScrollView};
+use crate::replaced;
+use crate::report::{RenderReport, ReportEntry, Severity};
+use crate::resource::{DenyAllLoader, ResolvedUrl, ResourceLoader};
+use crate::style::{self, CascadeCache, RuleChanges, StyleContext, StyledNode};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::sync::Arc;
+
+// Options and errors shared by every stage of the rendering pipeline.
+
+#[derive(Debug)]
+pub enum EngineError {
+    Parse(ParseError),
+    // a document exceeded one of the configured `Limits`
+    LimitExceeded { limit: Limit, max: usize },
+    // a `RenderOptions` field that nothing sensible can be rendered with
+    InvalidOption { option: &'static str, value: f32, expected: &'static str },
+    // a stylesheet asked for by its place among `count` of them, which has none
+    NoSuchStylesheet { index: usize, count: usize },
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EngineError::Parse(error) => write!(f, "parse error: {}", error),
+            EngineError::LimitExceeded { limit, max } => write!(f, "limit exceeded: more than {} {}", max, limit.describe()),
+            EngineError::InvalidOption { option, value, expected } => {
+                write!(f, "invalid {}: {}, expected {}", option, value, expected)
+            }
+            EngineError::NoSuchStylesheet { index, count } => {
+                write!(f, "no stylesheet at index {}, there are {}", index, count)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EngineError {}
+
+impl From<ParseError> for EngineError {
+    fn from(error: ParseError) -> EngineError {
+        EngineError::Parse(error)
+    }
+}
+
+// the resources a single render may use, each with a cap in `Limits`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    InputBytes,
+    DomNodes,
+    AttributesPerElement,
+    CssRules,
+    LayoutBoxes,
+    ImportDepth,
+    Imports,
+    CanvasPixels,
+    Pages,
+}
+
+impl Limit {
+    fn describe(&self) -> &'static str {
+        match self {
+            Limit::InputBytes => "bytes of input",
+            Limit::DomNodes => "DOM nodes",
+            Limit::AttributesPerElement => "attributes on one element",
+            Limit::CssRules => "CSS rules",
+            Limit::LayoutBoxes => "layout boxes",
+            Limit::ImportDepth => "stylesheets nested in imports",
+            Limit::Imports => "stylesheet imports",
+            Limit::CanvasPixels => "pixels in a raster image",
+            Limit::Pages => "pages of paged output",
+        }
+    }
+}
+
+/// Caps that keep pathological or hostile input from exhausting memory or time.
+///
+/// The defaults are far beyond anything a real document needs, but finite.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limits {
+    pub max_input_bytes: usize, // per HTML document or stylesheet
+    pub max_dom_nodes: usize,
+    pub max_attributes_per_element: usize,
+    pub max_css_rules: usize, // per stylesheet
+    pub max_layout_boxes: usize,
+    pub max_import_depth: usize, // stylesheets importing each other in a chain
+    pub max_imports: usize, // per render
+    pub max_canvas_pixels: usize, // of each raster image painted
+    pub max_pages: usize, // of each paged document written
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_input_bytes: 64 * 1024 * 1024,
+            max_dom_nodes: 1_000_000,
+            max_attributes_per_element: 1024,
+            max_css_rules: 100_000,
+            max_layout_boxes: 1_000_000,
+            max_import_depth: 32,
+            max_imports: 1_000,
+            max_canvas_pixels: 64 * 1024 * 1024,
+            max_pages: 100_000,
+        }
+    }
+}
+
+impl Limits {
+    /// Caps for renders of documents known to be small, such as the fixtures of a
+    /// test suite, which still fit any page a person would write by hand.
+    pub fn conservative() -> Limits {
+        Limits {
+            max_input_bytes: 4 * 1024 * 1024,
+            max_dom_nodes: 100_000,
+            max_attributes_per_element: 256,
+            max_css_rules: 10_000,
+            max_layout_boxes: 100_000,
+            max_import_depth: 16,
+            max_imports: 100,
+            max_canvas_pixels: 16 * 1024 * 1024,
+            max_pages: 1_000,
+        }
+    }
+
+    // fail with `limit` if `count` is over `max`
+    pub fn check(limit: Limit, count: usize, max: usize) -> Result<(), EngineError> {
+        if count > max {
+            Err(EngineError::LimitExceeded { limit, max })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RenderOptions {
+    pub width: f32, // viewport size in px
+    pub height: f32,
+    pub limits: Limits,
+    pub blending: Blending, // for raster output
+    // only the viewport will be painted, not the rest of the document, so layout may
+    // skip `content-visibility: auto` subtrees below it; never set for paged output
+    pub viewport_only: bool,
+    // with `viewport_only`, paint the viewport scrolled down to this element
+    pub scroll_to: Option<ScrollTarget>,
+    // what the document's images are fetched with; by default nothing is fetched,
+    // and `<img>` elements are laid out as if their images were missing
+    pub loader: Arc<dyn ResourceLoader>,
+    pub document_url: ResolvedUrl, // what the document's references are relative to
+    // paint the margins, padding and content box of every box over the page
+    pub debug_overlay: bool,
+    pub default_font_size: f32, // the size of `medium` text, and so of the root's text, in px
+    pub minimum_font_size: f32, // text computing to a smaller size is enlarged to this
+    // how many px of output each px of the document takes up; the viewport is
+    // `width / zoom` document px wide, so the document reflows like a zoomed page
+    pub zoom: f32,
+    pub color_scheme: ColorScheme, // the scheme `prefers-color-scheme` media queries see
+    // what media queries take the output to be; the backend writing it should set
+    // this, to print for paged output such as PDF
+    pub media_type: MediaType,
+}
+
+impl Default for RenderOptions {
+    fn default() -> RenderOptions {
+        RenderOptions {
+            width: 800.0,
+            height: 600.0,
+            limits: Limits::default(),
+            blending: Blending::default(),
+            viewport_only: false,
+            scroll_to: None,
+            loader: Arc::new(DenyAllLoader),
+            document_url: ResolvedUrl::default(),
+            debug_overlay: false,
+            default_font_size: 16.0,
+            minimum_font_size: 0.0,
+            zoom: 1.0,
+            color_scheme: ColorScheme::Light,
+            media_type: MediaType::Screen,
+        }
+    }
+}
+
+// the largest viewport side a render accepts, far beyond any real page or screen
+// but small enough that layout arithmetic stays finite
+pub const MAX_VIEWPORT_SIZE: f32 = 1_000_000.0;
+
+impl RenderOptions {
+    /// Options for a render that comes out the same on any machine, as a CI check
+    /// comparing output needs: nothing but the inputs given is read, since the
+    /// loader refuses every resource, and the limits are `Limits::conservative`.
+    ///
+    /// Everything else is as by default, which depends on nothing in the
+    /// environment: text is measured with the built-in Helvetica metrics, not any
+    /// installed font. A resource refused is reported as a warning, the same on
+    /// every render, and its element renders as if the resource were missing; a
+    /// document over a limit fails with `EngineError::LimitExceeded`.
+    pub fn hermetic() -> RenderOptions {
+        RenderOptions { limits: Limits::conservative(), loader: Arc::new(DenyAllLoader), ..Default::default() }
+    }
+
+    /// Check that the viewport is something layout can work with: at least 1px
+    /// each way, and finite. A 1×1 viewport is fine; everything just overflows it.
+    /// The font sizes must be finite, and the zoom from 1/100 to 100.
+    pub fn validate(&self) -> Result<(), EngineError> {
+        let viewport_size = "a size from 1px to 1000000px";
+        let font_size = "a size from 0px to 1000000px";
+        let checks = [
+            ("viewport width", self.width, 1.0, MAX_VIEWPORT_SIZE, viewport_size),
+            ("viewport height", self.height, 1.0, MAX_VIEWPORT_SIZE, viewport_size),
+            ("default font size", self.default_font_size, 0.0, MAX_VIEWPORT_SIZE, font_size),
+            ("minimum font size", self.minimum_font_size, 0.0, MAX_VIEWPORT_SIZE, font_size),
+            ("zoom", self.zoom, 0.01, 100.0, "a factor from 0.01 to 100"),
+        ];
+        for (option, value, min, max, expected) in checks {
+            check_option(option, value, min, max, expected)?;
+        }
+        Ok(())
+    }
+
+    // the viewport as the initial containing block
+    pub fn viewport(&self) -> Dimensions {
+        let mut viewport: Dimensions = Default::default();
+        viewport.content.width = self.width;
+        viewport.content.height = self.height;
+        viewport
+    }
+
+    /// The environment to style documents in for this render.
+    pub fn style_context(&self) -> StyleContext {
+        StyleContext {
+            viewport_width: self.width,
+            viewport_height: self.height,
+            default_font_size: self.default_font_size,
+            minimum_font_size: self.minimum_font_size,
+            zoom: self.zoom,
+            color_scheme: self.color_scheme,
+            media_type: self.media_type,
+            ancestor_filter: true,
+            // a document's own, which has to be parsed first
+            quirks_mode: QuirksMode::NoQuirks,
+        }
+    }
+}
+
+// `value` for `option` if it's from `min` to `max`, which NaN isn't
+fn check_option(option: &'static str, value: f32, min: f32, max: f32, expected: &'static str) -> Result<(), EngineError> {
+    match (min..=max).contains(&value) {
+        true => Ok(()),
+        false => Err(EngineError::InvalidOption { option, value, expected }),
+    }
+}
+
+// build and lay out the box tree, refusing trees with more boxes than the limit allows
+pub fn layout<'a>(style_root: &'a StyledNode<'a>, options: &RenderOptions) -> Result<LayoutBox<'a>, EngineError> {
+    layout_with_warnings(style_root, options).map(|(root_box, _)| root_box)
+}
+
+// same as `layout`, also returning a warning for each image that couldn't be loaded,
+// and one if all of the document's content is hidden by `display: none`
+pub fn layout_with_warnings<'a>(style_root: &'a StyledNode<'a>, options: &RenderOptions) -> Result<(LayoutBox<'a>, Vec<String>), EngineError> {
+    options.validate()?;
+    let mut root_box = layout::build_layout_tree(style_root);
+    Limits::check(Limit::LayoutBoxes, root_box.count_boxes(), options.limits.max_layout_boxes)?;
+    let mut warnings = replaced::load_images(&mut root_box, &options.document_url, options.loader.as_ref(), &mut ImageCache::new());
+    // where the viewport will be scrolled to isn't known until after layout
+    let paint_bottom = (options.viewport_only && options.scroll_to.is_none()).then_some(options.height);
+    layout::layout_root_box(&mut root_box, options.viewport(), &StandardFontMeasurer, paint_bottom);
+    warnings.extend(hidden_content_warning(style_root, &root_box));
+    Ok((root_box, warnings))
+}
+
+// a warning for output that's blank because `display: none` hides everything in the
+// document that would have shown, naming the outermost element that hid it
+fn hidden_content_warning(style_root: &StyledNode, root_box: &LayoutBox) -> Option<String> {
+    fn shows_something(layout_box: &LayoutBox) -> bool {
+        layout_box.image.is_some()
+            || layout_box.lines.iter().any(|line| !line.fragments.is_empty())
+            || layout_box.children.iter().any(shows_something)
+    }
+    fn has_content(style: &StyledNode) -> bool {
+        match style.node.node_type {
+            dom::NodeType::Text(ref text) => !text.trim().is_empty(),
+            // the text of a script or style is never shown
+            dom::NodeType::Element(ref elem) if html::is_raw_text(&elem.tag_name) => false,
+            _ => replaced::is_replaced(style) || style.children.iter().any(has_content)
+        }
+    }
+    fn hiding_element(style: &StyledNode) -> Option<String> {
+        match style.node.node_type {
+            dom::NodeType::Element(ref elem) if style.display() == style::Display::None => {
+                has_content(style).then(|| elem.tag_name.clone())
+            }
+            _ => style.children.iter().find_map(hiding_element)
+        }
+    }
+    if shows_something(root_box) {
+        return None;
+    }
+    let element = hiding_element(style_root)?;
+    Some(match root_box.is_hidden() {
+        true => format!("nothing is rendered, because the root element <{}> has display: none", element),
+        false => format!("nothing is rendered, because the document's content is in <{}>, which has display: none", element),
+    })
+}
+
+/// How far down the document the viewport is scrolled to show `options.scroll_to`:
+/// to the target's top, but never so far that the viewport passes the end of the
+/// document. Without a target, or if nothing matches it, the viewport isn't scrolled.
+pub fn scroll_offset(layout_root: &LayoutBox, options: &RenderOptions) -> f32 {
+    let top = options.scroll_to.as_ref().and_then(|target| target.top(layout_root)).unwrap_or(0.0);
+    let document_height = InitialContainingBlock::from_viewport(options.viewport()).canvas_rect(layout_root).height;
+    let mut view = ScrollView::new(options.width, options.height);
+    view.scroll_to(top, document_height);
+    view.offset
+}
+
+/// The root to render a few nodes under on their own, outside any document, such as
+/// one component of a page: an anonymous block-level element holding `nodes`.
+///
+/// Its tag name can't be written in HTML, so no type selector matches it; rules for
+/// the nodes' ids and classes, and the user agent stylesheet, apply as in a document.
+pub fn fragment_root(nodes: Vec<Node>) -> Node {
+    let attrs = [("style".to_string(), "display: block;".to_string())].into_iter().collect();
+    dom::elem("#document-fragment".to_string(), attrs, nodes)
+}
+
+/// Lay out and paint a fragment styled from a `fragment_root`, `available_width`
+/// wide and as tall as its content, and return both the boxes and what to paint.
+///
+/// The caller styles the fragment, since the boxes borrow from the styled tree.
+/// Percentage heights have no definite height to refer to, and behave as auto.
+/// The width must be one a viewport could be, from 1px to `MAX_VIEWPORT_SIZE`.
+pub fn render_fragment<'a>(root: &'a StyledNode<'a>, available_width: f32, measurer: &dyn TextMeasurer) -> Result<(LayoutBox<'a>, DisplayList), EngineError> {
+    check_option("fragment width", available_width, 1.0, MAX_VIEWPORT_SIZE, "a size from 1px to 1000000px")?;
+    let mut root_box = layout::build_layout_tree(root);
+    layout::layout_fragment_root(&mut root_box, available_width, measurer);
+    let display_list = painting::build_display_list(&root_box);
+    Ok((root_box, display_list))
+}
+
+/// Where a stylesheet's imports, and a document's linked stylesheets, are loaded
+/// from: `loader`, which serves local URLs from under `root`, the directory warnings
+/// name the stylesheets by. With no such directory, as for an `InMemoryLoader`,
+/// `root` is empty and stylesheets are named by their URLs.
+#[derive(Clone, Copy)]
+pub struct StylesheetResources<'a> {
+    pub loader: &'a dyn ResourceLoader,
+    pub root: &'a Path,
+}
+
+/// Loads the author stylesheets of a render, in the order they cascade in: each
+/// preceded by the stylesheets it imports, and those by theirs. Each is parsed and
+/// validated, and what the engine won't use of it goes in the report.
+///
+/// An import or linked stylesheet that can't be loaded is left out with a warning,
+/// as browsers do, but one past the limits on imports fails the render.
+pub struct StylesheetLoader {
+    limits: Limits,
+    // the stylesheets importing the one being loaded, to stop at import cycles; every
+    // stylesheet loaded in the render, so one imported again is left out rather than
+    // loaded once more for each importer; and how many imports were loaded
+    importers: Vec<ResolvedUrl>,
+    loaded: HashSet<ResolvedUrl>,
+    imports: usize,
+    pub stylesheets: Vec<Stylesheet>, // loaded so far, in cascade order
+}
+
+impl StylesheetLoader {
+    pub fn new(limits: &Limits) -> StylesheetLoader {
+        StylesheetLoader {
+            limits: limits.clone(),
+            importers: Vec::new(),
+            loaded: HashSet::new(),
+            imports: 0,
+            stylesheets: Vec::new(),
+        }
+    }
+
+    /// Load the stylesheets `root` brings with it, in document order, as the
+    /// document at `url` that warnings call `name` has them: the text of each
+    /// `<style>` element, and each stylesheet a `<link>` refers to.
+    pub fn load_document(&mut self, root: &Node, url: &ResolvedUrl, name: &str, resources: StylesheetResources, report: &mut RenderReport) -> Result<(), EngineError> {
+        let mut embedded = 0;
+        for stylesheet in html::document_stylesheets(root) {
+            match stylesheet {
+                html::DocumentStylesheet::Embedded(source) => {
+                    embedded += 1;
+                    self.load(source, url, format!("{} (<style> {})", name, embedded), resources, report)?;
+                }
+                html::DocumentStylesheet::Linked(href) => match url.join(&href).and_then(|linked| Ok((resources.loader.load(&linked)?, linked))) {
+                    Ok((source, linked)) => {
+                        let linked_name = resources.root.join(linked.as_str()).display().to_string();
+                        self.load(encoding::decode_stylesheet(&source), &linked, linked_name, resources, report)?;
+                    }
+                    Err(error) => {
+                        let message = format!("can't load the stylesheet {} linked from {}: {}", href, name, error);
+                        report.push(ReportEntry::new(Severity::Warning, "parse", message));
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Load `source`, the stylesheet at `url`, which warnings call `name`, after the
+    /// stylesheets it imports.
+    pub fn load(&mut self, source: String, url: &ResolvedUrl, name: String, resources: StylesheetResources, report: &mut RenderReport) -> Result<(), EngineError> {
+        let mut stylesheet = css::parse_with_limits(source, Origin::Author, &self.limits)?;
+        stylesheet.source_name = Some(name.clone());
+        for error in &stylesheet.syntax_errors {
+            let message = format!("skipping what doesn't parse: {}", error.message);
+            report.push(ReportEntry::new(Severity::Warning, "parse", message).at(stylesheet.location(error.span.start)));
+        }
+        let warnings = css::validate(&mut stylesheet);
+        for warning in &warnings {
+            report.push(ReportEntry::new(Severity::Warning, "parse", warning.problem()).at(warning.location.clone()));
+        }
+        let source_coverage = SourceCoverage::of(&stylesheet, &warnings);
+        self.importers.push(url.clone());
+        self.loaded.insert(url.clone());
+        for import in css::import_urls(&stylesheet) {
+            let imported = url.join(&import);
+            if imported.as_ref().is_ok_and(|imported| self.importers.contains(imported)) {
+                report.push(ReportEntry::new(Severity::Warning, "parse", format!("skipping the cyclic import of {} in {}", import, name)));
+                continue;
+            }
+            if imported.as_ref().is_ok_and(|imported| self.loaded.contains(imported)) {
+                report.push(ReportEntry::new(Severity::Note, "parse", format!("skipping the repeated import of {} in {}", import, name)));
+                continue;
+            }
+            // counted as it's fetched, so the loader is never asked for more imports
+            // than the limit, even ones that fail to load
+            self.imports += 1;
+            Limits::check(Limit::Imports, self.imports, self.limits.max_imports)?;
+            match imported.and_then(|imported| Ok((resources.loader.load(&imported)?, imported))) {
+                Ok((source, imported)) => {
+                    Limits::check(Limit::ImportDepth, self.importers.len(), self.limits.max_import_depth)?;
+                    let imported_name = resources.root.join(imported.as_str()).display().to_string();
+                    self.load(encoding::decode_stylesheet(&source), &imported, imported_name, resources, report)?
+                }
+                Err(error) => {
+                    let message = format!("can't load the import of {} in {}: {}", import, name, error);
+                    report.push(ReportEntry::new(Severity::Warning, "parse", message));
+                }
+            }
+        }
+        self.importers.pop();
+        // imported rules come before the importing stylesheet's own in the cascade
+        self.stylesheets.push(stylesheet);
+        report.css_coverage.sources.push(source_coverage);
+        Ok(())
+    }
+}
+
+/// The results of earlier renders, for re-rendering when only some inputs change,
+/// as when iterating on a stylesheet.
+///
+/// Parsed documents and stylesheets are kept by content, so a change to the CSS
+/// alone doesn't reparse the HTML, and vice versa. A document is kept with the
+/// stylesheets it brings with it, which come after the user agent stylesheet and
+/// before the ones given in the cascade; its linked stylesheets and their imports
+/// are fetched with the options' loader, relative to their document URL, and those
+/// that can't be are left out. Whole frames are kept by the
+/// documents and stylesheets they were made from and the viewport, so rendering
+/// the same inputs twice does no work the second time. Styling and layout aren't
+/// cached on their own: the styled tree borrows from the document, and both depend
+/// on the viewport through viewport units and the initial containing block.
+pub struct RenderCache {
+    documents: Lru<DocumentKey, CachedDocument>,
+    stylesheets: StylesheetCache,
+    frames: Lru<FrameKey, Arc<DisplayList>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RenderCacheStats {
+    pub documents: CacheStats,
+    pub stylesheets: CacheStats,
+    pub frames: CacheStats,
+}
+
+// what a document was parsed from: its source, the limits it was parsed within,
+// and what its linked stylesheets were loaded with
+struct DocumentKey {
+    hash: u64, // of the source
+    source: String,
+    limits: Limits,
+    loader: Arc<dyn ResourceLoader>,
+    document_url: ResolvedUrl,
+}
+
+impl PartialEq for DocumentKey {
+    fn eq(&self, other: &DocumentKey) -> bool {
+        self.hash == other.hash
+            && self.source == other.source
+            && self.limits == other.limits
+            && Arc::ptr_eq(&self.loader, &other.loader)
+            && self.document_url == other.document_url
+    }
+}
+
+// a parsed document, its mode, and the stylesheets it brings with it
+#[derive(Clone)]
+struct CachedDocument {
+    root: Arc<Node>,
+    quirks_mode: QuirksMode,
+    stylesheets: Arc<Vec<Stylesheet>>,
+}
+
+// what a frame was rendered from; documents and stylesheets are compared by
+// identity, which the cache keeps stable by holding on to them
+struct FrameKey {
+    document: Arc<Node>,
+    stylesheets: Vec<Arc<Stylesheet>>,
+    width: f32,
+    height: f32,
+    limits: Limits, // a render within other limits may fail, or paint fewer pixels
+    viewport_only: bool,
+    scroll_to: Option<ScrollTarget>,
+    loader: Arc<dyn ResourceLoader>,
+    document_url: ResolvedUrl,
+    debug_overlay: bool,
+    default_font_size: f32,
+    minimum_font_size: f32,
+    zoom: f32,
+    color_scheme: ColorScheme,
+    media_type: MediaType,
+}
+
+impl PartialEq for FrameKey {
+    fn eq(&self, other: &FrameKey) -> bool {
+        Arc::ptr_eq(&self.document, &other.document)
+            && self.stylesheets.len() == other.stylesheets.len()
+            && self.stylesheets.iter().zip(&other.stylesheets).all(|(a, b)| Arc::ptr_eq(a, b))
+            && self.width == other.width
+            && self.height == other.height
+            && self.limits == other.limits
+            && self.viewport_only == other.viewport_only
+            && self.scroll_to == other.scroll_to
+            && Arc::ptr_eq(&self.loader, &other.loader)
+            && self.document_url == other.document_url
+            && self.debug_overlay == other.debug_overlay
+            && self.default_font_size == other.default_font_size
+            && self.minimum_font_size == other.minimum_font_size
+            && self.zoom == other.zoom
+            && self.color_scheme == other.color_scheme
+            && self.media_type == other.media_type
+    }
+}
+
+impl RenderCache {
+    // `capacity` is how many of each of documents, stylesheets and frames to keep
+    pub fn new(capacity: usize) -> RenderCache {
+        RenderCache {
+            documents: Lru::new(capacity),
+            stylesheets: StylesheetCache::new(capacity),
+            frames: Lru::new(capacity),
+        }
+    }
+
+    /// Render a document with the user agent stylesheet and `stylesheets` to a display
+    /// list, reusing whatever earlier renders already produced.
+    pub fn render(&mut self, html: &str, stylesheets: &[(&str, Origin)], options: &RenderOptions) -> Result<Arc<DisplayList>, EngineError> {
+        let document = self.document(html, options)?;
+        let mut sheets = Vec::with_capacity(stylesheets.len());
+        for &(source, origin) in stylesheets {
+            sheets.push(self.stylesheets.get_or_parse(source, origin, &options.limits)?);
+        }
+        let key = FrameKey {
+            document: document.root.clone(),
+            stylesheets: sheets,
+            width: options.width,
+            height: options.height,
+            limits: options.limits.clone(),
+            viewport_only: options.viewport_only,
+            scroll_to: options.scroll_to.clone(),
+            loader: options.loader.clone(),
+            document_url: options.document_url.clone(),
+            debug_overlay: options.debug_overlay,
+            default_font_size: options.default_font_size,
+            minimum_font_size: options.minimum_font_size,
+            zoom: options.zoom,
+            color_scheme: options.color_scheme,
+            media_type: options.media_type,
+        };
+        if let Some(frame) = self.frames.get(&key) {
+            return Ok(frame);
+        }
+
+        // styling takes the stylesheets by value; copying them is still far cheaper
+        // than parsing them again
+        let mut owned = vec![css::default_user_agent_stylesheet()];
+        owned.extend(document.stylesheets.iter().cloned());
+        owned.extend(key.stylesheets.iter().map(|sheet| Stylesheet::clone(sheet)));
+        let context = StyleContext { quirks_mode: document.quirks_mode, ..options.style_context() };
+        let style_root = style::style_tree_with_context(&key.document, &owned, &context);
+        let layout_root = layout(&style_root, options)?;
+        let frame = paint_frame(&layout_root, options);
+        let frame = Arc::new(frame);
+        self.frames.insert(key, frame.clone());
+        Ok(frame)
+    }
+
+    // a parsed document, with its mode and its stylesheets
+    fn document(&mut self, source: &str, options: &RenderOptions) -> Result<CachedDocument, EngineError> {
+        let mut hasher = DefaultHasher::new();
+        source.hash(&mut hasher);
+        let key = DocumentKey {
+            hash: hasher.finish(),
+            source: source.to_string(),
+            limits: options.limits.clone(),
+            loader: options.loader.clone(),
+            document_url: options.document_url.clone(),
+        };
+        if let Some(document) = self.documents.get(&key) {
+            return Ok(document);
+        }
+        let mut parser = StreamingParser::new(ParserOptions { limits: options.limits.clone(), ..Default::default() });
+        parser.feed(source)?;
+        let parsed = parser.finish_document()?;
+        // what loading them reports has nowhere to go from a cached render
+        let mut loader = StylesheetLoader::new(&options.limits);
+        let resources = StylesheetResources { loader: &*options.loader, root: Path::new("") };
+        loader.load_document(&parsed.root, &options.document_url, options.document_url.as_str(), resources, &mut RenderReport::default())?;
+        let document = CachedDocument {
+            root: Arc::new(parsed.root),
+            quirks_mode: parsed.quirks_mode,
+            stylesheets: Arc::new(loader.stylesheets),
+        };
+        self.documents.insert(key, document.clone());
+        Ok(document)
+    }
+
+    pub fn stats(&self) -> RenderCacheStats {
+        RenderCacheStats {
+            documents: self.documents.stats,
+            stylesheets: self.stylesheets.stats(),
+            frames: self.frames.stats,
+        }
+    }
+}
+
+// the display list of a laid out document, as `options` asks for it painted
+fn paint_frame(layout_root: &LayoutBox, options: &RenderOptions) -> DisplayList {
+    let bounds = options.viewport_only.then(|| {
+        Rect { x: 0.0, y: scroll_offset(layout_root, options), width: options.width, height: options.height }
+    });
+    let mut frame = match bounds {
+        Some(bounds) => painting::build_display_list_in(layout_root, bounds),
+        None => painting::build_display_list(layout_root),
+    };
+    if options.debug_overlay {
+        painting::append_debug_overlay(&mut frame, layout_root, bounds);
+    }
+    // a scrolled viewport's frame is drawn from the top of the viewport
+    if let Some(bounds) = bounds.filter(|bounds| bounds.y != 0.0) {
+        frame.iter_mut().for_each(|item| item.translate(0.0, -bounds.y));
+    }
+    frame
+}
+
+/// A document kept rendered while one of its stylesheets is edited, as a live
+/// preview needs: swapping in a stylesheet's new source reparses only that one.
+///
+/// The document, with the stylesheets it brings as `RenderCache` finds them, and the
+/// other stylesheets are parsed once. On a swap only the
+/// elements a changed rule matches, in its old or new version, are cascaded again;
+/// the rest keep what they cascaded to last time. The whole document is still laid
+/// out again, since a change anywhere can move any box. What comes back says which
+/// boxes changed, so a viewer need only repaint those.
+pub struct LivePreview {
+    cache: RenderCache,
+    document: Arc<Node>,
+    stylesheets: Vec<(String, Origin)>,
+    // the user agent stylesheet, the document's, then each of `stylesheets` parsed
+    parsed: Vec<Stylesheet>,
+    cascade: CascadeCache,
+    boxes: Vec<BoxSnapshot>, // of the boxes of the current frame, in tree order
+    context: StyleContext, // with the document's mode
+    options: RenderOptions,
+    frame: Arc<DisplayList>,
+}
+
+/// What swapping a stylesheet changed.
+#[derive(Debug, Clone)]
+pub struct RenderDelta {
+    pub frame: Arc<DisplayList>, // the new frame
+    // the border boxes, in document coordinates, of the boxes that moved, resized or
+    // were styled differently, where they were and where they are now; every box of
+    // both frames if the document broke into a different number of boxes
+    pub changed_boxes: Vec<Rect>,
+    pub restyled: usize, // how many elements were cascaded again
+}
+
+// where a box was laid out and what it was styled with
+#[derive(Debug, PartialEq)]
+struct BoxSnapshot {
+    border_box: Rect,
+    specified_values: HashMap<String, Value>,
+}
+
+impl LivePreview {
+    /// Render `html` with the user agent stylesheet and `stylesheets`.
+    pub fn new(html: &str, stylesheets: &[(&str, Origin)], options: RenderOptions) -> Result<LivePreview, EngineError> {
+        // room for each stylesheet and the new source of one, so a swap never evicts
+        // the others
+        let mut cache = RenderCache::new(stylesheets.len() + 1);
+        let document = cache.document(html, &options)?;
+        let context = StyleContext { quirks_mode: document.quirks_mode, ..options.style_context() };
+        let mut parsed = vec![css::default_user_agent_stylesheet()];
+        parsed.extend(document.stylesheets.iter().cloned());
+        for &(source, origin) in stylesheets {
+            let sheet = cache.stylesheets.get_or_parse(source, origin, &options.limits)?;
+            parsed.push(Stylesheet::clone(&sheet));
+        }
+        let (frame, boxes, cascade) = {
+            let (style_root, cascade) = style::style_tree_keeping_cascade(&document.root, &parsed, &context);
+            let layout_root = layout(&style_root, &options)?;
+            (paint_frame(&layout_root, &options), box_snapshots(&layout_root), cascade)
+        };
+        Ok(LivePreview {
+            cache,
+            document: document.root,
+            stylesheets: stylesheets.iter().map(|&(source, origin)| (source.to_string(), origin)).collect(),
+            parsed,
+            cascade,
+            boxes,
+            context,
+            options,
+            frame: Arc::new(frame),
+        })
+    }
+
+    /// The frame for the stylesheets as they are now.
+    pub fn frame(&self) -> &Arc<DisplayList> {
+        &self.frame
+    }
+
+    /// Replace the source of the stylesheet at `index`, counting from 0 in the order
+    /// they were given, and render the document again.
+    ///
+    /// If there's no stylesheet at `index`, or the new source can't be rendered, such
+    /// as for having more rules than the limits allow, the error is returned and the
+    /// old source stays.
+    pub fn replace_stylesheet(&mut self, index: usize, css: &str) -> Result<RenderDelta, EngineError> {
+        let count = self.stylesheets.len();
+        let origin = self.stylesheets.get(index).ok_or(EngineError::NoSuchStylesheet { index, count })?.1;
+        let new = Stylesheet::clone(&*self.cache.stylesheets.get_or_parse(css, origin, &self.options.limits)?);
+        // after the user agent stylesheet and the document's
+        let position = self.parsed.len() - count + index;
+        let changes = RuleChanges::between(&self.parsed[position], &new);
+        let old = std::mem::replace(&mut self.parsed[position], new);
+        let rendered = {
+            let (style_root, cascade, restyled) = style::restyle_tree(&self.document, &self.parsed, &self.context, &self.cascade, &changes);
+            layout(&style_root, &self.options)
+                .map(|layout_root| (paint_frame(&layout_root, &self.options), box_snapshots(&layout_root), cascade, restyled))
+        };
+        let (frame, boxes, cascade, restyled) = match rendered {
+            Ok(rendered) => rendered,
+            Err(error) => {
+                self.parsed[position] = old;
+                return Err(error);
+            }
+        };
+        let changed_boxes = changed_boxes(&self.boxes, &boxes);
+        let frame = Arc::new(frame);
+        self.stylesheets[index].0 = css.to_string();
+        self.cascade = cascade;
+        self.boxes = boxes;
+        self.frame = frame.clone();
+        Ok(RenderDelta { frame, changed_boxes, restyled })
+    }
+
+    pub fn cache_stats(&self) -> RenderCacheStats {
+        self.cache.stats()
+    }
+}
+
+// each box of a layout tree, in tree order
+fn box_snapshots(layout_root: &LayoutBox) -> Vec<BoxSnapshot> {
+    let mut snapshots = Vec::new();
+    let mut stack = vec![layout_root];
+    while let Some(layout_box) = stack.pop() {
+        snapshots.push(BoxSnapshot {
+            border_box: layout_box.dimensions.border_box(),
+            specified_values: layout_box.style_node().map(|node| node.specified_values.clone()).unwrap_or_default(),
+        });
+        stack.extend(layout_box.children.iter().rev());
+    }
+    snapshots
+}
+
+// the boxes that differ between two frames, before and after
+fn changed_boxes(old: &[BoxSnapshot], new: &[BoxSnapshot]) -> Vec<Rect> {
+    if old.len() != new.len() {
+        return old.iter().chain(new).map(|snapshot| snapshot.border_box).collect();
+    }
+    let mut changed = Vec::new();
+    for (old, new) in old.iter().zip(new).filter(|(old, new)| old != new) {
+        if old.border_box != new.border_box {
+            changed.push(old.border_box);
+        }
+        changed.push(new.border_box);
+    }
+    changed
+}
+
+// a least-recently-used cache holding at most `capacity` entries
+struct Lru<K, V> {
+    capacity: usize,
+    entries: Vec<(K, V)>, // least recently used first
+    stats: CacheStats,
+}
+
+impl<K: PartialEq, V: Clone> Lru<K, V> {
+    fn new(capacity: usize) -> Lru<K, V> {
+        Lru { capacity, entries: Vec::new(), stats: CacheStats::default() }
+    }
+
+    // look up a value, counting the hit or miss
+    fn get(&mut self, key: &K) -> Option<V> {
+        match self.entries.iter().position(|(k, _)| k == key) {
+            Some(i) => {
+                self.stats.hits += 1;
+                let entry = self.entries.remove(i);
+                let value = entry.1.clone();
+                self.entries.push(entry);
+                Some(value)
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.remove(0);
+            self.stats.evictions += 1;
+        }
+        self.entries.push((key, value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fragment_root, layout_with_warnings, render_fragment, EngineError, Limits, LivePreview, RenderCache, RenderOptions};
+    use crate::font::StandardFontMeasurer;
+    use crate::css::{self, Origin};
+    use crate::html::{self, ParserOptions};
+    use crate::layout::InitialContainingBlock;
+    use crate::resource::InMemoryLoader;
+    use crate::{painting, pdf, png, style};
+    use std::sync::Arc;
+
+    const HTML: &str = "<html><body><h1 class=\"title\">Title</h1><p id=\"intro\">Some <em>text</em></p>\
+        <ul><li>one</li><li class=\"last\">two</li></ul></body></html>";
+    const BASE: &str = "p { margin: 10px } .last { color: red }";
+
+    // the frame a fresh cache renders `css` to, as a swap to it must come to
+    fn cold_render(css: &str) -> String {
+        let frame = RenderCache::new(2).render(HTML, &[(BASE, Origin::Author), (css, Origin::Author)], &RenderOptions::default()).unwrap();
+        format!("{:?}", frame)
+    }
+
+    #[test]
+    fn swapped_stylesheets_render_as_if_rendered_afresh() {
+        let mut preview = LivePreview::new(HTML, &[(BASE, Origin::Author), ("", Origin::Author)], RenderOptions::default()).unwrap();
+        let edits = [
+            "em { color: blue }",
+            "em { color: blue } h1::before { content: \"> \" }",
+            "em { color: blue; font-size: 30px } h1::before { content: \"> \" }",
+            ".title { padding-left: 40px } h1::before { content: \"> \" }",
+            "#intro em { display: block } li:first-child { margin-top: 20px }",
+            "",
+        ];
+        for css in edits {
+            let delta = preview.replace_stylesheet(1, css).unwrap();
+            assert_eq!(format!("{:?}", delta.frame), cold_render(css), "after swapping in {:?}", css);
+            assert!(!delta.changed_boxes.is_empty(), "after swapping in {:?}", css);
+        }
+    }
+
+    #[test]
+    fn swaps_cascade_only_elements_the_change_matches() {
+        let mut preview = LivePreview::new(HTML, &[(BASE, Origin::Author), ("", Origin::Author)], RenderOptions::default()).unwrap();
+        let delta = preview.replace_stylesheet(1, "em { color: blue }").unwrap();
+        assert_eq!(delta.restyled, 1);
+        // the same source again changes nothing
+        let delta = preview.replace_stylesheet(1, "em { color: blue }").unwrap();
+        assert_eq!((delta.restyled, delta.changed_boxes.len()), (0, 0));
+        // editing one rule of a sheet leaves the elements of its other rules alone
+        let delta = preview.replace_stylesheet(0, "p { margin: 20px } .last { color: red }").unwrap();
+        assert_eq!(delta.restyled, 1);
+        assert_eq!(format!("{:?}", delta.frame), {
+            let sheets = [("p { margin: 20px } .last { color: red }", Origin::Author), ("em { color: blue }", Origin::Author)];
+            format!("{:?}", RenderCache::new(2).render(HTML, &sheets, &RenderOptions::default()).unwrap())
+        });
+    }
+
+    #[test]
+    fn swapping_a_stylesheet_that_isnt_there_is_an_error() {
+        let mut preview = LivePreview::new(HTML, &[(BASE, Origin::Author)], RenderOptions::default()).unwrap();
+        let frame = preview.frame().clone();
+        match preview.replace_stylesheet(1, "p { color: red }") {
+            Err(EngineError::NoSuchStylesheet { index: 1, count: 1 }) => {}
+            other => panic!("expected no such stylesheet, got {:?}", other.map(|delta| delta.restyled)),
+        }
+        assert!(std::sync::Arc::ptr_eq(preview.frame(), &frame));
+    }
+
+    #[test]
+    fn fragments_are_rendered_only_at_widths_a_viewport_could_have() {
+        let root = fragment_root(html::parse_fragment("<p>One</p><p>Two</p>", ParserOptions::default()).unwrap());
+        let stylesheets = [css::default_user_agent_stylesheet()];
+        let style_root = style::style_tree(&root, &stylesheets);
+        for width in [0.0, -10.0, f32::NAN, f32::INFINITY, 2e6] {
+            match render_fragment(&style_root, width, &StandardFontMeasurer) {
+                Err(EngineError::InvalidOption { option: "fragment width", .. }) => {}
+                other => panic!("expected width {} to be refused, got {:?}", width, other.map(|(_, list)| list.len())),
+            }
+        }
+        let (layout_root, display_list) = render_fragment(&style_root, 1.0, &StandardFontMeasurer).unwrap();
+        assert_eq!(layout_root.dimensions.content.width, 1.0);
+        assert!(!display_list.is_empty());
+    }
+
+    #[test]
+    fn cached_frames_are_only_reused_within_the_same_limits() {
+        let mut cache = RenderCache::new(2);
+        cache.render(HTML, &[(BASE, Origin::Author)], &RenderOptions::default()).unwrap();
+        let tight = RenderOptions { limits: Limits { max_layout_boxes: 3, ..Default::default() }, ..Default::default() };
+        match cache.render(HTML, &[(BASE, Origin::Author)], &tight) {
+            Err(EngineError::LimitExceeded { .. }) => {}
+            other => panic!("expected the box limit to be exceeded, got {:?}", other.map(|_| ())),
+        }
+        let tight = RenderOptions { limits: Limits { max_css_rules: 1, ..Default::default() }, ..Default::default() };
+        assert!(cache.render(HTML, &[(BASE, Origin::Author)], &tight).is_err());
+    }
+
+    #[test]
+    fn cached_renders_apply_the_documents_own_stylesheets() {
+        let mut loader = InMemoryLoader::new();
+        loader.insert("linked.css", "@import \"imported.css\"; em { color: blue }");
+        loader.insert("imported.css", "li { margin-left: 30px }");
+        let options = RenderOptions { loader: Arc::new(loader), ..Default::default() };
+        let styled = "<html><head><style>p { color: green }</style><link rel=\"stylesheet\" href=\"linked.css\">\
+            <link rel=\"stylesheet\" href=\"missing.css\"></head>".to_string() + &HTML[6..];
+        let frame = RenderCache::new(2).render(&styled, &[(BASE, Origin::Author)], &options).unwrap();
+        // as if they were given after the user agent stylesheet, with the imports first
+        let sheets = [("li { margin-left: 30px }", Origin::Author), ("p { color: green }", Origin::Author),
+            ("em { color: blue }", Origin::Author), (BASE, Origin::Author)];
+        let unstyled = format!("{:?}", RenderCache::new(2).render(HTML, &sheets, &options).unwrap());
+        assert_eq!(format!("{:?}", frame), unstyled);
+        assert_ne!(unstyled, cold_render(""));
+
+        // and a live preview swaps the given stylesheets, after the document's
+        let mut preview = LivePreview::new(&styled, &[(BASE, Origin::Author)], options.clone()).unwrap();
+        assert_eq!(format!("{:?}", preview.frame()), unstyled);
+        let delta = preview.replace_stylesheet(0, "em { color: red }").unwrap();
+        let sheets = [sheets[0], sheets[1], sheets[2], ("em { color: red }", Origin::Author)];
+        assert_eq!(format!("{:?}", delta.frame), format!("{:?}", RenderCache::new(2).render(HTML, &sheets, &options).unwrap()));
+    }
+
+    // a PDF and a PNG of `source`, with the warnings of laying it out, as rendered
+    // with the hermetic options
+    fn hermetic_render(source: &str) -> (Vec<u8>, Vec<u8>, Vec<String>) {
+        let options = RenderOptions::hermetic();
+        let root = html::parse_with_options(source, ParserOptions { limits: options.limits.clone(), ..Default::default() }).unwrap();
+        let stylesheets = [css::default_user_agent_stylesheet()];
+        let style_root = style::style_tree_with_context(&root, &stylesheets, &options.style_context());
+        let (layout_root, warnings) = layout_with_warnings(&style_root, &options).unwrap();
+        let mut pdf = std::io::Cursor::new(Vec::new());
+        pdf::render_with_options(&layout_root, &pdf::Options::default(), &options.limits, &mut pdf).unwrap();
+        let bounds = InitialContainingBlock::from_viewport(options.viewport()).canvas_rect(&layout_root);
+        let mut png = Vec::new();
+        png::write(&painting::paint(&layout_root, bounds, &options.limits).unwrap(), &mut png).unwrap();
+        (pdf.into_inner(), png, warnings)
+    }
+
+    #[test]
+    fn hermetic_renders_of_missing_resources_are_identical() {
+        let source = "<html><body><p>Text <img src=\"missing.png\" alt=\"gone\"></p>\
+            <div style=\"background-image: url(https://example.com/x.png); height: 20px\"></div>\
+            <img src=\"file:///etc/hostname\" width=\"10\" height=\"10\"></body></html>";
+        let first = hermetic_render(source);
+        let second = hermetic_render(source);
+        assert!(first.0 == second.0, "the PDFs differ");
+        assert!(first.1 == second.1, "the PNGs differ");
+        // every refused resource is warned about, the same way each time
+        assert!(first.2.len() >= 2, "{:?}", first.2);
+        assert_eq!(first.2, second.2);
+    }
+}
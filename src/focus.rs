@@ -0,0 +1,161 @@
+use crate::a11y::json_string;
+use crate::dom::{ElementData, Node, NodeType};
+use crate::layout::{BoxType, LayoutBox, Rect};
+use crate::style::NodeRef;
+use std::fmt;
+use std::fmt::Write;
+
+// Where keyboard focus would land in a document: the elements it can be moved to
+// with Tab, in the order it visits them, and where each is on the page. There's no
+// event loop to move focus, but `--focus N` can apply `:focus` to the Nth of them so
+// that its focus styles render.
+
+/// The elements of `root` that Tab moves focus to, in the order it does.
+///
+/// https://html.spec.whatwg.org/multipage/interaction.html#sequential-focus-navigation
+///
+/// Links with an `href`, enabled form controls and any element with an integer
+/// `tabindex` are focusable. Those with a positive `tabindex` come first, in
+/// ascending order of it, then those with `tabindex="0"` or none, in document
+/// order; a negative `tabindex` takes the element out of the order. Elements that
+/// aren't rendered are still listed, since that needs style.
+pub fn tab_order(root: &Node) -> Vec<&Node> {
+    fn collect<'a>(node: &'a Node, found: &mut Vec<(&'a Node, i32)>) {
+        if let NodeType::Element(ref element) = node.node_type {
+            let tabindex = tabindex(element);
+            if tabindex.map_or(is_focusable(element), |tabindex| tabindex >= 0) {
+                found.push((node, tabindex.unwrap_or(0)));
+            }
+        }
+        for child in &node.children {
+            collect(child, found);
+        }
+    }
+    let mut found = Vec::new();
+    collect(root, &mut found);
+    // a stable sort keeps document order among equal indices
+    found.sort_by_key(|&(_, tabindex)| match tabindex {
+        0 => i32::MAX,
+        n => n,
+    });
+    found.into_iter().map(|(node, _)| node).collect()
+}
+
+// the `tabindex` attribute, if it's a valid integer
+fn tabindex(element: &ElementData) -> Option<i32> {
+    element.attrs.get("tabindex")?.trim().parse().ok()
+}
+
+// is the element focusable without a `tabindex`?
+fn is_focusable(element: &ElementData) -> bool {
+    let disabled = element.attrs.contains_key("disabled");
+    match element.tag_name.as_str() {
+        "a" | "area" => element.attrs.contains_key("href"),
+        "button" | "select" | "textarea" => !disabled,
+        "input" => !disabled && element.attrs.get("type").is_none_or(|kind| !kind.eq_ignore_ascii_case("hidden")),
+        _ => false,
+    }
+}
+
+/// The tab order of a laid out document, as `--format focus` prints.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FocusReport {
+    pub entries: Vec<FocusEntry>,
+}
+
+/// One element of the tab order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FocusEntry {
+    pub index: usize, // from 1, as `--focus` counts
+    pub tag_name: String,
+    pub id: Option<String>,
+    pub tabindex: Option<i32>,
+    // its border box, or one per line for an inline element; none if it has no box
+    pub rects: Vec<Rect>,
+}
+
+impl FocusReport {
+    /// Find where each element of the tab order of `root` was laid out in `layout_root`.
+    pub fn of(root: &Node, layout_root: &LayoutBox) -> FocusReport {
+        let entries = tab_order(root).into_iter().enumerate().map(|(i, node)| {
+            let element = match node.node_type {
+                NodeType::Element(ref element) => element,
+                _ => unreachable!("only elements are focusable"),
+            };
+            let mut rects = Vec::new();
+            collect_rects(layout_root, node, &mut rects);
+            FocusEntry {
+                index: i + 1,
+                tag_name: element.tag_name.clone(),
+                id: element.id().cloned(),
+                tabindex: tabindex(element),
+                rects,
+            }
+        }).collect();
+        FocusReport { entries }
+    }
+
+    /// Render the report as a JSON array with an object per element, in tab order.
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("[");
+        for (i, entry) in self.entries.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let id = entry.id.as_deref().map_or("null".to_string(), json_string);
+            let tabindex = entry.tabindex.map_or("null".to_string(), |tabindex| tabindex.to_string());
+            let _ = write!(out, "{{\"index\":{},\"element\":{},\"id\":{},\"tabindex\":{},\"rects\":[",
+                           entry.index, json_string(&entry.tag_name), id, tabindex);
+            for (j, rect) in entry.rects.iter().enumerate() {
+                if j > 0 {
+                    out.push(',');
+                }
+                let _ = write!(out, "{{\"x\":{},\"y\":{},\"width\":{},\"height\":{}}}",
+                               rect.x, rect.y, rect.width, rect.height);
+            }
+            out.push_str("]}");
+        }
+        out.push(']');
+        out
+    }
+}
+
+// the border boxes laid out for `node`: an element split around blocks has a box
+// for each piece, and an inline element a fragment for each line
+fn collect_rects(layout_box: &LayoutBox, node: &Node, rects: &mut Vec<Rect>) {
+    if let Some(style) = layout_box.style_node() {
+        if matches!(style.node, NodeRef::Dom(dom) if std::ptr::eq(dom, node)) {
+            match layout_box.box_type {
+                BoxType::InlineNode(_) => rects.extend(layout_box.inline_fragments.iter().map(|fragment| fragment.border_box)),
+                _ => rects.push(layout_box.dimensions.border_box()),
+            }
+        }
+    }
+    for child in &layout_box.children {
+        collect_rects(child, node, rects);
+    }
+}
+
+impl fmt::Display for FocusReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for entry in &self.entries {
+            let mut line = format!("{}. <{}", entry.index, entry.tag_name);
+            if let Some(ref id) = entry.id {
+                let _ = write!(line, " id={}", id);
+            }
+            if let Some(tabindex) = entry.tabindex {
+                let _ = write!(line, " tabindex={}", tabindex);
+            }
+            line.push('>');
+            match entry.rects.first() {
+                Some(rect) => { let _ = write!(line, " at ({}, {}) {}x{}", rect.x, rect.y, rect.width, rect.height); }
+                None => line.push_str(" not rendered"),
+            }
+            if entry.rects.len() > 1 {
+                let _ = write!(line, " and {} more", entry.rects.len() - 1);
+            }
+            writeln!(f, "{}", line)?;
+        }
+        Ok(())
+    }
+}
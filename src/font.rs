@@ -0,0 +1,161 @@
+use crate::css::Value;
+use crate::style::StyledNode;
+
+// the font properties that affect how much room a run of text takes up
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Font {
+    pub size: f32, // font-size in px
+    pub weight: u16, // font-weight, 100 to 900
+    pub italic: bool, // font-style: italic or oblique
+}
+
+impl Font {
+    // read the font properties of an (element or text) styled node
+    pub fn from_style(style: &StyledNode) -> Font {
+        let size = match style.value("font-size") {
+            Some(Value::Length(size, _)) if size > 0.0 => size,
+            _ => 16.0
+        };
+        // relative weights are resolved as if the parent had a normal weight
+        let weight = match style.value("font-weight") {
+            Some(Value::Number(w)) => w.clamp(100.0, 900.0) as u16,
+            Some(Value::Keyword(k)) => match &*k {
+                "bold" | "bolder" => 700,
+                "lighter" => 100,
+                _ => 400
+            },
+            _ => 400
+        };
+        let italic = matches!(style.value("font-style"), Some(Value::Keyword(k)) if k == "italic" || k == "oblique");
+        Font { size, weight, italic }
+    }
+
+    // weights from 600 up select the bold face
+    pub fn is_bold(&self) -> bool {
+        self.weight >= 600
+    }
+
+    // where text in this font sits on a line box: the ascent of a font centered in
+    // the line ends at the baseline
+    pub fn baseline(&self, line_top: f32, line_height: f32) -> f32 {
+        line_top + (line_height - self.size) / 2.0 + self.size * 0.8
+    }
+}
+
+/// Measures text for inline layout.
+///
+/// Layout never looks at glyphs directly; everything it knows about text comes
+/// through this trait so the font backend can be swapped out.
+pub trait TextMeasurer {
+    /// The advance width of `text` in px when set in `font`.
+    fn measure(&self, text: &str, font: &Font) -> f32;
+
+    /// The height of a line box containing only text in `font`.
+    fn line_height(&self, font: &Font) -> f32 {
+        font.size * 1.2
+    }
+}
+
+// crude metrics: every character advances by half the font size, which is
+// roughly the average advance of a proportional sans-serif face. Bold text
+// is widened by a tenth, the way a synthesized bold would be.
+pub struct AverageWidthMeasurer;
+
+impl TextMeasurer for AverageWidthMeasurer {
+    fn measure(&self, text: &str, font: &Font) -> f32 {
+        let advance = if font.is_bold() { 0.55 } else { 0.5 };
+        text.chars().count() as f32 * font.size * advance
+    }
+}
+
+// Advance widths, in thousandths of the font size, of the printable ASCII
+// characters (U+0020 to U+007E) in Helvetica and Helvetica-Bold, from Adobe's
+// Core 14 AFM files. The oblique faces share the widths of the upright ones. The
+// AFM files may be used, copied and distributed for any purpose provided that
+// Adobe's copyright notice in them is kept with them; only the widths, which are
+// facts about the fonts, are reproduced here.
+const HELVETICA_WIDTHS: [u16; 95] = [
+    278, 278, 355, 556, 556, 889, 667, 191, 333, 333, 389, 584, 278, 333, 278, 278, // ' ' to '/'
+    556, 556, 556, 556, 556, 556, 556, 556, 556, 556, 278, 278, 584, 584, 584, 556, // '0' to '?'
+    1015, 667, 667, 722, 722, 667, 611, 778, 722, 278, 500, 667, 556, 833, 722, 778, // '@' to 'O'
+    667, 778, 722, 667, 611, 722, 667, 944, 667, 667, 611, 278, 278, 278, 469, 556, // 'P' to '_'
+    333, 556, 556, 500, 556, 556, 278, 556, 556, 222, 222, 500, 222, 833, 556, 556, // '`' to 'o'
+    556, 556, 333, 500, 278, 556, 500, 722, 500, 500, 500, 334, 260, 334, 584,      // 'p' to '~'
+];
+
+const HELVETICA_BOLD_WIDTHS: [u16; 95] = [
+    278, 333, 474, 556, 556, 889, 722, 238, 333, 333, 389, 584, 278, 333, 278, 278, // ' ' to '/'
+    556, 556, 556, 556, 556, 556, 556, 556, 556, 556, 333, 333, 584, 584, 584, 611, // '0' to '?'
+    975, 722, 722, 722, 722, 667, 611, 778, 722, 278, 556, 722, 611, 833, 722, 778, // '@' to 'O'
+    667, 778, 722, 667, 611, 722, 667, 944, 667, 667, 611, 333, 278, 333, 584, 556, // 'P' to '_'
+    333, 556, 611, 556, 611, 556, 333, 611, 611, 278, 278, 556, 278, 889, 611, 611, // '`' to 'o'
+    611, 611, 389, 556, 333, 611, 556, 778, 556, 556, 500, 389, 280, 389, 584,      // 'p' to '~'
+];
+
+/// Measures text with the metrics of the fonts the PDF output is set in, the
+/// standard Helvetica faces, so that line breaks fall where the text really ends.
+///
+/// The metrics are built in, so measurement is the same on every machine. Every
+/// `font-family` is measured as Helvetica, since that's the only face the output
+/// draws with.
+pub struct StandardFontMeasurer;
+
+impl StandardFontMeasurer {
+    // the advance of `c` in thousandths of the font size
+    fn advance(c: char, bold: bool) -> u16 {
+        let widths = if bold { &HELVETICA_BOLD_WIDTHS } else { &HELVETICA_WIDTHS };
+        match c {
+            ' '..='~' => widths[c as usize - 0x20],
+            '\u{a0}' => widths[0], // a no-break space is as wide as a space
+            '\u{2026}' => 1000, // ellipsis
+            'ì'..='ï' => 278, // built on a dotless i, which is wider than an i
+            // the punctuation and symbols WinAnsiEncoding has above ASCII
+            '—' | '‰' | '™' | 'Œ' => 1000,
+            'œ' => 944,
+            '–' | '€' | '†' | '‡' | 'ƒ' => 556,
+            '•' => 350,
+            '‹' | '›' | 'ˆ' | '˜' => 333,
+            '‘' | '’' | '‚' => if bold { 278 } else { 222 },
+            '“' | '”' | '„' => if bold { 500 } else { 333 },
+            // accented Latin-1 letters are as wide as the letters they're built on
+            _ => match base_letter(c) {
+                Some(base) => widths[base as usize - 0x20],
+                None => 556, // the width of a digit, a typical advance
+            }
+        }
+    }
+}
+
+impl TextMeasurer for StandardFontMeasurer {
+    fn measure(&self, text: &str, font: &Font) -> f32 {
+        let bold = font.is_bold();
+        let total: u32 = text.chars().map(|c| StandardFontMeasurer::advance(c, bold) as u32).sum();
+        total as f32 * font.size / 1000.0
+    }
+}
+
+// the unaccented letter an accented Latin-1 or WinAnsiEncoding letter is drawn from
+fn base_letter(c: char) -> Option<char> {
+    Some(match c {
+        'À'..='Å' => 'A',
+        'Ç' => 'C',
+        'È'..='Ë' => 'E',
+        'Ì'..='Ï' => 'I',
+        'Ñ' => 'N',
+        'Ò'..='Ö' | 'Ø' => 'O',
+        'Ù'..='Ü' => 'U',
+        'Ý' | 'Ÿ' => 'Y',
+        'Š' => 'S',
+        'Ž' => 'Z',
+        'à'..='å' => 'a',
+        'ç' => 'c',
+        'è'..='ë' => 'e',
+        'ñ' => 'n',
+        'ò'..='ö' | 'ø' => 'o',
+        'ù'..='ü' => 'u',
+        'ý' | 'ÿ' => 'y',
+        'š' => 's',
+        'ž' => 'z',
+        _ => return None,
+    })
+}
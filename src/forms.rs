@@ -0,0 +1,131 @@
+use crate::dom::{ElementData, Node, NodeType};
+
+// Form controls: `<input>`, `<button>`, `<select>` and `<textarea>`, rendered the
+// way they look before anyone interacts with them.
+//
+// https://html.spec.whatwg.org/multipage/rendering.html#form-controls
+//
+// Controls are inline-blocks. The user agent stylesheet gives them their borders,
+// padding and font, and presentational hints the sizes that depend on their type
+// and attributes. The text a control shows, its value, placeholder, button label
+// or selected option, becomes a text child in the style tree, so it's laid out,
+// measured and painted like any other text. Painting adds what isn't text: the
+// marks of checkboxes and radio buttons, and the arrow of a select. Nothing reacts
+// to input, and a value wider than its field isn't clipped.
+
+/// The kinds of form control rendered differently.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Control {
+    TextField, // `<input>` of a type holding text, and of unknown types
+    Password,
+    TextArea,
+    Button, // `<button>`, and inputs of type submit, reset, button and file
+    Checkbox,
+    Radio,
+    Select,
+    Hidden, // `<input type=hidden>`, which isn't rendered
+}
+
+/// The kind of control an element is, or `None` if it isn't one.
+pub fn control(elem: &ElementData) -> Option<Control> {
+    let tag = elem.tag_name.to_ascii_lowercase();
+    Some(match tag.as_str() {
+        "button" => Control::Button,
+        "select" => Control::Select,
+        "textarea" => Control::TextArea,
+        "input" => match input_type(elem).as_str() {
+            "hidden" => Control::Hidden,
+            "password" => Control::Password,
+            "checkbox" => Control::Checkbox,
+            "radio" => Control::Radio,
+            "submit" | "reset" | "button" | "file" => Control::Button,
+            _ => Control::TextField,
+        },
+        _ => return None,
+    })
+}
+
+fn input_type(elem: &ElementData) -> String {
+    elem.attrs.get("type").map_or(String::new(), |kind| kind.trim().to_ascii_lowercase())
+}
+
+/// Does a checkbox or radio button have the `checked` attribute?
+pub fn is_checked(elem: &ElementData) -> bool {
+    elem.attrs.contains_key("checked")
+}
+
+/// What a control shows as text.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ShownText {
+    Value(String),
+    // the hint shown in an empty field, styled by `::placeholder` rules
+    Placeholder(String),
+}
+
+/// The text a control shows in place of its children, or `None` for one that
+/// shows its own children, as `<button>` and a `<textarea>` with text do, or no
+/// text at all.
+///
+/// A password is masked with one `*` for each character, and a select shows its
+/// first option with the `selected` attribute, or else its first option.
+pub fn shown_text(node: &Node) -> Option<ShownText> {
+    let elem = match node.node_type {
+        NodeType::Element(ref elem) => elem,
+        _ => return None,
+    };
+    let attr = |name: &str| elem.attrs.get(name).cloned();
+    let value_or_placeholder = |value: Option<String>| match value.filter(|value| !value.is_empty()) {
+        Some(value) => Some(ShownText::Value(value)),
+        None => attr("placeholder").filter(|placeholder| !placeholder.is_empty()).map(ShownText::Placeholder),
+    };
+    match control(elem)? {
+        Control::TextField => value_or_placeholder(attr("value")),
+        Control::Password => value_or_placeholder(attr("value").map(|value| "*".repeat(value.chars().count()))),
+        Control::TextArea if node.children.is_empty() => value_or_placeholder(None),
+        Control::Button if elem.tag_name.eq_ignore_ascii_case("input") => {
+            let default = match input_type(elem).as_str() {
+                "submit" => "Submit",
+                "reset" => "Reset",
+                "file" => "Choose File",
+                _ => "",
+            };
+            let label = match input_type(elem).as_str() {
+                "file" => default.to_string(), // a file input's value isn't its label
+                _ => attr("value").unwrap_or_else(|| default.to_string()),
+            };
+            Some(ShownText::Value(label))
+        }
+        Control::Select => {
+            let mut options = Vec::new();
+            collect_options(node, &mut options);
+            let selected = options.iter()
+                .find(|option| matches!(option.node_type, NodeType::Element(ref elem) if elem.attrs.contains_key("selected")))
+                .or(options.first());
+            let label = selected.map_or(String::new(), |option| {
+                let mut text = String::new();
+                collect_text(option, &mut text);
+                text.split_whitespace().collect::<Vec<_>>().join(" ")
+            });
+            Some(ShownText::Value(label))
+        }
+        Control::TextArea | Control::Button | Control::Checkbox | Control::Radio | Control::Hidden => None,
+    }
+}
+
+// the `<option>` elements in a select, including those in option groups
+fn collect_options<'a>(node: &'a Node, options: &mut Vec<&'a Node>) {
+    for child in &node.children {
+        match child.node_type {
+            NodeType::Element(ref elem) if elem.tag_name.eq_ignore_ascii_case("option") => options.push(child),
+            NodeType::Element(_) => collect_options(child, options),
+            _ => {}
+        }
+    }
+}
+
+fn collect_text(node: &Node, text: &mut String) {
+    match node.node_type {
+        NodeType::Text(ref data) => text.push_str(data),
+        _ => node.children.iter().for_each(|child| collect_text(child, text)),
+    }
+}
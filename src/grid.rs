@@ -0,0 +1,453 @@
+use crate::bidi::Direction;
+use crate::css::{Value, Value::{Keyword, Length, Number}, Unit::{Fr, Percent, Px}};
+use crate::font::Font;
+use crate::inline::TextAlign;
+use crate::layout::{AnonymousBlock, BlockNode, Dimensions, LayoutBox, LayoutContext, Rect};
+use crate::style::StyledNode;
+
+// Grid layout, for boxes with `display: grid` and the items in them.
+//
+// https://www.w3.org/TR/css-grid-1/
+//
+// Columns and rows come from `grid-template-columns` and `grid-template-rows`, as
+// px, percentage and fr tracks, separated by `column-gap` and `row-gap`. Items are
+// placed at the line numbers and spans of `grid-column` and `grid-row`, and the
+// rest are auto-placed in row-major order into the first cells free for them.
+// Fr tracks share out the space the other tracks and the gaps leave. Tracks
+// outside the explicit grid are auto: columns as wide as the max-content width of
+// their items, and rows as tall as their items, auto tracks stretching to fill
+// space no fr track takes. Items are stretched over their grid areas. Named lines
+// and areas, `grid-auto-flow`, `order` and intrinsic track sizes like
+// `min-content` and `minmax()` aren't supported.
+
+// lines and spans beyond this are clamped to it, so a huge line number doesn't
+// make a huge grid
+const MAX_TRACKS: usize = 1000;
+
+/// The size of a grid track, as given in a track template or for an implicit track.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TrackSize {
+    Fixed(f32), // px
+    Percent(f32), // of the container's content size on that axis
+    Flex(f32), // fr
+    Auto,
+}
+
+/// The tracks an item covers: rows `row..row + rowspan` and columns
+/// `column..column + colspan`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GridArea {
+    pub row: usize,
+    pub column: usize,
+    pub rowspan: usize,
+    pub colspan: usize,
+}
+
+/// The grid of a grid container, with its items placed in it.
+///
+/// https://www.w3.org/TR/css-grid-1/#placement
+#[derive(Debug, Default)]
+pub struct GridPlacement {
+    pub rows: usize,
+    pub columns: usize,
+    pub areas: Vec<GridArea>, // for each item, in tree order
+}
+
+// where an item goes on one axis: between two lines, counted from 0, or anywhere
+// it fits, over a number of tracks
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum AxisPlacement {
+    Definite(usize, usize),
+    Auto(usize),
+}
+
+// a `grid-*-start` or `grid-*-end` value
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Line {
+    Auto,
+    Number(i32), // from 1, or negative from the end of the explicit grid
+    Span(usize),
+}
+
+impl GridPlacement {
+    /// Place the items of a grid container whose explicit grid has `explicit_rows`
+    /// rows and `explicit_columns` columns.
+    pub fn resolve(container: &LayoutBox, explicit_rows: usize, explicit_columns: usize) -> GridPlacement {
+        let placements: Vec<(AxisPlacement, AxisPlacement)> = container.children.iter().map(|item| {
+            let line = |property| item.style_node().map_or(Line::Auto, |style| grid_line(style, property));
+            (axis_placement(line("grid-row-start"), line("grid-row-end"), explicit_rows),
+             axis_placement(line("grid-column-start"), line("grid-column-end"), explicit_columns))
+        }).collect();
+
+        // the implicit grid has columns for every item placed beyond the explicit
+        // ones, and for the widest span of an auto-placed item
+        let mut columns = explicit_columns.max(1);
+        for (_, column) in &placements {
+            columns = columns.max(match *column {
+                AxisPlacement::Definite(_, end) => end,
+                AxisPlacement::Auto(span) => span,
+            });
+        }
+
+        let mut occupied = Occupancy::default();
+        let mut areas = vec![None; placements.len()];
+        // first the items with a definite place on both axes, then those locked to
+        // some rows, which go in the first columns free in those rows
+        for (i, placement) in placements.iter().enumerate() {
+            if let (AxisPlacement::Definite(row, row_end), AxisPlacement::Definite(column, column_end)) = *placement {
+                areas[i] = Some(GridArea { row, column, rowspan: row_end - row, colspan: column_end - column });
+            }
+        }
+        for area in areas.iter().flatten() {
+            occupied.take(area);
+        }
+        for (i, placement) in placements.iter().enumerate() {
+            if let (AxisPlacement::Definite(row, row_end), AxisPlacement::Auto(colspan)) = *placement {
+                let rowspan = row_end - row;
+                let column = (0..=columns - colspan)
+                    .find(|&column| occupied.is_free(&GridArea { row, column, rowspan, colspan }))
+                    .unwrap_or_else(|| {
+                        // a full row gets columns of its own at the end
+                        let column = columns;
+                        columns += colspan;
+                        column
+                    });
+                let area = GridArea { row, column, rowspan, colspan };
+                occupied.take(&area);
+                areas[i] = Some(area);
+            }
+        }
+
+        // then the rest, in order, from a cursor that moves along the rows
+        let (mut cursor_row, mut cursor_column) = (0, 0);
+        for (i, placement) in placements.iter().enumerate() {
+            let (rowspan, column) = match *placement {
+                (AxisPlacement::Auto(rowspan), column) => (rowspan, column),
+                _ => continue
+            };
+            let area = match column {
+                AxisPlacement::Definite(column, column_end) => {
+                    if column < cursor_column {
+                        cursor_row += 1;
+                    }
+                    let mut area = GridArea { row: cursor_row, column, rowspan, colspan: column_end - column };
+                    while !occupied.is_free(&area) {
+                        area.row += 1;
+                    }
+                    area
+                }
+                AxisPlacement::Auto(colspan) => loop {
+                    if cursor_column + colspan > columns {
+                        cursor_row += 1;
+                        cursor_column = 0;
+                        continue;
+                    }
+                    let area = GridArea { row: cursor_row, column: cursor_column, rowspan, colspan };
+                    if occupied.is_free(&area) {
+                        break area;
+                    }
+                    cursor_column += 1;
+                }
+            };
+            cursor_row = area.row;
+            cursor_column = area.column + area.colspan;
+            occupied.take(&area);
+            areas[i] = Some(area);
+        }
+
+        let areas: Vec<GridArea> = areas.into_iter().map(|area| area.expect("every item was placed")).collect();
+        let rows = areas.iter().map(|area| area.row + area.rowspan).fold(explicit_rows, usize::max);
+        GridPlacement { rows, columns, areas }
+    }
+}
+
+// which cells of the grid items cover, by row
+#[derive(Default)]
+struct Occupancy(Vec<Vec<bool>>);
+
+impl Occupancy {
+    fn is_free(&self, area: &GridArea) -> bool {
+        self.0.iter().skip(area.row).take(area.rowspan).all(|cells| {
+            cells.iter().skip(area.column).take(area.colspan).all(|&taken| !taken)
+        })
+    }
+
+    fn take(&mut self, area: &GridArea) {
+        if self.0.len() < area.row + area.rowspan {
+            self.0.resize(area.row + area.rowspan, Vec::new());
+        }
+        for cells in &mut self.0[area.row..area.row + area.rowspan] {
+            if cells.len() < area.column + area.colspan {
+                cells.resize(area.column + area.colspan, false);
+            }
+            cells[area.column..area.column + area.colspan].fill(true);
+        }
+    }
+}
+
+// a line longhand of an item
+fn grid_line(style: &StyledNode, property: &str) -> Line {
+    match style.value(property) {
+        Some(Number(n)) if n.fract() == 0.0 && n != 0.0 => Line::Number(n.clamp(-(MAX_TRACKS as f32), MAX_TRACKS as f32) as i32),
+        Some(Value::List(components)) => match components.as_slice() {
+            [Keyword(span), Number(n)] if span == "span" && *n >= 1.0 => Line::Span((*n as usize).min(MAX_TRACKS)),
+            _ => Line::Auto
+        },
+        _ => Line::Auto
+    }
+}
+
+// the placement of an item on an axis with `explicit` tracks, from its start and
+// end lines; a span with no line to count from is an auto placement, a start
+// after its end is swapped with it, and equal lines, like an end span next to a
+// start span, leave a span of one
+//
+// https://www.w3.org/TR/css-grid-1/#grid-placement-errors
+fn axis_placement(start: Line, end: Line, explicit: usize) -> AxisPlacement {
+    // negative lines count back from the last explicit one; lines before the
+    // first are clamped to it
+    let line = |n: i32| match n {
+        n if n > 0 => (n - 1) as usize,
+        n => (explicit as i32 + 1 + n).max(0) as usize,
+    };
+    let (start, end) = match (start, end) {
+        (Line::Number(start), Line::Number(end)) => {
+            let (start, end) = (line(start), line(end));
+            match start.cmp(&end) {
+                std::cmp::Ordering::Less => (start, end),
+                std::cmp::Ordering::Equal => (start, start + 1),
+                std::cmp::Ordering::Greater => (end, start),
+            }
+        }
+        (Line::Number(start), Line::Span(span)) => (line(start), line(start) + span),
+        (Line::Number(start), Line::Auto) => (line(start), line(start) + 1),
+        (Line::Span(span), Line::Number(end)) => (line(end).saturating_sub(span), line(end)),
+        (Line::Auto, Line::Number(end)) => (line(end).saturating_sub(1), line(end)),
+        (Line::Span(span), _) | (Line::Auto, Line::Span(span)) => return AxisPlacement::Auto(span),
+        (Line::Auto, Line::Auto) => return AxisPlacement::Auto(1),
+    };
+    let start = start.min(MAX_TRACKS - 1);
+    AxisPlacement::Definite(start, end.min(MAX_TRACKS).max(start + 1))
+}
+
+// the explicit tracks of `grid-template-columns` or `grid-template-rows`
+fn track_template(style: &StyledNode, property: &str) -> Vec<TrackSize> {
+    let track = |value: &Value| match *value {
+        Length(n, Fr) => TrackSize::Flex(n.max(0.0)),
+        Length(n, Percent) => TrackSize::Percent(n.max(0.0)),
+        Length(n, Px) => TrackSize::Fixed(n.max(0.0)),
+        Number(n) => TrackSize::Fixed(n.max(0.0)),
+        _ => TrackSize::Auto,
+    };
+    let mut tracks: Vec<TrackSize> = match style.value(property) {
+        Some(Value::List(values)) => values.iter().map(track).collect(),
+        Some(Keyword(k)) if k == "none" => Vec::new(),
+        Some(value) => vec![track(&value)],
+        None => Vec::new(),
+    };
+    tracks.truncate(MAX_TRACKS);
+    tracks
+}
+
+// a `row-gap` or `column-gap` in px, percentages being of `reference`
+fn gap(style: &StyledNode, property: &str, reference: f32) -> f32 {
+    match style.value(property) {
+        Some(Keyword(_)) | None => 0.0, // normal
+        Some(value) => value.to_px_of(reference).max(0.0),
+    }
+}
+
+/// The used sizes of the tracks on an axis `space` px long, or of indefinite
+/// length if `None`.
+///
+/// https://www.w3.org/TR/css-grid-1/#algo-track-sizing
+///
+/// `contents` is, for each track, what its items need, which is the size of
+/// auto tracks, and of fr and percentage tracks on an indefinite axis. The free
+/// space the other tracks and the gaps leave is shared out among fr tracks;
+/// fractions adding up to less than 1fr take only their share of it. Without fr
+/// tracks, auto tracks stretch to fill it.
+pub fn size_tracks(tracks: &[TrackSize], contents: &[f32], space: Option<f32>, gap: f32) -> Vec<f32> {
+    let mut sizes: Vec<f32> = tracks.iter().zip(contents).map(|(&track, &content)| match (track, space) {
+        (TrackSize::Fixed(px), _) => px,
+        (TrackSize::Percent(p), Some(space)) => p * space / 100.0,
+        (TrackSize::Flex(_), Some(_)) => 0.0,
+        (TrackSize::Percent(_) | TrackSize::Flex(_) | TrackSize::Auto, _) => content,
+    }).collect();
+    let space = match space {
+        Some(space) => space,
+        None => return sizes
+    };
+    let gaps = tracks.len().saturating_sub(1) as f32 * gap;
+    let free = space - gaps - sizes.iter().sum::<f32>();
+    let fractions: f32 = tracks.iter().map(|track| match track {
+        TrackSize::Flex(fr) => *fr,
+        _ => 0.0
+    }).sum();
+    let autos = tracks.iter().filter(|&&track| track == TrackSize::Auto).count();
+    let has_flex = tracks.iter().any(|track| matches!(track, TrackSize::Flex(_)));
+    if has_flex {
+        let per_fr = free.max(0.0) / fractions.max(1.0);
+        for (size, track) in sizes.iter_mut().zip(tracks) {
+            if let TrackSize::Flex(fr) = track {
+                *size = fr * per_fr;
+            }
+        }
+    } else if free > 0.0 && autos > 0 {
+        for (size, track) in sizes.iter_mut().zip(tracks) {
+            if *track == TrackSize::Auto {
+                *size += free / autos as f32;
+            }
+        }
+    }
+    sizes
+}
+
+// the start of each track on an axis starting at `origin`, and the end of the last
+fn track_edges(origin: f32, sizes: &[f32], gap: f32) -> Vec<f32> {
+    let mut edges = Vec::with_capacity(sizes.len() + 1);
+    let mut position = origin;
+    for (i, size) in sizes.iter().enumerate() {
+        if i > 0 {
+            position += gap;
+        }
+        edges.push(position);
+        position += size;
+    }
+    edges.push(position);
+    edges
+}
+
+// the size of the tracks `start..start + span`, with the gaps between them
+fn span_size(edges: &[f32], start: usize, span: usize, gap: f32) -> f32 {
+    let last_gap = if start + span == edges.len() - 1 { 0.0 } else { gap };
+    edges[start + span] - last_gap - edges[start]
+}
+
+// Lay out a grid container: the container is sized and placed like a block, its
+// items are placed in the grid, the columns sized to the container's width, and
+// the items laid out in theirs. Then the rows are sized to fit the items, which
+// are moved down to their rows and stretched over them.
+pub(crate) fn layout_grid(container: &mut LayoutBox, containing_block: Dimensions, containing_height: Option<f32>, ctx: &LayoutContext) {
+    container.calculate_block_width(containing_block, ctx);
+    container.calculate_block_position(containing_block);
+    let style = container.get_style_node();
+    let width = container.dimensions.content.width;
+    let height = container.definite_height(containing_height);
+    let column_gap = gap(style, "column-gap", width);
+    let row_gap = gap(style, "row-gap", height.unwrap_or(0.0));
+    let mut column_tracks = track_template(style, "grid-template-columns");
+    let mut row_tracks = track_template(style, "grid-template-rows");
+
+    let grid = GridPlacement::resolve(container, row_tracks.len(), column_tracks.len());
+    column_tracks.resize(grid.columns, TrackSize::Auto);
+    row_tracks.resize(grid.rows, TrackSize::Auto);
+
+    // auto columns are as wide as the items in them alone
+    let mut column_contents = vec![0.0; grid.columns];
+    for (item, area) in container.children.iter().zip(&grid.areas) {
+        if area.colspan == 1 && column_tracks[area.column] == TrackSize::Auto {
+            let needed = item.max_content_contribution(ctx);
+            column_contents[area.column] = f32::max(column_contents[area.column], needed);
+        }
+    }
+    let column_sizes = size_tracks(&column_tracks, &column_contents, Some(width), column_gap);
+    let column_x = track_edges(container.dimensions.content.x, &column_sizes, column_gap);
+
+    // lay out each item at the top of the grid in the columns it spans, then size
+    // the rows to fit, items spanning several rows considered after the others,
+    // each adding what it lacks to the rows sized by their items
+    let top = container.dimensions.content.y;
+    let align = TextAlign::of(style);
+    let direction = Direction::of(style);
+    let strut = Font::from_style(style);
+    let mut item_heights = Vec::with_capacity(grid.areas.len());
+    for (item, area) in container.children.iter_mut().zip(&grid.areas) {
+        let block = Dimensions {
+            content: Rect {
+                x: column_x[area.column],
+                y: top,
+                width: span_size(&column_x, area.column, area.colspan, column_gap),
+                height: 0.0,
+            },
+            ..Default::default()
+        };
+        match item.box_type {
+            AnonymousBlock => item.layout_inline_container(block, 0.0, align, direction, Some(strut), ctx),
+            _ => item.layout(block, None, ctx),
+        }
+        item_heights.push(item.dimensions.margin_box().height);
+    }
+    let fixed_size = |track: TrackSize| match (track, height) {
+        (TrackSize::Fixed(px), _) => px,
+        (TrackSize::Percent(p), Some(height)) => p * height / 100.0,
+        _ => 0.0, // an fr row's size isn't known yet
+    };
+    let sized_by_content = |track: TrackSize| match track {
+        TrackSize::Fixed(_) => false,
+        TrackSize::Percent(_) | TrackSize::Flex(_) => height.is_none(),
+        TrackSize::Auto => true,
+    };
+    let mut row_contents = vec![0.0; grid.rows];
+    let mut by_rowspan: Vec<(&GridArea, f32)> = grid.areas.iter().zip(item_heights).collect();
+    by_rowspan.sort_by_key(|(area, _)| area.rowspan);
+    for (area, needed) in by_rowspan {
+        let rows = area.row..area.row + area.rowspan;
+        let fixed: f32 = rows.clone()
+            .filter(|&row| !sized_by_content(row_tracks[row]))
+            .map(|row| fixed_size(row_tracks[row]))
+            .sum();
+        let needed = needed - fixed - (area.rowspan - 1) as f32 * row_gap;
+        let mut sizes: Vec<f32> = rows.clone().filter(|&row| sized_by_content(row_tracks[row])).map(|row| row_contents[row]).collect();
+        crate::table::distribute(&mut sizes, needed);
+        for (row, size) in rows.filter(|&row| sized_by_content(row_tracks[row])).zip(sizes) {
+            row_contents[row] = size;
+        }
+    }
+    let row_sizes = size_tracks(&row_tracks, &row_contents, height, row_gap);
+    let row_y = track_edges(top, &row_sizes, row_gap);
+
+    // move the items down to their rows, and stretch those with auto heights over
+    // the rows they span
+    for (item, area) in container.children.iter_mut().zip(&grid.areas) {
+        item.translate(0.0, row_y[area.row] - top);
+        let stretches = match item.box_type {
+            BlockNode(_) => item.definite_height(None).is_none() && crate::replaced::used_size(item).is_none(),
+            _ => true,
+        };
+        if stretches {
+            let span = span_size(&row_y, area.row, area.rowspan, row_gap);
+            item.dimensions.content.height += (span - item.dimensions.margin_box().height).max(0.0);
+        }
+    }
+
+    let rows_height = row_y.last().unwrap() - top;
+    container.dimensions.content.height = height.unwrap_or(rows_height);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::css::{self, Origin};
+    use crate::engine::{self, RenderOptions};
+    use crate::{html, style};
+
+    #[test]
+    fn fr_tracks_share_the_space_the_fixed_tracks_and_gaps_leave() {
+        let root = html::parse("<div><p>a</p><p>b</p><p>c</p><p class=\"wide\">d</p></div>".to_string());
+        let css = "div { display: grid; width: 700px; grid-template-columns: 1fr 2fr 100px; column-gap: 10px; row-gap: 5px } \
+            p { margin: 0; height: 20px } .wide { grid-column: 1 / 4 }";
+        let stylesheets = [css::default_user_agent_stylesheet(), css::parse(css.to_string(), Origin::Author)];
+        let style_root = style::style_tree(&root, &stylesheets);
+        let layout_root = engine::layout(&style_root, &RenderOptions::default()).unwrap();
+        // the two gaps and the 100px track leave 580px, a third of it for 1fr
+        let fr = 580.0 / 3.0;
+        let expected = [(0.0, 0.0, fr), (fr + 10.0, 0.0, 2.0 * fr), (600.0, 0.0, 100.0), (0.0, 25.0, 700.0)];
+        assert_eq!(layout_root.children.len(), expected.len());
+        for (item, (x, y, width)) in layout_root.children.iter().zip(expected) {
+            let content = item.dimensions.content;
+            assert!((content.x - x).abs() < 1e-3 && content.y == y && (content.width - width).abs() < 1e-3, "{:?}", content);
+        }
+        // the container is as tall as its rows
+        assert_eq!(layout_root.dimensions.content.height, 45.0);
+    }
+}
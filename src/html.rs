@@ -1,15 +1,474 @@
-use crate::dom;
+use crate::dom::{self, QuirksMode};
+use crate::engine::{EngineError, Limit, Limits};
 use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Write;
 
-pub struct Parser {
+// The parser is push-based: input arrives in chunks through `StreamingParser::feed`,
+// is split into tokens (tags, text and comments) as soon as each token is complete,
+// and the tokens are assembled into a tree with a stack of open elements. A token
+// cut off by the end of a chunk stays in the buffer until the next chunk completes
+// it, so only the unfinished tail of the input and the open elements are held.
+//
+// Malformed markup, like a stray `<` in text or an element that's never closed, is
+// an error. With `ParserOptions::recover_errors` it's reported as a diagnostic
+// instead, and parsing goes on the way browsers recover from it.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub message: String,
+    pub position: usize // byte offset into the whole document
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at byte {}", self.message, self.position)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, Default)]
+pub struct ParserOptions {
+    pub limits: Limits,
+    // keep each node's source on it, so that `serialize` writes back unchanged
+    // nodes exactly as they were written, as a formatter or linter needs
+    pub preserve_fidelity: bool,
+    // recover from malformed markup, collecting its errors, rather than failing on
+    // the first; only exceeding one of the `limits` still fails
+    pub recover_errors: bool
+}
+
+/// A whole document as the parser leaves it.
+pub struct ParsedDocument {
+    pub root: dom::Node,
+    pub quirks_mode: QuirksMode, // for styling and laying out the document
+    pub diagnostics: Vec<ParseError>, // the errors recovered from, in document order
+}
+
+enum Token {
+    StartTag(String, dom::AttrsMap, bool), // whether it's self-closing, like `<div/>`
+    EndTag(String),
+    Text(String),
+    Comment(String),
+    Doctype(dom::Doctype)
+}
+
+// elements that never have contents, so their start tag is the whole element; an
+// end tag for one, like `</img>`, is ignored
+//
+// https://html.spec.whatwg.org/multipage/syntax.html#void-elements
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track", "wbr",
+];
+
+fn is_void(tag_name: &str) -> bool {
+    VOID_ELEMENTS.iter().any(|void| void.eq_ignore_ascii_case(tag_name))
+}
+
+// elements whose end tag may be left out, where the next tag or the end of the
+// document implies it
+//
+// https://html.spec.whatwg.org/multipage/syntax.html#optional-tags
+const OPTIONAL_END_TAGS: &[&str] = &[
+    "html", "head", "body", "p", "li", "dt", "dd", "tr", "td", "th", "tbody", "thead", "tfoot", "caption",
+    "colgroup", "option", "optgroup",
+];
+
+// start tags that close an open `p`, since a paragraph can't contain them
+const CLOSES_P: &[&str] = &[
+    "address", "article", "aside", "blockquote", "center", "details", "dialog", "dir", "div", "dl", "dd", "dt",
+    "fieldset", "figcaption", "figure", "footer", "form", "h1", "h2", "h3", "h4", "h5", "h6", "header", "hgroup",
+    "hr", "li", "main", "menu", "nav", "ol", "p", "pre", "section", "summary", "table", "ul",
+];
+
+// elements whose contents are text up to their end tag, so that markup in them, like
+// the `<` of `if (a < b)` in a script, is part of the text, and character references
+// aren't decoded
+//
+// https://html.spec.whatwg.org/multipage/syntax.html#raw-text-elements
+const RAW_TEXT_ELEMENTS: &[&str] = &["script", "style"];
+
+const BYTE_ORDER_MARK: char = '\u{FEFF}';
+
+pub(crate) fn is_raw_text(tag_name: &str) -> bool {
+    RAW_TEXT_ELEMENTS.iter().any(|name| name.eq_ignore_ascii_case(tag_name))
+}
+
+fn has_optional_end_tag(tag_name: &str) -> bool {
+    OPTIONAL_END_TAGS.iter().any(|name| name.eq_ignore_ascii_case(tag_name))
+}
+
+// an element whose closing tag hasn't been seen yet
+struct OpenElement {
+    tag_name: String,
+    attrs: dom::AttrsMap,
+    children: Vec<dom::Node>,
+    source: Option<dom::SourceSpan> // without its end, until the element is closed
+}
+
+impl OpenElement {
+    fn into_node(self) -> dom::Node {
+        let mut node = dom::elem(self.tag_name, self.attrs, self.children);
+        node.source = self.source;
+        node
+    }
+}
+
+// the markup skipped before a token, and the token as written
+type RawToken = (String, String);
+
+// the source of a node parsed from `raw`, if the parser keeps sources
+fn span(node: &dom::Node, raw: Option<RawToken>) -> Option<dom::SourceSpan> {
+    raw.map(|(before, start)| dom::SourceSpan { before, start, end: String::new(), parsed: opening(node) })
+}
+
+pub struct StreamingParser {
+    options: ParserOptions,
+    buffer: String, // input received but not yet tokenized
+    offset: usize, // bytes of input already tokenized and dropped from `buffer`
+    node_count: usize,
+    open_elements: Vec<OpenElement>,
+    roots: Vec<dom::Node>,
+    ignored: String, // the source of ignored end tags with no node before them to keep it
+    diagnostics: Vec<ParseError>, // the errors recovered from, with `recover_errors`
+    doctype: Option<dom::Doctype>,
+    quirks_mode: Option<QuirksMode>, // once the first token that isn't a comment settles it
+    raw_text: Option<String> // the raw text element whose contents are next
+}
+
+impl StreamingParser {
+    pub fn new(options: ParserOptions) -> StreamingParser {
+        StreamingParser {
+            options,
+            buffer: String::new(),
+            offset: 0,
+            node_count: 0,
+            open_elements: Vec::new(),
+            roots: Vec::new(),
+            ignored: String::new(),
+            diagnostics: Vec::new(),
+            doctype: None,
+            quirks_mode: None,
+            raw_text: None
+        }
+    }
+
+    /// The document's doctype, once it's been read.
+    pub fn doctype(&self) -> Option<&dom::Doctype> {
+        self.doctype.as_ref()
+    }
+
+    /// The mode the document is in: quirks, unless it begins, after any comments,
+    /// with a doctype for another.
+    pub fn quirks_mode(&self) -> QuirksMode {
+        self.quirks_mode.unwrap_or(QuirksMode::Quirks)
+    }
+
+    /// Parse the next chunk of the document. A chunk may end anywhere, even mid-tag.
+    pub fn feed(&mut self, chunk: &str) -> Result<(), EngineError> {
+        let total = self.offset + self.buffer.len() + chunk.len();
+        Limits::check(Limit::InputBytes, total, self.options.limits.max_input_bytes)?;
+        self.buffer.push_str(chunk);
+        self.process(false)
+    }
+
+    /// Signal the end of input, and return the document's root element.
+    ///
+    /// If the document has a single top-level node it's the root; otherwise the
+    /// top-level nodes are wrapped in an `html` element.
+    pub fn finish(self) -> Result<dom::Node, EngineError> {
+        self.finish_document().map(|document| document.root)
+    }
+
+    /// Same as `finish`, also returning the document's mode and the errors recovered
+    /// from, which are none unless the parser was made with `recover_errors`.
+    pub fn finish_document(self) -> Result<ParsedDocument, EngineError> {
+        let preserve_fidelity = self.options.preserve_fidelity;
+        let (mut roots, diagnostics, quirks_mode) = self.end()?;
+        let root = if roots.len() == 1 {
+            roots.remove(0)
+        } else {
+            let mut root = dom::elem("html".to_string(), HashMap::new(), roots);
+            // the wrapper's tags are implied, so it adds nothing to the source
+            root.source = span(&root, preserve_fidelity.then(RawToken::default));
+            root
+        };
+        Ok(ParsedDocument { root, quirks_mode, diagnostics })
+    }
+
+    /// Signal the end of input, and return the top-level nodes as they are.
+    pub fn finish_fragment(self) -> Result<Vec<dom::Node>, EngineError> {
+        self.end().map(|(roots, ..)| roots)
+    }
+
+    fn end(mut self) -> Result<(Vec<dom::Node>, Vec<ParseError>, QuirksMode), EngineError> {
+        self.process(true)?;
+        // the end of the document closes elements whose end tags may be left out,
+        // like the last `p` of `<p>one<p>two`
+        let required = self.open_elements.iter().rposition(|open| !has_optional_end_tag(&open.tag_name));
+        self.close_elements(required.map_or(0, |i| i + 1));
+        if let Some(open) = self.open_elements.last() {
+            let message = format!("Expected closing tag for {:?}", open.tag_name);
+            self.report(ParseError { message, position: self.offset })?;
+            self.close_elements(0);
+        }
+        // all that's left unprocessed is a tag the document ended inside of
+        if let Some(source) = self.roots.last_mut().and_then(|root| root.source.as_mut()) {
+            source.end.push_str(&self.ignored);
+            source.end.push_str(&self.buffer);
+        }
+        let quirks_mode = self.quirks_mode();
+        Ok((self.roots, self.diagnostics, quirks_mode))
+    }
+
+    // fail with `error`, or keep it as a diagnostic if the parser recovers from errors
+    fn report(&mut self, error: ParseError) -> Result<(), EngineError> {
+        if !self.options.recover_errors {
+            return Err(EngineError::Parse(error));
+        }
+        self.diagnostics.push(error);
+        Ok(())
+    }
+
+    // tokenize as much of the buffer as possible; at `eof`, everything left must form tokens
+    fn process(&mut self, eof: bool) -> Result<(), EngineError> {
+        let raw_text = self.raw_text.take();
+        let mut parser = Parser { pos: 0, token_start: 0, input: &self.buffer, eof, diagnostics: Vec::new(), raw_text };
+        let mut tokens = Vec::new();
+        let result = loop {
+            let checkpoint = parser.pos;
+            let token = parser.next_token();
+            // without recovery, the first error ends the parse
+            if let (false, Some(error)) = (self.options.recover_errors, parser.diagnostics.first()) {
+                let position = error.position + self.offset;
+                break Err(EngineError::Parse(ParseError { message: error.message.clone(), position }));
+            }
+            match token {
+                Some(token) => {
+                    let raw = self.options.preserve_fidelity.then(|| {
+                        let input = parser.input;
+                        (input[checkpoint..parser.token_start].to_string(), input[parser.token_start..parser.pos].to_string())
+                    });
+                    tokens.push((token, self.offset + checkpoint, raw))
+                }
+                None => {
+                    // incomplete token: rewind and wait for more input
+                    parser.pos = checkpoint;
+                    break Ok(());
+                }
+            }
+        };
+        self.raw_text = parser.raw_text.take();
+        let offset = self.offset;
+        self.diagnostics.extend(parser.diagnostics.into_iter().map(|error| ParseError { position: error.position + offset, ..error }));
+        let consumed = parser.pos;
+        self.buffer.drain(..consumed);
+        self.offset += consumed;
+
+        for (token, position, raw) in tokens {
+            self.insert(token, position, raw)?;
+        }
+        result
+    }
+
+    // add a token to the tree under construction, written as `raw` if the parser
+    // keeps sources
+    fn insert(&mut self, token: Token, position: usize, raw: Option<RawToken>) -> Result<(), EngineError> {
+        let raw = raw.map(|(before, token)| (std::mem::take(&mut self.ignored) + &before, token));
+        // a byte order mark the document begins with isn't part of its text
+        let token = match token {
+            Token::Text(text) if position == 0 && text.starts_with(BYTE_ORDER_MARK) => {
+                Token::Text(text[BYTE_ORDER_MARK.len_utf8()..].to_string())
+            }
+            token => token
+        };
+        // whitespace outside every element, like the newlines between a doctype and
+        // `<html>`, isn't part of the tree, so its source is kept as an ignored end
+        // tag's is
+        if matches!(token, Token::Text(ref text) if text.trim().is_empty()) && self.open_elements.is_empty() {
+            self.keep_ignored(raw);
+            return Ok(());
+        }
+        // only a doctype ahead of everything but comments is the document's
+        let settles_mode = self.quirks_mode.is_none() && !matches!(token, Token::Comment(_));
+        if settles_mode {
+            self.quirks_mode = Some(match token {
+                Token::Doctype(ref doctype) => doctype_quirks_mode(doctype),
+                _ => QuirksMode::Quirks
+            });
+        }
+        let limits = &self.options.limits;
+        if !matches!(token, Token::EndTag(_) | Token::Doctype(_)) {
+            self.node_count += 1;
+            Limits::check(Limit::DomNodes, self.node_count, limits.max_dom_nodes)?;
+        }
+        let mut node = match token {
+            Token::StartTag(tag_name, attrs, self_closing) => {
+                Limits::check(Limit::AttributesPerElement, attrs.len(), limits.max_attributes_per_element)?;
+                if let Some(index) = self.implicitly_closed(&tag_name) {
+                    self.close_elements(index);
+                }
+                if is_void(&tag_name) || self_closing {
+                    dom::elem(tag_name, attrs, Vec::new())
+                } else {
+                    let mut open = OpenElement { tag_name, attrs, children: Vec::new(), source: None };
+                    open.source = raw.map(|(before, start)| {
+                        let parsed = start_tag(&open.tag_name, &open.attrs);
+                        dom::SourceSpan { before, start, end: String::new(), parsed }
+                    });
+                    self.open_elements.push(open);
+                    return Ok(());
+                }
+            }
+            Token::EndTag(tag_name) if is_void(&tag_name) => {
+                self.keep_ignored(raw);
+                return Ok(());
+            }
+            Token::EndTag(tag_name) => {
+                // the end tag may close elements opened inside this one whose end tags
+                // were left out, like the `li`s of `<ul><li>one<li>two</ul>`; recovering
+                // from errors, it closes any others too, like the `span` of
+                // `<div><span>one</div>`
+                let index = self.open_elements.iter().rposition(|open| open.tag_name == tag_name);
+                match index {
+                    Some(index) => {
+                        if !self.open_elements[index + 1..].iter().all(|open| has_optional_end_tag(&open.tag_name)) {
+                            let message = format!("Expected closing tag for {:?} but found {:?}", self.open_elements.last().unwrap().tag_name, tag_name);
+                            self.report(ParseError { message, position })?;
+                        }
+                        self.close_elements(index + 1);
+                        let mut open = self.open_elements.pop().unwrap();
+                        if let (Some(source), Some((before, end))) = (open.source.as_mut(), raw) {
+                            source.end = before + &end;
+                        }
+                        self.append(open.into_node());
+                        return Ok(());
+                    }
+                    // a `</p>` without a `p` to close stands for an empty paragraph
+                    None if tag_name.eq_ignore_ascii_case("p") => {
+                        let mut node = dom::elem(tag_name, HashMap::new(), Vec::new());
+                        // its start tag is implied, and the end tag is the one written
+                        node.source = raw.map(|(before, end)| dom::SourceSpan { before, start: String::new(), end, parsed: opening(&node) });
+                        self.append(node);
+                        return Ok(());
+                    }
+                    // any other end tag for an element that isn't open, like the `</a>` of
+                    // `<p><a>one<div>two</div></a>` after the `div` closed the paragraph,
+                    // is ignored
+                    None => {
+                        self.keep_ignored(raw);
+                        return Ok(());
+                    }
+                }
+            }
+            // a doctype isn't a node, so its source is kept as an ignored end tag's is
+            Token::Doctype(doctype) => {
+                match settles_mode {
+                    true => self.doctype = Some(doctype),
+                    false => self.report(ParseError { message: "Unexpected doctype".to_string(), position })?
+                }
+                self.keep_ignored(raw);
+                return Ok(());
+            }
+            Token::Text(text) => dom::text(text),
+            Token::Comment(comment) => dom::comment(comment)
+        };
+        node.source = span(&node, raw);
+        self.append(node);
+        Ok(())
+    }
+
+    // add a finished node to the innermost open element, or the top level
+    fn append(&mut self, node: dom::Node) {
+        match self.open_elements.last_mut() {
+            Some(parent) => parent.children.push(node),
+            None => self.roots.push(node)
+        }
+    }
+
+    // keep the source of an ignored end tag, like the `</img>` of `<img></img>`, at the
+    // end of the node before it, or else ahead of the node after it
+    fn keep_ignored(&mut self, raw: Option<RawToken>) {
+        let Some((before, tag)) = raw else { return };
+        let previous = match self.open_elements.last_mut() {
+            Some(parent) => parent.children.last_mut(),
+            None => self.roots.last_mut()
+        };
+        let kept = match previous.and_then(|node| node.source.as_mut()) {
+            Some(source) => &mut source.end,
+            None => &mut self.ignored
+        };
+        kept.push_str(&before);
+        kept.push_str(&tag);
+    }
+
+    // finish the open element at `index`, and every element opened inside it
+    fn close_elements(&mut self, index: usize) {
+        while self.open_elements.len() > index {
+            let open = self.open_elements.pop().unwrap();
+            self.append(open.into_node());
+        }
+    }
+
+    // the open element a start tag implies the end of, as a `p` ends before a block
+    // or another `p`, and an `li` before the next `li`; the search for it stops at an
+    // element that can contain the new one, like the `ul` of a nested list or the
+    // cell of a table inside the paragraph
+    //
+    // This follows the spec's tree construction for a document's body in a simplified
+    // form: elements closed on the way, like a `b` left open in the paragraph, aren't
+    // reopened in the next one.
+    //
+    // https://html.spec.whatwg.org/multipage/parsing.html#parsing-main-inbody
+    fn implicitly_closed(&self, tag_name: &str) -> Option<usize> {
+        let find = |closes: &[&str], stops: &[&str]| {
+            for (i, open) in self.open_elements.iter().enumerate().rev() {
+                let is = |names: &[&str]| names.iter().any(|name| name.eq_ignore_ascii_case(&open.tag_name));
+                if is(closes) {
+                    return Some(i);
+                }
+                if is(stops) {
+                    return None;
+                }
+            }
+            None
+        };
+        let tag_name = tag_name.to_ascii_lowercase();
+        let own = match tag_name.as_str() {
+            "li" => find(&["li"], &["ul", "ol", "html", "table", "td", "th", "caption", "button"]),
+            "dt" | "dd" => find(&["dt", "dd"], &["dl", "html", "table", "td", "th", "caption", "button"]),
+            "tr" => find(&["tr"], &["table", "tbody", "thead", "tfoot"]),
+            "td" | "th" => find(&["td", "th"], &["tr", "table"]),
+            "tbody" | "thead" | "tfoot" => find(&["tbody", "thead", "tfoot"], &["table"]),
+            _ => None
+        };
+        own.or_else(|| match CLOSES_P.contains(&tag_name.as_str()) {
+            true => find(&["p"], &["html", "table", "td", "th", "caption", "button", "object", "template"]),
+            false => None
+        })
+    }
+}
+
+// Reads tokens from the buffered input. Each `Option` result is `None` when the
+// input ends before the token does and more input may still arrive.
+//
+// Malformed markup is read the way browsers recover from it, with an error for it
+// in `diagnostics`. Input read before a `None` is read again once more arrives, so
+// the errors found in it are dropped until then.
+struct Parser<'a> {
     pos: usize,
-    input: String
+    token_start: usize, // where the last token read starts, after any markup skipped
+    input: &'a str,
+    eof: bool, // whether `input` is the rest of the document
+    diagnostics: Vec<ParseError>,
+    raw_text: Option<String> // the raw text element whose contents are next
 }
 
-impl Parser {
+impl Parser<'_> {
     // Read the current character without consuming it
-    fn next_char(&self) -> char {
-        self.input[self.pos..].chars().next().unwrap()
+    fn next_char(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
     }
 
     // Do the coming chars start with the provided string?
@@ -17,33 +476,25 @@ impl Parser {
         self.input[self.pos..].starts_with(s)
     }
 
-    // If the exact string `s` is found at the current position, consume it; otherwise, panic
-    fn expect(&mut self, s: &str) {
-        if self.starts_with(s) {
-            self.pos += s.len();
-        } else {
-            panic!("Expected {:?} at byte {} but it was not found", s, self.pos);
-        }
-    }
-
-    // Return true if all input is consumed
-    fn eof(&self) -> bool {
-        self.pos >= self.input.len()
+    // Note an error at the current position
+    fn error(&mut self, message: &str) {
+        self.diagnostics.push(ParseError { message: message.to_string(), position: self.pos });
     }
 
-    fn consume_char(&mut self) -> char {
-        let c = self.next_char();
+    fn consume_char(&mut self) -> Option<char> {
+        let c = self.next_char()?;
         self.pos += c.len_utf8();
-        return c;
+        Some(c)
     }
 
     // Consume characters until `test` returns false
     fn consume_while(&mut self, test: impl Fn(char) -> bool) -> String {
         let mut result = String::new();
-        while !self.eof() && test(self.next_char()) {
-            result.push(self.consume_char()); // push the consumed char to the result String
+        while let Some(c) = self.next_char().filter(|&c| test(c)) {
+            result.push(c);
+            self.pos += c.len_utf8();
         }
-        return result;
+        result
     }
 
     // Consume and discard any number of whitespaces
@@ -51,108 +502,717 @@ impl Parser {
         self.consume_while(char::is_whitespace);
     }
 
-    // Parse a tag or attribute name
+    // Parse a tag name, including hyphenated ones like `my-widget`; names are
+    // case-insensitive, so they're lowercased
     fn parse_name(&mut self) -> String {
-        self.consume_while(|c| matches!(c, 'a'..='z' | 'A'..='Z' | '0'..='9'))
+        self.consume_while(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_').to_ascii_lowercase()
     }
 
-    // Parse a single node
-    fn parse_node(&mut self) -> dom::Node {
-        if self.starts_with("<!") {
-            self.parse_comment()
-        } else if self.starts_with("<") {
-            self.parse_element()
-        } else {
-            self.parse_text()
+    // Read a single token; markup read as a comment, like `<?xml?>`, is skipped
+    fn next_token(&mut self) -> Option<Token> {
+        let reported = self.diagnostics.len();
+        let token = self.read_token();
+        if token.is_none() && !self.eof {
+            // what was read will be read again with more input
+            self.diagnostics.truncate(reported);
         }
+        token
     }
 
-    // In our subset of HTML, text node can contain any char except "<"
-    fn parse_text(&mut self) -> dom::Node {
-        dom::text(self.consume_while(|c| c != '<'))
+    fn read_token(&mut self) -> Option<Token> {
+        loop {
+            self.token_start = self.pos;
+            if self.pos == self.input.len() {
+                return None;
+            }
+            if self.raw_text.is_some() {
+                match self.parse_raw_text() {
+                    Some(text) if text.is_empty() => continue,
+                    text => return text.map(Token::Text)
+                }
+            }
+            let reported = self.diagnostics.len();
+            let after = |offset: usize| self.input[self.pos + offset..].chars().next();
+            let token = if self.starts_with("<!--") || (!self.eof && "<!--".starts_with(&self.input[self.pos..])) {
+                self.parse_comment()
+            } else if self.input[self.pos..].get(.."<!doctype".len()).is_some_and(|start| start.eq_ignore_ascii_case("<!doctype")) {
+                self.input[self.pos..].find('>').map(|i| {
+                    let doctype = parse_doctype(&self.input[self.pos + "<!doctype".len()..self.pos + i]);
+                    self.pos += i + 1;
+                    Token::Doctype(doctype)
+                })
+            } else if self.starts_with("<!") || self.starts_with("<?") || (self.starts_with("</") && after(2).is_some_and(|c| !c.is_ascii_alphabetic())) {
+                // what browsers read as a comment, like `<?xml version="1.0"?>` or
+                // `<![CDATA[x]]>`, or an end tag with no name, like `</>`, is skipped,
+                // staying in the source of the node after it
+                if !self.starts_with("<!") {
+                    self.error("Expected a tag name");
+                }
+                match self.input[self.pos..].find('>') {
+                    Some(i) => self.pos += i + 1,
+                    None if self.eof => {
+                        self.error("Expected \">\"");
+                        self.pos = self.input.len();
+                    }
+                    None => return None
+                }
+                continue;
+            } else if self.starts_with("<") && after(1).is_some_and(|c| c.is_ascii_alphabetic() || c == '/') {
+                self.parse_tag()
+            } else if self.starts_with("<") && after(1).is_none() && !self.eof {
+                None
+            } else {
+                self.parse_text().map(Token::Text)
+            };
+            if token.is_none() && self.eof {
+                // the document ends inside a tag, which is dropped
+                self.diagnostics.truncate(reported);
+                self.pos = self.token_start;
+                self.error("Expected \">\"");
+                self.pos = self.input.len();
+                continue;
+            }
+            if let Some(Token::StartTag(ref tag_name, _, false)) = token {
+                self.raw_text = is_raw_text(tag_name).then(|| tag_name.clone());
+            }
+            return token;
+        }
     }
-    
-    // In our subset of HTML, comment node can contain any char except -
-    fn parse_comment(&mut self) -> dom::Node {
-        self.expect("<!--");
-        let text = self.consume_while(|c| c != '-');
-        self.expect("-->");
 
-        return dom::comment(text);
+    // Text runs up to the next "<" that starts markup; a "<" that can't, like the
+    // one in `a < b`, is an error, and part of the text
+    fn parse_text(&mut self) -> Option<String> {
+        let mut end = self.pos;
+        let mut stray = Vec::new();
+        loop {
+            match self.input[end..].find('<') {
+                Some(i) => {
+                    end += i;
+                    match self.input[end + 1..].chars().next() {
+                        Some(c) if c.is_ascii_alphabetic() || "/!?".contains(c) => break,
+                        None if !self.eof => return None,
+                        _ => {
+                            stray.push(end);
+                            end += 1;
+                        }
+                    }
+                }
+                None if self.eof => {
+                    end = self.input.len();
+                    break;
+                }
+                None => return None
+            }
+        }
+        for position in stray {
+            self.diagnostics.push(ParseError { message: "Expected a tag name".to_string(), position });
+        }
+        let text = decode_character_references(&self.input[self.pos..end]);
+        self.pos = end;
+        Some(text)
     }
 
-    // Element node contains open and close tag
-    fn parse_element(&mut self) -> dom::Node {
-        // Opening tag
-        self.expect("<");
-        let tag_name = self.parse_name();
-        let attrs = self.parse_attributes();
-        self.expect(">");
-
-        // Contents
-        let children = self.parse_nodes();
+    // The contents of a raw text element, up to its end tag, or to the end of a
+    // document ending inside it
+    fn parse_raw_text(&mut self) -> Option<String> {
+        let tag_name = self.raw_text.as_deref()?;
+        let rest = &self.input[self.pos..];
+        let mut end = None;
+        for (i, _) in rest.match_indices("</") {
+            let after_name = i + "</".len() + tag_name.len();
+            if !rest.get(i + "</".len()..after_name).is_some_and(|name| name.eq_ignore_ascii_case(tag_name)) {
+                continue;
+            }
+            // `</scripts>` doesn't end a script, but `</script >` and `</script/>` do
+            match rest[after_name..].chars().next() {
+                Some(c) if c.is_whitespace() || c == '/' || c == '>' => {
+                    end = Some(i);
+                    break;
+                }
+                None if !self.eof => return None,
+                _ => {}
+            }
+        }
+        let end = match end {
+            Some(end) => end,
+            None if self.eof => rest.len(),
+            None => return None
+        };
+        let text = rest[..end].to_string();
+        self.pos += end;
+        self.raw_text = None;
+        Some(text)
+    }
 
-        // Closing tag
-        self.expect("</");
-        self.expect(&tag_name);
-        self.expect(">");
+    // A comment runs to the first "-->", or to the end of a document ending inside it
+    fn parse_comment(&mut self) -> Option<Token> {
+        if !self.starts_with("<!--") {
+            return None;
+        }
+        self.pos += "<!--".len();
+        let text = match self.input[self.pos..].find("-->") {
+            Some(i) => {
+                let text = self.input[self.pos..self.pos + i].to_string();
+                self.pos += i + "-->".len();
+                text
+            }
+            None if self.eof => {
+                let text = self.input[self.pos..].to_string();
+                self.pos = self.input.len();
+                self.error("Expected \"-->\"");
+                text
+            }
+            None => return None
+        };
+        Some(Token::Comment(text))
+    }
 
-        return dom::elem(tag_name, attrs, children);
+    // An opening or closing tag, up to and including its ">"; attributes on a closing
+    // tag are an error, and ignored
+    fn parse_tag(&mut self) -> Option<Token> {
+        self.consume_char(); // "<"
+        if self.starts_with("/") {
+            self.consume_char();
+            let tag_name = self.parse_name();
+            self.consume_whitespace();
+            if self.next_char()? != '>' {
+                self.error("Expected \">\"");
+            }
+            self.parse_attributes()?;
+            return Some(Token::EndTag(tag_name));
+        }
+        let tag_name = self.parse_name();
+        let (attrs, self_closing) = self.parse_attributes()?;
+        Some(Token::StartTag(tag_name, attrs, self_closing))
     }
 
-    // parse a single name="value" pair
-    fn parse_attr(&mut self) -> (String, String) {
-        let name = self.parse_name(); // attribute name
-        self.expect("=");
-        let value = self.parse_attr_value(); // attribute value
-        return (name, value);
+    // parse a single name="value" pair, or just a name, like `disabled`, whose value
+    // is then empty; names like `@click` and `:value` that frameworks use are kept
+    fn parse_attr(&mut self) -> Option<(String, String)> {
+        let name = self.consume_while(|c| !c.is_whitespace() && !"/>=\"'".contains(c)).to_ascii_lowercase();
+        self.consume_whitespace();
+        if self.next_char()? != '=' {
+            return Some((name, String::new()));
+        }
+        self.consume_char();
+        self.consume_whitespace();
+        let value = self.parse_attr_value()?;
+        Some((name, value))
     }
 
-    // parse a quoted value
-    fn parse_attr_value(&mut self) -> String {
-        let open_quote = self.consume_char();
-        assert!(open_quote == '"' || open_quote == '\'');
-        let value = self.consume_while(|c| c != open_quote);
-        let close_quote = self.consume_char();
-        assert_eq!(open_quote, close_quote);
-        return value;
+    // parse a quoted value, or an unquoted one, which ends at whitespace or the end
+    // of the tag
+    fn parse_attr_value(&mut self) -> Option<String> {
+        let value = match self.next_char()? {
+            open_quote @ ('"' | '\'') => {
+                self.consume_char();
+                let value = self.consume_while(|c| c != open_quote);
+                self.consume_char()?;
+                value
+            }
+            _ => self.consume_while(|c| !c.is_whitespace() && c != '>')
+        };
+        Some(decode_character_references(&value))
     }
 
-    // parse a list of name="value" pairs, separated by whitespace
-    fn parse_attributes(&mut self) -> dom::AttrsMap {
+    // parse a list of name="value" pairs, separated by whitespace, up to and including
+    // the ">" ending the tag, and whether it ends with the slash of a self-closing
+    // tag like `<br/>`
+    fn parse_attributes(&mut self) -> Option<(dom::AttrsMap, bool)> {
         let mut attributes = HashMap::new();
         loop {
             self.consume_whitespace();
-            if self.next_char() == '>' {
-                break;
+            match self.next_char()? {
+                '>' => {
+                    self.consume_char();
+                    return Some((attributes, false));
+                }
+                '/' if self.starts_with("/>") => {
+                    self.pos += "/>".len();
+                    return Some((attributes, true));
+                }
+                '/' => {
+                    self.error("Unexpected \"/\"");
+                    self.consume_char();
+                }
+                '=' | '"' | '\'' => {
+                    self.error("Expected an attribute name");
+                    self.consume_char();
+                }
+                _ => {
+                    let (name, value) = self.parse_attr()?;
+                    attributes.insert(name, value);
+                }
             }
-            let (name, value) = self.parse_attr();
-            attributes.insert(name, value);
         }
-        return attributes;
     }
+}
 
-    // parse a sequence of sibling nodes separated by whitespace
-    fn parse_nodes(&mut self) -> Vec<dom::Node> {
-        let mut nodes = Vec::new();
-        loop {
-            self.consume_whitespace();
-            if self.eof() || self.starts_with("</") {
-                break;
+// the doctype written `<!DOCTYPE` followed by `contents` and `>`, like ` html` or
+// ` HTML PUBLIC "-//W3C//DTD HTML 4.01//EN" "http://www.w3.org/TR/html4/strict.dtd"`
+//
+// https://html.spec.whatwg.org/multipage/parsing.html#doctype-state
+fn parse_doctype(contents: &str) -> dom::Doctype {
+    // an identifier, in either kind of quotes, and what's after it
+    fn quoted(rest: &str) -> Option<(String, &str)> {
+        let quote = rest.chars().next().filter(|&c| c == '"' || c == '\'')?;
+        let (id, after) = rest[1..].split_once(quote)?;
+        Some((id.to_string(), after.trim_start()))
+    }
+    let mut doctype = dom::Doctype::default();
+    let rest = contents.trim_start();
+    let name_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+    doctype.name = rest[..name_end].to_ascii_lowercase();
+    let rest = rest[name_end..].trim_start();
+    let keyword = |keyword: &str| rest.get(..keyword.len()).is_some_and(|start| start.eq_ignore_ascii_case(keyword));
+    let ids_found = if keyword("PUBLIC") {
+        match quoted(rest["PUBLIC".len()..].trim_start()) {
+            Some((public_id, after)) => {
+                doctype.public_id = Some(public_id);
+                // the system identifier may be left out
+                doctype.system_id = quoted(after).map(|(system_id, _)| system_id);
+                after.is_empty() || doctype.system_id.is_some()
+            }
+            None => false
+        }
+    } else if keyword("SYSTEM") {
+        doctype.system_id = quoted(rest["SYSTEM".len()..].trim_start()).map(|(system_id, _)| system_id);
+        doctype.system_id.is_some()
+    } else {
+        rest.is_empty()
+    };
+    doctype.force_quirks = doctype.name.is_empty() || !ids_found;
+    doctype
+}
+
+// the public identifiers of doctypes that put a document in quirks mode, after
+// ASCII case-insensitive matching of their beginnings
+const QUIRKS_PUBLIC_ID_PREFIXES: &[&str] = &[
+    "+//Silmaril//dtd html Pro v0r11 19970101//", "-//AS//DTD HTML 3.0 asWedit + extensions//",
+    "-//AdvaSoft Ltd//DTD HTML 3.0 asWedit + extensions//", "-//IETF//DTD HTML 2.0 Level 1//",
+    "-//IETF//DTD HTML 2.0 Level 2//", "-//IETF//DTD HTML 2.0 Strict Level 1//", "-//IETF//DTD HTML 2.0 Strict Level 2//",
+    "-//IETF//DTD HTML 2.0 Strict//", "-//IETF//DTD HTML 2.0//", "-//IETF//DTD HTML 2.1E//", "-//IETF//DTD HTML 3.0//",
+    "-//IETF//DTD HTML 3.2 Final//", "-//IETF//DTD HTML 3.2//", "-//IETF//DTD HTML 3//", "-//IETF//DTD HTML Level 0//",
+    "-//IETF//DTD HTML Level 1//", "-//IETF//DTD HTML Level 2//", "-//IETF//DTD HTML Level 3//",
+    "-//IETF//DTD HTML Strict Level 0//", "-//IETF//DTD HTML Strict Level 1//", "-//IETF//DTD HTML Strict Level 2//",
+    "-//IETF//DTD HTML Strict Level 3//", "-//IETF//DTD HTML Strict//", "-//IETF//DTD HTML//",
+    "-//Metrius//DTD Metrius Presentational//", "-//Microsoft//DTD Internet Explorer 2.0 HTML Strict//",
+    "-//Microsoft//DTD Internet Explorer 2.0 HTML//", "-//Microsoft//DTD Internet Explorer 2.0 Tables//",
+    "-//Microsoft//DTD Internet Explorer 3.0 HTML Strict//", "-//Microsoft//DTD Internet Explorer 3.0 HTML//",
+    "-//Microsoft//DTD Internet Explorer 3.0 Tables//", "-//Netscape Comm. Corp.//DTD HTML//",
+    "-//Netscape Comm. Corp.//DTD Strict HTML//", "-//O'Reilly and Associates//DTD HTML 2.0//",
+    "-//O'Reilly and Associates//DTD HTML Extended 1.0//", "-//O'Reilly and Associates//DTD HTML Extended Relaxed 1.0//",
+    "-//SQ//DTD HTML 2.0 HoTMetaL + extensions//",
+    "-//SoftQuad Software//DTD HoTMetaL PRO 6.0::19990601::extensions to HTML 4.0//",
+    "-//SoftQuad//DTD HoTMetaL PRO 4.0::19971010::extensions to HTML 4.0//", "-//Spyglass//DTD HTML 2.0 Extended//",
+    "-//Sun Microsystems Corp.//DTD HotJava HTML//", "-//Sun Microsystems Corp.//DTD HotJava Strict HTML//",
+    "-//W3C//DTD HTML 3 1995-03-24//", "-//W3C//DTD HTML 3.2 Draft//", "-//W3C//DTD HTML 3.2 Final//",
+    "-//W3C//DTD HTML 3.2//", "-//W3C//DTD HTML 3.2S Draft//", "-//W3C//DTD HTML 4.0 Frameset//",
+    "-//W3C//DTD HTML 4.0 Transitional//", "-//W3C//DTD HTML Experimental 19960712//",
+    "-//W3C//DTD HTML Experimental 970421//", "-//W3C//DTD W3 HTML//", "-//W3O//DTD W3 HTML 3.0//",
+    "-//WebTechs//DTD Mozilla HTML 2.0//", "-//WebTechs//DTD Mozilla HTML//",
+];
+
+// HTML 4.01's transitional and frameset doctypes, which are quirky without a system
+// identifier and only limited-quirky with one
+const HTML_401_PUBLIC_ID_PREFIXES: &[&str] = &["-//W3C//DTD HTML 4.01 Frameset//", "-//W3C//DTD HTML 4.01 Transitional//"];
+
+const LIMITED_QUIRKS_PUBLIC_ID_PREFIXES: &[&str] = &["-//W3C//DTD XHTML 1.0 Frameset//", "-//W3C//DTD XHTML 1.0 Transitional//"];
+
+// the mode a document with `doctype` is in
+//
+// https://html.spec.whatwg.org/multipage/parsing.html#the-initial-insertion-mode
+fn doctype_quirks_mode(doctype: &dom::Doctype) -> QuirksMode {
+    let public_id = doctype.public_id.as_deref();
+    let system_id = doctype.system_id.as_deref();
+    let is = |id: Option<&str>, value: &str| id.is_some_and(|id| id.eq_ignore_ascii_case(value));
+    let public_id_starts = |prefixes: &[&str]| public_id.is_some_and(|id| {
+        prefixes.iter().any(|prefix| id.get(..prefix.len()).is_some_and(|start| start.eq_ignore_ascii_case(prefix)))
+    });
+    if doctype.force_quirks
+        || doctype.name != "html"
+        || is(public_id, "-//W3O//DTD W3 HTML Strict 3.0//EN//")
+        || is(public_id, "-/W3C/DTD HTML 4.0 Transitional/EN")
+        || is(public_id, "HTML")
+        || is(system_id, "http://www.ibm.com/data/dtd/v11/ibmxhtml1-transitional.dtd")
+        || public_id_starts(QUIRKS_PUBLIC_ID_PREFIXES)
+        || (system_id.is_none() && public_id_starts(HTML_401_PUBLIC_ID_PREFIXES)) {
+        QuirksMode::Quirks
+    } else if public_id_starts(LIMITED_QUIRKS_PUBLIC_ID_PREFIXES) || public_id_starts(HTML_401_PUBLIC_ID_PREFIXES) {
+        QuirksMode::LimitedQuirks
+    } else {
+        QuirksMode::NoQuirks
+    }
+}
+
+// the named character references of HTML 4, with `&apos;`, sorted by name; HTML adds
+// some two thousand more, mostly mathematical symbols, that pages rarely use
+//
+// https://html.spec.whatwg.org/multipage/named-characters.html
+const NAMED_CHARACTER_REFERENCES: &[(&str, char)] = &[
+    ("AElig", '\u{C6}'), ("Aacute", '\u{C1}'), ("Acirc", '\u{C2}'), ("Agrave", '\u{C0}'), ("Alpha", '\u{391}'),
+    ("Aring", '\u{C5}'), ("Atilde", '\u{C3}'), ("Auml", '\u{C4}'), ("Beta", '\u{392}'), ("Ccedil", '\u{C7}'),
+    ("Chi", '\u{3A7}'), ("Dagger", '\u{2021}'), ("Delta", '\u{394}'), ("ETH", '\u{D0}'), ("Eacute", '\u{C9}'),
+    ("Ecirc", '\u{CA}'), ("Egrave", '\u{C8}'), ("Epsilon", '\u{395}'), ("Eta", '\u{397}'), ("Euml", '\u{CB}'),
+    ("Gamma", '\u{393}'), ("Iacute", '\u{CD}'), ("Icirc", '\u{CE}'), ("Igrave", '\u{CC}'), ("Iota", '\u{399}'),
+    ("Iuml", '\u{CF}'), ("Kappa", '\u{39A}'), ("Lambda", '\u{39B}'), ("Mu", '\u{39C}'), ("Ntilde", '\u{D1}'),
+    ("Nu", '\u{39D}'), ("OElig", '\u{152}'), ("Oacute", '\u{D3}'), ("Ocirc", '\u{D4}'), ("Ograve", '\u{D2}'),
+    ("Omega", '\u{3A9}'), ("Omicron", '\u{39F}'), ("Oslash", '\u{D8}'), ("Otilde", '\u{D5}'),
+    ("Ouml", '\u{D6}'), ("Phi", '\u{3A6}'), ("Pi", '\u{3A0}'), ("Prime", '\u{2033}'), ("Psi", '\u{3A8}'),
+    ("Rho", '\u{3A1}'), ("Scaron", '\u{160}'), ("Sigma", '\u{3A3}'), ("THORN", '\u{DE}'), ("Tau", '\u{3A4}'),
+    ("Theta", '\u{398}'), ("Uacute", '\u{DA}'), ("Ucirc", '\u{DB}'), ("Ugrave", '\u{D9}'),
+    ("Upsilon", '\u{3A5}'), ("Uuml", '\u{DC}'), ("Xi", '\u{39E}'), ("Yacute", '\u{DD}'), ("Yuml", '\u{178}'),
+    ("Zeta", '\u{396}'), ("aacute", '\u{E1}'), ("acirc", '\u{E2}'), ("acute", '\u{B4}'), ("aelig", '\u{E6}'),
+    ("agrave", '\u{E0}'), ("alefsym", '\u{2135}'), ("alpha", '\u{3B1}'), ("amp", '\u{26}'),
+    ("and", '\u{2227}'), ("ang", '\u{2220}'), ("apos", '\u{27}'), ("aring", '\u{E5}'), ("asymp", '\u{2248}'),
+    ("atilde", '\u{E3}'), ("auml", '\u{E4}'), ("bdquo", '\u{201E}'), ("beta", '\u{3B2}'), ("brvbar", '\u{A6}'),
+    ("bull", '\u{2022}'), ("cap", '\u{2229}'), ("ccedil", '\u{E7}'), ("cedil", '\u{B8}'), ("cent", '\u{A2}'),
+    ("chi", '\u{3C7}'), ("circ", '\u{2C6}'), ("clubs", '\u{2663}'), ("cong", '\u{2245}'), ("copy", '\u{A9}'),
+    ("crarr", '\u{21B5}'), ("cup", '\u{222A}'), ("curren", '\u{A4}'), ("dArr", '\u{21D3}'),
+    ("dagger", '\u{2020}'), ("darr", '\u{2193}'), ("deg", '\u{B0}'), ("delta", '\u{3B4}'),
+    ("diams", '\u{2666}'), ("divide", '\u{F7}'), ("eacute", '\u{E9}'), ("ecirc", '\u{EA}'),
+    ("egrave", '\u{E8}'), ("empty", '\u{2205}'), ("emsp", '\u{2003}'), ("ensp", '\u{2002}'),
+    ("epsilon", '\u{3B5}'), ("equiv", '\u{2261}'), ("eta", '\u{3B7}'), ("eth", '\u{F0}'), ("euml", '\u{EB}'),
+    ("euro", '\u{20AC}'), ("exist", '\u{2203}'), ("fnof", '\u{192}'), ("forall", '\u{2200}'),
+    ("frac12", '\u{BD}'), ("frac14", '\u{BC}'), ("frac34", '\u{BE}'), ("frasl", '\u{2044}'),
+    ("gamma", '\u{3B3}'), ("ge", '\u{2265}'), ("gt", '\u{3E}'), ("hArr", '\u{21D4}'), ("harr", '\u{2194}'),
+    ("hearts", '\u{2665}'), ("hellip", '\u{2026}'), ("iacute", '\u{ED}'), ("icirc", '\u{EE}'),
+    ("iexcl", '\u{A1}'), ("igrave", '\u{EC}'), ("image", '\u{2111}'), ("infin", '\u{221E}'),
+    ("int", '\u{222B}'), ("iota", '\u{3B9}'), ("iquest", '\u{BF}'), ("isin", '\u{2208}'), ("iuml", '\u{EF}'),
+    ("kappa", '\u{3BA}'), ("lArr", '\u{21D0}'), ("lambda", '\u{3BB}'), ("lang", '\u{2329}'),
+    ("laquo", '\u{AB}'), ("larr", '\u{2190}'), ("lceil", '\u{2308}'), ("ldquo", '\u{201C}'),
+    ("le", '\u{2264}'), ("lfloor", '\u{230A}'), ("lowast", '\u{2217}'), ("loz", '\u{25CA}'),
+    ("lrm", '\u{200E}'), ("lsaquo", '\u{2039}'), ("lsquo", '\u{2018}'), ("lt", '\u{3C}'), ("macr", '\u{AF}'),
+    ("mdash", '\u{2014}'), ("micro", '\u{B5}'), ("middot", '\u{B7}'), ("minus", '\u{2212}'), ("mu", '\u{3BC}'),
+    ("nabla", '\u{2207}'), ("nbsp", '\u{A0}'), ("ndash", '\u{2013}'), ("ne", '\u{2260}'), ("ni", '\u{220B}'),
+    ("not", '\u{AC}'), ("notin", '\u{2209}'), ("nsub", '\u{2284}'), ("ntilde", '\u{F1}'), ("nu", '\u{3BD}'),
+    ("oacute", '\u{F3}'), ("ocirc", '\u{F4}'), ("oelig", '\u{153}'), ("ograve", '\u{F2}'),
+    ("oline", '\u{203E}'), ("omega", '\u{3C9}'), ("omicron", '\u{3BF}'), ("oplus", '\u{2295}'),
+    ("or", '\u{2228}'), ("ordf", '\u{AA}'), ("ordm", '\u{BA}'), ("oslash", '\u{F8}'), ("otilde", '\u{F5}'),
+    ("otimes", '\u{2297}'), ("ouml", '\u{F6}'), ("para", '\u{B6}'), ("part", '\u{2202}'),
+    ("permil", '\u{2030}'), ("perp", '\u{22A5}'), ("phi", '\u{3C6}'), ("pi", '\u{3C0}'), ("piv", '\u{3D6}'),
+    ("plusmn", '\u{B1}'), ("pound", '\u{A3}'), ("prime", '\u{2032}'), ("prod", '\u{220F}'),
+    ("prop", '\u{221D}'), ("psi", '\u{3C8}'), ("quot", '\u{22}'), ("rArr", '\u{21D2}'), ("radic", '\u{221A}'),
+    ("rang", '\u{232A}'), ("raquo", '\u{BB}'), ("rarr", '\u{2192}'), ("rceil", '\u{2309}'),
+    ("rdquo", '\u{201D}'), ("real", '\u{211C}'), ("reg", '\u{AE}'), ("rfloor", '\u{230B}'), ("rho", '\u{3C1}'),
+    ("rlm", '\u{200F}'), ("rsaquo", '\u{203A}'), ("rsquo", '\u{2019}'), ("sbquo", '\u{201A}'),
+    ("scaron", '\u{161}'), ("sdot", '\u{22C5}'), ("sect", '\u{A7}'), ("shy", '\u{AD}'), ("sigma", '\u{3C3}'),
+    ("sigmaf", '\u{3C2}'), ("sim", '\u{223C}'), ("spades", '\u{2660}'), ("sub", '\u{2282}'),
+    ("sube", '\u{2286}'), ("sum", '\u{2211}'), ("sup", '\u{2283}'), ("sup1", '\u{B9}'), ("sup2", '\u{B2}'),
+    ("sup3", '\u{B3}'), ("supe", '\u{2287}'), ("szlig", '\u{DF}'), ("tau", '\u{3C4}'), ("there4", '\u{2234}'),
+    ("theta", '\u{3B8}'), ("thetasym", '\u{3D1}'), ("thinsp", '\u{2009}'), ("thorn", '\u{FE}'),
+    ("tilde", '\u{2DC}'), ("times", '\u{D7}'), ("trade", '\u{2122}'), ("uArr", '\u{21D1}'),
+    ("uacute", '\u{FA}'), ("uarr", '\u{2191}'), ("ucirc", '\u{FB}'), ("ugrave", '\u{F9}'), ("uml", '\u{A8}'),
+    ("upsih", '\u{3D2}'), ("upsilon", '\u{3C5}'), ("uuml", '\u{FC}'), ("weierp", '\u{2118}'),
+    ("xi", '\u{3BE}'), ("yacute", '\u{FD}'), ("yen", '\u{A5}'), ("yuml", '\u{FF}'), ("zeta", '\u{3B6}'),
+    ("zwj", '\u{200D}'), ("zwnj", '\u{200C}'),
+];
+
+// the characters that numeric references to the C1 controls stand for, from 0x80 to
+// 0x9F, since pages meant windows-1252 by them; `None` where it has no character,
+// so the reference stays a control
+const WINDOWS_1252_C1: [Option<char>; 32] = [
+    Some('\u{20AC}'), None, Some('\u{201A}'), Some('\u{192}'), Some('\u{201E}'), Some('\u{2026}'), Some('\u{2020}'),
+    Some('\u{2021}'), Some('\u{2C6}'), Some('\u{2030}'), Some('\u{160}'), Some('\u{2039}'), Some('\u{152}'), None,
+    Some('\u{17D}'), None, None, Some('\u{2018}'), Some('\u{2019}'), Some('\u{201C}'), Some('\u{201D}'),
+    Some('\u{2022}'), Some('\u{2013}'), Some('\u{2014}'), Some('\u{2DC}'), Some('\u{2122}'), Some('\u{161}'),
+    Some('\u{203A}'), Some('\u{153}'), None, Some('\u{17E}'), Some('\u{178}'),
+];
+
+// the character a numeric reference stands for; one to a code point that isn't a
+// character, like `&#0;` or a surrogate, stands for U+FFFD
+fn numeric_reference(digits: &str, radix: u32) -> Option<char> {
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+    let code = u32::from_str_radix(digits, radix).unwrap_or(u32::MAX);
+    let c = match code {
+        0x80..=0x9F => WINDOWS_1252_C1[code as usize - 0x80].or(char::from_u32(code)),
+        0 => None,
+        _ => char::from_u32(code),
+    };
+    Some(c.unwrap_or(char::REPLACEMENT_CHARACTER))
+}
+
+// replace the character references in text or an attribute value with the
+// characters they stand for: numeric ones like `&#169;` and `&#x2603;`, and named
+// ones like `&amp;` and `&nbsp;`; anything else, including a reference without its
+// closing `;`, is kept as written
+//
+// https://html.spec.whatwg.org/multipage/syntax.html#character-references
+fn decode_character_references(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(ampersand) = rest.find('&') {
+        decoded.push_str(&rest[..ampersand]);
+        rest = &rest[ampersand..];
+        // the reference's name runs up to the `;`, and no name has characters other
+        // than these, so there's no need to look any further for it
+        let name_len = rest[1..].find(|c: char| !c.is_ascii_alphanumeric() && c != '#').unwrap_or(rest.len() - 1);
+        let reference = Some(&rest[1..1 + name_len]).filter(|_| rest[1 + name_len..].starts_with(';')).and_then(|name| {
+            let c = match name.strip_prefix('#') {
+                Some(hex) if hex.starts_with(['x', 'X']) => numeric_reference(&hex[1..], 16),
+                Some(decimal) => numeric_reference(decimal, 10),
+                None => NAMED_CHARACTER_REFERENCES.binary_search_by_key(&name, |&(name, _)| name).ok()
+                    .map(|i| NAMED_CHARACTER_REFERENCES[i].1),
+            };
+            c.map(|c| (c, name.len() + 2))
+        });
+        match reference {
+            Some((c, length)) => {
+                decoded.push(c);
+                rest = &rest[length..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
             }
-            nodes.push(self.parse_node());
         }
-        return nodes;
     }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Write a tree back out as HTML.
+///
+/// A node parsed with `ParserOptions::preserve_fidelity` is written exactly as in its
+/// source, with the whitespace around it, unless it has changed since; an element
+/// whose attributes changed gets a new start tag, and keeps its end tag unless it
+/// was renamed. Other nodes are written in a normal form: attributes sorted by name
+/// and double-quoted, and `&`, `<`, `>` and `"` escaped where they'd be misread.
+pub fn serialize(node: &dom::Node) -> String {
+    let mut out = String::new();
+    write_node(node, &mut out);
+    out
+}
+
+fn write_node(node: &dom::Node, out: &mut String) {
+    let opening = opening(node);
+    match node.source {
+        Some(ref source) => {
+            out.push_str(&source.before);
+            out.push_str(if opening == source.parsed { &source.start } else { &opening });
+        }
+        None => out.push_str(&opening)
+    }
+    let raw_text = matches!(node.node_type, dom::NodeType::Element(ref elem) if is_raw_text(&elem.tag_name));
+    for child in &node.children {
+        match (&child.node_type, &child.source) {
+            // the text of a script or style is written as it is, as it was never escaped
+            (dom::NodeType::Text(text), source) if raw_text => {
+                let (before, end) = source.as_ref().map_or(("", ""), |source| (&*source.before, &*source.end));
+                out.push_str(before);
+                out.push_str(text);
+                out.push_str(end);
+            }
+            _ => write_node(child, out)
+        }
+    }
+    let renamed = |elem: &dom::ElementData, end: &str| {
+        end_tag_name(end).is_some_and(|name| !name.eq_ignore_ascii_case(&elem.tag_name))
+    };
+    match (&node.node_type, &node.source) {
+        (dom::NodeType::Element(elem), Some(source)) if renamed(elem, &source.end) => {
+            let _ = write!(out, "</{}>", elem.tag_name);
+        }
+        (_, Some(source)) => out.push_str(&source.end),
+        (dom::NodeType::Element(elem), None) if !is_void(&elem.tag_name) => {
+            let _ = write!(out, "</{}>", elem.tag_name);
+        }
+        _ => {}
+    }
+}
+
+// a node's start tag, text or comment in normal form
+fn opening(node: &dom::Node) -> String {
+    match node.node_type {
+        dom::NodeType::Element(ref elem) => start_tag(&elem.tag_name, &elem.attrs),
+        dom::NodeType::Text(ref text) => text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;"),
+        dom::NodeType::Comment(ref comment) => format!("<!--{}-->", comment.comment)
+    }
+}
+
+fn start_tag(tag_name: &str, attrs: &dom::AttrsMap) -> String {
+    let mut names: Vec<&String> = attrs.keys().collect();
+    names.sort();
+    let mut tag = format!("<{}", tag_name);
+    for name in names {
+        let _ = write!(tag, " {}=\"{}\"", name, attrs[name].replace('&', "&amp;").replace('"', "&quot;"));
+    }
+    tag.push('>');
+    tag
+}
+
+// the name in the end tag in `end`, after any whitespace before it
+fn end_tag_name(end: &str) -> Option<&str> {
+    let name = end.trim_start().strip_prefix("</")?;
+    let length = name.find(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '_')).unwrap_or(name.len());
+    Some(&name[..length])
 }
 
 // parse entire HTML doc and return its root element
 pub fn parse(source: String) -> dom::Node {
-    let mut nodes = Parser { pos: 0, input: source }.parse_nodes();
-    // if the DOM contains a root element, return it; otherwise, create one
-    if nodes.len() == 1 {
-        return nodes.remove(0);
-    } else {
-        return dom::elem("html".to_string(), HashMap::new(), nodes);
+    parse_with_options(&source, ParserOptions::default()).unwrap_or_else(|error| panic!("{}", error))
+}
+
+// parse entire HTML doc within the limits in `options`
+pub fn parse_with_options(source: &str, options: ParserOptions) -> Result<dom::Node, EngineError> {
+    let mut parser = StreamingParser::new(options);
+    parser.feed(source)?;
+    parser.finish()
+}
+
+/// Parse an entire HTML document, recovering from malformed markup instead of
+/// failing on it, and return its root element with its mode and the errors
+/// recovered from.
+///
+/// Any input parses, so only exceeding one of the limits in `options` fails.
+pub fn parse_document(source: &str, options: ParserOptions) -> Result<ParsedDocument, EngineError> {
+    let mut parser = StreamingParser::new(ParserOptions { recover_errors: true, ..options });
+    parser.feed(source)?;
+    parser.finish_document()
+}
+
+/// The mode an HTML document is rendered in: quirks, unless it begins, after any
+/// comments, with a doctype for another, as `<!DOCTYPE html>` is for no quirks.
+/// A byte order mark, and whitespace around the comments, don't count, as they
+/// don't for the tree `parse` builds.
+///
+/// Only the document's beginning is read, up to the first token that isn't a comment.
+pub fn quirks_mode(source: &str) -> QuirksMode {
+    let input = source.strip_prefix(BYTE_ORDER_MARK).unwrap_or(source);
+    let mut parser = Parser { pos: 0, token_start: 0, input, eof: true, diagnostics: Vec::new(), raw_text: None };
+    loop {
+        match parser.next_token() {
+            Some(Token::Comment(_)) => {}
+            Some(Token::Text(ref text)) if text.trim().is_empty() => {}
+            Some(Token::Doctype(doctype)) => return doctype_quirks_mode(&doctype),
+            _ => return QuirksMode::Quirks
+        }
+    }
+}
+
+// parse a piece of HTML that needn't have a single root, such as one component of
+// a page, and return its top-level nodes
+pub fn parse_fragment(source: &str, options: ParserOptions) -> Result<Vec<dom::Node>, EngineError> {
+    let mut parser = StreamingParser::new(options);
+    parser.feed(source)?;
+    parser.finish_fragment()
+}
+
+/// A stylesheet that a document brings with it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DocumentStylesheet {
+    Embedded(String), // the text of a `<style>` element
+    Linked(String), // the `href` of a `<link rel="stylesheet">`, as written
+}
+
+/// The stylesheets of a document, in document order, which is their order in the
+/// cascade: the text of each `<style>` element, and the address of each `<link>` to
+/// a stylesheet.
+///
+/// https://html.spec.whatwg.org/multipage/semantics.html#the-style-element
+/// https://html.spec.whatwg.org/multipage/links.html#link-type-stylesheet
+///
+/// Alternate stylesheets, linked with `rel="alternate stylesheet"`, are left out, as
+/// browsers only apply them when the reader picks one. So are links with no `href`.
+pub fn document_stylesheets(root: &dom::Node) -> Vec<DocumentStylesheet> {
+    fn collect(node: &dom::Node, stylesheets: &mut Vec<DocumentStylesheet>) {
+        let elem = match node.node_type {
+            dom::NodeType::Element(ref elem) => elem,
+            _ => return
+        };
+        match &*elem.tag_name {
+            "style" => {
+                let text: String = node.children.iter()
+                    .filter_map(|child| match child.node_type {
+                        dom::NodeType::Text(ref text) => Some(text.as_str()),
+                        _ => None
+                    })
+                    .collect();
+                stylesheets.push(DocumentStylesheet::Embedded(text));
+            }
+            "link" => {
+                let rel = elem.attrs.get("rel").map_or("", |rel| rel.as_str());
+                let has = |keyword: &str| rel.split_ascii_whitespace().any(|word| word.eq_ignore_ascii_case(keyword));
+                match elem.attrs.get("href").filter(|href| !href.trim().is_empty()) {
+                    Some(href) if has("stylesheet") && !has("alternate") => {
+                        stylesheets.push(DocumentStylesheet::Linked(href.trim().to_string()));
+                    }
+                    _ => {}
+                }
+            }
+            _ => {
+                for child in &node.children {
+                    collect(child, stylesheets);
+                }
+            }
+        }
+    }
+    let mut stylesheets = Vec::new();
+    collect(root, &mut stylesheets);
+    stylesheets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn character_references() {
+        let decoded = decode_character_references("a &amp; b &lt;&#x41;&#66;&nbsp;&nope; &amp &am;p; &;");
+        assert_eq!(decoded, "a & b <AB\u{a0}&nope; &amp &am;p; &;");
+        // each ampersand with no `;` after its name is passed over once, not scanned
+        // to the end of the text
+        let text = "&a ".repeat(200_000);
+        assert_eq!(decode_character_references(&text), text);
+    }
+
+    // the tree under `node`, as `tag(children)`, with text quoted
+    fn outline(node: &dom::Node) -> String {
+        match node.node_type {
+            dom::NodeType::Element(ref elem) => {
+                let children: Vec<String> = node.children.iter().map(outline).collect();
+                format!("{}({})", elem.tag_name, children.join(" "))
+            }
+            dom::NodeType::Text(ref text) => format!("{:?}", text),
+            dom::NodeType::Comment(_) => "#comment".to_string()
+        }
+    }
+
+    #[test]
+    fn page_heads_void_elements_and_case() {
+        let source = "<!DOCTYPE html><HTML><head><meta charset=\"utf-8\"><link rel=\"stylesheet\" href=\"a.css\">\
+            <title>Page</title></head><BODY><DIV CLASS=\"x\">one<br>two<img src=\"x.png\"><Input type=\"checkbox\" disabled>\
+            <hr/></Div><p>after</P></body></html>";
+        let root = parse_with_options(source, ParserOptions::default()).unwrap();
+        assert_eq!(
+            outline(&root),
+            "html(head(meta() link() title(\"Page\")) body(div(\"one\" br() \"two\" img() input() hr()) p(\"after\")))"
+        );
+        let div = &root.children[1].children[0];
+        let input = &div.children[4];
+        match (&div.node_type, &input.node_type) {
+            (dom::NodeType::Element(div), dom::NodeType::Element(input)) => {
+                assert_eq!(div.attrs.get("class").map(String::as_str), Some("x"));
+                assert_eq!(input.attrs.get("disabled").map(String::as_str), Some(""));
+                assert_eq!(input.attrs.get("type").map(String::as_str), Some("checkbox"));
+            }
+            _ => panic!("expected elements, got {}", outline(div))
+        }
+    }
+
+    #[test]
+    fn whitespace_and_a_byte_order_mark_before_the_doctype_leave_the_mode() {
+        for source in ["<!DOCTYPE html><p>x", "\n<!DOCTYPE html>\n<p>x", "\u{FEFF}<!DOCTYPE html><p>x", "\u{FEFF} <!-- c --> <!DOCTYPE html><p>x"] {
+            assert_eq!(quirks_mode(source), QuirksMode::NoQuirks, "{:?}", source);
+            let parsed = parse_document(source, ParserOptions::default()).unwrap();
+            assert_eq!(parsed.quirks_mode, QuirksMode::NoQuirks, "{:?}", source);
+            assert!(parsed.diagnostics.is_empty(), "{:?}", source);
+        }
+        for source in ["<p>x", "x<!DOCTYPE html>", "\u{FEFF}\u{FEFF}<!DOCTYPE html>"] {
+            assert_eq!(quirks_mode(source), QuirksMode::Quirks, "{:?}", source);
+            assert_eq!(parse_document(source, ParserOptions::default()).unwrap().quirks_mode, QuirksMode::Quirks, "{:?}", source);
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,1194 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use crate::resource::{LoadError, ResolvedUrl, ResourceLoader};
+use std::sync::Arc;
+
+// Decoders for the image formats documents embed: PNG and baseline JPEG. Both
+// produce 8-bit RGBA, and `probe_dimensions` reads no further than the header so
+// layout can find an image's intrinsic size without decoding it.
+
+// refuse images with more pixels than this rather than allocate for them
+const MAX_PIXELS: u64 = 1 << 26;
+// how much of a file `probe_dimensions` reads before falling back to all of it
+const PROBE_BYTES: u64 = 64 * 1024;
+
+#[derive(Debug)]
+pub enum ImageError {
+    Io(io::Error),
+    Load(LoadError), // the loader couldn't fetch the image
+    UnknownFormat, // neither a PNG nor a JPEG signature
+    Truncated, // the data ended before the image did
+    Malformed(&'static str),
+    Unsupported(&'static str), // valid, but uses a feature these decoders lack
+    TooLarge { width: u32, height: u32 },
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::Io(error) => write!(f, "{}", error),
+            ImageError::Load(error) => write!(f, "{}", error),
+            ImageError::UnknownFormat => write!(f, "not a PNG or JPEG image"),
+            ImageError::Truncated => write!(f, "image data is truncated"),
+            ImageError::Malformed(what) => write!(f, "malformed image: {}", what),
+            ImageError::Unsupported(what) => write!(f, "unsupported image: {}", what),
+            ImageError::TooLarge { width, height } => write!(f, "image too large: {}x{}", width, height),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+impl From<LoadError> for ImageError {
+    fn from(error: LoadError) -> ImageError {
+        ImageError::Load(error)
+    }
+}
+
+impl From<io::Error> for ImageError {
+    fn from(error: io::Error) -> ImageError {
+        ImageError::Io(error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+}
+
+impl ImageFormat {
+    // recognise the format by its signature
+    pub fn sniff(bytes: &[u8]) -> Option<ImageFormat> {
+        if bytes.starts_with(&PNG_SIGNATURE) {
+            Some(ImageFormat::Png)
+        } else if bytes.starts_with(&[0xFF, 0xD8]) {
+            Some(ImageFormat::Jpeg)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedImage {
+    pub width: u32,
+    pub height: u32,
+    pub rgba: Vec<u8>, // row-major, 4 bytes per pixel, not premultiplied
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ImageSource<'a> {
+    Path(&'a Path),
+    Bytes(&'a [u8]),
+}
+
+pub fn decode(source: ImageSource) -> Result<DecodedImage, ImageError> {
+    match source {
+        ImageSource::Path(path) => decode_bytes(&fs::read(path)?),
+        ImageSource::Bytes(bytes) => decode_bytes(bytes)
+    }
+}
+
+fn decode_bytes(bytes: &[u8]) -> Result<DecodedImage, ImageError> {
+    match ImageFormat::sniff(bytes) {
+        Some(ImageFormat::Png) => decode_png(bytes),
+        Some(ImageFormat::Jpeg) => decode_jpeg(bytes),
+        None => Err(ImageError::UnknownFormat)
+    }
+}
+
+/// The width and height of an image, read from its header alone.
+pub fn probe_dimensions(source: ImageSource) -> Result<(u32, u32), ImageError> {
+    match source {
+        ImageSource::Bytes(bytes) => probe_bytes(bytes),
+        ImageSource::Path(path) => {
+            // JPEG metadata can push the frame header past the first read
+            let mut file = File::open(path)?;
+            let mut head = Vec::new();
+            file.by_ref().take(PROBE_BYTES).read_to_end(&mut head)?;
+            match probe_bytes(&head) {
+                Err(ImageError::Truncated) if head.len() as u64 == PROBE_BYTES => {
+                    file.read_to_end(&mut head)?;
+                    probe_bytes(&head)
+                }
+                result => result
+            }
+        }
+    }
+}
+
+fn probe_bytes(bytes: &[u8]) -> Result<(u32, u32), ImageError> {
+    match ImageFormat::sniff(bytes) {
+        Some(ImageFormat::Png) => {
+            let ihdr = bytes.get(12..24).ok_or(ImageError::Truncated)?;
+            if &ihdr[..4] != b"IHDR" {
+                return Err(ImageError::Malformed("first chunk is not IHDR"));
+            }
+            Ok((read_u32(&ihdr[4..]), read_u32(&ihdr[8..])))
+        }
+        Some(ImageFormat::Jpeg) => {
+            let mut pos = 2;
+            loop {
+                let (marker, segment) = next_jpeg_segment(bytes, &mut pos)?;
+                match marker {
+                    0xC0..=0xCF if marker != 0xC4 && marker != 0xC8 && marker != 0xCC => {
+                        let frame = segment.get(..5).ok_or(ImageError::Truncated)?;
+                        return Ok((read_u16(&frame[3..]) as u32, read_u16(&frame[1..]) as u32));
+                    }
+                    0xD9 | 0xDA => return Err(ImageError::Malformed("no frame header")),
+                    _ => {}
+                }
+            }
+        }
+        None => Err(ImageError::UnknownFormat)
+    }
+}
+
+fn check_size(width: u32, height: u32) -> Result<(), ImageError> {
+    if width == 0 || height == 0 {
+        Err(ImageError::Malformed("image has no pixels"))
+    } else if width as u64 * height as u64 > MAX_PIXELS {
+        Err(ImageError::TooLarge { width, height })
+    } else {
+        Ok(())
+    }
+}
+
+/// Decoded images for one render, so an image used many times is decoded once.
+#[derive(Debug, Default)]
+pub struct ImageCache {
+    images: HashMap<ResolvedUrl, Arc<DecodedImage>>,
+}
+
+impl ImageCache {
+    pub fn new() -> ImageCache {
+        Default::default()
+    }
+
+    // failures aren't cached, so each caller gets its own error to report
+    pub fn get_or_decode(&mut self, url: &ResolvedUrl, loader: &dyn ResourceLoader) -> Result<Arc<DecodedImage>, ImageError> {
+        // different spellings of the same URL resolve to the same entry
+        if let Some(image) = self.images.get(url) {
+            return Ok(image.clone());
+        }
+        let image = Arc::new(decode(ImageSource::Bytes(&loader.load(url)?))?);
+        self.images.insert(url.clone(), image.clone());
+        Ok(image)
+    }
+
+    pub fn len(&self) -> usize {
+        self.images.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.images.is_empty()
+    }
+}
+
+fn read_u16(bytes: &[u8]) -> u16 {
+    u16::from_be_bytes([bytes[0], bytes[1]])
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+// Huffman decoding, shared by DEFLATE and JPEG
+
+trait BitSource {
+    fn bit(&mut self) -> Result<u32, ImageError>;
+}
+
+// a canonical Huffman code, decoded a bit at a time
+struct Huffman {
+    counts: [u16; 17], // number of codes of each length
+    symbols: Vec<u16>, // symbols ordered by code
+}
+
+impl Huffman {
+    // from the code length of each symbol, as DEFLATE describes its codes
+    fn from_lengths(lengths: &[u8]) -> Result<Huffman, ImageError> {
+        let mut counts = [0u16; 17];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut symbols = Vec::with_capacity(lengths.len());
+        for length in 1..17 {
+            symbols.extend((0..lengths.len() as u16).filter(|&symbol| lengths[symbol as usize] == length));
+        }
+        Huffman::from_counts(counts, symbols)
+    }
+
+    // from the number of codes of each length and the symbols in code order, as JPEG does
+    fn from_counts(counts: [u16; 17], symbols: Vec<u16>) -> Result<Huffman, ImageError> {
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return Err(ImageError::Malformed("oversubscribed Huffman code"));
+            }
+        }
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn decode(&self, bits: &mut impl BitSource) -> Result<u16, ImageError> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0usize);
+        for &count in &self.counts[1..] {
+            code |= bits.bit()? as i32;
+            let count = count as i32;
+            if code - first < count {
+                return Ok(self.symbols[index + (code - first) as usize]);
+            }
+            index += count as usize;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(ImageError::Malformed("invalid Huffman code"))
+    }
+}
+
+// zlib and DEFLATE, for PNG image data
+
+// bits of a DEFLATE stream, least significant first
+struct DeflateBits<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buffer: u32,
+    count: u32,
+}
+
+impl DeflateBits<'_> {
+    fn bits(&mut self, n: u32) -> Result<u32, ImageError> {
+        while self.count < n {
+            let byte = *self.data.get(self.pos).ok_or(ImageError::Truncated)?;
+            self.pos += 1;
+            self.buffer |= (byte as u32) << self.count;
+            self.count += 8;
+        }
+        let value = self.buffer & ((1 << n) - 1);
+        self.buffer >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+
+    // skip to the next byte boundary
+    fn align(&mut self) {
+        self.buffer = 0;
+        self.count = 0;
+    }
+}
+
+impl BitSource for DeflateBits<'_> {
+    fn bit(&mut self) -> Result<u32, ImageError> {
+        self.bits(1)
+    }
+}
+
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59,
+    67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4,
+    5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385,
+    513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10,
+    10, 11, 11, 12, 12, 13, 13];
+// the order code length code lengths are sent in
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+// decompress a zlib stream, failing as soon as it would produce more than `max_len`
+// bytes, so that a small file can't be made to inflate into a huge one
+fn inflate(data: &[u8], max_len: usize) -> Result<Vec<u8>, ImageError> {
+    if data.len() < 2 {
+        return Err(ImageError::Truncated);
+    }
+    let (cmf, flg) = (data[0], data[1]);
+    if cmf & 0x0F != 8 || !(cmf as u16 * 256 + flg as u16).is_multiple_of(31) {
+        return Err(ImageError::Malformed("bad zlib header"));
+    }
+    if flg & 0x20 != 0 {
+        return Err(ImageError::Unsupported("zlib preset dictionary"));
+    }
+
+    let mut bits = DeflateBits { data, pos: 2, buffer: 0, count: 0 };
+    let mut out = Vec::new();
+    loop {
+        let last = bits.bits(1)? == 1;
+        match bits.bits(2)? {
+            0 => {
+                bits.align();
+                let header = data.get(bits.pos..bits.pos + 4).ok_or(ImageError::Truncated)?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                if len != !u16::from_le_bytes([header[2], header[3]]) {
+                    return Err(ImageError::Malformed("stored block length mismatch"));
+                }
+                let start = bits.pos + 4;
+                if out.len() + len as usize > max_len {
+                    return Err(too_much_data());
+                }
+                out.extend_from_slice(data.get(start..start + len as usize).ok_or(ImageError::Truncated)?);
+                bits.pos = start + len as usize;
+            }
+            1 => {
+                let mut lengths = [8u8; 288];
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                let literals = Huffman::from_lengths(&lengths)?;
+                let distances = Huffman::from_lengths(&[5; 30])?;
+                inflate_block(&mut bits, &mut out, max_len, &literals, &distances)?;
+            }
+            2 => {
+                let (literals, distances) = read_dynamic_codes(&mut bits)?;
+                inflate_block(&mut bits, &mut out, max_len, &literals, &distances)?;
+            }
+            _ => return Err(ImageError::Malformed("invalid DEFLATE block type"))
+        }
+        if last {
+            break;
+        }
+    }
+
+    bits.align();
+    let checksum = data.get(bits.pos..bits.pos + 4).ok_or(ImageError::Truncated)?;
+    if read_u32(checksum) != adler32(&out) {
+        return Err(ImageError::Malformed("zlib checksum mismatch"));
+    }
+    Ok(out)
+}
+
+fn read_dynamic_codes(bits: &mut DeflateBits) -> Result<(Huffman, Huffman), ImageError> {
+    let literal_count = bits.bits(5)? as usize + 257;
+    let distance_count = bits.bits(5)? as usize + 1;
+    let code_length_count = bits.bits(4)? as usize + 4;
+
+    let mut code_lengths = [0u8; 19];
+    for &index in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_lengths[index] = bits.bits(3)? as u8;
+    }
+    let code_length_code = Huffman::from_lengths(&code_lengths)?;
+
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let (length, repeat) = match code_length_code.decode(bits)? {
+            16 => {
+                let previous = *lengths.last().ok_or(ImageError::Malformed("length repeat with no previous length"))?;
+                (previous, 3 + bits.bits(2)?)
+            }
+            17 => (0, 3 + bits.bits(3)?),
+            18 => (0, 11 + bits.bits(7)?),
+            length => (length as u8, 1)
+        };
+        for _ in 0..repeat {
+            lengths.push(length);
+        }
+    }
+    if lengths.len() > literal_count + distance_count {
+        return Err(ImageError::Malformed("code lengths overrun"));
+    }
+    Ok((Huffman::from_lengths(&lengths[..literal_count])?, Huffman::from_lengths(&lengths[literal_count..])?))
+}
+
+fn too_much_data() -> ImageError {
+    ImageError::Malformed("more image data than the header allows")
+}
+
+fn inflate_block(bits: &mut DeflateBits, out: &mut Vec<u8>, max_len: usize, literals: &Huffman, distances: &Huffman) -> Result<(), ImageError> {
+    loop {
+        let symbol = literals.decode(bits)? as usize;
+        if symbol < 256 {
+            if out.len() >= max_len {
+                return Err(too_much_data());
+            }
+            out.push(symbol as u8);
+        } else if symbol == 256 {
+            return Ok(());
+        } else {
+            let index = symbol - 257;
+            if index >= LENGTH_BASE.len() {
+                return Err(ImageError::Malformed("invalid length code"));
+            }
+            let length = LENGTH_BASE[index] as usize + bits.bits(LENGTH_EXTRA[index] as u32)? as usize;
+            let index = distances.decode(bits)? as usize;
+            if index >= DISTANCE_BASE.len() {
+                return Err(ImageError::Malformed("invalid distance code"));
+            }
+            let distance = DISTANCE_BASE[index] as usize + bits.bits(DISTANCE_EXTRA[index] as u32)? as usize;
+            if distance > out.len() {
+                return Err(ImageError::Malformed("distance before start of data"));
+            }
+            if out.len() + length > max_len {
+                return Err(too_much_data());
+            }
+            // byte by byte, since a copy may overlap what it produces
+            let start = out.len() - distance;
+            for i in 0..length {
+                out.push(out[start + i]);
+            }
+        }
+    }
+}
+
+pub(crate) fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+// PNG
+
+pub(crate) const PNG_SIGNATURE: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
+
+// pass origins and steps of Adam7 interlacing: (x, y, dx, dy)
+const ADAM7: [(usize, usize, usize, usize); 7] = [(0, 0, 8, 8), (4, 0, 8, 8), (0, 4, 4, 8), (2, 0, 4, 4),
+    (0, 2, 2, 4), (1, 0, 2, 2), (0, 1, 1, 2)];
+
+struct PngHeader {
+    width: u32,
+    height: u32,
+    bit_depth: u8,
+    color_type: u8,
+    interlaced: bool,
+}
+
+impl PngHeader {
+    fn parse(data: &[u8]) -> Result<PngHeader, ImageError> {
+        if data.len() != 13 {
+            return Err(ImageError::Malformed("bad IHDR length"));
+        }
+        let header = PngHeader {
+            width: read_u32(data),
+            height: read_u32(&data[4..]),
+            bit_depth: data[8],
+            color_type: data[9],
+            interlaced: data[12] == 1,
+        };
+        let depth_allowed = match header.color_type {
+            0 => matches!(header.bit_depth, 1 | 2 | 4 | 8 | 16),
+            3 => matches!(header.bit_depth, 1 | 2 | 4 | 8),
+            2 | 4 | 6 => matches!(header.bit_depth, 8 | 16),
+            _ => return Err(ImageError::Malformed("unknown color type"))
+        };
+        if !depth_allowed {
+            return Err(ImageError::Malformed("bit depth not allowed for color type"));
+        }
+        if data[10] != 0 || data[11] != 0 || data[12] > 1 {
+            return Err(ImageError::Malformed("unknown compression, filter or interlace method"));
+        }
+        check_size(header.width, header.height)?;
+        Ok(header)
+    }
+
+    fn channels(&self) -> usize {
+        match self.color_type {
+            2 => 3,
+            4 => 2,
+            6 => 4,
+            _ => 1
+        }
+    }
+}
+
+fn decode_png(bytes: &[u8]) -> Result<DecodedImage, ImageError> {
+    let mut header = None;
+    let mut palette: Vec<[u8; 4]> = Vec::new();
+    let mut transparency = None;
+    let mut image_data = Vec::new();
+
+    let mut pos = PNG_SIGNATURE.len();
+    loop {
+        let chunk = bytes.get(pos..pos + 8).ok_or(ImageError::Truncated)?;
+        let (length, kind) = (read_u32(chunk) as usize, &chunk[4..8]);
+        let end = pos + 8 + length;
+        let checksum = bytes.get(end..end + 4).ok_or(ImageError::Truncated)?;
+        if read_u32(checksum) != crc32(&bytes[pos + 4..end]) {
+            return Err(ImageError::Malformed("chunk checksum mismatch"));
+        }
+        let data = &bytes[pos + 8..end];
+        pos = end + 4;
+
+        match kind {
+            b"IHDR" if header.is_none() => header = Some(PngHeader::parse(data)?),
+            _ if header.is_none() => return Err(ImageError::Malformed("first chunk is not IHDR")),
+            b"PLTE" => palette = data.chunks_exact(3).map(|rgb| [rgb[0], rgb[1], rgb[2], 255]).collect(),
+            b"tRNS" => transparency = Some(data),
+            b"IDAT" => image_data.extend_from_slice(data),
+            b"IEND" => break,
+            // a chunk we don't know is only safe to skip if it's ancillary
+            _ if kind[0] & 0x20 == 0 => return Err(ImageError::Unsupported("unknown critical chunk")),
+            _ => {}
+        }
+    }
+    let header = header.ok_or(ImageError::Malformed("missing IHDR"))?;
+    if header.color_type == 3 && palette.is_empty() {
+        return Err(ImageError::Malformed("missing palette"));
+    }
+
+    // tRNS gives palette alphas, or the one sample value that is transparent
+    let mut color_key = None;
+    if let Some(trns) = transparency {
+        match header.color_type {
+            3 => palette.iter_mut().zip(trns).for_each(|(entry, &alpha)| entry[3] = alpha),
+            0 | 2 if trns.len() >= 2 * header.channels() => {
+                color_key = Some(trns.chunks_exact(2).map(read_u16).collect::<Vec<_>>());
+            }
+            _ => {}
+        }
+    }
+
+    let (width, height) = (header.width as usize, header.height as usize);
+    let depth = header.bit_depth as usize;
+    let channels = header.channels();
+    let filter_step = (channels * depth).div_ceil(8);
+    let passes: &[_] = if header.interlaced { &ADAM7 } else { &[(0, 0, 1, 1)] };
+    // the size of each pass in pixels, and of its rows in bytes
+    let pass_sizes: Vec<(usize, usize, usize)> = passes.iter().map(|&(x0, y0, dx, dy)| {
+        let columns = (width + dx - 1 - x0) / dx;
+        let rows = (height + dy - 1 - y0) / dy;
+        (columns, rows, (columns * channels * depth).div_ceil(8))
+    }).collect();
+    let expected: usize = pass_sizes.iter()
+        .filter(|&&(columns, _, _)| columns > 0)
+        .map(|&(_, rows, stride)| rows * (stride + 1))
+        .sum();
+    let raw = inflate(&image_data, expected)?;
+    if raw.len() < expected {
+        return Err(ImageError::Truncated);
+    }
+
+    let mut rgba = vec![0u8; width * height * 4];
+    let mut offset = 0;
+    for (&(x0, y0, dx, dy), &(columns, rows, stride)) in passes.iter().zip(&pass_sizes) {
+        if columns == 0 || rows == 0 {
+            continue;
+        }
+        let mut previous = vec![0u8; stride];
+        for row in 0..rows {
+            let filter = raw[offset];
+            let mut line = raw[offset + 1..offset + 1 + stride].to_vec();
+            unfilter_png_row(filter, &mut line, &previous, filter_step)?;
+            for column in 0..columns {
+                let sample = |channel: usize| png_sample(&line, column * channels + channel, depth);
+                let samples: Vec<u16> = (0..channels).map(sample).collect();
+                let pixel = match header.color_type {
+                    3 => *palette.get(samples[0] as usize).ok_or(ImageError::Malformed("palette index out of range"))?,
+                    _ => {
+                        let scale = |value: u16| scale_png_sample(value, depth);
+                        let transparent = color_key.as_ref().is_some_and(|key| *key == samples);
+                        let opaque = if transparent { 0 } else { 255 };
+                        match header.color_type {
+                            0 => [scale(samples[0]), scale(samples[0]), scale(samples[0]), opaque],
+                            2 => [scale(samples[0]), scale(samples[1]), scale(samples[2]), opaque],
+                            4 => [scale(samples[0]), scale(samples[0]), scale(samples[0]), scale(samples[1])],
+                            _ => [scale(samples[0]), scale(samples[1]), scale(samples[2]), scale(samples[3])]
+                        }
+                    }
+                };
+                let (x, y) = (x0 + column * dx, y0 + row * dy);
+                rgba[(y * width + x) * 4..][..4].copy_from_slice(&pixel);
+            }
+            previous = line;
+            offset += stride + 1;
+        }
+    }
+    Ok(DecodedImage { width: header.width, height: header.height, rgba })
+}
+
+// undo a row's filter in place; `step` is the distance in bytes to the corresponding
+// byte of the pixel to the left
+fn unfilter_png_row(filter: u8, line: &mut [u8], previous: &[u8], step: usize) -> Result<(), ImageError> {
+    for i in 0..line.len() {
+        let left = if i >= step { line[i - step] } else { 0 };
+        let up = previous[i];
+        let up_left = if i >= step { previous[i - step] } else { 0 };
+        let predictor = match filter {
+            0 => 0,
+            1 => left,
+            2 => up,
+            3 => ((left as u16 + up as u16) / 2) as u8,
+            4 => paeth(left, up, up_left),
+            _ => return Err(ImageError::Malformed("unknown filter type"))
+        };
+        line[i] = line[i].wrapping_add(predictor);
+    }
+    Ok(())
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = ((p - a as i16).abs(), (p - b as i16).abs(), (p - c as i16).abs());
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+// the `index`th sample of a row, at its own bit depth
+fn png_sample(line: &[u8], index: usize, depth: usize) -> u16 {
+    match depth {
+        16 => read_u16(&line[index * 2..]),
+        8 => line[index] as u16,
+        _ => {
+            let bit = index * depth;
+            let shift = 8 - depth - bit % 8;
+            ((line[bit / 8] >> shift) & ((1 << depth) - 1) as u8) as u16
+        }
+    }
+}
+
+fn scale_png_sample(value: u16, depth: usize) -> u8 {
+    match depth {
+        16 => (value >> 8) as u8,
+        8 => value as u8,
+        _ => (value as u32 * 255 / ((1 << depth) - 1)) as u8
+    }
+}
+
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// baseline JPEG
+
+// the natural (row-major) position of each coefficient in zigzag order
+const ZIGZAG: [usize; 64] = [0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40,
+    48, 41, 34, 27, 20, 13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44,
+    51, 58, 59, 52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63];
+
+struct JpegComponent {
+    id: u8,
+    h: usize, // sampling factors
+    v: usize,
+    quant_table: usize,
+    dc_table: usize,
+    ac_table: usize,
+    dc_prediction: i32,
+    plane: Vec<u8>, // decoded samples, whole blocks wide and high
+    stride: usize,
+}
+
+struct JpegFrame {
+    width: usize,
+    height: usize,
+    components: Vec<JpegComponent>,
+    max_h: usize,
+    max_v: usize,
+    mcus_x: usize,
+    mcus_y: usize,
+}
+
+// the tables a scan decodes with
+#[derive(Default)]
+struct JpegTables {
+    quant: [Option<[u16; 64]>; 4],
+    dc: [Option<Huffman>; 4],
+    ac: [Option<Huffman>; 4],
+    restart_interval: usize,
+}
+
+// read the marker at `pos` and the segment that follows it, skipping fill bytes
+fn next_jpeg_segment<'a>(bytes: &'a [u8], pos: &mut usize) -> Result<(u8, &'a [u8]), ImageError> {
+    loop {
+        let marker = bytes.get(*pos..*pos + 2).ok_or(ImageError::Truncated)?;
+        if marker[0] != 0xFF {
+            return Err(ImageError::Malformed("expected a marker"));
+        }
+        match marker[1] {
+            0xFF => *pos += 1,
+            // markers without a segment
+            0x01 | 0xD0..=0xD9 => {
+                *pos += 2;
+                return Ok((marker[1], &[]));
+            }
+            kind => {
+                let length = read_u16(bytes.get(*pos + 2..*pos + 4).ok_or(ImageError::Truncated)?) as usize;
+                if length < 2 {
+                    return Err(ImageError::Malformed("bad segment length"));
+                }
+                let segment = bytes.get(*pos + 4..*pos + 2 + length).ok_or(ImageError::Truncated)?;
+                *pos += 2 + length;
+                return Ok((kind, segment));
+            }
+        }
+    }
+}
+
+fn decode_jpeg(bytes: &[u8]) -> Result<DecodedImage, ImageError> {
+    let mut tables = JpegTables::default();
+    let mut frame: Option<JpegFrame> = None;
+    let mut scanned = false;
+    let mut adobe_transform = None;
+
+    let mut pos = 2;
+    loop {
+        let (marker, segment) = next_jpeg_segment(bytes, &mut pos)?;
+        match marker {
+            0xD9 => break,
+            0xC0 | 0xC1 => frame = Some(parse_jpeg_frame(segment)?),
+            0xC2 | 0xC3 | 0xC5..=0xC7 | 0xC9..=0xCB | 0xCD..=0xCF => {
+                return Err(ImageError::Unsupported("progressive, lossless or arithmetic-coded JPEG"));
+            }
+            0xC4 => parse_huffman_tables(segment, &mut tables)?,
+            0xDB => parse_quant_tables(segment, &mut tables)?,
+            0xDD => {
+                let interval = segment.get(..2).ok_or(ImageError::Truncated)?;
+                tables.restart_interval = read_u16(interval) as usize;
+            }
+            0xDA => {
+                let frame = frame.as_mut().ok_or(ImageError::Malformed("scan before frame header"))?;
+                pos = decode_jpeg_scan(bytes, pos, segment, frame, &tables)?;
+                scanned = true;
+            }
+            0xEE if segment.starts_with(b"Adobe") && segment.len() >= 12 => adobe_transform = Some(segment[11]),
+            _ => {}
+        }
+    }
+    let frame = match frame {
+        Some(frame) if scanned => frame,
+        _ => return Err(ImageError::Malformed("no image data"))
+    };
+
+    // upsample each component to full size and convert to RGB
+    let mut rgba = Vec::with_capacity(frame.width * frame.height * 4);
+    for y in 0..frame.height {
+        for x in 0..frame.width {
+            let sample = |c: &JpegComponent| {
+                let (cx, cy) = (x * c.h / frame.max_h, y * c.v / frame.max_v);
+                c.plane[cy * c.stride + cx] as f32
+            };
+            let pixel = match &frame.components[..] {
+                [gray] => {
+                    let gray = sample(gray) as u8;
+                    [gray, gray, gray]
+                }
+                [r, g, b] if adobe_transform == Some(0) => [sample(r) as u8, sample(g) as u8, sample(b) as u8],
+                [y, cb, cr] => {
+                    let (y, cb, cr) = (sample(y), sample(cb) - 128.0, sample(cr) - 128.0);
+                    [
+                        (y + 1.402 * cr).round().clamp(0.0, 255.0) as u8,
+                        (y - 0.344_136 * cb - 0.714_136 * cr).round().clamp(0.0, 255.0) as u8,
+                        (y + 1.772 * cb).round().clamp(0.0, 255.0) as u8,
+                    ]
+                }
+                _ => unreachable!("frame headers only allow one or three components")
+            };
+            rgba.extend_from_slice(&pixel);
+            rgba.push(255);
+        }
+    }
+    Ok(DecodedImage { width: frame.width as u32, height: frame.height as u32, rgba })
+}
+
+fn parse_jpeg_frame(segment: &[u8]) -> Result<JpegFrame, ImageError> {
+    let header = segment.get(..6).ok_or(ImageError::Truncated)?;
+    if header[0] != 8 {
+        return Err(ImageError::Unsupported("JPEG sample precision other than 8 bits"));
+    }
+    let (height, width) = (read_u16(&header[1..]) as u32, read_u16(&header[3..]) as u32);
+    if height == 0 {
+        return Err(ImageError::Unsupported("height given after the first scan"));
+    }
+    check_size(width, height)?;
+    let count = header[5] as usize;
+    if count != 1 && count != 3 {
+        return Err(ImageError::Unsupported("JPEG with other than one or three components"));
+    }
+    let specs = segment.get(6..6 + 3 * count).ok_or(ImageError::Truncated)?;
+    let mut components = Vec::with_capacity(count);
+    for spec in specs.chunks_exact(3) {
+        let (h, v) = ((spec[1] >> 4) as usize, (spec[1] & 0x0F) as usize);
+        if !(1..=4).contains(&h) || !(1..=4).contains(&v) || spec[2] > 3 {
+            return Err(ImageError::Malformed("bad component specification"));
+        }
+        components.push(JpegComponent {
+            id: spec[0], h, v, quant_table: spec[2] as usize, dc_table: 0, ac_table: 0,
+            dc_prediction: 0, plane: Vec::new(), stride: 0,
+        });
+    }
+
+    let max_h = components.iter().map(|c| c.h).max().unwrap_or(1);
+    let max_v = components.iter().map(|c| c.v).max().unwrap_or(1);
+    let mcus_x = (width as usize).div_ceil(8 * max_h);
+    let mcus_y = (height as usize).div_ceil(8 * max_v);
+    for c in &mut components {
+        c.stride = mcus_x * c.h * 8;
+        c.plane = vec![0; c.stride * mcus_y * c.v * 8];
+    }
+    Ok(JpegFrame { width: width as usize, height: height as usize, components, max_h, max_v, mcus_x, mcus_y })
+}
+
+fn parse_huffman_tables(mut segment: &[u8], tables: &mut JpegTables) -> Result<(), ImageError> {
+    while !segment.is_empty() {
+        let header = segment.get(..17).ok_or(ImageError::Truncated)?;
+        let (class, index) = (header[0] >> 4, (header[0] & 0x0F) as usize);
+        if class > 1 || index > 3 {
+            return Err(ImageError::Malformed("bad Huffman table"));
+        }
+        let mut counts = [0u16; 17];
+        for (count, &n) in counts[1..].iter_mut().zip(&header[1..]) {
+            *count = n as u16;
+        }
+        let total: usize = counts.iter().map(|&n| n as usize).sum();
+        let symbols = segment.get(17..17 + total).ok_or(ImageError::Truncated)?;
+        let table = Huffman::from_counts(counts, symbols.iter().map(|&s| s as u16).collect())?;
+        if class == 0 {
+            tables.dc[index] = Some(table);
+        } else {
+            tables.ac[index] = Some(table);
+        }
+        segment = &segment[17 + total..];
+    }
+    Ok(())
+}
+
+fn parse_quant_tables(mut segment: &[u8], tables: &mut JpegTables) -> Result<(), ImageError> {
+    while !segment.is_empty() {
+        let (precision, index) = (segment[0] >> 4, (segment[0] & 0x0F) as usize);
+        if precision > 1 || index > 3 {
+            return Err(ImageError::Malformed("bad quantization table"));
+        }
+        let size = if precision == 0 { 64 } else { 128 };
+        let values = segment.get(1..1 + size).ok_or(ImageError::Truncated)?;
+        let mut table = [0u16; 64];
+        for (k, entry) in table.iter_mut().enumerate() {
+            *entry = if precision == 0 { values[k] as u16 } else { read_u16(&values[2 * k..]) };
+        }
+        tables.quant[index] = Some(table);
+        segment = &segment[1 + size..];
+    }
+    Ok(())
+}
+
+// entropy-coded bits, most significant first, with stuffed zero bytes removed
+struct JpegBits<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buffer: u32,
+    count: u32,
+}
+
+impl JpegBits<'_> {
+    fn bits(&mut self, n: u32) -> Result<u32, ImageError> {
+        let mut value = 0;
+        for _ in 0..n {
+            value = (value << 1) | self.bit()?;
+        }
+        Ok(value)
+    }
+
+    // a coefficient of `size` bits, with its sign folded in as JPEG encodes it
+    fn signed(&mut self, size: u32) -> Result<i32, ImageError> {
+        if size == 0 {
+            return Ok(0);
+        }
+        let value = self.bits(size)? as i32;
+        Ok(if value < 1 << (size - 1) { value - (1 << size) + 1 } else { value })
+    }
+
+    // drop any partial byte and step over the restart marker that must follow
+    fn restart(&mut self) -> Result<(), ImageError> {
+        self.buffer = 0;
+        self.count = 0;
+        match self.data.get(self.pos..self.pos + 2) {
+            Some([0xFF, 0xD0..=0xD7]) => {
+                self.pos += 2;
+                Ok(())
+            }
+            Some(_) => Err(ImageError::Malformed("missing restart marker")),
+            None => Err(ImageError::Truncated)
+        }
+    }
+}
+
+impl BitSource for JpegBits<'_> {
+    fn bit(&mut self) -> Result<u32, ImageError> {
+        if self.count == 0 {
+            let byte = *self.data.get(self.pos).ok_or(ImageError::Truncated)?;
+            if byte == 0xFF {
+                match self.data.get(self.pos + 1) {
+                    Some(0) => {
+                        self.buffer = 0xFF;
+                        self.pos += 2;
+                    }
+                    // a marker ends the data; pad with zeros without consuming it
+                    Some(_) => self.buffer = 0,
+                    None => return Err(ImageError::Truncated)
+                }
+            } else {
+                self.buffer = byte as u32;
+                self.pos += 1;
+            }
+            self.count = 8;
+        }
+        self.count -= 1;
+        Ok((self.buffer >> self.count) & 1)
+    }
+}
+
+// decode the scan whose header is `segment` and whose data starts at `pos`,
+// returning where the next marker is
+fn decode_jpeg_scan(bytes: &[u8], pos: usize, segment: &[u8], frame: &mut JpegFrame, tables: &JpegTables) -> Result<usize, ImageError> {
+    let count = *segment.first().ok_or(ImageError::Truncated)? as usize;
+    let specs = segment.get(1..1 + 2 * count).ok_or(ImageError::Truncated)?;
+    let mut scan_components = Vec::with_capacity(count);
+    for spec in specs.chunks_exact(2) {
+        let index = frame.components.iter().position(|c| c.id == spec[0])
+            .ok_or(ImageError::Malformed("scan names an unknown component"))?;
+        let component = &mut frame.components[index];
+        component.dc_table = (spec[1] >> 4) as usize;
+        component.ac_table = (spec[1] & 0x0F) as usize;
+        component.dc_prediction = 0;
+        scan_components.push(index);
+    }
+    if scan_components.is_empty() {
+        return Err(ImageError::Malformed("scan has no components"));
+    }
+
+    // a scan of one component codes its blocks one at a time rather than in MCUs
+    let (units_x, units_y) = match &scan_components[..] {
+        &[only] => {
+            let c = &frame.components[only];
+            ((frame.width * c.h).div_ceil(frame.max_h).div_ceil(8), (frame.height * c.v).div_ceil(frame.max_v).div_ceil(8))
+        }
+        _ => (frame.mcus_x, frame.mcus_y)
+    };
+    let single = scan_components.len() == 1;
+
+    let mut bits = JpegBits { data: bytes, pos, buffer: 0, count: 0 };
+    let mut coefficients = [0i32; 64];
+    for unit in 0..units_x * units_y {
+        if tables.restart_interval > 0 && unit > 0 && unit % tables.restart_interval == 0 {
+            bits.restart()?;
+            for &index in &scan_components {
+                frame.components[index].dc_prediction = 0;
+            }
+        }
+        let (unit_x, unit_y) = (unit % units_x, unit / units_x);
+        for &index in &scan_components {
+            let component = &mut frame.components[index];
+            let (blocks_h, blocks_v) = if single { (1, 1) } else { (component.h, component.v) };
+            for block_y in 0..blocks_v {
+                for block_x in 0..blocks_h {
+                    decode_jpeg_block(&mut bits, component, tables, &mut coefficients)?;
+                    let (x, y) = ((unit_x * blocks_h + block_x) * 8, (unit_y * blocks_v + block_y) * 8);
+                    let stride = component.stride;
+                    inverse_dct(&coefficients, &mut component.plane[y * stride + x..], stride);
+                }
+            }
+        }
+    }
+
+    // skip to the marker after the entropy-coded data
+    let mut end = bits.pos;
+    loop {
+        match bytes.get(end..end + 2) {
+            Some([0xFF, next]) if *next != 0 && !(0xD0..=0xD7).contains(next) => return Ok(end),
+            Some(_) => end += 1,
+            None => return Err(ImageError::Truncated)
+        }
+    }
+}
+
+fn decode_jpeg_block(bits: &mut JpegBits, component: &mut JpegComponent, tables: &JpegTables, coefficients: &mut [i32; 64]) -> Result<(), ImageError> {
+    let missing = || ImageError::Malformed("scan uses a missing table");
+    let quant = tables.quant[component.quant_table].as_ref().ok_or_else(missing)?;
+    let dc = tables.dc.get(component.dc_table).and_then(Option::as_ref).ok_or_else(missing)?;
+    let ac = tables.ac.get(component.ac_table).and_then(Option::as_ref).ok_or_else(missing)?;
+
+    coefficients.fill(0);
+    let size = dc.decode(bits)? as u32;
+    if size > 11 {
+        return Err(ImageError::Malformed("bad DC coefficient size"));
+    }
+    // a corrupt scan can push the prediction anywhere, one block after another
+    component.dc_prediction = component.dc_prediction.saturating_add(bits.signed(size)?);
+    coefficients[0] = dequantize(component.dc_prediction, quant[0]);
+
+    let mut k = 1;
+    while k < 64 {
+        let symbol = ac.decode(bits)?;
+        let (run, size) = ((symbol >> 4) as usize, (symbol & 0x0F) as u32);
+        if size == 0 {
+            if run != 15 {
+                break; // end of block
+            }
+            k += 16;
+            continue;
+        }
+        k += run;
+        if k > 63 {
+            return Err(ImageError::Malformed("coefficient index out of range"));
+        }
+        coefficients[ZIGZAG[k]] = dequantize(bits.signed(size)?, quant[k]);
+        k += 1;
+    }
+    Ok(())
+}
+
+// A coefficient scaled by its quantizer, clamped to what a baseline JPEG of 8-bit
+// samples can hold, so that corrupt data only makes for a garbled block.
+fn dequantize(value: i32, quant: u16) -> i32 {
+    (value as i64 * quant as i64).clamp(i16::MIN as i64, i16::MAX as i64) as i32
+}
+
+// the separable 8x8 inverse DCT, writing level-shifted samples into `out`
+fn inverse_dct(coefficients: &[i32; 64], out: &mut [u8], stride: usize) {
+    let mut cosines = [[0f32; 8]; 8];
+    for (x, row) in cosines.iter_mut().enumerate() {
+        for (u, cosine) in row.iter_mut().enumerate() {
+            let scale = if u == 0 { std::f32::consts::FRAC_1_SQRT_2 } else { 1.0 };
+            *cosine = scale * (((2 * x + 1) * u) as f32 * std::f32::consts::PI / 16.0).cos();
+        }
+    }
+
+    // rows first, then columns
+    let mut rows = [0f32; 64];
+    for v in 0..8 {
+        for x in 0..8 {
+            rows[v * 8 + x] = (0..8).map(|u| cosines[x][u] * coefficients[v * 8 + u] as f32).sum::<f32>() / 2.0;
+        }
+    }
+    for y in 0..8 {
+        for x in 0..8 {
+            let value = (0..8).map(|v| cosines[y][v] * rows[v * 8 + x]).sum::<f32>() / 2.0;
+            out[y * stride + x] = (value + 128.0).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a JPEG segment: the marker, then the payload with its length in front
+    fn segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0xFF, marker];
+        bytes.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    // DEFLATE bits, least significant first, with Huffman codes most significant first
+    #[derive(Default)]
+    struct DeflateWriter {
+        bytes: Vec<u8>,
+        count: u32,
+    }
+
+    impl DeflateWriter {
+        fn bits(&mut self, value: u32, n: u32) {
+            for i in 0..n {
+                if self.count.is_multiple_of(8) {
+                    self.bytes.push(0);
+                }
+                *self.bytes.last_mut().unwrap() |= ((value >> i & 1) as u8) << (self.count % 8);
+                self.count += 1;
+            }
+        }
+
+        fn code(&mut self, code: u32, length: u32) {
+            for i in (0..length).rev() {
+                self.bits(code >> i & 1, 1);
+            }
+        }
+    }
+
+    // a PNG of one gray pixel, with `image_data` as its IDAT
+    fn gray_pixel_png(image_data: &[u8]) -> Vec<u8> {
+        fn chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+            png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            let start = png.len();
+            png.extend_from_slice(kind);
+            png.extend_from_slice(data);
+            let crc = crc32(&png[start..]);
+            png.extend_from_slice(&crc.to_be_bytes());
+        }
+        let mut png = PNG_SIGNATURE.to_vec();
+        chunk(&mut png, b"IHDR", &[0, 0, 0, 1, 0, 0, 0, 1, 8, 0, 0, 0, 0]);
+        chunk(&mut png, b"IDAT", image_data);
+        chunk(&mut png, b"IEND", &[]);
+        png
+    }
+
+    #[test]
+    fn png_data_inflating_past_the_image_size_is_refused() {
+        // a fixed-code block of one literal, then copies of 258 bytes of it: 1 MB
+        // of output for a 2-byte image, refused within the block
+        let mut deflate = DeflateWriter::default();
+        deflate.bits(1, 1);
+        deflate.bits(1, 2);
+        deflate.code(0x30, 8); // literal 0
+        for _ in 0..4000 {
+            deflate.code(0xC5, 8); // length 258
+            deflate.code(0, 5); // distance 1
+        }
+        deflate.code(0, 7); // end of block
+        let mut zlib = vec![0x78, 0x01];
+        zlib.extend_from_slice(&deflate.bytes);
+        zlib.extend_from_slice(&[0; 4]);
+        let bomb = decode(ImageSource::Bytes(&gray_pixel_png(&zlib)));
+        assert!(matches!(bomb, Err(ImageError::Malformed("more image data than the header allows"))), "{:?}", bomb);
+        // which is only the size: with no limit, the stream inflates to its end
+        assert!(matches!(inflate(&zlib, usize::MAX), Err(ImageError::Malformed("zlib checksum mismatch"))));
+
+        // a stored block longer than the image
+        let mut zlib = vec![0x78, 0x01, 0x01, 100, 0, !100, 0xFF];
+        zlib.extend_from_slice(&[0; 104]);
+        let stored = decode(ImageSource::Bytes(&gray_pixel_png(&zlib)));
+        assert!(matches!(stored, Err(ImageError::Malformed("more image data than the header allows"))), "{:?}", stored);
+    }
+
+    #[test]
+    fn jpeg_dc_coefficients_too_large_decode_without_overflow() {
+        let blocks = 64;
+        let mut jpeg = vec![0xFF, 0xD8];
+        // a 16-bit quantization table of the largest values
+        let mut quant = vec![0x10];
+        quant.extend(std::iter::repeat_n(0xFF, 128));
+        jpeg.extend(segment(0xDB, &quant));
+        // one gray component, a row of `blocks` blocks
+        let width = (8 * blocks as u16).to_be_bytes();
+        jpeg.extend(segment(0xC0, &[8, 0, 8, width[0], width[1], 1, 1, 0x11, 0]));
+        // DC and AC tables with one code each, 0: for a DC difference of 11 bits,
+        // and for the end of the block
+        let mut counts = [0u8; 16];
+        counts[0] = 1;
+        for (class, symbol) in [(0x00, 11), (0x10, 0)] {
+            let mut table = vec![class];
+            table.extend_from_slice(&counts);
+            table.push(symbol);
+            jpeg.extend(segment(0xC4, &table));
+        }
+        jpeg.extend(segment(0xDA, &[1, 1, 0x00, 0, 63, 0]));
+        // every block raises the DC prediction by 2047
+        let mut bits: Vec<bool> = (0..blocks).flat_map(|_| [false].into_iter().chain([true; 11]).chain([false])).collect();
+        bits.resize(bits.len().div_ceil(8) * 8, true);
+        for byte in bits.chunks(8).map(|bits| bits.iter().fold(0u8, |byte, &bit| byte << 1 | bit as u8)) {
+            jpeg.push(byte);
+            if byte == 0xFF {
+                jpeg.push(0);
+            }
+        }
+        jpeg.extend_from_slice(&[0xFF, 0xD9]);
+
+        let image = decode(ImageSource::Bytes(&jpeg)).unwrap();
+        assert_eq!((image.width, image.height), (8 * blocks as u32, 8));
+        assert!(image.rgba.chunks(4).all(|pixel| pixel[..3] == [255, 255, 255]));
+    }
+}
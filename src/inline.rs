@@ -0,0 +1,955 @@
+use crate::bidi::{self, Direction};
+use crate::css::{Unit, Value};
+use crate::dom::NodeType;
+use crate::font::Font;
+use crate::layout::{AnonymousBlock, BlockNode, Dimensions, EdgeSizes, InlineBlockNode, InlineNode, LayoutBox, LayoutContext, Rect};
+use crate::style::StyledNode;
+use std::borrow::Cow;
+
+// a single row of inline content within a block container
+pub struct LineBox<'a> {
+    pub rect: Rect,
+    pub baseline: f32, // distance from the top of the line to its baseline
+    pub fragments: Vec<Fragment<'a>>,
+    // the union of the fragments' rects, kept so that what a line paints can be
+    // found without going through its text; `None` if it has no fragments
+    pub text_bounds: Option<Rect>
+}
+
+// a run of text placed on a line
+pub struct Fragment<'a> {
+    pub node: &'a StyledNode<'a>, // the styled text node the run came from
+    // borrowed from the text node, unless characters without glyphs were left out
+    pub text: Cow<'a, str>,
+    pub index: usize, // byte offset of `text` within the text node
+    pub font: Font,
+    pub rect: Rect
+}
+
+/// The part of an inline element on one line, as painted.
+///
+/// https://www.w3.org/TR/css-break-3/#break-decoration
+///
+/// An element split across lines has its border and padding sliced: the left edge
+/// only on its first fragment and the right edge only on its last. Neither takes up
+/// room on the line; they're painted around the text as it was laid out.
+#[derive(Clone, Copy, Debug)]
+pub struct InlineFragment {
+    pub border_box: Rect,
+    pub border: EdgeSizes, // widths of the borders this fragment paints
+    pub padding: EdgeSizes, // and of its padding, sliced the same way
+}
+
+// inline content flattened into layout order, with whitespace already split out
+// text items carry the alignment of the inline element they're in
+enum InlineItem<'a> {
+    // a word, or a piece of one; a word right after another, with no space between
+    // them, is glued to it unless there's a break opportunity between the two
+    Word(&'a StyledNode<'a>, &'a str, usize, VerticalAlign),
+    Space(&'a StyledNode<'a>),
+    // a soft hyphen inside a word, at this offset in its text node: the line may
+    // break there, and ends in a hyphen if it does
+    SoftHyphen(&'a StyledNode<'a>, usize, VerticalAlign),
+    // a `<wbr>`, where the line may break with nothing shown
+    BreakOpportunity,
+    // text whose spaces and tabs are kept as written, with no break opportunities inside
+    Preserved(&'a StyledNode<'a>, &'a str, usize, VerticalAlign),
+    // a preserved newline, which always ends the line
+    ForcedBreak(&'a StyledNode<'a>),
+    // an inline-block, placed as a single unit with the given margin-box size and
+    // the distance from its top to its baseline
+    Atomic(&'a StyledNode<'a>, f32, f32, f32)
+}
+
+// the values of `white-space` we distinguish
+#[derive(Clone, Copy, PartialEq)]
+enum WhiteSpace {
+    Normal,
+    Nowrap,
+    Pre,
+    PreWrap,
+    PreLine
+}
+
+impl WhiteSpace {
+    fn of(style: &StyledNode) -> WhiteSpace {
+        match style.value("white-space") {
+            Some(Value::Keyword(k)) => match &*k {
+                "nowrap" => WhiteSpace::Nowrap,
+                "pre" => WhiteSpace::Pre,
+                "pre-wrap" => WhiteSpace::PreWrap,
+                "pre-line" => WhiteSpace::PreLine,
+                _ => WhiteSpace::Normal
+            },
+            _ => WhiteSpace::Normal
+        }
+    }
+
+    // may lines break between words?
+    fn wraps(self) -> bool {
+        !matches!(self, WhiteSpace::Nowrap | WhiteSpace::Pre)
+    }
+}
+
+// whether a node is text of nothing but white space that collapses, which only
+// separates the words around it on a line
+pub(crate) fn is_collapsible_whitespace(style: &StyledNode) -> bool {
+    match style.node.node_type {
+        NodeType::Text(ref text) => {
+            text.chars().all(char::is_whitespace) && matches!(WhiteSpace::of(style), WhiteSpace::Normal | WhiteSpace::Nowrap)
+        }
+        _ => false
+    }
+}
+
+/// How a block container's lines are aligned within it, from `text-align`.
+///
+/// https://www.w3.org/TR/css-text-3/#text-align-property
+///
+/// `justify` isn't supported and aligns lines like `start`.
+#[derive(Clone, Copy, Default, PartialEq)]
+pub(crate) enum TextAlign {
+    #[default]
+    Left,
+    Right,
+    Center
+}
+
+impl TextAlign {
+    pub(crate) fn of(style: &StyledNode) -> TextAlign {
+        let rtl = Direction::of(style) == Direction::Rtl;
+        match style.value("text-align") {
+            Some(Value::Keyword(k)) => match (&*k, rtl) {
+                ("right", _) | ("end", false) | ("start" | "justify", true) => TextAlign::Right,
+                ("center", _) => TextAlign::Center,
+                _ => TextAlign::Left
+            },
+            _ => TextAlign::Left
+        }
+    }
+}
+
+/// How an inline-level box is placed within its line, from `vertical-align`.
+///
+/// https://www.w3.org/TR/CSS2/visudet.html#propdef-vertical-align
+///
+/// Boxes aligned by their baseline, or relative to it, decide where the line's
+/// baseline goes and so how tall the line is; `top` and `bottom` boxes are then
+/// placed against its edges, and only make it taller if they don't fit.
+#[derive(Clone, Copy, Default, PartialEq)]
+enum VerticalAlign {
+    #[default]
+    Baseline,
+    Top,
+    Middle, // centered on the parent's baseline, raised by half an x-height
+    Bottom,
+    Raise(f32) // the baseline raised by a length in px; percentages are of the line height
+}
+
+impl VerticalAlign {
+    fn of(style: &StyledNode, ctx: &LayoutContext) -> VerticalAlign {
+        match style.value("vertical-align") {
+            Some(Value::Keyword(k)) => match &*k {
+                "top" => VerticalAlign::Top,
+                "middle" => VerticalAlign::Middle,
+                "bottom" => VerticalAlign::Bottom,
+                _ => VerticalAlign::Baseline
+            },
+            Some(value @ (Value::Length(..) | Value::Number(_))) => {
+                VerticalAlign::Raise(value.to_px_of(ctx.measurer.line_height(&Font::from_style(style))))
+            }
+            _ => VerticalAlign::Baseline
+        }
+    }
+
+    // the distance from the top of a box `height` tall, whose own baseline is
+    // `ascent` below its top, up from the line's baseline; `None` for boxes placed
+    // against the line's edges instead
+    fn top_above_baseline(self, height: f32, ascent: f32, x_height: f32) -> Option<f32> {
+        match self {
+            VerticalAlign::Baseline => Some(ascent),
+            VerticalAlign::Raise(offset) => Some(ascent + offset),
+            VerticalAlign::Middle => Some((height + x_height) / 2.0),
+            VerticalAlign::Top | VerticalAlign::Bottom => None
+        }
+    }
+}
+
+// the baseline of an inline-block, below the top of its margin box: that of its
+// last line box, or its bottom margin edge if it has none or clips its overflow
+//
+// https://www.w3.org/TR/CSS2/visudet.html#leading
+fn inline_block_baseline(atomic: &LayoutBox) -> f32 {
+    let margin_box = atomic.dimensions.margin_box();
+    let visible = Value::Keyword("visible".to_string());
+    let clips = atomic.style_node().is_some_and(|style| style.lookup("overflow-y", "overflow", &visible) != visible);
+    match last_line_baseline(atomic) {
+        Some(baseline) if !clips => baseline - margin_box.y,
+        _ => margin_box.height
+    }
+}
+
+fn last_line_baseline(layout_box: &LayoutBox) -> Option<f32> {
+    if let Some(line) = layout_box.lines.last() {
+        return Some(line.rect.y + line.baseline);
+    }
+    layout_box.children.iter().rev()
+        .filter(|child| matches!(child.box_type, BlockNode(_) | AnonymousBlock))
+        .find_map(last_line_baseline)
+}
+
+/// Where lines may break inside a word, from `word-break` and `overflow-wrap`.
+///
+/// https://www.w3.org/TR/css-text-3/#word-break-property
+/// https://www.w3.org/TR/css-text-3/#overflow-wrap-property
+#[derive(Clone, Copy, PartialEq)]
+enum WordBreaking {
+    Normal, // only between words
+    BreakAll, // between any two characters, `word-break: break-all`
+    Anywhere, // inside a word only if it overflows a line on its own
+}
+
+impl WordBreaking {
+    // `overflow-wrap: break-word` breaks words as `anywhere` does, except that the
+    // breaks don't count when sizing content to its min-content width
+    fn of(style: &StyledNode, min_content: bool) -> WordBreaking {
+        let keyword = |value: Option<Value>| match value {
+            Some(Value::Keyword(k)) => k,
+            _ => String::new()
+        };
+        // `word-wrap` is the legacy name of `overflow-wrap`
+        let overflow_wrap = keyword(style.value("overflow-wrap").or_else(|| style.value("word-wrap")));
+        match (&*keyword(style.value("word-break")), &*overflow_wrap) {
+            ("break-all", _) => WordBreaking::BreakAll,
+            // the legacy `word-break: break-word` means `overflow-wrap: anywhere`
+            ("break-word", _) | (_, "anywhere") => WordBreaking::Anywhere,
+            (_, "break-word") if !min_content => WordBreaking::Anywhere,
+            _ => WordBreaking::Normal
+        }
+    }
+}
+
+// the places a word can be broken and the width of the text before each, as running
+// totals of the characters' advances so no piece needs measuring again; a
+// combining mark or a character joined on stays with the character before it
+fn word_break_points(text: &str, font: &Font, ctx: &LayoutContext) -> Vec<(usize, f32)> {
+    let mut points = Vec::new();
+    let mut width = 0.0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        width += ctx.measurer.measure(c.encode_utf8(&mut [0; 4]), font);
+        let joined = c == '\u{200D}' || chars.peek().is_some_and(|&(_, next)| is_combining(next));
+        if !joined {
+            points.push((i + c.len_utf8(), width));
+        }
+    }
+    points
+}
+
+// combining diacritical marks, variation selectors and the zero-width joiner, which
+// are part of the same grapheme as the character before them
+pub(crate) fn is_combining(c: char) -> bool {
+    matches!(c, '\u{300}'..='\u{36F}' | '\u{1AB0}'..='\u{1AFF}' | '\u{1DC0}'..='\u{1DFF}' | '\u{20D0}'..='\u{20FF}'
+        | '\u{FE00}'..='\u{FE0F}' | '\u{FE20}'..='\u{FE2F}' | '\u{200D}')
+}
+
+// the width of a tab stop: `tab-size` counts advances of the space glyph, or is a length
+fn tab_width(style: &StyledNode, font: &Font, ctx: &LayoutContext) -> f32 {
+    match style.value("tab-size") {
+        Some(Value::Number(n)) => n.max(0.0) * ctx.measurer.measure(" ", font),
+        Some(Value::Length(px, Unit::Px)) => px.max(0.0),
+        _ => 8.0 * ctx.measurer.measure(" ", font)
+    }
+}
+
+// control characters other than the whitespace handled by line breaking have no
+// glyphs; text with none of them, as nearly all is, is borrowed rather than copied
+fn visible_text(text: &str) -> Cow<'_, str> {
+    let hidden = |c: char| c.is_control() || c == SOFT_HYPHEN;
+    match text.contains(hidden) {
+        true => Cow::Owned(text.chars().filter(|&c| !hidden(c)).collect()),
+        false => Cow::Borrowed(text)
+    }
+}
+
+// shows only where a line breaks at it, as a hyphen
+const SOFT_HYPHEN: char = '\u{AD}';
+
+impl<'a> LayoutBox<'a> {
+    /// Lay out a box's inline-level children into line boxes.
+    ///
+    /// https://www.w3.org/TR/CSS2/visuren.html#inline-formatting
+    ///
+    /// The box fills the width of its containing block and stacks below any previous
+    /// siblings. `text_indent` offsets the start of the first line only, and each line
+    /// is aligned within the box as `align` says, then has its text put in visual
+    /// order for a block whose base direction is `direction`. Each line is at least
+    /// as tall as text in the `strut` font, the font of the block the lines belong to.
+    pub(crate) fn layout_inline_container(
+        &mut self,
+        containing_block: Dimensions,
+        text_indent: f32,
+        align: TextAlign,
+        direction: Direction,
+        strut: Option<Font>,
+        ctx: &LayoutContext
+    ) {
+        let d = &mut self.dimensions;
+        d.content.x = containing_block.content.x;
+        d.content.y = containing_block.content.y + containing_block.content.height;
+        d.content.width = containing_block.content.width;
+
+        // inline-blocks are laid out first, so their sizes are known when breaking lines
+        let mut available = *d;
+        available.content.height = 0.0;
+        layout_atomic_inlines(&mut self.children, available, ctx);
+
+        let mut items = Vec::new();
+        collect_items(&self.children, &mut items, VerticalAlign::Baseline, &|atomic| {
+            let margin_box = atomic.dimensions.margin_box();
+            (margin_box.width, margin_box.height)
+        }, ctx);
+        let (mut lines, placements) = break_lines(&items, self.dimensions.content, text_indent, align, strut, false, ctx);
+        for line in &mut lines {
+            bidi::reorder_line(line, direction, &placements, ctx);
+        }
+        self.lines = lines;
+        self.dimensions.content.height = self.lines.iter().map(|line| line.rect.height).sum();
+        place_atomic_inlines(&mut self.children, &mut placements.into_iter());
+        fragment_inline_elements(&mut self.children, &self.lines, self.dimensions.content.width);
+    }
+}
+
+impl LayoutBox<'_> {
+    /// Cut each line that overflows this box's content area short and end it in "…",
+    /// for `text-overflow: ellipsis`.
+    ///
+    /// Text is removed a character at a time, with any combining marks on it, until
+    /// it and the ellipsis, measured in the font of the text it follows, fit. If the
+    /// ellipsis alone doesn't fit, the line keeps only the characters that fit
+    /// entirely and gets no ellipsis.
+    pub(crate) fn truncate_lines_with_ellipsis(&mut self, ctx: &LayoutContext) {
+        let limit = self.dimensions.content.x + self.dimensions.content.width;
+        for line in &mut self.lines {
+            truncate_line(line, limit, ctx);
+        }
+    }
+}
+
+const ELLIPSIS: &str = "\u{2026}";
+
+fn truncate_line(line: &mut LineBox, limit: f32, ctx: &LayoutContext) {
+    let overflows = |f: &Fragment| f.rect.x + f.rect.width > limit;
+    let first = match line.fragments.iter().position(overflows) {
+        Some(first) => first,
+        None => return
+    };
+    let (node, font) = (line.fragments[first].node, line.fragments[first].font);
+    let height = ctx.measurer.line_height(&font);
+    let ellipsis_width = ctx.measurer.measure(ELLIPSIS, &font);
+
+    // drop whole fragments until the ellipsis fits after what's left
+    let mut end = limit - ellipsis_width;
+    let with_ellipsis = end >= line.rect.x;
+    if !with_ellipsis {
+        end = limit;
+    }
+    line.fragments.truncate(first + 1);
+    while let Some(last) = line.fragments.last_mut() {
+        if last.rect.x + last.rect.width <= end {
+            break;
+        }
+        // trim the fragment to the longest prefix that fits, found among running
+        // totals of the advances so that no prefix is measured over again
+        let points = word_break_points(&last.text, &last.font, ctx);
+        let cut = match points.partition_point(|&(_, width)| last.rect.x + width <= end) {
+            0 => 0,
+            n => points[n - 1].0
+        };
+        if cut == 0 {
+            line.fragments.pop();
+            continue;
+        }
+        match last.text {
+            Cow::Borrowed(text) => last.text = Cow::Borrowed(&text[..cut]),
+            Cow::Owned(ref mut text) => text.truncate(cut)
+        }
+        last.rect.width = ctx.measurer.measure(&last.text, &last.font);
+    }
+
+    if with_ellipsis {
+        let (node, x, index) = match line.fragments.last() {
+            Some(last) => (last.node, last.rect.x + last.rect.width, last.index + last.text.len()),
+            None => (node, line.rect.x, 0)
+        };
+        // on the line's baseline, like text that isn't otherwise aligned
+        let y = line.rect.y + line.baseline - font.baseline(0.0, height);
+        line.fragments.push(Fragment {
+            node,
+            text: Cow::Borrowed(ELLIPSIS),
+            index,
+            font,
+            rect: Rect { x, y, width: ellipsis_width, height }
+        });
+    }
+    line.text_bounds = text_bounds(&line.fragments);
+}
+
+// the union of the rects of `fragments`
+pub(crate) fn text_bounds(fragments: &[Fragment]) -> Option<Rect> {
+    fragments.iter().map(|fragment| fragment.rect).reduce(Rect::union)
+}
+
+/// The width of a run of inline boxes laid out on lines that never wrap.
+pub(crate) fn max_content_width(boxes: &[LayoutBox], ctx: &LayoutContext) -> f32 {
+    widest_line(boxes, f32::INFINITY, &|atomic| (atomic.max_content_contribution(ctx), 0.0), ctx)
+}
+
+/// The width of a run of inline boxes laid out on lines that wrap wherever they can.
+pub(crate) fn min_content_width(boxes: &[LayoutBox], ctx: &LayoutContext) -> f32 {
+    widest_line(boxes, 0.0, &|atomic| (atomic.min_content_contribution(ctx), 0.0), ctx)
+}
+
+// the width of the widest line when `boxes` are broken into lines `width` wide
+fn widest_line<'a>(boxes: &[LayoutBox<'a>], width: f32, atomic_size: &dyn Fn(&LayoutBox<'a>) -> (f32, f32), ctx: &LayoutContext) -> f32 {
+    let mut items = Vec::new();
+    collect_items(boxes, &mut items, VerticalAlign::Baseline, atomic_size, ctx);
+    let area = Rect { width, ..Default::default() };
+    // lines are only ever 0 wide to find the min-content width
+    let (lines, placements) = break_lines(&items, area, 0.0, TextAlign::Left, None, width == 0.0, ctx);
+    let text_width = lines.iter()
+        .flat_map(|line| line.fragments.iter().map(|f| f.rect.x + f.rect.width))
+        .fold(0.0, f32::max);
+    placements.iter().map(|rect| rect.x + rect.width).fold(text_width, f32::max)
+}
+
+// lay out every inline-block among `boxes` and their inline descendants
+fn layout_atomic_inlines(boxes: &mut [LayoutBox], containing_block: Dimensions, ctx: &LayoutContext) {
+    for layout_box in boxes {
+        match layout_box.box_type {
+            InlineBlockNode(_) => layout_box.layout_inline_block(containing_block, ctx),
+            InlineNode(_) => layout_atomic_inlines(&mut layout_box.children, containing_block, ctx),
+            BlockNode(_) | AnonymousBlock => {}
+        }
+    }
+}
+
+// move each inline-block to the margin-box position `break_lines` chose for it,
+// visiting them in the same order as `collect_items`
+fn place_atomic_inlines(boxes: &mut [LayoutBox], placements: &mut impl Iterator<Item = Rect>) {
+    for layout_box in boxes {
+        match layout_box.box_type {
+            InlineBlockNode(_) => {
+                if let Some(rect) = placements.next() {
+                    let margin_box = layout_box.dimensions.margin_box();
+                    layout_box.translate(rect.x - margin_box.x, rect.y - margin_box.y);
+                }
+            }
+            InlineNode(_) => place_atomic_inlines(&mut layout_box.children, placements),
+            BlockNode(_) | AnonymousBlock => {}
+        }
+    }
+}
+
+// give each inline element among `boxes` a fragment on every line its content is on
+fn fragment_inline_elements(boxes: &mut [LayoutBox], lines: &[LineBox], containing_width: f32) {
+    for layout_box in boxes {
+        let style = match layout_box.box_type {
+            InlineNode(style) if matches!(style.node.node_type, NodeType::Element(_)) => style,
+            _ => continue
+        };
+        fragment_inline_elements(&mut layout_box.children, lines, containing_width);
+
+        let mut text_nodes = Vec::new();
+        let mut atomics = Vec::new();
+        collect_inline_content(&layout_box.children, &mut text_nodes, &mut atomics);
+        let content_rects: Vec<Rect> = lines.iter().filter_map(|line| {
+            let on_line = |rect: &&Rect| rect.y >= line.rect.y && rect.y < line.rect.y + line.rect.height;
+            line.fragments.iter()
+                .filter(|f| text_nodes.iter().any(|&node| node.is_same_text(f.node)))
+                .map(|f| f.rect)
+                .chain(atomics.iter().filter(on_line).copied())
+                .reduce(Rect::union)
+        }).collect();
+
+        // percentages refer to the width of the containing block, even vertically
+        let zero = Value::Length(0.0, Unit::Px);
+        // neither can be negative, though the grammar doesn't rule it out
+        let edge = |name: &str, shorthand: &str| style.lookup(name, shorthand, &zero).to_px_of(containing_width).max(0.0);
+        let padding = EdgeSizes {
+            left: edge("padding-left", "padding"),
+            right: edge("padding-right", "padding"),
+            top: edge("padding-top", "padding"),
+            bottom: edge("padding-bottom", "padding"),
+        };
+        let border = EdgeSizes {
+            left: edge("border-left-width", "border-width"),
+            right: edge("border-right-width", "border-width"),
+            top: edge("border-top-width", "border-width"),
+            bottom: edge("border-bottom-width", "border-width"),
+        };
+        let last = content_rects.len().saturating_sub(1);
+        let (continued_before, continued_after) = (layout_box.continued_before, layout_box.continued_after);
+        layout_box.inline_fragments = content_rects.into_iter().enumerate().map(|(i, rect)| {
+            let slice = |value: f32, kept: bool| if kept { value } else { 0.0 };
+            let (starts, ends) = (i == 0 && !continued_before, i == last && !continued_after);
+            let border = EdgeSizes { left: slice(border.left, starts), right: slice(border.right, ends), ..border };
+            let padding = EdgeSizes { left: slice(padding.left, starts), right: slice(padding.right, ends), ..padding };
+            InlineFragment { border_box: rect.expanded_by(padding).expanded_by(border), border, padding }
+        }).collect();
+    }
+}
+
+// the text nodes and inline-block margin boxes inside an inline element
+fn collect_inline_content<'a>(boxes: &[LayoutBox<'a>], text_nodes: &mut Vec<&'a StyledNode<'a>>, atomics: &mut Vec<Rect>) {
+    for layout_box in boxes {
+        match layout_box.box_type {
+            InlineNode(style) => match style.node.node_type {
+                NodeType::Text(_) => text_nodes.push(style),
+                _ => collect_inline_content(&layout_box.children, text_nodes, atomics)
+            },
+            InlineBlockNode(_) => atomics.push(layout_box.dimensions.margin_box()),
+            BlockNode(_) | AnonymousBlock => {}
+        }
+    }
+}
+
+// flatten the text of an inline subtree into words and collapsible spaces, and
+// inline-blocks into atomic items sized by `atomic_size`
+//
+// Text is aligned as the nearest inline element around it with a `vertical-align`
+// other than baseline says, which `align` is for the boxes given. Nested aligned
+// elements don't add up: the innermost one wins.
+fn collect_items<'a>(
+    boxes: &[LayoutBox<'a>],
+    items: &mut Vec<InlineItem<'a>>,
+    align: VerticalAlign,
+    atomic_size: &dyn Fn(&LayoutBox<'a>) -> (f32, f32),
+    ctx: &LayoutContext
+) {
+    for layout_box in boxes {
+        match layout_box.box_type {
+            InlineNode(style) => match style.node.node_type {
+                NodeType::Text(ref text) => match WhiteSpace::of(style) {
+                    WhiteSpace::Pre | WhiteSpace::PreWrap => collect_preserved(style, text, align, items),
+                    white_space => collect_words(style, text, white_space == WhiteSpace::PreLine, align, items)
+                },
+                NodeType::Element(ref elem) if elem.tag_name.eq_ignore_ascii_case("wbr") => {
+                    items.push(InlineItem::BreakOpportunity);
+                }
+                NodeType::Element(_) => {
+                    let align = match VerticalAlign::of(style, ctx) {
+                        VerticalAlign::Baseline => align,
+                        own => own
+                    };
+                    collect_items(&layout_box.children, items, align, atomic_size, ctx)
+                }
+                NodeType::Comment(_) => {}
+            },
+            InlineBlockNode(style) => {
+                let (width, height) = atomic_size(layout_box);
+                items.push(InlineItem::Atomic(style, width, height, inline_block_baseline(layout_box)));
+            }
+            // `build_layout_tree` splits inline boxes around block-level ones
+            BlockNode(_) | AnonymousBlock => {}
+        }
+    }
+}
+
+// split collapsible text into words; newlines are forced breaks only under `pre-line`
+fn collect_words<'a>(style: &'a StyledNode<'a>, text: &'a str, keep_newlines: bool, align: VerticalAlign, items: &mut Vec<InlineItem<'a>>) {
+    let mut word_start = None;
+    // the first word of the text that has the block's first letter is split after it
+    let mut first_letter = style.first_letter.as_deref();
+    let mut push_word = |start: usize, end: usize, items: &mut Vec<InlineItem<'a>>| {
+        let word = &text[start..end];
+        let letter = match first_letter.take() {
+            Some(letter_style) => first_letter_length(word).map(|length| (letter_style, length)),
+            None => None
+        };
+        match letter {
+            Some((letter_style, length)) => {
+                items.push(InlineItem::Word(letter_style, &word[..length], start, align));
+                if length < word.len() {
+                    items.push(InlineItem::Word(style, &word[length..], start + length, align));
+                }
+            }
+            None => items.push(InlineItem::Word(style, word, start, align))
+        }
+    };
+    // the pieces of a word between its soft hyphens, which may each end a line
+    let mut push_pieces = |start: usize, end: usize, items: &mut Vec<InlineItem<'a>>| {
+        let mut piece_start = start;
+        for (i, _) in text[start..end].match_indices(SOFT_HYPHEN) {
+            let at = start + i;
+            if at > piece_start {
+                push_word(piece_start, at, items);
+            }
+            // a soft hyphen starting the word has nothing before it to hyphenate
+            if at > start {
+                items.push(InlineItem::SoftHyphen(style, at, align));
+            }
+            piece_start = at + SOFT_HYPHEN.len_utf8();
+        }
+        if end > piece_start {
+            push_word(piece_start, end, items);
+        }
+    };
+    for (i, c) in text.char_indices() {
+        if c.is_whitespace() {
+            if let Some(start) = word_start.take() {
+                push_pieces(start, i, items);
+            }
+            match c {
+                '\n' if keep_newlines => items.push(InlineItem::ForcedBreak(style)),
+                '\r' => {} // normalized away, so CRLF is a single newline
+                _ => items.push(InlineItem::Space(style))
+            }
+        } else if word_start.is_none() {
+            word_start = Some(i);
+        }
+    }
+    if let Some(start) = word_start {
+        push_pieces(start, text.len(), items);
+    }
+}
+
+// the length of the `::first-letter` at the start of `word`: any punctuation before
+// it, its first letter or digit with the combining marks that follow, and any
+// punctuation right after it; `None` if the word is all punctuation
+//
+// https://www.w3.org/TR/css-pseudo-4/#first-letter-pattern
+fn first_letter_length(word: &str) -> Option<usize> {
+    let is_punctuation = |c: char| c.is_ascii_punctuation() || matches!(c, '\u{2018}'..='\u{201F}' | '\u{AB}' | '\u{BB}');
+    let mut chars = word.char_indices().skip_while(|&(_, c)| is_punctuation(c));
+    let (_, letter) = chars.next()?;
+    let end = chars.find(|&(_, c)| !is_combining(c) && !is_punctuation(c)).map_or(word.len(), |(i, _)| i);
+    letter.is_alphanumeric().then_some(end)
+}
+
+// split preformatted text into lines at each newline
+fn collect_preserved<'a>(style: &'a StyledNode<'a>, text: &'a str, align: VerticalAlign, items: &mut Vec<InlineItem<'a>>) {
+    let mut start = 0;
+    for (i, line) in text.split('\n').enumerate() {
+        if i > 0 {
+            items.push(InlineItem::ForcedBreak(style));
+        }
+        if !line.is_empty() {
+            items.push(InlineItem::Preserved(style, line, start, align));
+        }
+        start += line.len() + 1;
+    }
+}
+
+// the contents of the line being filled, each with its alignment
+#[derive(Default)]
+struct PendingLine<'a> {
+    fragments: Vec<(Fragment<'a>, VerticalAlign)>,
+    atomics: Vec<PendingAtomic>
+}
+
+struct PendingAtomic {
+    index: usize, // into the placements
+    rect: Rect, // margin box
+    ascent: f32, // from the top of the margin box to the baseline
+    align: VerticalAlign
+}
+
+impl PendingLine<'_> {
+    fn is_empty(&self) -> bool {
+        self.fragments.is_empty() && self.atomics.is_empty()
+    }
+}
+
+// greedily fill lines with as many words as fit within `area`; also returns the
+// margin box chosen for each atomic item, in order
+fn break_lines<'a>(
+    items: &[InlineItem<'a>],
+    area: Rect,
+    text_indent: f32,
+    align: TextAlign,
+    strut: Option<Font>,
+    min_content: bool, // sizing the content as narrow as it goes, rather than laying it out
+    ctx: &LayoutContext
+) -> (Vec<LineBox<'a>>, Vec<Rect>) {
+    let mut lines = Vec::new();
+    let mut placements = Vec::new();
+    let mut line = PendingLine::default();
+    let mut x = text_indent;
+    let mut y = area.y;
+    // width of the collapsed space waiting to be placed before the next word
+    let mut pending_space: Option<f32> = None;
+    // whether the last item was a word, which glues the next word to it unless
+    // there's a break opportunity between them, and the soft hyphen there if any
+    let mut after_word = false;
+    let mut opportunity: Option<Option<(&StyledNode, usize, VerticalAlign)>> = None;
+
+    for (i, item) in items.iter().enumerate() {
+        let breakable = !after_word || opportunity.is_some();
+        let soft_hyphen = opportunity.take().flatten();
+        after_word = matches!(item, InlineItem::Word(..));
+        match *item {
+            InlineItem::SoftHyphen(style, index, vertical_align) => {
+                after_word = true;
+                opportunity = Some(Some((style, index, vertical_align)));
+            }
+            InlineItem::BreakOpportunity => {
+                after_word = true;
+                opportunity = Some(None);
+            }
+            InlineItem::Space(style) => {
+                let style = line_style(style, lines.is_empty());
+                // spaces collapse, and are dropped at the start of a line
+                if !line.is_empty() && pending_space.is_none() {
+                    pending_space = Some(ctx.measurer.measure(" ", &Font::from_style(style)));
+                }
+            }
+            InlineItem::Word(style, word, index, vertical_align) => {
+                let mut space = pending_space.take().unwrap_or(0.0);
+                let wraps = WhiteSpace::of(style).wraps() && breakable;
+                // a piece of a word that may end the line at the soft hyphen after it
+                // only fits if the hyphen does too
+                let hyphen_after = |first_line: bool| match items.get(i + 1) {
+                    Some(&InlineItem::SoftHyphen(hyphen_style, ..)) => {
+                        ctx.measurer.measure("-", &Font::from_style(line_style(hyphen_style, first_line)))
+                    }
+                    _ => 0.0
+                };
+                // ending the line at the soft hyphen before this piece leaves a hyphen there
+                let hyphenate = |line: &mut PendingLine<'a>, x: f32, first_line: bool| {
+                    if let Some((hyphen_style, hyphen_index, hyphen_align)) = soft_hyphen {
+                        let font = Font::from_style(line_style(hyphen_style, first_line));
+                        line.fragments.push((Fragment {
+                            node: hyphen_style,
+                            text: Cow::Borrowed("-"),
+                            index: hyphen_index,
+                            font,
+                            rect: Rect { x: area.x + x, y: 0.0, width: ctx.measurer.measure("-", &font), height: ctx.measurer.line_height(&font) }
+                        }, hyphen_align));
+                    }
+                };
+                // a word that doesn't fit on the first line, styled as the first line is,
+                // starts the next line styled as itself
+                let text = visible_text(word);
+                if let Some(first_line_style) = style.first_line.as_deref().filter(|_| lines.is_empty()) {
+                    let width = ctx.measurer.measure(&text, &Font::from_style(first_line_style));
+                    if wraps && !line.is_empty() && x + space + width + hyphen_after(true) > area.width {
+                        hyphenate(&mut line, x, true);
+                        let finished = finish_line(std::mem::take(&mut line), area, y, strut, align, &mut placements, ctx);
+                        y += finished.rect.height;
+                        lines.push(finished);
+                        x = 0.0;
+                        space = 0.0;
+                    }
+                }
+                let style = line_style(style, lines.is_empty());
+                let font = Font::from_style(style);
+                let width = ctx.measurer.measure(&text, &font);
+                let breaking = WordBreaking::of(style, min_content);
+                // a piece of the word, borrowed from its text node if the word is
+                let piece = |start: usize, end: usize| match text {
+                    Cow::Borrowed(text) => Cow::Borrowed(&text[start..end]),
+                    Cow::Owned(ref text) => Cow::Owned(text[start..end].to_string())
+                };
+                let fragment = |text: Cow<'a, str>, start: usize, x: f32, width: f32| (Fragment {
+                    node: style,
+                    text,
+                    index: index + start,
+                    font,
+                    rect: Rect { x: area.x + x, y: 0.0, width, height: ctx.measurer.line_height(&font) }
+                }, vertical_align);
+
+                // wrap unless this word is alone on the line, in which case it overflows,
+                // or is broken into pieces as `word-break` and `overflow-wrap` allow;
+                // `start` and `placed` are the length and width of the pieces placed so far
+                let (mut start, mut placed) = (0, 0.0);
+                let mut break_points = None;
+                let hyphen_width = hyphen_after(lines.is_empty());
+                while wraps && x + space + width + hyphen_width - placed > area.width {
+                    let may_break = match breaking {
+                        WordBreaking::Normal => false,
+                        WordBreaking::BreakAll => true,
+                        WordBreaking::Anywhere => line.is_empty()
+                    };
+                    if may_break {
+                        let points: &Vec<(usize, f32)> = break_points.get_or_insert_with(|| word_break_points(&text, &font, ctx));
+                        // the furthest break that leaves the piece before it room on the line,
+                        // or on an empty line the first, so every line takes something
+                        let first = points.partition_point(|&(end, _)| end <= start);
+                        let room = area.width - x - space;
+                        let fitting = points[first..].partition_point(|&(_, end_width)| end_width - placed <= room);
+                        let point = match fitting {
+                            0 if line.is_empty() => points.get(first),
+                            0 => None,
+                            n => points.get(first + n - 1)
+                        };
+                        if let Some(&(end, end_width)) = point.filter(|&&(end, _)| end < text.len()) {
+                            line.fragments.push(fragment(piece(start, end), start, x + space, end_width - placed));
+                            (start, placed) = (end, end_width);
+                        }
+                    }
+                    if line.is_empty() {
+                        break;
+                    }
+                    if start == 0 {
+                        hyphenate(&mut line, x, lines.is_empty());
+                    }
+                    let finished = finish_line(std::mem::take(&mut line), area, y, strut, align, &mut placements, ctx);
+                    y += finished.rect.height;
+                    lines.push(finished);
+                    x = 0.0;
+                    space = 0.0;
+                }
+
+                x += space;
+                line.fragments.push(fragment(piece(start, text.len()), start, x, width - placed));
+                x += width - placed;
+            }
+            InlineItem::Atomic(style, width, height, ascent) => {
+                // an inline-block wraps like a word, as a single unit
+                let mut space = pending_space.take().unwrap_or(0.0);
+                if !line.is_empty() && x + space + width > area.width && WhiteSpace::of(style).wraps() {
+                    let finished = finish_line(std::mem::take(&mut line), area, y, strut, align, &mut placements, ctx);
+                    y += finished.rect.height;
+                    lines.push(finished);
+                    x = 0.0;
+                    space = 0.0;
+                }
+                x += space;
+                let index = placements.len();
+                placements.push(Rect::default());
+                line.atomics.push(PendingAtomic {
+                    index,
+                    rect: Rect { x: area.x + x, y: 0.0, width, height },
+                    ascent,
+                    align: VerticalAlign::of(style, ctx)
+                });
+                x += width;
+            }
+            InlineItem::Preserved(style, text, index, vertical_align) => {
+                let style = line_style(style, lines.is_empty());
+                // each tab advances to the next multiple of the tab width from the line start
+                let font = Font::from_style(style);
+                let tab = tab_width(style, &font, ctx);
+                x += pending_space.take().unwrap_or(0.0);
+                let mut start = index;
+                for (i, piece) in text.split('\t').enumerate() {
+                    if i > 0 && tab > 0.0 {
+                        x = ((x / tab).floor() + 1.0) * tab;
+                    }
+                    let text = visible_text(piece);
+                    if !text.is_empty() {
+                        let width = ctx.measurer.measure(&text, &font);
+                        line.fragments.push((Fragment {
+                            node: style,
+                            text,
+                            index: start,
+                            font,
+                            rect: Rect { x: area.x + x, y: 0.0, width, height: ctx.measurer.line_height(&font) }
+                        }, vertical_align));
+                        x += width;
+                    }
+                    start += piece.len() + 1;
+                }
+            }
+            InlineItem::ForcedBreak(style) => {
+                // an empty line still takes up the height of a line of text
+                let strut = strut.or(Some(Font::from_style(style)));
+                let finished = finish_line(std::mem::take(&mut line), area, y, strut, align, &mut placements, ctx);
+                y += finished.rect.height;
+                lines.push(finished);
+                x = 0.0;
+                pending_space = None;
+            }
+        }
+    }
+    if !line.is_empty() {
+        lines.push(finish_line(line, area, y, strut, align, &mut placements, ctx));
+    }
+    (lines, placements)
+}
+
+// text on the first line of its block is styled by the block's `::first-line`
+fn line_style<'a>(style: &'a StyledNode<'a>, first_line: bool) -> &'a StyledNode<'a> {
+    match first_line {
+        true => style.first_line.as_deref().unwrap_or(style),
+        false => style
+    }
+}
+
+// size a line to fit its fragments and inline-blocks, placed as their
+// `vertical-align` says, and a strut of text in the `strut` font on its baseline;
+// a line narrower than `area` is then moved across as `align` says
+//
+// https://www.w3.org/TR/CSS2/visudet.html#line-height
+fn finish_line<'a>(
+    line: PendingLine<'a>,
+    area: Rect,
+    y: f32,
+    strut: Option<Font>,
+    align: TextAlign,
+    placements: &mut [Rect],
+    ctx: &LayoutContext
+) -> LineBox<'a> {
+    let PendingLine { fragments, atomics } = line;
+    // `middle` is relative to the x-height of the parent, taken as half its font size
+    let x_height = strut.map_or(0.0, |font| font.size / 2.0);
+    let text_box = |font: &Font, height: f32| (height, font.baseline(0.0, height));
+    let strut_box = strut.map(|font| (text_box(&font, ctx.measurer.line_height(&font)), VerticalAlign::Baseline));
+    // the height of each box, how far its baseline is below its top, and its alignment
+    let boxes: Vec<((f32, f32), VerticalAlign)> = strut_box.into_iter()
+        .chain(fragments.iter().map(|(f, vertical_align)| (text_box(&f.font, f.rect.height), *vertical_align)))
+        .chain(atomics.iter().map(|atomic| ((atomic.rect.height, atomic.ascent), atomic.align)))
+        .collect();
+
+    // how far the boxes aligned relative to the baseline reach above and below it
+    let (mut above, mut below) = (0.0f32, 0.0f32);
+    let (mut top_height, mut bottom_height) = (0.0f32, 0.0f32);
+    for &((height, ascent), vertical_align) in &boxes {
+        match vertical_align.top_above_baseline(height, ascent, x_height) {
+            Some(top) => {
+                above = above.max(top);
+                below = below.max(height - top);
+            }
+            None if vertical_align == VerticalAlign::Top => top_height = top_height.max(height),
+            None => bottom_height = bottom_height.max(height)
+        }
+    }
+    // a `bottom` box taller than everything else pushes the rest down to meet it
+    let span = above + below;
+    let height = span.max(top_height).max(bottom_height);
+    let baseline = above + if bottom_height > top_height.max(span) { bottom_height - span } else { 0.0 };
+    let top_of = |box_height: f32, ascent: f32, vertical_align: VerticalAlign| y + match vertical_align {
+        VerticalAlign::Top => 0.0,
+        VerticalAlign::Bottom => height - box_height,
+        _ => baseline - vertical_align.top_above_baseline(box_height, ascent, x_height).unwrap_or(ascent)
+    };
+
+    let right = fragments.iter().map(|(f, _)| f.rect.x + f.rect.width)
+        .chain(atomics.iter().map(|atomic| atomic.rect.x + atomic.rect.width))
+        .fold(area.x, f32::max);
+    let free = (area.x + area.width - right).max(0.0);
+    let dx = match align {
+        TextAlign::Left => 0.0,
+        TextAlign::Right => free,
+        TextAlign::Center => free / 2.0
+    };
+    let fragments: Vec<_> = fragments.into_iter().map(|(mut fragment, vertical_align)| {
+        let (box_height, ascent) = text_box(&fragment.font, fragment.rect.height);
+        fragment.rect.x += dx;
+        fragment.rect.y = top_of(box_height, ascent, vertical_align);
+        fragment
+    }).collect();
+    for atomic in atomics {
+        let mut rect = atomic.rect;
+        rect.x += dx;
+        rect.y = top_of(rect.height, atomic.ascent, atomic.align);
+        placements[atomic.index] = rect;
+    }
+    LineBox {
+        rect: Rect { x: area.x, y, width: area.width, height },
+        baseline,
+        text_bounds: text_bounds(&fragments),
+        fragments
+    }
+}
@@ -1,8 +1,16 @@
+use crate::bidi::Direction;
 use crate::style::{StyledNode, Display};
-use crate::css::{Value::{Keyword, Length}, Unit::Px};
+use crate::dom::NodeType;
+use crate::css::{Color, Value, Value::{Keyword, Length}, Unit::{Percent, Px}};
+use crate::font::{Font, StandardFontMeasurer, TextMeasurer};
+use crate::image_decode::DecodedImage;
+use crate::inline::{self, InlineFragment, LineBox, TextAlign};
+use crate::replaced;
 use std::default::Default;
+use std::sync::Arc;
 
-pub use self::BoxType::{AnonymousBlock, InlineNode, BlockNode};
+pub use self::BoxType::{AnonymousBlock, InlineNode, InlineBlockNode, BlockNode};
+pub use crate::validate::check_invariants;
 
 // CSS box model - we assume all sizes in px
 #[derive(Clone, Copy, Default, Debug)]
@@ -17,7 +25,7 @@ pub struct Dimensions {
 }
 
 // rectangle that defines the box position, width, and height
-#[derive(Clone, Copy, Default, Debug)]
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
 pub struct Rect {
     pub x: f32,
     pub y: f32,
@@ -33,15 +41,46 @@ pub struct EdgeSizes {
     pub bottom: f32,
 }
 
+/// A box in the layout tree, produced by `layout_tree` from a style tree.
+///
+/// Children are in flow order, which is also the order they're painted in: the
+/// document order of the elements and text they come from, with runs of inline
+/// content wrapped in anonymous blocks where they sit among block siblings.
 pub struct LayoutBox<'a> {
     pub dimensions: Dimensions,
     pub box_type: BoxType<'a>,
-    pub children: Vec<LayoutBox<'a>>
+    pub children: Vec<LayoutBox<'a>>,
+    // line boxes, for boxes that establish an inline formatting context
+    pub lines: Vec<LineBox<'a>>,
+    // for inline elements, what they cover on each line they're on
+    pub inline_fragments: Vec<InlineFragment>,
+    // for a piece of an inline element split around the block-level boxes inside
+    // it, whether other pieces come before and after it; the element's left edge
+    // is only drawn on its first piece, and its right edge on its last
+    pub continued_before: bool,
+    pub continued_after: bool,
+    // a `content-visibility: auto` box below the painted area, whose children
+    // weren't laid out and mustn't be painted
+    pub contents_skipped: bool,
+    // for tables with `border-collapse: collapse`, the borders of the table and its
+    // cells, which the table paints
+    pub collapsed_borders: Vec<(Rect, Color)>,
+    // for replaced elements, the decoded image, if it could be loaded
+    pub image: Option<Arc<DecodedImage>>,
+    // the decoded `url()` image of each background layer, if it has one and it
+    // could be loaded; empty if no layer has one
+    pub background_images: Vec<Option<Arc<DecodedImage>>>,
 }
 
+/// What generated a layout box. Every kind but `AnonymousBlock` comes from an
+/// element (or a `::before`/`::after` pseudo-element) in the style tree.
 pub enum BoxType<'a> {
     BlockNode(&'a StyledNode<'a>),
     InlineNode(&'a StyledNode<'a>),
+    // a block container placed on a line as a single atomic inline
+    InlineBlockNode(&'a StyledNode<'a>),
+    // a block wrapping inline content next to block siblings, or the inline
+    // content of an inline-block; its lines hold the text
     AnonymousBlock
 }
 
@@ -52,71 +91,549 @@ impl<'a> LayoutBox<'a> {
             box_type,
             dimensions: Default::default(), // initially set all fields to 0.0
             children: Vec::new(),
+            lines: Vec::new(),
+            inline_fragments: Vec::new(),
+            continued_before: false,
+            continued_after: false,
+            contents_skipped: false,
+            collapsed_borders: Vec::new(),
+            image: None,
+            background_images: Vec::new(),
+        }
+    }
+
+    /// The area painted by this box and its descendants: the union of border boxes,
+    /// outlines and text, which can reach beyond this box's own border box when content overflows.
+    pub fn subtree_bounds(&self) -> Rect {
+        let mut bounds = self.dimensions.border_box();
+        // outlines are painted outside the border edge
+        if let Some(outline) = self.style_node().and_then(StyledNode::outline) {
+            let reach = (outline.offset + outline.width).max(0.0);
+            let edge = EdgeSizes { left: reach, right: reach, top: reach, bottom: reach };
+            bounds = bounds.union(bounds.expanded_by(edge));
+            for fragment in &self.inline_fragments {
+                bounds = bounds.union(fragment.border_box.expanded_by(edge));
+            }
+        }
+        if self.contents_skipped {
+            return bounds;
+        }
+        for line in &self.lines {
+            if let Some(text) = line.text_bounds {
+                bounds = bounds.union(text);
+            }
+        }
+        for fragment in &self.inline_fragments {
+            bounds = bounds.union(fragment.border_box);
+        }
+        for &(rect, _) in &self.collapsed_borders {
+            bounds = bounds.union(rect);
+        }
+        for child in &self.children {
+            bounds = bounds.union(child.subtree_bounds());
+        }
+        bounds
+    }
+
+    // move this box and everything inside it, including its line boxes
+    pub(crate) fn translate(&mut self, dx: f32, dy: f32) {
+        self.dimensions.content.x += dx;
+        self.dimensions.content.y += dy;
+        for line in &mut self.lines {
+            line.rect.x += dx;
+            line.rect.y += dy;
+            for fragment in &mut line.fragments {
+                fragment.rect.x += dx;
+                fragment.rect.y += dy;
+            }
+            line.text_bounds = line.text_bounds.map(|text| text.translated(dx, dy));
+        }
+        for fragment in &mut self.inline_fragments {
+            fragment.border_box = fragment.border_box.translated(dx, dy);
+        }
+        for (rect, _) in &mut self.collapsed_borders {
+            *rect = rect.translated(dx, dy);
+        }
+        for child in &mut self.children {
+            child.translate(dx, dy);
         }
     }
 
-    fn get_style_node(&self) -> &'a StyledNode<'a> {
+    /// The number of boxes in this subtree whose border box sticks out of the side of
+    /// their containing block, which is what an over-constrained width leads to.
+    pub fn count_overflowing_boxes(&self) -> usize {
+        self.overflowing_boxes().len()
+    }
+
+    /// The boxes `count_overflowing_boxes` counts, in tree order.
+    pub fn overflowing_boxes(&self) -> Vec<&LayoutBox<'a>> {
+        fn collect<'b, 'a>(boxes: &'b [LayoutBox<'a>], container: Rect, found: &mut Vec<&'b LayoutBox<'a>>) {
+            for child in boxes {
+                match child.box_type {
+                    // inline boxes don't contain the blocks inside them
+                    InlineNode(_) => collect(&child.children, container, found),
+                    _ => {
+                        let border_box = child.dimensions.border_box();
+                        let tolerance = 0.01;
+                        if border_box.x < container.x - tolerance
+                            || border_box.x + border_box.width > container.x + container.width + tolerance {
+                            found.push(child);
+                        }
+                        let contents = if child.contents_skipped { &[][..] } else { &child.children[..] };
+                        collect(contents, child.dimensions.content, found);
+                    }
+                }
+            }
+        }
+        let mut found = Vec::new();
+        collect(&self.children, self.dimensions.content, &mut found);
+        found
+    }
+
+    // the number of boxes in this subtree, including this one
+    pub fn count_boxes(&self) -> usize {
+        1 + self.children.iter().map(LayoutBox::count_boxes).sum::<usize>()
+    }
+
+    /// The styled node this box was generated for, or `None` for an anonymous block.
+    pub fn style_node(&self) -> Option<&'a StyledNode<'a>> {
         match self.box_type {
-            BlockNode(node) | InlineNode(node) => node,
-            AnonymousBlock => panic!("Anonymous block box has no style node")
+            BlockNode(node) | InlineNode(node) | InlineBlockNode(node) => Some(node),
+            AnonymousBlock => None
         }
     }
+
+    /// Is this the box of a root element with `display: none`? It has no children,
+    /// and stays empty at the origin.
+    pub fn is_hidden(&self) -> bool {
+        self.style_node().is_some_and(|style| style.display() == Display::None)
+    }
+
+    pub fn box_type(&self) -> &BoxType<'a> {
+        &self.box_type
+    }
+
+    /// The child boxes, in flow order.
+    pub fn children(&self) -> &[LayoutBox<'a>] {
+        &self.children
+    }
+
+    /// Where layout put the box: its content area and the edges around it, in px
+    /// from the document origin.
+    pub fn dimensions(&self) -> Dimensions {
+        self.dimensions
+    }
+
+    /// The line boxes of a box that holds inline content, top to bottom.
+    pub fn lines(&self) -> &[LineBox<'a>] {
+        &self.lines
+    }
+
+    pub(crate) fn get_style_node(&self) -> &'a StyledNode<'a> {
+        self.style_node().expect("Anonymous block box has no style node")
+    }
+}
+
+// state shared by every box during a layout pass
+pub(crate) struct LayoutContext<'m> {
+    pub(crate) measurer: &'m dyn TextMeasurer,
+    // the bottom of the area that will be painted, if only part of the document
+    // will be; `content-visibility: auto` boxes entirely below it skip their contents
+    pub(crate) paint_bottom: Option<f32>,
 }
 
 // transform style tree into layout tree
-pub fn layout_tree<'a>(node: &'a StyledNode<'a>, mut containing_block: Dimensions) -> LayoutBox<'a> {
-    // the layout algorithm expects the container height to start at 0
-    // TODO: save the initial containing block height, for calculating percent heights
-    containing_block.content.height = 0.0;
+pub fn layout_tree<'a>(node: &'a StyledNode<'a>, containing_block: Dimensions) -> LayoutBox<'a> {
+    layout_tree_with_measurer(node, containing_block, &StandardFontMeasurer)
+}
 
+// same as `layout_tree`, but measuring text with the given measurer
+pub fn layout_tree_with_measurer<'a>(
+    node: &'a StyledNode<'a>,
+    containing_block: Dimensions,
+    measurer: &dyn TextMeasurer
+) -> LayoutBox<'a> {
     let mut root_box = build_layout_tree(node);
-    root_box.layout(containing_block);
+    layout_root_box(&mut root_box, containing_block, measurer, None);
     root_box
 }
 
+/// The containing block of the root element: a rectangle the size of the viewport,
+/// anchored at the canvas origin.
+///
+/// https://www.w3.org/TR/CSS2/visudet.html#containing-block-details
+///
+/// Its height is definite, so percentage heights on the root element resolve
+/// against the viewport, and chain down through descendants with definite heights
+/// (as in the usual `html, body { height: 100% }`).
+#[derive(Clone, Copy, Default, Debug)]
+pub struct InitialContainingBlock {
+    pub width: f32,
+    pub height: f32,
+}
+
+impl InitialContainingBlock {
+    pub fn from_viewport(viewport: Dimensions) -> InitialContainingBlock {
+        InitialContainingBlock { width: viewport.content.width, height: viewport.content.height }
+    }
+
+    /// The area of the canvas to paint: the initial containing block, grown to the
+    /// root element's margin box, or whatever overflows it, when the document is larger.
+    pub fn canvas_rect(&self, root: &LayoutBox) -> Rect {
+        let painted = root.dimensions.margin_box().union(root.subtree_bounds());
+        Rect {
+            x: 0.0,
+            y: 0.0,
+            width: self.width.max(painted.x + painted.width),
+            height: self.height.max(painted.y + painted.height),
+        }
+    }
+}
+
+// lay out a box tree from `build_layout_tree`
+//
+// `paint_bottom` is set when nothing below it will be painted, so that offscreen
+// `content-visibility: auto` subtrees can be skipped; it must be `None` whenever
+// the whole document is output, as for paged or auto-height output.
+pub(crate) fn layout_root_box(root_box: &mut LayoutBox, viewport: Dimensions, measurer: &dyn TextMeasurer, paint_bottom: Option<f32>) {
+    let icb = InitialContainingBlock::from_viewport(viewport);
+    // While laying out children, the height of a containing block is the position of the
+    // next child below its top, so it starts at 0; the real height travels separately.
+    let mut containing_block = Dimensions::default();
+    containing_block.content.width = icb.width;
+    let ctx = LayoutContext { measurer, paint_bottom };
+    if !root_box.is_hidden() {
+        root_box.layout(containing_block, Some(icb.height), &ctx);
+    }
+    assert_invariants(root_box);
+}
+
+// lay out a box tree from `build_layout_tree` on its own, `width` wide with no
+// definite height, rather than as a document in a viewport
+pub(crate) fn layout_fragment_root(root_box: &mut LayoutBox, width: f32, measurer: &dyn TextMeasurer) {
+    let mut containing_block = Dimensions::default();
+    containing_block.content.width = width;
+    let ctx = LayoutContext { measurer, paint_bottom: None };
+    if !root_box.is_hidden() {
+        root_box.layout(containing_block, None, &ctx);
+    }
+    assert_invariants(root_box);
+}
+
+// in debug builds and with the `check-layout` feature, stop at a layout that broke an
+// invariant, naming each box that broke one
+fn assert_invariants(root_box: &LayoutBox) {
+    if cfg!(any(debug_assertions, feature = "check-layout")) {
+        let violations = check_invariants(root_box);
+        let report: Vec<String> = violations.iter().map(|violation| format!("  {}", violation)).collect();
+        assert!(violations.is_empty(), "layout broke its invariants:\n{}", report.join("\n"));
+    }
+}
+
 // build the tree of LayoutBoxes, but don't perform any layout calculations yet
 pub fn build_layout_tree<'a>(style_node: &'a StyledNode<'a>) -> LayoutBox<'a> {
-    // Create the root box.
+    // Create the root box. A hidden root gets one too, left empty and never laid out,
+    // so that the document renders as a blank canvas.
     let mut root = LayoutBox::new(match style_node.display() {
-        Display::Block => BlockNode(style_node),
+        Display::Block | Display::Table | Display::TableRowGroup | Display::TableRow | Display::TableCell | Display::Grid => BlockNode(style_node),
+        // a replaced element is an atomic inline, like an inline-block
+        Display::Inline if replaced::is_replaced(style_node) => InlineBlockNode(style_node),
         Display::Inline => InlineNode(style_node),
-        Display::None => panic!("Root node has display: none.")
+        Display::InlineBlock => InlineBlockNode(style_node),
+        Display::None => return LayoutBox::new(BlockNode(style_node))
     });
 
-    // create descendant boxes
+    add_child_boxes(&mut root, style_node);
+    root
+}
+
+// create the boxes of a style node's children in its box; without anonymous table
+// boxes, a table, row group or row only has boxes for the table parts that belong in it
+fn add_child_boxes<'a>(root: &mut LayoutBox<'a>, style_node: &'a StyledNode<'a>) {
+    let parts: &[Display] = match style_node.display() {
+        Display::Table => &[Display::TableRowGroup, Display::TableRow],
+        Display::TableRowGroup => &[Display::TableRow],
+        Display::TableRow => &[Display::TableCell],
+        _ => &[]
+    };
     for child in &style_node.children {
+        if style_node.display() == Display::Grid {
+            root.children.extend(grid_item(child));
+            continue;
+        }
+        if !parts.is_empty() {
+            if parts.contains(&child.display()) {
+                root.children.push(build_layout_tree(child));
+            }
+            continue;
+        }
+        // white space before the first inline content of a block, or after a block-level
+        // child, collapses away, so it doesn't start an anonymous block
+        let starts_line = !matches!(root.children.last(), Some(LayoutBox { box_type: AnonymousBlock, .. }));
+        if starts_line && matches!(root.box_type, BlockNode(_) | InlineBlockNode(_)) && inline::is_collapsible_whitespace(child) {
+            continue;
+        }
         match child.display() {
-            Display::Block => root.children.push(build_layout_tree(child)),
-            Display::Inline => root.get_inline_container().children.push(build_layout_tree(child)),
+            Display::Block | Display::Table | Display::TableRowGroup | Display::TableRow | Display::TableCell | Display::Grid => {
+                root.children.push(build_layout_tree(child))
+            }
+            Display::Inline if !replaced::is_replaced(child) => {
+                for piece in split_inline(child) {
+                    match piece.box_type {
+                        InlineNode(_) => root.get_inline_container().children.push(piece),
+                        _ => root.children.push(piece)
+                    }
+                }
+            }
+            Display::Inline | Display::InlineBlock => root.get_inline_container().children.push(build_layout_tree(child)),
             Display::None => {} // don't lay out nodes with display: none
         }
     }
-    root
+}
+
+// the box of a child of a grid container as a grid item: a block box whatever its
+// display, or for text, an anonymous block holding it. Text that's all white space
+// doesn't make an item, and neither does an element with `display: none`.
+//
+// https://www.w3.org/TR/css-grid-1/#grid-items
+fn grid_item<'a>(style_node: &'a StyledNode<'a>) -> Option<LayoutBox<'a>> {
+    match style_node.node.node_type {
+        NodeType::Text(ref text) if text.chars().all(char::is_whitespace) => None,
+        NodeType::Text(_) => {
+            let mut item = LayoutBox::new(AnonymousBlock);
+            item.children.push(LayoutBox::new(InlineNode(style_node)));
+            Some(item)
+        }
+        _ => match style_node.display() {
+            Display::None => None,
+            // inline-level elements are blockified
+            Display::Inline | Display::InlineBlock => {
+                let mut item = LayoutBox::new(BlockNode(style_node));
+                add_child_boxes(&mut item, style_node);
+                Some(item)
+            }
+            _ => Some(build_layout_tree(style_node))
+        }
+    }
+}
+
+// the boxes of an inline element, split around any block-level boxes inside it:
+// pieces of the element holding the inline content between them, which go in
+// anonymous blocks, and the block-level boxes themselves, which become siblings
+// of those blocks
+//
+// https://www.w3.org/TR/CSS2/visuren.html#anonymous-block-level
+//
+// Pieces without content, or with only white space that collapses away next to
+// the blocks, are left out. Without block-level descendants, this is just the
+// element's box.
+fn split_inline<'a>(style_node: &'a StyledNode<'a>) -> Vec<LayoutBox<'a>> {
+    let mut boxes = Vec::new();
+    let mut piece = LayoutBox::new(InlineNode(style_node));
+    let has_content = |piece: &LayoutBox| piece.children.iter()
+        .any(|child| !matches!(child.box_type, InlineNode(text) if inline::is_collapsible_whitespace(text)));
+    let end_piece = |piece: &mut LayoutBox<'a>, boxes: &mut Vec<LayoutBox<'a>>| {
+        let mut next = LayoutBox::new(InlineNode(style_node));
+        next.continued_before = true;
+        let mut done = std::mem::replace(piece, next);
+        done.continued_after = true;
+        if has_content(&done) {
+            boxes.push(done);
+        }
+    };
+    for child in &style_node.children {
+        match child.display() {
+            Display::Block | Display::Table | Display::TableRowGroup | Display::TableRow | Display::TableCell | Display::Grid => {
+                end_piece(&mut piece, &mut boxes);
+                boxes.push(build_layout_tree(child));
+            }
+            Display::Inline if !replaced::is_replaced(child) => {
+                for inner in split_inline(child) {
+                    match inner.box_type {
+                        InlineNode(_) => piece.children.push(inner),
+                        _ => {
+                            end_piece(&mut piece, &mut boxes);
+                            boxes.push(inner);
+                        }
+                    }
+                }
+            }
+            Display::Inline | Display::InlineBlock => piece.children.push(build_layout_tree(child)),
+            Display::None => {}
+        }
+    }
+    // a text node, or an element with nothing split off, keeps its box even if empty
+    if boxes.is_empty() || has_content(&piece) {
+        boxes.push(piece);
+    }
+    boxes
 }
 
 impl<'a> LayoutBox<'a> {
-    // lay out a box and its descendants
-    fn layout(&mut self, containing_block: Dimensions) {
+    // lay out a box and its descendants; `containing_height` is the height of the
+    // containing block if it's definite, for resolving percentage heights
+    pub(crate) fn layout(&mut self, containing_block: Dimensions, containing_height: Option<f32>, ctx: &LayoutContext) {
+        match self.box_type {
+            BlockNode(style) if style.display() == Display::Table => {
+                crate::table::layout_table(self, containing_block, containing_height, ctx)
+            }
+            BlockNode(style) if style.display() == Display::Grid => {
+                crate::grid::layout_grid(self, containing_block, containing_height, ctx)
+            }
+            BlockNode(_) => self.layout_block(containing_block, containing_height, ctx),
+            AnonymousBlock => self.layout_inline_container(containing_block, 0.0, TextAlign::default(), Direction::default(), None, ctx),
+            // laid out by the inline formatting context of their container
+            InlineNode(_) | InlineBlockNode(_) => {}
+        }
+    }
+
+    /// Lay out an inline-block's contents, shrink-to-fit when its width is auto.
+    ///
+    /// https://www.w3.org/TR/CSS2/visudet.html#inlineblock-width
+    ///
+    /// The box is laid out with its margin box at the top left of `containing_block`;
+    /// the inline formatting context then moves it to its place on a line.
+    pub(crate) fn layout_inline_block(&mut self, containing_block: Dimensions, ctx: &LayoutContext) {
+        let auto_width = match self.get_style_node().value("width") {
+            _ if replaced::used_size(self).is_some() => false,
+            Some(Length(_, Px)) => false,
+            Some(Keyword(k)) => k == "auto",
+            _ => true
+        };
+        self.calculate_block_width(containing_block, ctx);
+        if auto_width {
+            // the available width is what calculate_block_width gave an auto width
+            let available = self.dimensions.content.width;
+            self.dimensions.content.width = self.max_content_width(ctx).min(available);
+        }
+        // unlike in-flow blocks, an inline-block's margins are never stretched to fill
+        // the line, and auto margins are zero
+        let style = self.get_style_node();
+        let zero = Length(0.0, Px);
+        self.dimensions.margin.left = style.lookup("margin-left", "margin", &zero).to_px();
+        self.dimensions.margin.right = style.lookup("margin-right", "margin", &zero).to_px();
+        self.calculate_block_position(containing_block);
+        // percentage heights of inline-blocks are treated as auto
+        let height = self.definite_height(None).or_else(|| replaced::used_size(self).map(|(_, h)| h));
+        self.layout_block_children(height, ctx);
+        self.calculate_block_height(height);
+    }
+
+    /// The width of this box's content if no line were ever wrapped.
+    ///
+    /// https://www.w3.org/TR/css-sizing-3/#max-content
+    pub(crate) fn max_content_width(&self, ctx: &LayoutContext) -> f32 {
+        if let Some((width, _)) = replaced::used_size(self) {
+            return width;
+        }
+        match self.box_type {
+            AnonymousBlock => crate::inline::max_content_width(&self.children, ctx),
+            _ => self.children.iter()
+                .map(|child| child.max_content_contribution(ctx))
+                .fold(0.0, f32::max)
+        }
+    }
+
+    /// The narrowest this box's content can be without overflowing, short of
+    /// breaking words: the width of its widest word or inline-block.
+    ///
+    /// https://www.w3.org/TR/css-sizing-3/#min-content
+    pub(crate) fn min_content_width(&self, ctx: &LayoutContext) -> f32 {
+        if let Some((width, _)) = replaced::used_size(self) {
+            return width;
+        }
         match self.box_type {
-            BlockNode(_) => self.layout_block(containing_block),
-            InlineNode(_) | AnonymousBlock => {} // TODO
+            AnonymousBlock => crate::inline::min_content_width(&self.children, ctx),
+            _ => self.children.iter()
+                .map(|child| child.min_content_contribution(ctx))
+                .fold(0.0, f32::max)
         }
     }
 
-    fn layout_block(&mut self, containing_block: Dimensions) {
+    /// The width of this box's margin box when laid out at its max-content width.
+    pub(crate) fn max_content_contribution(&self, ctx: &LayoutContext) -> f32 {
+        self.intrinsic_contribution(false, ctx)
+    }
+
+    /// The width of this box's margin box when laid out at its min-content width.
+    pub(crate) fn min_content_contribution(&self, ctx: &LayoutContext) -> f32 {
+        self.intrinsic_contribution(true, ctx)
+    }
+
+    // the margin box width at the min-content width if `min`, else the max-content
+    // width, unless the box's own `width` says otherwise
+    fn intrinsic_contribution(&self, min: bool, ctx: &LayoutContext) -> f32 {
+        let content_width = |layout_box: &LayoutBox| {
+            if min { layout_box.min_content_width(ctx) } else { layout_box.max_content_width(ctx) }
+        };
+        let style = match self.box_type {
+            BlockNode(style) | InlineBlockNode(style) => style,
+            InlineNode(_) | AnonymousBlock => return content_width(self)
+        };
+        let zero = Length(0.0, Px);
+        // auto margins count as zero
+        let margins = sum([
+            style.lookup("margin-left", "margin", &zero), style.lookup("margin-right", "margin", &zero),
+        ].iter().map(|v| v.to_px()));
+        let edges = margins + sum([
+            style.lookup("border-left-width", "border-width", &zero), style.lookup("border-right-width", "border-width", &zero),
+            style.lookup("padding-left", "padding", &zero), style.lookup("padding-right", "padding", &zero),
+        ].iter().map(|v| v.to_px().max(0.0)));
+        // fit-content, like auto, is min-content or max-content depending on which
+        // the box is being measured for
+        let width = match style.value("width") {
+            Some(Length(w, Px)) => w,
+            Some(Keyword(k)) if k == "min-content" => self.min_content_width(ctx),
+            Some(Keyword(k)) if k == "max-content" => self.max_content_width(ctx),
+            _ => content_width(self)
+        };
+        width + edges
+    }
+
+    fn layout_block(&mut self, containing_block: Dimensions, containing_height: Option<f32>, ctx: &LayoutContext) {
         // Child width can depend on parent width, so we need to calculate
         // this box's width before laying out its children
-        self.calculate_block_width(containing_block);
+        self.calculate_block_width(containing_block, ctx);
 
         // Determine where the box is located within its container
         self.calculate_block_position(containing_block);
 
+        let height = self.definite_height(containing_height).or_else(|| replaced::used_size(self).map(|(_, h)| h));
+        if self.skips_contents(ctx) {
+            self.contents_skipped = true;
+            self.dimensions.content.height = height.unwrap_or_else(|| self.intrinsic_height_estimate());
+            return;
+        }
+
         // Recursively lay out the children of this box
-        self.layout_block_children();
+        self.layout_block_children(height, ctx);
 
         // Parent height can depend on child height, so calculate_height must be called
         // *after* the children are laid out.
-        self.calculate_block_height();
+        self.calculate_block_height(height);
+    }
+
+    /// Whether this box's contents go unrendered: always for `content-visibility:
+    /// hidden`, and for `auto` when the box starts below everything that will be
+    /// painted, so that laying out its contents would be wasted.
+    ///
+    /// https://www.w3.org/TR/css-contain-2/#content-visibility
+    fn skips_contents(&self, ctx: &LayoutContext) -> bool {
+        match self.get_style_node().value("content-visibility") {
+            Some(Keyword(k)) if k == "hidden" => true,
+            Some(Keyword(k)) if k == "auto" => {
+                ctx.paint_bottom.is_some_and(|bottom| self.dimensions.border_box().y >= bottom)
+            }
+            _ => false
+        }
+    }
+
+    // the height a box with skipped contents takes instead: `contain-intrinsic-height`
+    // (or the `contain-intrinsic-size` shorthand), since with its contents skipped the
+    // box is sized as if it had none
+    fn intrinsic_height_estimate(&self) -> f32 {
+        let none = Keyword("none".to_string());
+        self.get_style_node().lookup("contain-intrinsic-height", "contain-intrinsic-size", &none).to_px()
     }
 
     /// Calculate the width of a block-level non-replaced element in normal flow.
@@ -124,24 +641,53 @@ impl<'a> LayoutBox<'a> {
     /// http://www.w3.org/TR/CSS2/visudet.html#blockwidth
     ///
     /// Sets the horizontal margin/padding/border dimensions, and the `width`.
-    fn calculate_block_width(&mut self, containing_block: Dimensions) {
+    pub(crate) fn calculate_block_width(&mut self, containing_block: Dimensions, ctx: &LayoutContext) {
         let style = self.get_style_node();
 
         // `width` has initial value `auto`.
         let auto = Keyword("auto".to_string());
-        let mut width = style.value("width").unwrap_or(auto.clone());
 
         // margin, border, and padding have initial value 0.
         let zero = Length(0.0, Px);
 
-        let mut margin_left = style.lookup("margin-left", "margin", &zero);
-        let mut margin_right = style.lookup("margin-right", "margin", &zero);
+        // Percentages, and math functions of them, are of the containing block's
+        // width; a keyword like `auto` stays for the equation below to solve.
+        // https://www.w3.org/TR/CSS2/box.html#margin-properties
+        let of_width = |value: Value| match value {
+            Keyword(_) => value,
+            value => Length(value.to_px_of(containing_block.content.width), Px)
+        };
 
-        let border_left = style.lookup("border-left-width", "border-width", &zero);
-        let border_right = style.lookup("border-right-width", "border-width", &zero);
+        let mut margin_left = of_width(style.lookup("margin-left", "margin", &zero));
+        let mut margin_right = of_width(style.lookup("margin-right", "margin", &zero));
 
-        let padding_left = style.lookup("padding-left", "padding", &zero);
-        let padding_right = style.lookup("padding-right", "padding", &zero);
+        let border_left = non_negative(style.lookup("border-left-width", "border-width", &zero));
+        let border_right = non_negative(style.lookup("border-right-width", "border-width", &zero));
+
+        let padding_left = non_negative(of_width(style.lookup("padding-left", "padding", &zero)));
+        let padding_right = non_negative(of_width(style.lookup("padding-right", "padding", &zero)));
+
+        // An intrinsic size keyword stands for the width of the box's content;
+        // fit-content is the available width, but no narrower than min-content and
+        // no wider than max-content.
+        // https://www.w3.org/TR/css-sizing-3/#sizing-values
+        let mut width = match style.value("width") {
+            Some(Keyword(k)) if k == "min-content" => Length(self.min_content_width(ctx), Px),
+            Some(Keyword(k)) if k == "max-content" => Length(self.max_content_width(ctx), Px),
+            Some(Keyword(k)) if k == "fit-content" => {
+                let edges = sum([&margin_left, &margin_right, &border_left, &border_right, &padding_left, &padding_right]
+                    .iter().map(|v| v.to_px()));
+                let available = containing_block.content.width - edges;
+                Length(self.max_content_width(ctx).min(self.min_content_width(ctx).max(available)), Px)
+            }
+            Some(value) => of_width(value),
+            None => auto.clone()
+        };
+        if width == auto {
+            if let Some((replaced_width, _)) = replaced::used_size(self) {
+                width = Length(replaced_width, Px);
+            }
+        }
 
         let total = sum([&margin_left, &margin_right, &border_left, &border_right,
                          &padding_left, &padding_right, &width].iter().map(|v| v.to_px()));
@@ -162,9 +708,16 @@ impl<'a> LayoutBox<'a> {
         let underflow = containing_block.content.width - total;
 
         match (width == auto, margin_left == auto, margin_right == auto) {
-            // If the values are overconstrained, calculate margin_right.
+            // If the values are overconstrained, calculate the margin on the end side:
+            // the right in left-to-right text, so the box overflows to the right. The
+            // spec goes by the containing block's direction; we use the box's own,
+            // which is the same unless the box sets its own.
             (false, false, false) => {
-                margin_right = Length(margin_right.to_px() + underflow, Px);
+                if style.value("direction") == Some(Keyword("rtl".to_string())) {
+                    margin_left = Length(margin_left.to_px() + underflow, Px);
+                } else {
+                    margin_right = Length(margin_right.to_px() + underflow, Px);
+                }
             }
 
             // If exactly one size is auto, its used value follows from the equality.
@@ -194,7 +747,7 @@ impl<'a> LayoutBox<'a> {
         }
 
         let d = &mut self.dimensions;
-        d.content.width = width.to_px();
+        d.content.width = width.to_px().max(0.0);
 
         d.padding.left = padding_left.to_px();
         d.padding.right = padding_right.to_px();
@@ -211,22 +764,26 @@ impl<'a> LayoutBox<'a> {
     /// http://www.w3.org/TR/CSS2/visudet.html#normal-block
     ///
     /// Sets the vertical margin/padding/border dimensions, and the `x`, `y` values.
-    fn calculate_block_position(&mut self, containing_block: Dimensions) {
+    pub(crate) fn calculate_block_position(&mut self, containing_block: Dimensions) {
         let style = self.get_style_node();
         let d = &mut self.dimensions;
 
         // margin, border, and padding have initial value 0.
         let zero = Length(0.0, Px);
 
-        // If margin-top or margin-bottom is `auto`, the used value is zero.
-        d.margin.top = style.lookup("margin-top", "margin", &zero).to_px();
-        d.margin.bottom = style.lookup("margin-bottom", "margin", &zero).to_px();
+        // Percentages of vertical margins and padding refer to the containing block's
+        // *width*, like horizontal ones. If margin-top or margin-bottom is `auto`, the
+        // used value is zero.
+        // https://www.w3.org/TR/CSS2/box.html#margin-properties
+        let width = containing_block.content.width;
+        d.margin.top = style.lookup("margin-top", "margin", &zero).to_px_of(width);
+        d.margin.bottom = style.lookup("margin-bottom", "margin", &zero).to_px_of(width);
 
-        d.border.top = style.lookup("border-top-width", "border-width", &zero).to_px();
-        d.border.bottom = style.lookup("border-bottom-width", "border-width", &zero).to_px();
+        d.border.top = style.lookup("border-top-width", "border-width", &zero).to_px().max(0.0);
+        d.border.bottom = style.lookup("border-bottom-width", "border-width", &zero).to_px().max(0.0);
 
-        d.padding.top = style.lookup("padding-top", "padding", &zero).to_px();
-        d.padding.bottom = style.lookup("padding-bottom", "padding", &zero).to_px();
+        d.padding.top = style.lookup("padding-top", "padding", &zero).to_px_of(width).max(0.0);
+        d.padding.bottom = style.lookup("padding-bottom", "padding", &zero).to_px_of(width).max(0.0);
 
         d.content.x = containing_block.content.x +
                       d.margin.left + d.border.left + d.padding.left;
@@ -239,19 +796,71 @@ impl<'a> LayoutBox<'a> {
     /// Lay out the block's children within its content area.
     ///
     /// Sets `self.dimensions.height` to the total content height.
-    fn layout_block_children(&mut self) {
-        for child in &mut self.children {
-            child.layout(self.dimensions);
+    pub(crate) fn layout_block_children(&mut self, height: Option<f32>, ctx: &LayoutContext) {
+        let text_indent = self.text_indent();
+        let align = TextAlign::of(self.get_style_node());
+        let direction = Direction::of(self.get_style_node());
+        let strut = Font::from_style(self.get_style_node());
+        let ellipsis = self.truncates_with_ellipsis();
+        for (i, child) in self.children.iter_mut().enumerate() {
+            match child.box_type {
+                // only the first line of the block container is indented
+                AnonymousBlock => {
+                    let indent = if i == 0 { text_indent } else { 0.0 };
+                    child.layout_inline_container(self.dimensions, indent, align, direction, Some(strut), ctx)
+                }
+                _ => child.layout(self.dimensions, height, ctx),
+            }
+            if ellipsis && matches!(child.box_type, AnonymousBlock) {
+                child.truncate_lines_with_ellipsis(ctx);
+            }
             // Increment the height so each child is laid out below the previous one.
             self.dimensions.content.height += child.dimensions.margin_box().height;
         }
     }
 
+    /// The used `text-indent` of this block container, in px.
+    ///
+    /// https://www.w3.org/TR/css-text-3/#text-indent-property
+    ///
+    /// Percentages refer to the width of the block's own content box, which is the
+    /// containing block of its line boxes. Negative values give a hanging indent.
+    fn text_indent(&self) -> f32 {
+        match self.get_style_node().value("text-indent") {
+            Some(value) => value.to_px_of(self.dimensions.content.width),
+            None => 0.0
+        }
+    }
+
+    /// Do lines that overflow this block end in an ellipsis?
+    ///
+    /// https://www.w3.org/TR/css-overflow-3/#text-overflow
+    ///
+    /// Only when the inline overflow is clipped; otherwise the text stays visible.
+    fn truncates_with_ellipsis(&self) -> bool {
+        let style = self.get_style_node();
+        let visible = Keyword("visible".to_string());
+        style.lookup("overflow-x", "overflow", &visible) == Keyword("hidden".to_string())
+            && style.value("text-overflow") == Some(Keyword("ellipsis".to_string()))
+    }
+
+    /// The used `height` of this box if it doesn't depend on its content: an explicit
+    /// length, or a percentage of a containing block whose height is definite.
+    ///
+    /// https://www.w3.org/TR/CSS2/visudet.html#the-height-property
+    pub(crate) fn definite_height(&self, containing_height: Option<f32>) -> Option<f32> {
+        match self.get_style_node().value("height") {
+            Some(Length(h, Px)) => Some(h),
+            Some(Length(p, Percent)) => containing_height.map(|h| p * h / 100.0),
+            _ => None
+        }
+    }
+
     /// Height of a block-level non-replaced element in normal flow with overflow visible.
-    fn calculate_block_height(&mut self) {
-        // If the height is set to an explicit length, use that exact length.
+    fn calculate_block_height(&mut self, height: Option<f32>) {
+        // If the height is definite, use that exact length.
         // Otherwise, just keep the value set by `layout_block_children`.
-        if let Some(Length(h, Px)) = self.get_style_node().value("height") {
+        if let Some(h) = height {
             self.dimensions.content.height = h;
         }
     }
@@ -260,7 +869,7 @@ impl<'a> LayoutBox<'a> {
     fn get_inline_container(&mut self) -> &mut LayoutBox<'a> {
         match self.box_type {
             InlineNode(_) | AnonymousBlock => self,
-            BlockNode(_) => {
+            BlockNode(_) | InlineBlockNode(_) => {
                 // if just generated an anonymous block box, use it; else, create new one
                 match self.children.last() {
                     Some(&LayoutBox { box_type: AnonymousBlock,..}) => {}
@@ -281,6 +890,50 @@ impl Rect {
             height: self.height + edge.top + edge.bottom,
         }
     }
+
+    // the inverse of `expanded_by`, never narrower or shorter than nothing
+    pub fn shrunk_by(self, edge: EdgeSizes) -> Rect {
+        Rect {
+            x: self.x + edge.left,
+            y: self.y + edge.top,
+            width: (self.width - edge.left - edge.right).max(0.0),
+            height: (self.height - edge.top - edge.bottom).max(0.0),
+        }
+    }
+
+    pub fn translated(self, dx: f32, dy: f32) -> Rect {
+        Rect { x: self.x + dx, y: self.y + dy, ..self }
+    }
+
+    /// Does the rectangle cover no area? NaN sizes count as empty.
+    pub fn is_empty(self) -> bool {
+        !(self.width > 0.0 && self.height > 0.0)
+    }
+
+    /// The overlap of two rectangles, or `None` if they don't overlap.
+    pub fn intersection(self, other: Rect) -> Option<Rect> {
+        let x0 = self.x.max(other.x);
+        let y0 = self.y.max(other.y);
+        let x1 = (self.x + self.width).min(other.x + other.width);
+        let y1 = (self.y + self.height).min(other.y + other.height);
+        let rect = Rect { x: x0, y: y0, width: x1 - x0, height: y1 - y0 };
+        if rect.is_empty() { None } else { Some(rect) }
+    }
+
+    /// The smallest rectangle containing both; empty rectangles are ignored.
+    pub fn union(self, other: Rect) -> Rect {
+        if other.is_empty() {
+            return self;
+        }
+        if self.is_empty() {
+            return other;
+        }
+        let x0 = self.x.min(other.x);
+        let y0 = self.y.min(other.y);
+        let x1 = (self.x + self.width).max(other.x + other.width);
+        let y1 = (self.y + self.height).max(other.y + other.height);
+        Rect { x: x0, y: y0, width: x1 - x0, height: y1 - y0 }
+    }
 }
 
 impl Dimensions {
@@ -298,6 +951,100 @@ impl Dimensions {
     }
 }
 
+// Padding and border widths can't be negative, but the stylesheet grammar doesn't
+// rule negative lengths out, so they're used as zero.
+fn non_negative(value: Value) -> Value {
+    match value {
+        Length(n, unit) if n < 0.0 => Length(0.0, unit),
+        value => value,
+    }
+}
+
 fn sum<I>(iter: I) -> f32 where I: Iterator<Item=f32> {
     iter.fold(0., |a, b| a + b)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::css::{self, Origin};
+    use crate::engine::{self, RenderOptions};
+    use crate::{html, style};
+
+    #[test]
+    fn negative_padding_and_borders_are_used_as_zero() {
+        let root = html::parse("<div>block <span>inline</span></div><p>after</p>".to_string());
+        let css = "div { padding: -5px -10%; border: -3px solid red } span { padding: -4px; border-width: -2px; border-style: solid }";
+        let stylesheets = [css::default_user_agent_stylesheet(), css::parse(css.to_string(), Origin::Author)];
+        let style_root = style::style_tree(&root, &stylesheets);
+        // a box with negative edges would break the layout's invariants
+        let layout_root = engine::layout(&style_root, &RenderOptions::default()).unwrap();
+        let body = &layout_root.children[0];
+        let div = body.children[0].dimensions;
+        assert_eq!((div.padding.left, div.padding.top, div.border.left, div.border.bottom), (0.0, 0.0, 0.0, 0.0));
+        assert_eq!(div.border_box().width, body.dimensions.content.width);
+    }
+
+    #[test]
+    fn em_font_sizes_compound_and_percent_widths_take_the_containing_block() {
+        let root = html::parse("<div><p><span>x</span></p><div class=\"w\"><div>half</div></div></div>".to_string());
+        let css = "div, p, span { font-size: 1.2em } .w { width: 400px } .w div { width: 50%; font-size: 2rem }";
+        let stylesheets = [css::default_user_agent_stylesheet(), css::parse(css.to_string(), Origin::Author)];
+        let style_root = style::style_tree(&root, &stylesheets);
+        let span = &style_root.children[0].children[0];
+        match span.value("font-size") {
+            Some(css::Value::Length(size, css::Unit::Px)) => assert!((size - 16.0 * 1.2 * 1.2 * 1.2).abs() < 1e-3, "{}", size),
+            other => panic!("expected a px font size, got {:?}", other)
+        }
+        let layout_root = engine::layout(&style_root, &RenderOptions::default()).unwrap();
+        let wide = &layout_root.children[1];
+        let half = wide.children[0].dimensions;
+        assert_eq!((wide.dimensions.content.width, half.content.width), (400.0, 200.0));
+        // a rem is the root's font size, not the parent's
+        assert_eq!(wide.children[0].style_node().unwrap().value("font-size"), Some(css::Value::Length(38.4, css::Unit::Px)));
+    }
+
+    #[test]
+    fn shorthands_lay_out_as_their_longhands() {
+        let source = "<div><p>one</p><p>two</p></div>";
+        let dimensions = |css: &str| {
+            let root = html::parse(source.to_string());
+            let stylesheets = [css::default_user_agent_stylesheet(), css::parse(css.to_string(), Origin::Author)];
+            let style_root = style::style_tree(&root, &stylesheets);
+            let layout_root = engine::layout(&style_root, &RenderOptions::default()).unwrap();
+            layout_root.children.iter().map(|child| format!("{:?}", child.dimensions)).collect::<Vec<_>>()
+        };
+        let shorthands = dimensions("p { margin: 10px 20px 30px; padding: 1px 2px; border: 3px solid black }");
+        let longhands = dimensions("p { margin-top: 10px; margin-right: 20px; margin-bottom: 30px; margin-left: 20px; \
+            padding-top: 1px; padding-right: 2px; padding-bottom: 1px; padding-left: 2px; \
+            border-top-width: 3px; border-right-width: 3px; border-bottom-width: 3px; border-left-width: 3px; \
+            border-style: solid; border-color: black }");
+        assert_eq!(shorthands, longhands);
+        assert_ne!(shorthands, dimensions(""));
+    }
+
+    #[test]
+    fn long_text_lays_out_borrowing_it_from_the_dom() {
+        fn fragments<'a>(layout_box: &'a super::LayoutBox<'a>, found: &mut Vec<&'a crate::inline::Fragment<'a>>) {
+            found.extend(layout_box.lines.iter().flat_map(|line| &line.fragments));
+            for child in &layout_box.children {
+                fragments(child, found);
+            }
+        }
+        // `cargo bench --bench text` times megabytes of the same
+        let line = "x".repeat(200_000);
+        let paragraph = "word ".repeat(40_000);
+        for (source, css, lines) in [
+            (format!("<html><body><pre>{}</pre></body></html>", line), "pre { white-space: pre; }", 1..=1),
+            (format!("<html><body><p>{}</p></body></html>", paragraph), "", 100..=usize::MAX),
+        ] {
+            let root = html::parse(source);
+            let stylesheets = [css::default_user_agent_stylesheet(), css::parse(css.to_string(), Origin::Author)];
+            let style_root = style::style_tree(&root, &stylesheets);
+            let layout_root = engine::layout(&style_root, &RenderOptions::default()).unwrap();
+            let mut found = Vec::new();
+            fragments(&layout_root, &mut found);
+            assert!(lines.contains(&found.len()), "{} fragments", found.len());
+            assert!(found.iter().all(|fragment| matches!(fragment.text, std::borrow::Cow::Borrowed(_))));
+        }
+    }
+}
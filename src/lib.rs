@@ -0,0 +1,35 @@
+pub mod a11y;
+pub mod background;
+pub mod bidi;
+pub mod compare;
+pub mod coverage;
+pub mod css;
+pub mod font;
+pub mod forms;
+pub mod grid;
+pub mod dom;
+pub mod encoding;
+pub mod engine;
+pub mod focus;
+pub mod html;
+pub mod image_decode;
+pub mod inline;
+pub mod layout;
+pub mod links;
+pub mod media;
+pub mod style;
+pub mod table;
+pub mod template;
+pub mod painting;
+#[cfg(test)]
+mod paint_check;
+pub mod pdf;
+pub mod png;
+pub mod replaced;
+pub mod report;
+pub mod resource;
+pub mod selectors;
+pub mod stats;
+#[cfg(test)]
+mod structure_check;
+pub mod validate;
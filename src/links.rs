@@ -0,0 +1,169 @@
+use crate::css;
+use crate::dom::{Node, NodeType};
+use crate::layout::{InlineNode, LayoutBox, Rect};
+use std::collections::HashMap;
+use std::fmt;
+
+// Links within the document: `<a href="#name">` points at the element whose id is
+// `name`, or failing that the legacy `<a name="name">` anchor. Both are found in the
+// layout tree, so an element that generates no box (such as one with `display: none`)
+// can't be linked to.
+
+/// A link to a fragment of the document, and the area that follows it when clicked.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FragmentLink {
+    pub fragment: String, // the percent-decoded name after the `#`
+    pub rects: Vec<Rect>, // border boxes, one per line for a link that wraps
+}
+
+/// An element to scroll to: the one a fragment such as `#section-3` names, or the
+/// first one a selector matches.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScrollTarget {
+    Fragment(String), // percent-decoded, without the `#`
+    Selector(String), // checked to parse when the target was made
+}
+
+impl ScrollTarget {
+    /// A target written as `#fragment`, or as a selector; `None` if it's neither.
+    pub fn parse(target: &str) -> Option<ScrollTarget> {
+        match target.strip_prefix('#') {
+            Some(fragment) => Some(ScrollTarget::Fragment(percent_decode(fragment))),
+            None => css::parse_selector(target).map(|_| ScrollTarget::Selector(target.to_string())),
+        }
+    }
+
+    /// The top of the target in the layout tree, in px from the document origin.
+    pub fn top(&self, layout_root: &LayoutBox) -> Option<f32> {
+        match self {
+            ScrollTarget::Fragment(fragment) => Anchors::collect(layout_root).position(fragment),
+            ScrollTarget::Selector(selector) => {
+                let document = &layout_root.style_node()?.node;
+                let element = document.query_selector(&css::parse_selector(selector)?)?;
+                element_top(layout_root, element, 0.0)
+            }
+        }
+    }
+}
+
+// the top of the first box `element` generated, if it generated any
+fn element_top(layout_box: &LayoutBox, element: &Node, enclosing_top: f32) -> Option<f32> {
+    let top = box_top(layout_box).unwrap_or(enclosing_top);
+    if layout_box.style_node().is_some_and(|style| std::ptr::eq(&*style.node, element)) {
+        return Some(top);
+    }
+    layout_box.children.iter().find_map(|child| element_top(child, element, top))
+}
+
+impl fmt::Display for ScrollTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScrollTarget::Fragment(fragment) => write!(f, "#{}", fragment),
+            ScrollTarget::Selector(selector) => write!(f, "{}", selector),
+        }
+    }
+}
+
+/// Where the elements that fragments can name were laid out.
+#[derive(Debug, Clone, Default)]
+pub struct Anchors {
+    ids: HashMap<String, f32>,
+    names: HashMap<String, f32>, // from `<a name>`
+}
+
+impl Anchors {
+    /// Find the elements with an id and the `<a name>` anchors in the layout tree.
+    pub fn collect(layout_root: &LayoutBox) -> Anchors {
+        let mut anchors = Anchors::default();
+        anchors.collect_box(layout_root, 0.0);
+        anchors
+    }
+
+    // `enclosing_top` is where the nearest ancestor box that has a position starts
+    fn collect_box(&mut self, layout_box: &LayoutBox, enclosing_top: f32) {
+        let top = box_top(layout_box).unwrap_or(enclosing_top);
+        if let Some(style) = layout_box.style_node() {
+            if let NodeType::Element(ref elem) = style.node.node_type {
+                // the first element in document order with a name wins
+                if let Some(id) = elem.id() {
+                    self.ids.entry(id.clone()).or_insert(top);
+                }
+                if let ("a", Some(name)) = (elem.tag_name.as_str(), elem.attrs.get("name")) {
+                    self.names.entry(name.clone()).or_insert(top);
+                }
+            }
+        }
+        for child in &layout_box.children {
+            self.collect_box(child, top);
+        }
+    }
+
+    /// The top of the element `fragment` indicates, in px from the document origin.
+    ///
+    /// https://html.spec.whatwg.org/multipage/browsing-the-web.html#find-a-potential-indicated-element
+    ///
+    /// Ids match before `<a name>` anchors. An empty fragment, or `top` if no element
+    /// has that name, indicates the top of the document.
+    pub fn position(&self, fragment: &str) -> Option<f32> {
+        if let Some(&y) = self.ids.get(fragment).or_else(|| self.names.get(fragment)) {
+            return Some(y);
+        }
+        (fragment.is_empty() || fragment.eq_ignore_ascii_case("top")).then_some(0.0)
+    }
+}
+
+// where a box starts: the top of its border box, or of its first line for an inline
+// box, which has none if it's empty
+fn box_top(layout_box: &LayoutBox) -> Option<f32> {
+    match layout_box.box_type {
+        InlineNode(_) => layout_box.inline_fragments.first().map(|fragment| fragment.border_box.y),
+        _ => Some(layout_box.dimensions.border_box().y),
+    }
+}
+
+/// The links in the layout tree whose `href` is a fragment, in document order.
+pub fn fragment_links(layout_root: &LayoutBox) -> Vec<FragmentLink> {
+    fn collect(layout_box: &LayoutBox, links: &mut Vec<FragmentLink>) {
+        let href = layout_box.style_node().and_then(|style| match style.node.node_type {
+            NodeType::Element(ref elem) if elem.tag_name == "a" => elem.attrs.get("href"),
+            _ => None,
+        });
+        if let Some(fragment) = href.and_then(|href| href.strip_prefix('#')) {
+            let rects = match layout_box.box_type {
+                InlineNode(_) => layout_box.inline_fragments.iter().map(|f| f.border_box).collect(),
+                _ => vec![layout_box.dimensions.border_box()],
+            };
+            links.push(FragmentLink { fragment: percent_decode(fragment), rects });
+        }
+        for child in &layout_box.children {
+            collect(child, links);
+        }
+    }
+    let mut links = Vec::new();
+    collect(layout_root, &mut links);
+    links
+}
+
+// decode `%XX` escapes, keeping the fragment as written if that isn't UTF-8
+fn percent_decode(fragment: &str) -> String {
+    let bytes = fragment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let byte = match (bytes[i], fragment.get(i + 1..i + 3)) {
+            (b'%', Some(hex)) if hex.bytes().all(|b| b.is_ascii_hexdigit()) => u8::from_str_radix(hex, 16).ok(),
+            _ => None,
+        };
+        match byte {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).unwrap_or_else(|_| fragment.to_string())
+}
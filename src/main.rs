@@ -2,75 +2,822 @@
 // extern crate image;
 
 use std::default::Default;
-use std::io::{Read, BufWriter};
+use std::fmt;
+use std::io::{self, Read, Write};
 use std::fs::File;
+use std::process::ExitCode;
+use std::time::Instant;
+use rust_web_browser_from_scratch::{a11y, compare, css, dom, encoding, engine, focus, html, layout, links, media, style, table, template, painting, pdf, png, report, resource, selectors, stats};
+use report::{RenderReport, ReportEntry, Severity};
+use resource::ResourceLoader;
+
+// what to produce from the input document
+enum Format {
+    Render,      // PDF, PNG or PPM, chosen by the output file name
+    Outline,     // indented accessibility outline on stdout
+    OutlineJson, // the same outline as JSON on stdout
+    Focus,       // the tab order and where each element is, on stdout
+    FocusJson,   // the same as JSON
+}
+
+// how to print what the engine understood of the stylesheets
+enum CssReport {
+    Table,
+    Json,
+}
+
+struct Args {
+    positional: Vec<String>,
+    format: Format,
+    width: Option<f32>, // page size in px, unless the stylesheet's @page sets one
+    height: Option<f32>,
+    font_size: Option<f32>, // of `medium` text in px, 16 by default
+    minimum_font_size: Option<f32>,
+    zoom: Option<f32>, // px of output per px of the document
+    dark: bool, // render for a reader who prefers a dark color scheme
+    media_type: Option<media::MediaType>, // what `@media` queries see, print for PDF output by default
+    data: Option<String>, // JSON file to fill the document's `{{key}}` placeholders from
+    template: template::Options,
+    sanitize: bool, // strip scripts, event handlers and the like from an untrusted document
+    hover: Vec<css::Selector>, // style the elements these match as hovered
+    focus: Option<usize>, // style the element this far along the tab order, from 1, as focused
+    charset: Option<String>, // the encoding of the documents, as an HTTP charset would give it
+    debug_overlay: bool, // paint every box's margins, padding and content box over the page
+    no_clobber: bool, // refuse to replace an existing output file
+    profile: bool, // print how much the render handled at each stage
+    report_css: Option<CssReport>, // print how much of the stylesheets the engine understood
+    report: Option<String>, // write everything the render reported to this file, as JSON if it ends in .json
+    thumbnail: Option<u32>, // also write the first page this many px wide, next to the output
+    scroll_to: Option<links::ScrollTarget>, // start the output at this element
+    verbose: bool, // report the whole chain of causes of an error
+    hermetic: bool, // read nothing but the inputs given, within conservative limits
+}
+
+/// Everything that can stop the command line tool, each class with its own exit code:
+///
+/// | code | error                                            |
+/// |------|--------------------------------------------------|
+/// | 1    | usage: unknown option, bad option value          |
+/// | 2    | an input file is missing or unreadable           |
+/// | 3    | the template data doesn't parse                  |
+/// | 4    | rendering failed, e.g. a limit was exceeded      |
+/// | 5    | the output can't be written                      |
+#[derive(Debug)]
+enum CliError {
+    Usage(String),
+    Input { path: String, error: io::Error },
+    Parse(engine::EngineError),
+    Render(engine::EngineError),
+    Output { path: String, error: io::Error },
+}
+
+impl CliError {
+    fn exit_code(&self) -> u8 {
+        match self {
+            CliError::Usage(_) => 1,
+            CliError::Input { .. } => 2,
+            CliError::Parse(_) => 3,
+            CliError::Render(_) => 4,
+            CliError::Output { .. } => 5,
+        }
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliError::Usage(message) => write!(f, "usage: {}", message),
+            CliError::Input { path, .. } => write!(f, "can't read {}", path),
+            CliError::Parse(_) => write!(f, "can't parse the input"),
+            CliError::Render(_) => write!(f, "rendering failed"),
+            CliError::Output { path, .. } => write!(f, "can't write {}", path),
+        }
+    }
+}
+
+impl std::error::Error for CliError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CliError::Input { error, .. } | CliError::Output { error, .. } => Some(error),
+            CliError::Parse(error) | CliError::Render(error) => Some(error),
+            CliError::Usage(_) => None,
+        }
+    }
+}
+
+impl From<engine::EngineError> for CliError {
+    fn from(error: engine::EngineError) -> CliError {
+        match error {
+            engine::EngineError::Parse(_) => CliError::Parse(error),
+            _ => CliError::Render(error),
+        }
+    }
+}
+
+
+// Simplified version of getopts: `--flag value` options anywhere, positional arguments otherwise
+fn parse_args() -> Result<Args, CliError> {
+    let mut args = Args {
+        positional: Vec::new(),
+        format: Format::Render,
+        width: None,
+        height: None,
+        font_size: None,
+        minimum_font_size: None,
+        zoom: None,
+        dark: false,
+        media_type: None,
+        data: None,
+        template: Default::default(),
+        sanitize: false,
+        hover: Vec::new(),
+        focus: None,
+        charset: None,
+        debug_overlay: false,
+        no_clobber: false,
+        profile: false,
+        report_css: None,
+        report: None,
+        thumbnail: None,
+        scroll_to: None,
+        verbose: false,
+        hermetic: false,
+    };
+    let mut iter = std::env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--format" => {
+                args.format = match iter.next().as_deref() {
+                    Some("pdf") | Some("png") => Format::Render,
+                    Some("outline") => Format::Outline,
+                    Some("outline-json") => Format::OutlineJson,
+                    Some("focus") => Format::Focus,
+                    Some("focus-json") => Format::FocusJson,
+                    other => return Err(usage(format!("unknown --format {:?}, expected pdf, outline, outline-json, focus or focus-json", other)))
+                }
+            }
+            "--width" => args.width = Some(parse_px(iter.next(), "--width")?),
+            "--height" => args.height = Some(parse_px(iter.next(), "--height")?),
+            "--font-size" => args.font_size = Some(parse_px(iter.next(), "--font-size")?),
+            "--minimum-font-size" => args.minimum_font_size = Some(parse_px(iter.next(), "--minimum-font-size")?),
+            "--zoom" => {
+                let factor = iter.next();
+                match factor.as_deref().map(str::parse::<f32>) {
+                    Some(Ok(zoom)) if (0.01..=100.0).contains(&zoom) => args.zoom = Some(zoom),
+                    _ => return Err(usage(format!("--zoom expects a factor from 0.01 to 100, got {:?}", factor)))
+                }
+            }
+            "--sanitize" => args.sanitize = true,
+            "--dark" => args.dark = true,
+            "--media-type" => {
+                let media_type = iter.next();
+                match media_type.as_deref().and_then(media::MediaType::from_name) {
+                    Some(media_type) => args.media_type = Some(media_type),
+                    None => return Err(usage(format!("unknown --media-type {:?}, expected screen or print", media_type)))
+                }
+            }
+            "--debug-overlay" => args.debug_overlay = true,
+            "--no-clobber" => args.no_clobber = true,
+            "--profile" => args.profile = true,
+            "--hermetic" => args.hermetic = true,
+            "--report-css" => args.report_css = Some(CssReport::Table),
+            "--report-css-json" => args.report_css = Some(CssReport::Json),
+            "--report" => args.report = Some(iter.next().ok_or_else(|| usage("--report expects a file to write".to_string()))?),
+            "--thumbnail" => {
+                let width = iter.next();
+                match width.as_deref().map(str::parse::<u32>) {
+                    Some(Ok(width)) if width > 0 => args.thumbnail = Some(width),
+                    _ => return Err(usage(format!("--thumbnail expects a width in px, got {:?}", width)))
+                }
+            }
+            "--hover" => {
+                let selector = iter.next();
+                match selector.as_deref().and_then(css::parse_selector) {
+                    Some(selector) => args.hover.push(selector),
+                    None => return Err(usage(format!("--hover expects a selector, got {:?}", selector)))
+                }
+            }
+            "--focus" => {
+                let index = iter.next();
+                match index.as_deref().map(str::parse::<usize>) {
+                    Some(Ok(index)) if index > 0 => args.focus = Some(index),
+                    _ => return Err(usage(format!("--focus expects a position in the tab order from 1, got {:?}", index)))
+                }
+            }
+            "--scroll-to" => {
+                let target = iter.next();
+                match target.as_deref().and_then(links::ScrollTarget::parse) {
+                    Some(target) => args.scroll_to = Some(target),
+                    None => return Err(usage(format!("--scroll-to expects a selector or #fragment, got {:?}", target)))
+                }
+            }
+            "--charset" => {
+                let label = iter.next();
+                match label.as_deref().and_then(encoding::Encoding::from_label) {
+                    Some(_) => args.charset = label,
+                    None => return Err(usage(format!("--charset expects a supported encoding, got {:?}", label)))
+                }
+            }
+            "-v" | "--verbose" => args.verbose = true,
+            "--data" => args.data = Some(iter.next().ok_or_else(|| usage("--data expects a JSON file".to_string()))?),
+            "--missing-keys" => {
+                args.template.missing_keys = match iter.next().as_deref() {
+                    Some("keep") => template::MissingKeys::Keep,
+                    Some("empty") => template::MissingKeys::Empty,
+                    other => return Err(usage(format!("unknown --missing-keys {:?}, expected keep or empty", other)))
+                }
+            }
+            _ if arg.starts_with("--") => return Err(usage(format!("unknown option {}", arg))),
+            _ => args.positional.push(arg)
+        }
+    }
+    if args.positional.first().is_some_and(|command| command == "diff") {
+        if !(3..=4).contains(&args.positional.len()) {
+            return Err(usage("expected diff old.html new.html [css] besides options".to_string()));
+        }
+    } else if args.positional.first().is_some_and(|command| command == "css-min") {
+        if args.positional.len() != 2 {
+            return Err(usage("expected css-min input.css besides options".to_string()));
+        }
+    } else if args.positional.len() > 3 {
+        return Err(usage("expected at most [html [css [output]]] besides options".to_string()));
+    }
+    Ok(args)
+}
+
+// the render options the command line sets, besides the viewport
+fn render_options(args: &Args) -> engine::RenderOptions {
+    let defaults = match args.hermetic {
+        true => engine::RenderOptions::hermetic(),
+        false => engine::RenderOptions::default(),
+    };
+    engine::RenderOptions {
+        default_font_size: args.font_size.unwrap_or(defaults.default_font_size),
+        minimum_font_size: args.minimum_font_size.unwrap_or(defaults.minimum_font_size),
+        zoom: args.zoom.unwrap_or(defaults.zoom),
+        color_scheme: if args.dark { media::ColorScheme::Dark } else { media::ColorScheme::Light },
+        media_type: args.media_type.unwrap_or(defaults.media_type),
+        debug_overlay: args.debug_overlay,
+        scroll_to: args.scroll_to.clone(),
+        ..defaults
+    }
+}
+
+fn usage(message: String) -> CliError {
+    CliError::Usage(message)
+}
+
+fn parse_px(value: Option<String>, flag: &str) -> Result<f32, CliError> {
+    match value.as_deref().map(str::parse::<f32>) {
+        Some(Ok(px)) if px >= 0.0 => Ok(px),
+        _ => Err(usage(format!("{} expects a size in px, got {:?}", flag, value)))
+    }
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(error) => return report(&error, false),
+    };
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => report(&error, args.verbose),
+    }
+}
+
+// print a one-line message for the error and its immediate cause, or with
+// `verbose` the whole chain of causes a line each, and give its exit code
+fn report(error: &CliError, verbose: bool) -> ExitCode {
+    let cause = std::error::Error::source(error);
+    match cause {
+        Some(cause) if !verbose => eprintln!("Error: {}: {}", error, cause),
+        _ => eprintln!("Error: {}", error),
+    }
+    let mut source = cause;
+    while let Some(cause) = source.filter(|_| verbose) {
+        eprintln!("  caused by: {}", cause);
+        source = cause.source();
+    }
+    ExitCode::from(error.exit_code())
+}
+
+fn run(args: &Args) -> Result<(), CliError> {
+    if args.positional.first().is_some_and(|command| command == "diff") {
+        return run_diff(args);
+    }
+    if args.positional.first().is_some_and(|command| command == "css-min") {
+        return run_css_min(args);
+    }
 
-pub mod css;
-pub mod dom;
-pub mod html;
-pub mod layout;
-pub mod style;
-pub mod painting;
-pub mod pdf;
-
-fn main() {
-    // Simplified version - using hardcoded defaults due to dependency issues
-    let args: Vec<String> = std::env::args().collect();
-    
     // Default file paths
-    let html_file = if args.len() > 1 { &args[1] } else { "examples/test.html" };
-    let css_file = if args.len() > 2 { &args[2] } else { "examples/test.css" };
-    let output_file = if args.len() > 3 { &args[3] } else { "output.pdf" };
-    
+    let html_file = args.positional.first().map_or("examples/test.html", |s| s.as_str());
+    // a document that styles itself needs no CSS file, so given two paths, the
+    // second is the output if it names one: `page.html page.pdf`
+    let (css_file, output_file) = match args.positional.get(1..).unwrap_or_default() {
+        [output] if is_output_path(output) => (None, output.as_str()),
+        rest => (Some(rest.first().map_or("examples/test.css", |s| s.as_str())), rest.get(1).map_or("output.pdf", |s| s.as_str())),
+    };
+
+    let mut options = render_options(args);
+    let parser_options = html::ParserOptions { limits: options.limits.clone(), ..Default::default() };
+    let mut page = pdf::Options::default();
+    page.page_width = args.width.unwrap_or(page.page_width);
+    page.page_height = args.height.unwrap_or(page.page_height);
+    page.debug_overlay = args.debug_overlay;
+
+    if let Format::Outline | Format::OutlineJson = args.format {
+        let mut report = RenderReport::default();
+        let (root_node, quirks_mode) = parse_document(&read_document(html_file, args)?, parser_options, args, &mut report)?;
+        let stylesheets = stylesheets(css_file, Some((&root_node, html_file)), &options.limits, args, &mut report)?;
+        let style_context = style::StyleContext {
+            viewport_width: page.page_width,
+            viewport_height: page.page_height,
+            quirks_mode,
+            ..options.style_context()
+        };
+        let style_root = style::style_tree_with_state(&root_node, &stylesheets, &style_context, &pseudo_class_state(&root_node, args, &mut report));
+        let outline = a11y::build_tree(&style_root);
+        match args.format {
+            Format::OutlineJson => println!("{}", outline.to_json()),
+            _ => print!("{}", outline.to_outline()),
+        }
+        return Ok(());
+    }
+
+    if let Format::Focus | Format::FocusJson = args.format {
+        // laid out as for PDF output, so the rects are where the elements are in the
+        // pages' content, as one continuous flow
+        let mut report = RenderReport::default();
+        let (root_node, quirks_mode) = parse_document(&read_document(html_file, args)?, parser_options, args, &mut report)?;
+        let stylesheets = stylesheets(css_file, Some((&root_node, html_file)), &options.limits, args, &mut report)?;
+        page.apply_stylesheets(&stylesheets);
+        options.width = page.content_width();
+        options.height = page.content_height();
+        options.media_type = args.media_type.unwrap_or(media::MediaType::Print);
+        let style_context = style::StyleContext { quirks_mode, ..options.style_context() };
+        let style_root = style::style_tree_with_state(&root_node, &stylesheets, &style_context, &pseudo_class_state(&root_node, args, &mut report));
+        (options.loader, options.document_url) = document_resources(html_file, args);
+        let layout_root = engine::layout(&style_root, &options)?;
+        let focus = focus::FocusReport::of(&root_node, &layout_root);
+        match args.format {
+            Format::FocusJson => println!("{}", focus.to_json()),
+            _ => print!("{}", focus),
+        }
+        return Ok(());
+    }
+
+    let raster = RasterFormat::of(output_file);
+
+    // fail before doing any work if the output can't be written anyway
+    let output_error = |error| CliError::Output { path: output_file.to_string(), error };
+    let output_path = std::path::Path::new(output_file);
+    if args.no_clobber && output_path.exists() {
+        return Err(output_error(clobber_error()));
+    }
+    if let Some(dir) = output_path.parent().filter(|dir| !dir.as_os_str().is_empty() && !dir.is_dir()) {
+        let error = io::Error::new(io::ErrorKind::NotFound, format!("there's no directory {}", dir.display()));
+        return Err(output_error(error));
+    }
+
     println!("Parsing HTML: {}", html_file);
-    println!("Parsing CSS: {}", css_file);
+    if let Some(css_file) = css_file {
+        println!("Parsing CSS: {}", css_file);
+    }
     println!("Output: {}", output_file);
 
     // Read input files:
-    let html = read_source(html_file.to_string());
-    let css  = read_source(css_file.to_string());
-
-    // Since we don't have an actual window, hard-code the "viewport" size.
-    let mut viewport: layout::Dimensions = Default::default();
-    viewport.content.width  = 800.0;
-    viewport.content.height = 600.0;
-
-    // Parsing and rendering:
-    let root_node = html::parse(html);
-    let stylesheet = css::parse(css, css::Origin::Author);
-    let stylesheets = [stylesheet];
-    let style_root = style::style_tree(&root_node, &stylesheets);
-    let layout_root = layout::layout_tree(&style_root, viewport);
-
-    // Create the output file:
-    let png = output_file.ends_with(".png");
-    let filename = output_file.to_string();
-    let mut file = BufWriter::new(File::create(&filename).unwrap());
-
-    // Write to the file:
-    let ok = if png {
-        // Temporarily disabled PNG output due to image crate dependency issues
-        println!("PNG output temporarily disabled - use PDF format instead");
-        false
-        // let canvas = painting::paint(&layout_root, viewport.content);
-        // let (w, h) = (canvas.width as u32, canvas.height as u32);
-        // let img = image::ImageBuffer::from_fn(w, h, move |x, y| {
-        //     let color = canvas.pixels[(y * w + x) as usize];
-        //     image::Rgba([color.r, color.g, color.b, color.a])
-        // });
-        // image::DynamicImage::ImageRgba8(img).write_to(&mut file, image::ImageFormat::Png).is_ok()
-    } else {
-        pdf::render(&layout_root, viewport.content, &mut file).is_ok()
+    let html = read_document(html_file, args)?;
+
+    // Parsing and rendering, timing each stage and counting its allocations if built to:
+    let mut report = RenderReport::default();
+    let mut timings = Vec::new();
+    let mut allocations = Vec::new();
+    let mut started = Instant::now();
+    let mut allocated = stats::allocations();
+    let mut end_stage = |stage| {
+        timings.push((stage, started.elapsed()));
+        started = Instant::now();
+        let now = stats::allocations();
+        if let (Some(before), Some(after)) = (allocated, now) {
+            allocations.push((stage, after - before));
+        }
+        allocated = now;
+    };
+    let (root_node, quirks_mode) = parse_document(&html, parser_options, args, &mut report)?;
+    let stylesheets = stylesheets(css_file, Some((&root_node, html_file)), &options.limits, args, &mut report)?;
+    end_stage("parse");
+
+    // Since we don't have an actual window, the "viewport" is the content area of a page,
+    // and pages are printed; an image is of a screen the size of the viewport.
+    match raster {
+        Some(_) => {
+            options.width = args.width.unwrap_or(options.width);
+            options.height = args.height.unwrap_or(options.height);
+            options.media_type = args.media_type.unwrap_or(media::MediaType::Screen);
+        }
+        None => {
+            page.apply_stylesheets(&stylesheets);
+            options.width = page.content_width();
+            options.height = page.content_height();
+            options.media_type = args.media_type.unwrap_or(media::MediaType::Print);
+        }
+    }
+    let style_context = style::StyleContext { quirks_mode, ..options.style_context() };
+    let style_root = style::style_tree_with_state(&root_node, &stylesheets, &style_context, &pseudo_class_state(&root_node, args, &mut report));
+    end_stage("style");
+    // images are found next to the document
+    (options.loader, options.document_url) = document_resources(html_file, args);
+    let (layout_root, image_warnings) = engine::layout_with_warnings(&style_root, &options)?;
+    end_stage("layout");
+    for warning in image_warnings.into_iter().chain(table::span_warnings(&layout_root)) {
+        log(&mut report, ReportEntry::new(Severity::Warning, "layout", warning));
+    }
+    // printed as a count, but reported box by box
+    let overflowing = layout_root.overflowing_boxes();
+    match overflowing.len() {
+        0 => {}
+        1 => eprintln!("Note: 1 box overflows its containing block"),
+        n => eprintln!("Note: {} boxes overflow their containing block", n)
+    }
+    let nodes: Vec<&dom::Node> = overflowing.iter().filter_map(|layout_box| layout_box.style_node()).map(|styled| &*styled.node).collect();
+    let mut dom_paths = report::dom_paths(&root_node, &nodes).into_iter();
+    for layout_box in &overflowing {
+        // an anonymous box is about no element
+        let dom_path = layout_box.style_node().and_then(|_| dom_paths.next().flatten());
+        report.push(ReportEntry::new(Severity::Note, "layout", "the box overflows its containing block").on(dom_path));
+    }
+    // the first page starts at the element, as if the page were scrolled to it
+    if let Some(ref target) = args.scroll_to {
+        match target.top(&layout_root) {
+            Some(_) => page.start = engine::scroll_offset(&layout_root, &options),
+            None => log(&mut report, ReportEntry::new(Severity::Warning, "layout", format!("no element matches --scroll-to {}", target))),
+        }
+    }
+
+    // an image is of the viewport, scrolled to `--scroll-to` if it's given; however
+    // tall the document, what's painted is only as large as `--width` and `--height`
+    if let Some(format) = raster {
+        let bounds = layout::Rect { x: 0.0, y: page.start, width: options.width, height: options.height };
+        let canvas = painting::paint_with_blending(&layout_root, bounds, options.blending, &options.limits)?;
+        let bytes = match format {
+            RasterFormat::Png => {
+                let mut png = Vec::new();
+                png::write(&canvas, &mut png).map_err(output_error)?;
+                png
+            }
+            RasterFormat::Ppm => canvas.to_ppm(),
+        };
+        end_stage("paint");
+        if args.profile || args.report.is_some() {
+            let mut stats = stats::RenderStats::default();
+            stats.count_document(&root_node);
+            stats.count_stylesheets(&stylesheets);
+            stats.count_styled_nodes(&style_root);
+            stats.count_layout_boxes(&layout_root);
+            stats.count_display_list(&painting::build_display_list_in(&layout_root, bounds));
+            stats.peak_canvas_bytes = canvas.pixels.len() * std::mem::size_of::<css::Color>();
+            stats.allocations = allocations;
+            if args.profile {
+                println!("{}", stats);
+            }
+            report.stats = Some(stats);
+        }
+        write_output(output_file, &bytes, args.no_clobber).map_err(output_error)?;
+        println!("Saved output as {}", output_file);
+        report.timings = timings;
+        write_report(&report, args)?;
+        if let Some(width) = args.thumbnail {
+            write_thumbnail(output_path, &canvas, width, args)?;
+        }
+        return Ok(());
+    }
+
+    // Encode the whole file before touching the output, so that a failure leaves
+    // what was there before:
+    let mut pdf_bytes = io::Cursor::new(Vec::new());
+    let mut rendered = pdf::render_with_options(&layout_root, &page, &options.limits, &mut pdf_bytes).map_err(|error| match error {
+        pdf::Error::Engine(error) => CliError::from(error),
+        pdf::Error::Io(error) => output_error(error),
+    })?;
+    end_stage("paint");
+    for entry in rendered.report.entries {
+        log(&mut report, entry);
+    }
+    if args.profile || args.report.is_some() {
+        rendered.stats.count_document(&root_node);
+        rendered.stats.count_stylesheets(&stylesheets);
+        rendered.stats.allocations = allocations;
+        if args.profile {
+            println!("{}", rendered.stats);
+        }
+        report.stats = Some(rendered.stats);
+    }
+    write_output(output_file, pdf_bytes.get_ref(), args.no_clobber).map_err(output_error)?;
+    println!("Saved output as {}", output_file);
+    report.timings = timings;
+    write_report(&report, args)?;
+
+    if let Some(width) = args.thumbnail {
+        let first_page = layout::Rect { x: 0.0, y: page.start, width: page.content_width(), height: page.content_height() };
+        write_thumbnail(output_path, &painting::paint(&layout_root, first_page, &options.limits)?, width, args)?;
+    }
+    Ok(())
+}
+
+// the raster images an output file can be, by its extension
+#[derive(Clone, Copy)]
+enum RasterFormat {
+    Png,
+    Ppm,
+}
+
+impl RasterFormat {
+    fn of(output_file: &str) -> Option<RasterFormat> {
+        let extension = std::path::Path::new(output_file).extension()?.to_str()?;
+        match extension.to_ascii_lowercase().as_str() {
+            "png" => Some(RasterFormat::Png),
+            "ppm" => Some(RasterFormat::Ppm),
+            _ => None,
+        }
+    }
+}
+
+// whether `path` names an output file, which is a PDF or an image
+fn is_output_path(path: &str) -> bool {
+    let pdf = std::path::Path::new(path).extension().is_some_and(|extension| extension.eq_ignore_ascii_case("pdf"));
+    pdf || RasterFormat::of(path).is_some()
+}
+
+// `--thumbnail`: `canvas` scaled down to `width`, as a PPM next to the output
+fn write_thumbnail(output_path: &std::path::Path, canvas: &painting::Canvas, width: u32, args: &Args) -> Result<(), CliError> {
+    let thumbnail_file = output_path.with_extension("thumb.ppm").to_string_lossy().into_owned();
+    let thumbnail = painting::downsample(canvas, width);
+    write_output(&thumbnail_file, &thumbnail.to_ppm(), args.no_clobber)
+        .map_err(|error| CliError::Output { path: thumbnail_file.clone(), error })?;
+    println!("Saved thumbnail as {}", thumbnail_file);
+    Ok(())
+}
+
+// `css-min input.css`: print the stylesheet minified, without its imports
+fn run_css_min(args: &Args) -> Result<(), CliError> {
+    let css_file = &args.positional[1];
+    let source = encoding::decode_stylesheet(&read_bytes(css_file)?);
+    let stylesheet = css::parse_with_limits(source, css::Origin::Author, &render_options(args).limits)?;
+    println!("{}", css::minify(&stylesheet));
+    Ok(())
+}
+
+// `diff old.html new.html [css]`: render both documents with the same stylesheet
+// and viewport, and report how their layout trees and the pixels in view differ
+fn run_diff(args: &Args) -> Result<(), CliError> {
+    let (old_file, new_file) = (&args.positional[1], &args.positional[2]);
+    let css_file = args.positional.get(3).map_or("examples/test.css", |s| s.as_str());
+
+    let mut options = render_options(args);
+    options.width = args.width.unwrap_or(options.width);
+    options.height = args.height.unwrap_or(options.height);
+    let parser_options = html::ParserOptions { limits: options.limits.clone(), ..Default::default() };
+    let mut report = RenderReport::default();
+    let stylesheets = stylesheets(Some(css_file), None, &options.limits, args, &mut report)?;
+    let style_context = |quirks_mode| style::StyleContext { quirks_mode, ..options.style_context() };
+
+    let (old_root, old_mode) = parse_document(&read_document(old_file, args)?, parser_options.clone(), args, &mut report)?;
+    let (new_root, new_mode) = parse_document(&read_document(new_file, args)?, parser_options, args, &mut report)?;
+    let old_style = style::style_tree_with_state(&old_root, &stylesheets, &style_context(old_mode), &pseudo_class_state(&old_root, args, &mut report));
+    let new_style = style::style_tree_with_state(&new_root, &stylesheets, &style_context(new_mode), &pseudo_class_state(&new_root, args, &mut report));
+    let resources = |file| {
+        let (loader, document_url) = document_resources(file, args);
+        engine::RenderOptions { loader, document_url, ..options.clone() }
+    };
+    let old_layout = engine::layout(&old_style, &resources(old_file))?;
+    let new_layout = engine::layout(&new_style, &resources(new_file))?;
+
+    let diffs = compare::diff_layout(&old_layout, &new_layout);
+    match diffs.len() {
+        0 => println!("Layout: no differences"),
+        1 => println!("Layout: 1 difference"),
+        n => println!("Layout: {} differences", n)
+    }
+    for diff in &diffs {
+        println!("  {}", diff);
+    }
+
+    let viewport = layout::Rect { x: 0.0, y: 0.0, width: options.width, height: options.height };
+    let old_canvas = painting::paint_with_blending(&old_layout, viewport, options.blending, &options.limits)?;
+    let new_canvas = painting::paint_with_blending(&new_layout, viewport, options.blending, &options.limits)?;
+    println!("Pixels: {}", compare::diff_canvas(&old_canvas, &new_canvas, 0));
+    Ok(())
+}
+
+// what loads the resources `file` refers to, which may only be in its directory or
+// below, and the URL of `file` itself that they're relative to
+fn file_resources(file: &str) -> (resource::FsLoader, resource::ResolvedUrl) {
+    let path = std::path::Path::new(file);
+    let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let directory = path.parent().map(|dir| dir.to_path_buf()).unwrap_or_default();
+    (resource::FsLoader::new(directory), resource::ResolvedUrl::local(&name))
+}
+
+// what loads the resources the document in `file` refers to, as `file_resources`
+// does, or with --hermetic nothing at all, and the URL they're relative to
+fn document_resources(file: &str, args: &Args) -> (std::sync::Arc<dyn ResourceLoader>, resource::ResolvedUrl) {
+    let (loader, document_url) = file_resources(file);
+    match args.hermetic {
+        true => (std::sync::Arc::new(resource::DenyAllLoader), document_url),
+        false => (std::sync::Arc::new(loader), document_url),
+    }
+}
+
+// mark the elements matching a --hover selector, and their ancestors, as hovered,
+// and the --focus element as focused
+fn pseudo_class_state(root_node: &dom::Node, args: &Args, report: &mut RenderReport) -> selectors::PseudoClassState {
+    fn mark(node: &dom::Node, hovered: &[&dom::Node], state: &mut selectors::PseudoClassState) -> bool {
+        let mut is_hovered = hovered.iter().any(|&h| std::ptr::eq(h, node));
+        for child in &node.children {
+            is_hovered |= mark(child, hovered, state);
+        }
+        if is_hovered {
+            state.insert(node, selectors::PseudoClass::Hover);
+        }
+        is_hovered
+    }
+    let mut state = selectors::PseudoClassState::default();
+    let hovered: Vec<&dom::Node> = args.hover.iter().flat_map(|selector| root_node.query_selector_all(selector)).collect();
+    if !hovered.is_empty() {
+        mark(root_node, &hovered, &mut state);
+    }
+    if let Some(index) = args.focus {
+        match focus::tab_order(root_node).get(index - 1) {
+            Some(focused) => state.insert(focused, selectors::PseudoClass::Focus),
+            None => log(report, ReportEntry::new(Severity::Warning, "style", format!("--focus {} is past the end of the tab order", index))),
+        }
+    }
+    state
+}
+
+// parse the document, fill it in if the command line gave template data, and sanitize
+// it if asked; returns it with the mode to style it in
+fn parse_document(
+    source: &str,
+    parser_options: html::ParserOptions,
+    args: &Args,
+    report: &mut RenderReport
+) -> Result<(dom::Node, dom::QuirksMode), CliError> {
+    // malformed markup is read the way browsers read it, with a warning for each error
+    let html::ParsedDocument { root: mut root_node, quirks_mode, diagnostics } = html::parse_document(source, parser_options)?;
+    for error in diagnostics {
+        log(report, ReportEntry::new(Severity::Warning, "parse", error.to_string()));
+    }
+    if let Some(ref data_file) = args.data {
+        let data = template::Json::parse(&read_source(data_file)?).map_err(engine::EngineError::from)?;
+        template::apply(&mut root_node, &data, &args.template);
+    }
+    // after filling in the template, so data can't bring in anything unsafe either
+    if args.sanitize {
+        let sanitized = dom::sanitize(&mut root_node, &dom::SanitizePolicy::default());
+        for name in &sanitized.removed_elements {
+            log(report, ReportEntry::new(Severity::Note, "parse", format!("sanitizing removed <{}>", name)));
+        }
+        for (element, attribute) in &sanitized.removed_attributes {
+            log(report, ReportEntry::new(Severity::Note, "parse", format!("sanitizing removed {} from <{}>", attribute, element)));
+        }
+    }
+    Ok((root_node, quirks_mode))
+}
+
+// parse and validate the author stylesheets, reporting what the engine won't use:
+// after the user agent stylesheet, those of the document, as `document` has its
+// root and path, in document order, then the one in `css_file`, each preceded by
+// the stylesheets it imports (and they import) and named by its path so warnings
+// say which file they're about
+fn stylesheets(
+    css_file: Option<&str>,
+    document: Option<(&dom::Node, &str)>,
+    limits: &engine::Limits,
+    args: &Args,
+    report: &mut RenderReport
+) -> Result<Vec<css::Stylesheet>, CliError> {
+    let css_source = match css_file {
+        Some(css_file) => Some(encoding::decode_stylesheet(&read_bytes(css_file)?)),
+        None => None,
     };
-    if ok {
-        println!("Saved output as {}", filename)
-    } else {
-        println!("Error saving output as {}", filename)
+    let mut loader = engine::StylesheetLoader::new(limits);
+    let reported = report.entries.len();
+    let loaded = load_stylesheets(&mut loader, css_file.zip(css_source), document, args, report);
+    // print what loading them reported, as the other stages do as they go
+    for entry in &report.entries[reported..] {
+        eprintln!("{}", entry);
+    }
+    loaded?;
+    match args.report_css {
+        Some(CssReport::Table) => print!("{}", report.css_coverage),
+        Some(CssReport::Json) => println!("{}", report.css_coverage.to_json()),
+        None => {}
+    }
+    let mut stylesheets = vec![css::default_user_agent_stylesheet()];
+    stylesheets.append(&mut loader.stylesheets);
+    Ok(stylesheets)
+}
+
+// load the stylesheets of `document`, then the one at the path with the source in
+// `css_file`; what each loads besides, its imports and linked stylesheets, is found
+// next to it, and refused with --hermetic, as a document's images are
+fn load_stylesheets(
+    loader: &mut engine::StylesheetLoader,
+    css_file: Option<(&str, String)>,
+    document: Option<(&dom::Node, &str)>,
+    args: &Args,
+    report: &mut RenderReport
+) -> Result<(), engine::EngineError> {
+    fn resources<'a>(files: &'a resource::FsLoader, args: &Args) -> engine::StylesheetResources<'a> {
+        engine::StylesheetResources {
+            loader: if args.hermetic { &resource::DenyAllLoader } else { files },
+            root: files.root(),
+        }
+    }
+    if let Some((root_node, html_file)) = document {
+        let (files, url) = file_resources(html_file);
+        loader.load_document(root_node, &url, html_file, resources(&files, args), report)?;
+    }
+    if let Some((css_file, source)) = css_file {
+        let (files, url) = file_resources(css_file);
+        let name = files.root().join(url.as_str()).display().to_string();
+        loader.load(source, &url, name, resources(&files, args), report)?;
     }
+    Ok(())
 }
 
-fn read_source(filename: String) -> String {
+// print what a stage reported as it happens, and keep it for `--report`
+fn log(report: &mut RenderReport, entry: ReportEntry) {
+    eprintln!("{}", entry);
+    report.push(entry);
+}
+
+// write the render report to `--report`, as JSON if the file ends in .json
+fn write_report(report: &RenderReport, args: &Args) -> Result<(), CliError> {
+    let path = match args.report {
+        Some(ref path) => path,
+        None => return Ok(()),
+    };
+    let contents = match path.ends_with(".json") {
+        true => report.to_json() + "\n",
+        false => report.to_string(),
+    };
+    write_output(path, contents.as_bytes(), args.no_clobber)
+        .map_err(|error| CliError::Output { path: path.clone(), error })?;
+    println!("Saved the report as {}", path);
+    Ok(())
+}
+
+// Write `contents` to a temporary file next to `path`, then move it over `path`, so
+// nothing ever sees a partly written file and a failure leaves `path` as it was.
+// With `no_clobber`, an existing file at `path` is an error instead.
+fn write_output(path: &str, contents: &[u8], no_clobber: bool) -> io::Result<()> {
+    let path = std::path::Path::new(path);
+    let file_name = path.file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "the path doesn't name a file"))?;
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    let temp_path = path.with_file_name(temp_name);
+    let result = File::options().write(true).create_new(true).open(&temp_path)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()?;
+            match no_clobber {
+                // unlike renaming, linking fails if the target exists
+                true => std::fs::hard_link(&temp_path, path).map_err(|error| match error.kind() {
+                    io::ErrorKind::AlreadyExists => clobber_error(),
+                    _ => error,
+                }),
+                false => std::fs::rename(&temp_path, path),
+            }
+        });
+    // after a rename there's nothing left to remove
+    if result.is_err() || no_clobber {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result
+}
+
+fn clobber_error() -> io::Error {
+    io::Error::new(io::ErrorKind::AlreadyExists, "the file exists and --no-clobber was given")
+}
+
+fn read_source(filename: &str) -> Result<String, CliError> {
     let mut str = String::new();
-    File::open(filename).unwrap().read_to_string(&mut str).unwrap();
-    str
-}
\ No newline at end of file
+    File::open(filename)
+        .and_then(|mut file| file.read_to_string(&mut str))
+        .map_err(|error| CliError::Input { path: filename.to_string(), error })?;
+    Ok(str)
+}
+
+// an HTML document, decoded as its byte order mark, `--charset` or <meta> says
+fn read_document(filename: &str, args: &Args) -> Result<String, CliError> {
+    let (html, _) = encoding::decode_document(&read_bytes(filename)?, args.charset.as_deref());
+    Ok(html)
+}
+
+fn read_bytes(filename: &str) -> Result<Vec<u8>, CliError> {
+    std::fs::read(filename).map_err(|error| CliError::Input { path: filename.to_string(), error })
+}
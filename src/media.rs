@@ -0,0 +1,220 @@
+use crate::css::Unit;
+use std::fmt;
+
+// Media queries, which make the rules in `@media` blocks depend on what the document
+// is rendered for: paper or a screen, the size of the page or viewport, and the
+// color scheme the reader prefers.
+
+/// The kind of device a document is rendered for, which media types test.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum MediaType {
+    #[default]
+    Screen,
+    Print, // paged output, like PDF
+}
+
+impl MediaType {
+    // `screen` or `print`, as in a media query
+    pub fn from_name(name: &str) -> Option<MediaType> {
+        match name.to_ascii_lowercase().as_str() {
+            "screen" => Some(MediaType::Screen),
+            "print" => Some(MediaType::Print),
+            _ => None,
+        }
+    }
+}
+
+/// The color scheme a reader prefers, which `prefers-color-scheme` tests.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ColorScheme {
+    #[default]
+    Light,
+    Dark,
+}
+
+/// What media queries are evaluated against.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MediaContext {
+    pub media_type: MediaType,
+    pub width: f32, // of the viewport, or the page's content box for print, in px
+    pub height: f32,
+    pub color_scheme: ColorScheme,
+}
+
+impl Default for MediaContext {
+    fn default() -> MediaContext {
+        MediaContext { media_type: MediaType::Screen, width: 800.0, height: 600.0, color_scheme: ColorScheme::Light }
+    }
+}
+
+/// The condition of an `@media` rule: a comma-separated list of queries, which
+/// matches if any of them does.
+///
+/// https://www.w3.org/TR/mediaqueries-4/#mq-syntax
+///
+/// A query this parser doesn't understand, such as one using range syntax, matches
+/// nothing, as the spec has invalid queries do. An empty list matches everything.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaQueryList {
+    source: String, // as written, for writing the stylesheet back out
+    queries: Vec<MediaQuery>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct MediaQuery {
+    negated: bool, // `not`, which inverts the whole query
+    media_type: Option<String>, // `screen`, `print` or `all`
+    features: Vec<MediaFeature>, // joined by `and`
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum MediaFeature {
+    Width(Range, f32),
+    Height(Range, f32),
+    ColorScheme(Option<ColorScheme>), // `None` for the boolean form, which always matches
+    Orientation(bool), // portrait
+    Unknown, // never matches
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Range {
+    Min,
+    Max,
+    Exact,
+}
+
+impl MediaQueryList {
+    pub fn parse(source: &str) -> MediaQueryList {
+        let source = source.trim();
+        let queries = match source.is_empty() {
+            true => Vec::new(),
+            false => source.split(',').map(|query| parse_query(query).unwrap_or(MediaQuery::NOTHING)).collect(),
+        };
+        MediaQueryList { source: source.to_string(), queries }
+    }
+
+    pub fn matches(&self, context: &MediaContext) -> bool {
+        self.queries.is_empty() || self.queries.iter().any(|query| query.matches(context))
+    }
+}
+
+impl fmt::Display for MediaQueryList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl MediaQuery {
+    // `not all`
+    const NOTHING: MediaQuery = MediaQuery { negated: true, media_type: None, features: Vec::new() };
+
+    fn matches(&self, context: &MediaContext) -> bool {
+        let media_type = match self.media_type.as_deref() {
+            None | Some("all") => true,
+            Some(name) => MediaType::from_name(name) == Some(context.media_type),
+        };
+        let matches = media_type && self.features.iter().all(|feature| feature.matches(context));
+        matches != self.negated
+    }
+}
+
+impl MediaFeature {
+    fn matches(&self, context: &MediaContext) -> bool {
+        let compare = |range, actual: f32, value: f32| match range {
+            Range::Min => actual >= value,
+            Range::Max => actual <= value,
+            Range::Exact => actual == value,
+        };
+        match *self {
+            MediaFeature::Width(range, value) => compare(range, context.width, value),
+            MediaFeature::Height(range, value) => compare(range, context.height, value),
+            MediaFeature::ColorScheme(scheme) => scheme.is_none_or(|scheme| scheme == context.color_scheme),
+            MediaFeature::Orientation(portrait) => (context.height >= context.width) == portrait,
+            MediaFeature::Unknown => false,
+        }
+    }
+}
+
+// `[not | only] type [and (feature)]*`, or `(feature) [and (feature)]*`
+fn parse_query(query: &str) -> Option<MediaQuery> {
+    let query = query.trim().to_ascii_lowercase();
+    let mut rest = query.as_str();
+    let mut negated = false;
+    let mut media_type = None;
+    if !rest.starts_with('(') {
+        let (word, after) = split_word(rest);
+        let (word, after) = match word {
+            "not" | "only" => {
+                negated = word == "not";
+                split_word(after)
+            }
+            _ => (word, after),
+        };
+        if word.is_empty() || !word.chars().all(|c| c.is_ascii_alphabetic() || c == '-') {
+            return None;
+        }
+        media_type = Some(word.to_string());
+        rest = after;
+        if !rest.is_empty() {
+            rest = rest.strip_prefix("and")?.trim_start();
+        }
+    }
+    let mut features = Vec::new();
+    while !rest.is_empty() {
+        let (feature, after) = rest.strip_prefix('(')?.split_once(')')?;
+        features.push(parse_feature(feature));
+        rest = after.trim_start();
+        if !rest.is_empty() {
+            rest = rest.strip_prefix("and")?.trim_start();
+            if rest.is_empty() {
+                return None;
+            }
+        }
+    }
+    Some(MediaQuery { negated, media_type, features })
+}
+
+// the first whitespace-separated word of `text`, and what comes after it
+fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    let end = text.find(char::is_whitespace).unwrap_or(text.len());
+    (&text[..end], text[end..].trim_start())
+}
+
+// `name: value`, or just `name` for the boolean form
+fn parse_feature(feature: &str) -> MediaFeature {
+    let (name, value) = match feature.split_once(':') {
+        Some((name, value)) => (name.trim(), Some(value.trim())),
+        None => (feature.trim(), None),
+    };
+    let (range, dimension) = match name.split_once('-') {
+        Some(("min", dimension)) => (Range::Min, dimension),
+        Some(("max", dimension)) => (Range::Max, dimension),
+        _ => (Range::Exact, name),
+    };
+    match (dimension, value) {
+        ("width", Some(value)) => parse_length(value).map_or(MediaFeature::Unknown, |px| MediaFeature::Width(range, px)),
+        ("height", Some(value)) => parse_length(value).map_or(MediaFeature::Unknown, |px| MediaFeature::Height(range, px)),
+        // the boolean form of a size is true unless the size is 0
+        ("width", None) if range == Range::Exact => MediaFeature::Width(Range::Min, f32::MIN_POSITIVE),
+        ("height", None) if range == Range::Exact => MediaFeature::Height(Range::Min, f32::MIN_POSITIVE),
+        ("prefers-color-scheme", None) => MediaFeature::ColorScheme(None),
+        ("prefers-color-scheme", Some("light")) => MediaFeature::ColorScheme(Some(ColorScheme::Light)),
+        ("prefers-color-scheme", Some("dark")) => MediaFeature::ColorScheme(Some(ColorScheme::Dark)),
+        ("orientation", Some("portrait")) => MediaFeature::Orientation(true),
+        ("orientation", Some("landscape")) => MediaFeature::Orientation(false),
+        _ => MediaFeature::Unknown,
+    }
+}
+
+// an absolute length, or one in em or rem, which in a media query are always 16px
+fn parse_length(value: &str) -> Option<f32> {
+    let split = value.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let n = number.parse::<f32>().ok().filter(|n| n.is_finite())?;
+    match unit {
+        "em" | "rem" => Some(n * 16.0),
+        "" if n == 0.0 => Some(0.0),
+        unit => Unit::from_name(unit)?.absolute_px(n),
+    }
+}
@@ -0,0 +1,419 @@
+use crate::css::{self, Origin};
+use crate::engine::{self, EngineError, RenderOptions};
+use crate::html;
+use crate::layout::Rect;
+use crate::painting::{self, DisplayCommand, PaintPhase, PaintedBox};
+use crate::style;
+use std::fmt;
+use std::fmt::Write;
+
+// Structural checks on display lists, against the order painting is meant to emit
+// commands in, and a generator of seeded random documents to run them on. Each box paints its own background, borders and image, then
+// its lines, then its children one after another, and a transform pushed for a box is
+// popped right after it; a command out of that order is painted over by, or over,
+// something it shouldn't be. A document that breaks an invariant is reported by its
+// seed, which generates the same case again.
+
+/// A command in a display list that breaks one of the invariants painting keeps.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PaintViolation {
+    pub kind: PaintViolationKind,
+    pub command: usize, // its index in the display list
+    pub path: String, // the boxes from the root down to the one that painted it, if any
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PaintViolationKind {
+    // a `PopTransform` with no `PushTransform` left to pop
+    UnmatchedPop,
+    // this many transforms still pushed at the end of the list
+    UnclosedTransforms(usize),
+    // a box whose commands pop transforms pushed before it, or leave some pushed
+    TransformEscapesBox,
+    // a rectangle or transform with a NaN or infinite value
+    NonFinite,
+    // a background, border or image reaching outside the border box that paints it
+    OutsideBorderBox(Rect),
+    // a command of a kind, such as "text", that the phase doesn't paint
+    Misplaced(PaintPhase, &'static str),
+    // a phase painted after one it should come before, or outside the box's commands
+    OutOfOrder(PaintPhase),
+    // a box painted before its parent's own phases were done, after its parent's
+    // collapsed borders, or amid the commands of a sibling painted before it
+    ChildOutOfOrder,
+    // a command painted by no box
+    Unattributed,
+}
+
+impl fmt::Display for PaintViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "command {}: ", self.command)?;
+        match self.kind {
+            PaintViolationKind::UnmatchedPop => write!(f, "PopTransform with no PushTransform to pop")?,
+            PaintViolationKind::UnclosedTransforms(count) => write!(f, "{} transforms left pushed", count)?,
+            PaintViolationKind::TransformEscapesBox => write!(f, "transforms unbalanced within the box")?,
+            PaintViolationKind::NonFinite => write!(f, "non-finite rectangle or transform")?,
+            PaintViolationKind::OutsideBorderBox(rect) => write!(f, "paints {:?} outside the border box", rect)?,
+            PaintViolationKind::Misplaced(phase, what) => write!(f, "{} among {:?}", what, phase)?,
+            PaintViolationKind::OutOfOrder(phase) => write!(f, "{:?} painted out of order", phase)?,
+            PaintViolationKind::ChildOutOfOrder => write!(f, "box painted out of order with its parent or siblings")?,
+            PaintViolationKind::Unattributed => write!(f, "painted by no box")?,
+        }
+        match self.path.is_empty() {
+            true => Ok(()),
+            false => write!(f, " at {}", self.path),
+        }
+    }
+}
+
+// how far a rectangle may reach past its border box before it counts, since both
+// are sums of f32s
+const TOLERANCE: f32 = 0.01;
+
+/// Check a display list against the boxes `painting::build_display_list_traced`
+/// recorded painting it, returning every violation found, in the order of the
+/// commands they're at.
+pub fn check_display_list(list: &[DisplayCommand], boxes: &[PaintedBox]) -> Vec<PaintViolation> {
+    let mut violations = Vec::new();
+    let path = |index: Option<usize>| {
+        let mut names = Vec::new();
+        let mut index = index;
+        while let Some(i) = index {
+            names.push(boxes[i].name.as_str());
+            index = boxes[i].parent;
+        }
+        names.reverse();
+        names.join(" > ")
+    };
+    let mut report = |kind, command, painted_by: Option<usize>| {
+        violations.push(PaintViolation { kind, command, path: path(painted_by) });
+    };
+
+    // the number of transforms pushed before each command, and after the last
+    let mut depths = Vec::with_capacity(list.len() + 1);
+    let mut depth = 0;
+    for (i, item) in list.iter().enumerate() {
+        depths.push(depth);
+        match item {
+            DisplayCommand::PushTransform(_) => depth += 1,
+            DisplayCommand::PopTransform if depth == 0 => report(PaintViolationKind::UnmatchedPop, i, None),
+            DisplayCommand::PopTransform => depth -= 1,
+            _ => {}
+        }
+        if !is_finite(item) {
+            report(PaintViolationKind::NonFinite, i, None);
+        }
+    }
+    depths.push(depth);
+    if depth > 0 {
+        report(PaintViolationKind::UnclosedTransforms(depth), list.len(), None);
+    }
+
+    // which commands some box painted, or pushed or popped a transform for
+    let mut attributed = vec![false; list.len()];
+    for (i, painted) in boxes.iter().enumerate() {
+        let commands = painted.commands.clone();
+        let within = depths[commands.clone()].iter().all(|&d| d >= depths[commands.start]);
+        if !within || depths[commands.end] != depths[commands.start] {
+            report(PaintViolationKind::TransformEscapesBox, commands.start, Some(i));
+        }
+        if painted.transformed {
+            let pushed = commands.start > 0 && matches!(list[commands.start - 1], DisplayCommand::PushTransform(_));
+            let popped = matches!(list.get(commands.end), Some(DisplayCommand::PopTransform));
+            match pushed && popped {
+                true => {
+                    attributed[commands.start - 1] = true;
+                    attributed[commands.end] = true;
+                }
+                false => report(PaintViolationKind::TransformEscapesBox, commands.start, Some(i)),
+            }
+        }
+
+        let mut previous: Option<(PaintPhase, usize)> = None;
+        for &(phase, ref range) in &painted.phases {
+            let in_order = previous.is_none_or(|(before, end)| before < phase && end <= range.start);
+            if !in_order || range.start < commands.start || range.end > commands.end {
+                report(PaintViolationKind::OutOfOrder(phase), range.start, Some(i));
+            }
+            previous = Some((phase, range.end));
+            for command in range.clone() {
+                attributed[command] = true;
+                check_command(&list[command], phase, painted.border_box, |kind| report(kind, command, Some(i)));
+            }
+        }
+
+        if let Some(parent) = painted.parent.map(|parent| &boxes[parent]) {
+            let own_end = parent.phases.iter()
+                .filter(|(phase, _)| *phase <= PaintPhase::RowDecorations)
+                .map(|(_, range)| range.end)
+                .max()
+                .unwrap_or(parent.commands.start);
+            let borders_start = parent.phases.iter()
+                .find(|(phase, _)| *phase >= PaintPhase::CollapsedBorders)
+                .map_or(parent.commands.end, |(_, range)| range.start);
+            // the sibling painted just before this box, if any
+            let sibling_end = boxes[..i].iter().rev()
+                .find(|other| other.parent == painted.parent)
+                .map_or(own_end, |sibling| sibling.commands.end + sibling.transformed as usize);
+            // counting the transform pushed and popped around it
+            let outer = painted.transformed as usize;
+            if commands.start.saturating_sub(outer) < own_end.max(sibling_end) || commands.end + outer > borders_start {
+                report(PaintViolationKind::ChildOutOfOrder, commands.start, Some(i));
+            }
+        }
+    }
+    // the canvas background goes under everything, before the root
+    let canvas = boxes.first().is_some_and(|root| root.commands.start == 1);
+    for (i, attributed) in attributed.into_iter().enumerate() {
+        let canvas_background = canvas && i == 0;
+        if !attributed && !canvas_background {
+            report(PaintViolationKind::Unattributed, i, None);
+        }
+    }
+    violations.sort_by_key(|violation| violation.command);
+    violations
+}
+
+// is the command one the phase paints, inside `border_box` if it's one of a box's
+// own decorations?
+fn check_command(item: &DisplayCommand, phase: PaintPhase, border_box: Rect, mut report: impl FnMut(PaintViolationKind)) {
+    let what = match item {
+        DisplayCommand::SolidColor(..) => "fill",
+        DisplayCommand::Text(..) => "text",
+        DisplayCommand::Image(..) => "image",
+        DisplayCommand::PushTransform(_) | DisplayCommand::PopTransform => "transform",
+    };
+    let allowed = match phase {
+        PaintPhase::Decorations | PaintPhase::InlineDecorations | PaintPhase::RowDecorations => matches!(what, "fill" | "image"),
+        PaintPhase::Text => matches!(what, "fill" | "text"),
+        PaintPhase::CollapsedBorders | PaintPhase::Outline => what == "fill",
+    };
+    if !allowed {
+        return report(PaintViolationKind::Misplaced(phase, what));
+    }
+    // an image is cut off at its clip, so only the clip needs to be inside
+    let rect = match *item {
+        DisplayCommand::SolidColor(_, rect) | DisplayCommand::Image(_, _, rect) if phase == PaintPhase::Decorations => rect,
+        _ => return,
+    };
+    let inside = rect.x >= border_box.x - TOLERANCE
+        && rect.y >= border_box.y - TOLERANCE
+        && rect.x + rect.width <= border_box.x + border_box.width + TOLERANCE
+        && rect.y + rect.height <= border_box.y + border_box.height + TOLERANCE;
+    if !inside {
+        report(PaintViolationKind::OutsideBorderBox(rect));
+    }
+}
+
+fn is_finite(item: &DisplayCommand) -> bool {
+    let finite = |values: &[f32]| values.iter().all(|v| v.is_finite());
+    let rect = |r: Rect| finite(&[r.x, r.y, r.width, r.height]);
+    match *item {
+        DisplayCommand::SolidColor(_, r) | DisplayCommand::Text(_, _, _, r) => rect(r),
+        DisplayCommand::Image(_, r, clip) => rect(r) && rect(clip),
+        DisplayCommand::PushTransform(t) => finite(&[t.scale_x, t.scale_y, t.translate_x, t.translate_y]),
+        DisplayCommand::PopTransform => true,
+    }
+}
+
+/// Lay out and paint a document with one author stylesheet, and check its display list.
+pub fn check_document(source: &str, css_source: &str, options: &RenderOptions) -> Result<Vec<PaintViolation>, EngineError> {
+    let parser_options = html::ParserOptions { limits: options.limits.clone(), ..Default::default() };
+    let root_node = html::parse_with_options(source, parser_options)?;
+    let mut stylesheet = css::parse_with_limits(css_source.to_string(), Origin::Author, &options.limits)?;
+    css::validate(&mut stylesheet);
+    let stylesheets = vec![css::default_user_agent_stylesheet(), stylesheet];
+    let style_root = style::style_tree_with_context(&root_node, &stylesheets, &options.style_context());
+    let layout_root = engine::layout(&style_root, options)?;
+    let (list, boxes) = painting::build_display_list_traced(&layout_root);
+    Ok(check_display_list(&list, &boxes))
+}
+
+/// A generated document and its stylesheet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PaintCase {
+    pub seed: u64,
+    pub html: String,
+    pub css: String,
+}
+
+/// Generate a document from `seed`, always the same one for the same seed: nested
+/// blocks, inline elements, inline-blocks and tables with random backgrounds,
+/// borders, padding, margins (negative ones included), widths, transforms and
+/// visibility, each styled by a rule on its id.
+pub fn generate_case(seed: u64) -> PaintCase {
+    let mut generator = Generator { rng: Rng::new(seed), html: String::new(), css: String::new(), next_id: 0 };
+    generator.html.push_str("<html><body>");
+    for _ in 0..1 + generator.rng.below(4) {
+        generator.element(0);
+    }
+    generator.html.push_str("</body></html>\n");
+    PaintCase { seed, html: generator.html, css: generator.css }
+}
+
+struct Generator {
+    rng: Rng,
+    html: String,
+    css: String,
+    next_id: usize,
+}
+
+const WORDS: &[&str] = &["paint", "order", "layers", "of", "boxes", "and", "text", "overlap"];
+
+impl Generator {
+    fn element(&mut self, depth: usize) {
+        let choices: &[&str] = match depth {
+            0..=2 => &["div", "p", "span", "inline-block", "table"],
+            _ => &["div", "p", "span", "inline-block"],
+        };
+        let kind = *self.rng.pick(choices);
+        let id = self.style(kind == "inline-block" || kind == "div" || kind == "p", kind == "table");
+        match kind {
+            "table" => {
+                let _ = write!(self.html, "<table id=\"b{}\">", id);
+                for _ in 0..1 + self.rng.below(3) {
+                    self.html.push_str("<tr>");
+                    for _ in 0..1 + self.rng.below(3) {
+                        let cell = self.style(true, false);
+                        let _ = write!(self.html, "<td id=\"b{}\">", cell);
+                        self.text();
+                        self.html.push_str("</td>");
+                    }
+                    self.html.push_str("</tr>");
+                }
+                self.html.push_str("</table>");
+            }
+            _ => {
+                let tag = if kind == "inline-block" { "span" } else { kind };
+                if kind == "inline-block" {
+                    let _ = writeln!(self.css, "#b{} {{ display: inline-block; }}", id);
+                }
+                let _ = write!(self.html, "<{} id=\"b{}\">", tag, id);
+                for _ in 0..self.rng.below(4) {
+                    // inline elements hold only inline content, which this engine
+                    // doesn't check, so keep them inline
+                    match self.rng.below(3) {
+                        0 => self.text(),
+                        _ if depth < 4 && tag != "span" => self.element(depth + 1),
+                        _ if depth < 4 => {
+                            let inner = self.style(false, false);
+                            let _ = write!(self.html, "<span id=\"b{}\">", inner);
+                            self.text();
+                            self.html.push_str("</span>");
+                        }
+                        _ => self.text(),
+                    }
+                }
+                let _ = write!(self.html, "</{}>", tag);
+            }
+        }
+    }
+
+    fn text(&mut self) {
+        for _ in 0..1 + self.rng.below(6) {
+            let word = *self.rng.pick(WORDS);
+            self.html.push_str(word);
+            self.html.push(' ');
+        }
+    }
+
+    // write a rule for a new id, with sizes only for boxes that have them, and
+    // return the id
+    fn style(&mut self, sized: bool, table: bool) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        let rng = &mut self.rng;
+        let mut declarations = Vec::new();
+        if rng.chance(60) {
+            declarations.push(format!("background: #{:02x}{:02x}{:02x}", rng.below(256), rng.below(256), rng.below(256)));
+        }
+        if rng.chance(40) {
+            declarations.push(format!("border-width: {}px", rng.below(8)));
+            declarations.push(format!("border-color: #{:02x}{:02x}{:02x}", rng.below(256), rng.below(256), rng.below(256)));
+        }
+        if rng.chance(40) {
+            declarations.push(format!("padding: {}px {}px", rng.below(16), rng.below(16)));
+        }
+        if rng.chance(40) {
+            declarations.push(format!("margin: {}px {}px", rng.below(41) as i32 - 20, rng.below(41) as i32 - 20));
+        }
+        if sized && rng.chance(30) {
+            declarations.push(format!("width: {}px", 20 + rng.below(300)));
+        }
+        if sized && rng.chance(20) {
+            declarations.push(format!("height: {}px", rng.below(100)));
+        }
+        if rng.chance(20) {
+            let clip = *rng.pick(&["border-box", "padding-box", "content-box"]);
+            declarations.push(format!("background-clip: {}", clip));
+        }
+        if rng.chance(25) {
+            declarations.push(match rng.below(4) {
+                0 => format!("transform: translate({}px, {}px)", rng.below(81) as i32 - 40, rng.below(81) as i32 - 40),
+                1 => format!("transform: scale({})", rng.below(5) as f32 * 0.5),
+                2 => format!("transform: translatex({}%) scaley({})", rng.below(101) as i32 - 50, 1 + rng.below(3)),
+                _ => format!("transform: scale(2); transform-origin: {}", *rng.pick(&["left top", "right bottom", "center"])),
+            });
+        }
+        if rng.chance(10) {
+            declarations.push(format!("visibility: {}", *rng.pick(&["hidden", "visible"])));
+        }
+        if rng.chance(10) {
+            declarations.push("text-decoration: underline".to_string());
+        }
+        if rng.chance(10) {
+            declarations.push("white-space: nowrap".to_string());
+        }
+        if table && rng.chance(50) {
+            declarations.push("border-collapse: collapse".to_string());
+        }
+        if rng.chance(3) {
+            declarations.push("display: none".to_string());
+        }
+        if !declarations.is_empty() {
+            let _ = writeln!(self.css, "#b{} {{ {}; }}", id, declarations.join("; "));
+        }
+        id
+    }
+}
+
+// xorshift64*, which is plenty random for picking test cases
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        // the state must not be zero
+        Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    // a number from 0 up to but not including `n`
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() >> 33) as usize % n
+    }
+
+    fn chance(&mut self, percent: usize) -> bool {
+        self.below(100) < percent
+    }
+
+    fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len())]
+    }
+}
+
+#[test]
+fn generated_documents_paint_in_order() {
+    let options = RenderOptions::default();
+    for seed in 1..=100 {
+        let case = generate_case(seed);
+        let violations = check_document(&case.html, &case.css, &options).unwrap();
+        let report: Vec<String> = violations.iter().map(|violation| violation.to_string()).collect();
+        assert!(violations.is_empty(), "seed {}:\n{}\n{}\n{}", seed, report.join("\n"), case.html, case.css);
+    }
+}
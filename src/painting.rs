@@ -1,72 +1,1024 @@
-use crate::layout::{AnonymousBlock, BlockNode, InlineNode, LayoutBox, Rect};
-use crate::css::{Value, Color};
+use crate::background;
+use crate::layout::{AnonymousBlock, BlockNode, EdgeSizes, InlineBlockNode, InlineNode, LayoutBox, Rect};
+use crate::inline::InlineFragment;
+use crate::css::{Color, Unit, Value};
+use crate::dom::{Node, NodeType};
+use crate::engine::{EngineError, Limit, Limits};
+use crate::forms::{self, Control};
+use crate::font::{Font, StandardFontMeasurer, TextMeasurer};
+use crate::image_decode::DecodedImage;
+use crate::replaced::{self, ObjectFit};
+use crate::style::{Display, StyledNode};
+use crate::validate;
+use std::ops::Range;
+use std::sync::{Arc, OnceLock};
 
 pub struct Canvas {
     pub pixels: Vec<Color>,
     pub width: usize,
-    pub height: usize
+    pub height: usize,
+    pub blending: Blending
 }
 
-// paint a tree of LayoutBoxes to array of pixels
-pub fn paint(layout_root: &LayoutBox, bounds: Rect) -> Canvas {
-    let display_list = build_display_list(layout_root);
-    let mut canvas = Canvas::new(bounds.width as usize, bounds.height as usize);
-    for item in display_list {
+/// How translucent colors are composited onto the canvas.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Blending {
+    /// Blend the stored 8-bit sRGB values directly, as most browsers do by default.
+    #[default]
+    Srgb,
+    /// Convert to linear light, blend, and convert back. This avoids the dark
+    /// fringes of sRGB blending at the cost of two table lookups per channel.
+    Linear
+}
+
+// paint a tree of LayoutBoxes to array of pixels, refusing a canvas with more
+// pixels than `limits` allow
+pub fn paint(layout_root: &LayoutBox, bounds: Rect, limits: &Limits) -> Result<Canvas, EngineError> {
+    paint_with_blending(layout_root, bounds, Blending::default(), limits)
+}
+
+// same as `paint`, compositing with the chosen blending mode
+pub fn paint_with_blending(layout_root: &LayoutBox, bounds: Rect, blending: Blending, limits: &Limits) -> Result<Canvas, EngineError> {
+    paint_scaled(layout_root, bounds, blending, 1.0, limits)
+}
+
+// same as `paint_with_blending`, at `scale` device pixels per CSS px (2 for a
+// high-density display)
+pub fn paint_scaled(layout_root: &LayoutBox, bounds: Rect, blending: Blending, scale: f32, limits: &Limits) -> Result<Canvas, EngineError> {
+    // the canvas is checked before the display list is built for it
+    let mut canvas = Canvas::new((bounds.width * scale).round() as usize, (bounds.height * scale).round() as usize, limits)?;
+    let display_list = flatten_transforms(build_display_list_in(layout_root, bounds));
+    canvas.blending = blending;
+    // the canvas's top left pixel is the top left corner of `bounds`
+    for mut item in display_list {
+        item.translate(-bounds.x, -bounds.y);
+        item.snap_to_device_pixels(scale);
         canvas.paint_item(&item);
     }
-    return canvas;
+    Ok(canvas)
+}
+
+/// Paint again only the pixels of `canvas` inside `region`, as `paint` would paint
+/// them, leaving every other pixel as it was.
+///
+/// `canvas` is taken to be painted at 1 px per CSS px with its top left pixel at the
+/// origin, as `paint` does for the canvas rect of the initial containing block.
+/// `region` is widened to whole pixels, which are cleared, and every display
+/// command painting there is painted again, cut off at the region's edges. To bring
+/// a canvas of an old layout up to date with a new one, pass the region
+/// `dirty_region` finds between their display lists, each built with
+/// `build_display_list_in` for the whole canvas, so that the canvas background in
+/// each covers the canvas as painted.
+pub fn paint_region(layout_root: &LayoutBox, region: Rect, canvas: &mut Canvas) {
+    let whole = Rect { x: 0.0, y: 0.0, width: canvas.width as f32, height: canvas.height as f32 };
+    let (left, top) = (region.x.floor(), region.y.floor());
+    let widened = Rect { x: left, y: top, width: (region.x + region.width).ceil() - left, height: (region.y + region.height).ceil() - top };
+    let region = match widened.intersection(whole) {
+        Some(region) => region,
+        None => return
+    };
+    let (x0, y0) = (region.x as usize, region.y as usize);
+    let (x1, y1) = (x0 + region.width as usize, y0 + region.height as usize);
+    let white = Color { r: 255, g: 255, b: 255, a: 255 };
+    for y in y0..y1 {
+        canvas.pixels[y * canvas.width + x0..y * canvas.width + x1].fill(white);
+    }
+    // fixed backgrounds are still positioned in the whole canvas
+    for mut item in flatten_transforms(build_display_list_for(layout_root, whole, Some(region))) {
+        // snapped first, so the pixels are chosen as a full repaint chooses them
+        item.snap_to_device_pixels(1.0);
+        let clipped = match item {
+            DisplayCommand::SolidColor(color, rect) => rect.intersection(region).map(|rect| DisplayCommand::SolidColor(color, rect)),
+            DisplayCommand::Image(image, rect, clip) => clip.intersection(region).map(|clip| DisplayCommand::Image(image, rect, clip)),
+            _ => None
+        };
+        if let Some(item) = clipped {
+            canvas.paint_item(&item);
+        }
+    }
+}
+
+/// A viewport-sized window onto a laid-out document, scrolled vertically.
+///
+/// Layout is done once for the whole document; scrolling only changes which part
+/// of it is painted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScrollView {
+    pub width: f32,
+    pub height: f32,
+    pub offset: f32, // how far down the document the top of the view is, in px
+}
+
+impl ScrollView {
+    pub fn new(width: f32, height: f32) -> ScrollView {
+        ScrollView { width, height, offset: 0.0 }
+    }
+
+    // scroll by `delta` px (positive is down), never past either end of a document
+    // `document_height` tall
+    pub fn scroll_by(&mut self, delta: f32, document_height: f32) {
+        self.scroll_to(self.offset + delta, document_height);
+    }
+
+    // scroll so the top of the view is at `y`, or as near as the document allows
+    pub fn scroll_to(&mut self, y: f32, document_height: f32) {
+        let max_offset = (document_height - self.height).max(0.0);
+        self.offset = y.clamp(0.0, max_offset);
+    }
+
+    // the part of the document in view
+    pub fn bounds(&self) -> Rect {
+        Rect { x: 0.0, y: self.offset, width: self.width, height: self.height }
+    }
+
+    pub fn paint(&self, layout_root: &LayoutBox, blending: Blending, limits: &Limits) -> Result<Canvas, EngineError> {
+        paint_with_blending(layout_root, self.bounds(), blending, limits)
+    }
+}
+
+/// Shrink `canvas` to `target_width` pixels wide, keeping its aspect ratio, for a
+/// thumbnail.
+///
+/// Each output pixel is the average of the source area it covers, weighted by how
+/// much of each source pixel falls inside it, with colors premultiplied by alpha so
+/// transparent pixels don't darken their neighbours. A canvas is never enlarged.
+pub fn downsample(canvas: &Canvas, target_width: u32) -> Canvas {
+    let width = (target_width as usize).clamp(1, canvas.width.max(1));
+    let height = ((canvas.height as f64 * width as f64 / canvas.width.max(1) as f64).round() as usize).max(1);
+    // no larger than `canvas`, so within any limit it was
+    let mut thumbnail = Canvas::blank(width, height);
+    thumbnail.blending = canvas.blending;
+    if canvas.width == 0 || canvas.height == 0 {
+        return thumbnail;
+    }
+    let columns = coverage(canvas.width, width);
+    let rows = coverage(canvas.height, height);
+    for (y, (row_start, row_weights)) in rows.iter().enumerate() {
+        for (x, (column_start, column_weights)) in columns.iter().enumerate() {
+            let mut sum = [0.0f64; 4]; // premultiplied r, g, b and alpha
+            for (dy, row_weight) in row_weights.iter().enumerate() {
+                for (dx, column_weight) in column_weights.iter().enumerate() {
+                    let pixel = canvas.pixels[(row_start + dy) * canvas.width + column_start + dx];
+                    let weight = row_weight * column_weight;
+                    let alpha = pixel.a as f64 / 255.0 * weight;
+                    sum[0] += pixel.r as f64 * alpha;
+                    sum[1] += pixel.g as f64 * alpha;
+                    sum[2] += pixel.b as f64 * alpha;
+                    sum[3] += alpha;
+                }
+            }
+            let unpremultiply = |c: f64| if sum[3] > 0.0 { (c / sum[3]).round().clamp(0.0, 255.0) as u8 } else { 0 };
+            thumbnail.pixels[y * width + x] = Color {
+                r: unpremultiply(sum[0]),
+                g: unpremultiply(sum[1]),
+                b: unpremultiply(sum[2]),
+                a: (sum[3] * 255.0).round().clamp(0.0, 255.0) as u8,
+            };
+        }
+    }
+    thumbnail
+}
+
+// for each of `target` pixels spanning `source` pixels, the first source pixel it
+// covers and the fraction of its area each covered source pixel makes up
+fn coverage(source: usize, target: usize) -> Vec<(usize, Vec<f64>)> {
+    let scale = source as f64 / target as f64;
+    (0..target).map(|i| {
+        let (start, end) = (i as f64 * scale, (i + 1) as f64 * scale);
+        let first = start.floor() as usize;
+        let last = (end.ceil() as usize).min(source);
+        let weights = (first..last).map(|s| (end.min(s as f64 + 1.0) - start.max(s as f64)) / scale).collect();
+        (first, weights)
+    }).collect()
 }
 
 #[derive(Debug)]
 pub enum DisplayCommand {
-    SolidColor(Color, Rect)
+    SolidColor(Color, Rect),
+    // a run of text, with `Rect` covering its line-height-sized box
+    Text(String, Font, Color, Rect),
+    // an image scaled to fill the first `Rect`, and cut off outside the second
+    Image(Arc<DecodedImage>, Rect, Rect),
+    // the items up to the matching `PopTransform` are drawn through the transform,
+    // on top of any transforms already pushed
+    PushTransform(Transform),
+    PopTransform
+}
+
+/// An axis-aligned 2D transform, mapping `(x, y)` to
+/// `(scale_x * x + translate_x, scale_y * y + translate_y)`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Transform {
+    pub scale_x: f32,
+    pub scale_y: f32,
+    pub translate_x: f32,
+    pub translate_y: f32,
+}
+
+impl Transform {
+    pub const IDENTITY: Transform = Transform { scale_x: 1.0, scale_y: 1.0, translate_x: 0.0, translate_y: 0.0 };
+
+    pub fn translation(x: f32, y: f32) -> Transform {
+        Transform { translate_x: x, translate_y: y, ..Transform::IDENTITY }
+    }
+
+    pub fn scale(x: f32, y: f32) -> Transform {
+        Transform { scale_x: x, scale_y: y, ..Transform::IDENTITY }
+    }
+
+    /// The transform applying `other`, then this.
+    pub fn after(self, other: Transform) -> Transform {
+        Transform {
+            scale_x: self.scale_x * other.scale_x,
+            scale_y: self.scale_y * other.scale_y,
+            translate_x: self.scale_x * other.translate_x + self.translate_x,
+            translate_y: self.scale_y * other.translate_y + self.translate_y,
+        }
+    }
+
+    /// The transform undoing this one, unless it scales to nothing.
+    pub fn inverse(self) -> Option<Transform> {
+        if self.scale_x == 0.0 || self.scale_y == 0.0 {
+            return None;
+        }
+        Some(Transform {
+            scale_x: 1.0 / self.scale_x,
+            scale_y: 1.0 / self.scale_y,
+            translate_x: -self.translate_x / self.scale_x,
+            translate_y: -self.translate_y / self.scale_y,
+        })
+    }
+
+    /// The rectangle covered by `rect` once transformed, which a negative scale flips.
+    pub fn map_rect(self, rect: Rect) -> Rect {
+        let (x0, x1) = (self.scale_x * rect.x + self.translate_x, self.scale_x * (rect.x + rect.width) + self.translate_x);
+        let (y0, y1) = (self.scale_y * rect.y + self.translate_y, self.scale_y * (rect.y + rect.height) + self.translate_y);
+        Rect { x: x0.min(x1), y: y0.min(y1), width: (x1 - x0).abs(), height: (y1 - y0).abs() }
+    }
+}
+
+impl DisplayCommand {
+    pub fn translate(&mut self, dx: f32, dy: f32) {
+        match self {
+            DisplayCommand::SolidColor(_, rect) | DisplayCommand::Text(_, _, _, rect) => *rect = rect.translated(dx, dy),
+            DisplayCommand::Image(_, rect, clip) => {
+                *rect = rect.translated(dx, dy);
+                *clip = clip.translated(dx, dy);
+            }
+            // the same transform, of the moved items
+            DisplayCommand::PushTransform(transform) => {
+                *transform = Transform::translation(dx, dy).after(*transform).after(Transform::translation(-dx, -dy));
+            }
+            DisplayCommand::PopTransform => {}
+        }
+    }
+
+    /// Draw the item through `transform`, as the items inside a `PushTransform` are.
+    ///
+    /// Text is scaled by the vertical scale alone, since glyphs aren't stretched.
+    pub fn transform(&mut self, transform: Transform) {
+        match self {
+            DisplayCommand::SolidColor(_, rect) => *rect = transform.map_rect(*rect),
+            DisplayCommand::Text(_, font, _, rect) => {
+                font.size *= transform.scale_y.abs();
+                *rect = transform.map_rect(*rect);
+            }
+            DisplayCommand::Image(_, rect, clip) => {
+                *rect = transform.map_rect(*rect);
+                *clip = transform.map_rect(*clip);
+            }
+            DisplayCommand::PushTransform(inner) => *inner = transform.after(*inner),
+            DisplayCommand::PopTransform => {}
+        }
+    }
+
+    /// Scale to device pixels and round the edges of fills to whole pixels.
+    ///
+    /// Edges are rounded rather than sizes, so boxes that meet in layout still meet
+    /// exactly: three 33.33px columns in a 100px parent fill 33, 34 and 33 pixels,
+    /// where rounding their widths would leave a 1px gap.
+    pub fn snap_to_device_pixels(&mut self, scale: f32) {
+        match self {
+            DisplayCommand::SolidColor(_, rect) => *rect = snap_rect(*rect, scale),
+            // the image is scaled smoothly, but cut off at whole pixels like a fill
+            DisplayCommand::Image(_, rect, clip) => {
+                *rect = Rect { x: rect.x * scale, y: rect.y * scale, width: rect.width * scale, height: rect.height * scale };
+                *clip = snap_rect(*clip, scale);
+            }
+            // glyphs aren't rasterized yet; keep text where it'd be at this scale
+            DisplayCommand::Text(_, font, _, rect) => {
+                font.size *= scale;
+                *rect = Rect { x: rect.x * scale, y: rect.y * scale, width: rect.width * scale, height: rect.height * scale };
+            }
+            DisplayCommand::PushTransform(transform) => {
+                transform.translate_x *= scale;
+                transform.translate_y *= scale;
+            }
+            DisplayCommand::PopTransform => {}
+        }
+    }
+
+    pub fn color(&self) -> Color {
+        match *self {
+            DisplayCommand::SolidColor(color, _) | DisplayCommand::Text(_, _, color, _) => color,
+            // an image's pixels carry their own alpha, and a transform paints nothing
+            // but mustn't be skipped as transparent
+            DisplayCommand::Image(..) | DisplayCommand::PushTransform(_) | DisplayCommand::PopTransform => {
+                Color { r: 0, g: 0, b: 0, a: 255 }
+            }
+        }
+    }
+
+    // a fully transparent item paints nothing, so backends can skip it
+    pub fn is_transparent(&self) -> bool {
+        self.color().a == 0
+    }
+
+    // do the two commands paint the same? Images are the same only if they're the
+    // same decoded image.
+    fn same_as(&self, other: &DisplayCommand) -> bool {
+        match (self, other) {
+            (DisplayCommand::SolidColor(a, a_rect), DisplayCommand::SolidColor(b, b_rect)) => a == b && a_rect == b_rect,
+            (DisplayCommand::Text(a, a_font, a_color, a_rect), DisplayCommand::Text(b, b_font, b_color, b_rect)) => {
+                a == b && a_font == b_font && a_color == b_color && a_rect == b_rect
+            }
+            (DisplayCommand::Image(a, a_rect, a_clip), DisplayCommand::Image(b, b_rect, b_clip)) => {
+                Arc::ptr_eq(a, b) && a_rect == b_rect && a_clip == b_clip
+            }
+            (DisplayCommand::PushTransform(a), DisplayCommand::PushTransform(b)) => a == b,
+            (DisplayCommand::PopTransform, DisplayCommand::PopTransform) => true,
+            _ => false
+        }
+    }
+
+    // the area the command paints in, before any transforms around it
+    fn painted_area(&self) -> Option<Rect> {
+        match *self {
+            DisplayCommand::SolidColor(_, rect) | DisplayCommand::Text(_, _, _, rect) => Some(rect),
+            DisplayCommand::Image(_, rect, clip) => rect.intersection(clip),
+            DisplayCommand::PushTransform(_) | DisplayCommand::PopTransform => None
+        }
+    }
+}
+
+// scale `rect` to device pixels, moving each edge to the nearest pixel boundary
+fn snap_rect(rect: Rect, scale: f32) -> Rect {
+    let (left, top) = ((rect.x * scale).round(), (rect.y * scale).round());
+    let right = ((rect.x + rect.width) * scale).round();
+    let bottom = ((rect.y + rect.height) * scale).round();
+    Rect { x: left, y: top, width: right - left, height: bottom - top }
 }
 
 pub type DisplayList = Vec<DisplayCommand>;
 
+/// Draw each item of the list through the transforms around it, leaving a list with
+/// no `PushTransform` or `PopTransform`, for backends that can't transform.
+pub fn flatten_transforms(list: DisplayList) -> DisplayList {
+    let mut transforms = vec![Transform::IDENTITY];
+    let mut flattened = Vec::with_capacity(list.len());
+    for mut item in list {
+        match item {
+            DisplayCommand::PushTransform(transform) => {
+                let current = *transforms.last().unwrap();
+                transforms.push(current.after(transform));
+            }
+            DisplayCommand::PopTransform => {
+                if transforms.len() > 1 {
+                    transforms.pop();
+                }
+            }
+            _ => {
+                item.transform(*transforms.last().unwrap());
+                flattened.push(item);
+            }
+        }
+    }
+    flattened
+}
+
+/// The area where painting `new` could leave different pixels than painting `old`,
+/// or `None` if the two paint the same.
+///
+/// Commands the lists begin and end with in common paint the same either way, so
+/// the area is the union of what the commands between those paint in either list,
+/// as drawn through the transforms around them.
+pub fn dirty_region(old: &[DisplayCommand], new: &[DisplayCommand]) -> Option<Rect> {
+    let (old, new) = (placed_commands(old), placed_commands(new));
+    let same = |(a, a_transform): &(&DisplayCommand, Transform), (b, b_transform): &(&DisplayCommand, Transform)| {
+        a_transform == b_transform && a.same_as(b)
+    };
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| same(a, b)).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev()).take_while(|(a, b)| same(a, b)).count();
+    let changed = old[prefix..old.len() - suffix].iter().chain(&new[prefix..new.len() - suffix]);
+    changed.filter_map(|&(item, transform)| item.painted_area().map(|area| transform.map_rect(area)))
+        .reduce(Rect::union)
+}
+
+// each command that paints something, with the transform it's drawn through
+fn placed_commands(list: &[DisplayCommand]) -> Vec<(&DisplayCommand, Transform)> {
+    let mut transforms = vec![Transform::IDENTITY];
+    let mut placed = Vec::with_capacity(list.len());
+    for item in list {
+        match item {
+            DisplayCommand::PushTransform(transform) => {
+                let current = *transforms.last().unwrap();
+                transforms.push(current.after(*transform));
+            }
+            DisplayCommand::PopTransform => {
+                if transforms.len() > 1 {
+                    transforms.pop();
+                }
+            }
+            _ => placed.push((item, *transforms.last().unwrap())),
+        }
+    }
+    placed
+}
+
 pub fn build_display_list(layout_root: &LayoutBox) -> DisplayList {
+    // the canvas reaches from the origin to the far edges of whatever is painted
+    let painted = layout_root.dimensions.margin_box().union(layout_root.subtree_bounds());
+    let canvas = Rect { x: 0.0, y: 0.0, width: painted.x + painted.width, height: painted.y + painted.height };
+    build_display_list_for(layout_root, canvas, None)
+}
+
+// build the display list for only the part of the page inside `bounds`, skipping
+// subtrees that paint nothing there (such as everything off a page of a tall document)
+pub fn build_display_list_in(layout_root: &LayoutBox, bounds: Rect) -> DisplayList {
+    build_display_list_for(layout_root, bounds, Some(bounds))
+}
+
+/// Build the display list as `build_display_list` does, also recording which
+/// commands each box painted, for checking the order they're painted in.
+pub fn build_display_list_traced(layout_root: &LayoutBox) -> (DisplayList, Vec<PaintedBox>) {
+    let painted = layout_root.dimensions.margin_box().union(layout_root.subtree_bounds());
+    let canvas = Rect { x: 0.0, y: 0.0, width: painted.x + painted.width, height: painted.y + painted.height };
+    let mut boxes = Vec::new();
+    let list = build_display_list_traced_for(layout_root, canvas, None, &mut Trace { boxes: Some(&mut boxes), current: None, viewport: canvas });
+    (list, boxes)
+}
+
+fn build_display_list_for(layout_root: &LayoutBox, canvas: Rect, bounds: Option<Rect>) -> DisplayList {
+    build_display_list_traced_for(layout_root, canvas, bounds, &mut Trace { boxes: None, current: None, viewport: canvas })
+}
+
+fn build_display_list_traced_for(layout_root: &LayoutBox, canvas: Rect, bounds: Option<Rect>, trace: &mut Trace) -> DisplayList {
     let mut list = Vec::new();
-    render_layout_box(&mut list, layout_root);
-    return list;
+    let source = canvas_background(layout_root).map(|(color, source)| {
+        list.push(DisplayCommand::SolidColor(color, canvas));
+        source
+    });
+    render_layout_box(&mut list, layout_root, bounds, source, trace);
+    list
+}
+
+/// The commands one box painted: `commands` is the range of the display list it
+/// covers, its descendants' commands included, and `phases` the ranges it painted
+/// itself, in order. A box's transform is pushed just before `commands` and popped
+/// just after.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PaintedBox {
+    pub name: String, // its element's tag and id, like `div#main`
+    pub parent: Option<usize>, // the index of the box it's painted inside, in the trace
+    pub border_box: Rect,
+    pub transformed: bool, // painted between a `PushTransform` and a `PopTransform` of its own
+    pub commands: Range<usize>,
+    pub phases: Vec<(PaintPhase, Range<usize>)>,
+}
+
+/// What a box paints itself, in the order it paints it. Its children are painted
+/// after its `RowDecorations` and before its `CollapsedBorders` and `Outline`.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum PaintPhase {
+    Decorations, // its own background, borders and image
+    InlineDecorations, // backgrounds and borders of the inline elements on its lines
+    Text, // its lines' text, and their underlines
+    RowDecorations, // a table's row groups and rows, under its cells
+    CollapsedBorders,
+    Outline, // over everything inside the box
+}
+
+// where `build_display_list_traced` records each box as it's painted; without
+// `boxes`, nothing is recorded
+struct Trace<'t> {
+    boxes: Option<&'t mut Vec<PaintedBox>>,
+    current: Option<usize>, // the box being painted
+    // the area painted, which fixed backgrounds are positioned in: the viewport or
+    // page, or the whole canvas
+    viewport: Rect,
+}
+
+impl Trace<'_> {
+    // start recording `layout_box`, returning the box it's painted inside, to end with
+    fn begin(&mut self, layout_box: &LayoutBox, at: usize) -> Option<usize> {
+        let boxes = self.boxes.as_deref_mut()?;
+        boxes.push(PaintedBox {
+            name: validate::describe(layout_box),
+            parent: self.current,
+            border_box: layout_box.dimensions.border_box(),
+            transformed: transform_of(layout_box).is_some(),
+            commands: at..at,
+            phases: Vec::new(),
+        });
+        self.current.replace(boxes.len() - 1)
+    }
+
+    fn end(&mut self, parent: Option<usize>, at: usize) {
+        if let (Some(boxes), Some(current)) = (self.boxes.as_deref_mut(), self.current) {
+            boxes[current].commands.end = at;
+        }
+        self.current = parent;
+    }
+
+    fn phase(&mut self, phase: PaintPhase, commands: Range<usize>) {
+        if let (Some(boxes), Some(current)) = (self.boxes.as_deref_mut(), self.current) {
+            if !commands.is_empty() {
+                boxes[current].phases.push((phase, commands));
+            }
+        }
+    }
+}
+
+/// The background of the canvas, and the box it's taken from.
+///
+/// https://www.w3.org/TR/css-backgrounds-3/#special-backgrounds
+///
+/// The root element's background covers the whole canvas, margins included, instead
+/// of its own border box. If the root has no background, the body's is used.
+fn canvas_background<'b, 'a>(root: &'b LayoutBox<'a>) -> Option<(Color, &'b LayoutBox<'a>)> {
+    if let Some(color) = background_color(root) {
+        return Some((color, root));
+    }
+    let body = root.children.iter().find(|child| match child.box_type {
+        BlockNode(style) => matches!(style.node.node_type, NodeType::Element(ref e) if e.tag_name == "body"),
+        _ => false
+    })?;
+    background_color(body).map(|color| (color, body))
+}
+
+// `canvas_source` is the box whose background was painted on the canvas instead
+fn render_layout_box(list: &mut DisplayList, layout_box: &LayoutBox, bounds: Option<Rect>, canvas_source: Option<&LayoutBox>, trace: &mut Trace) {
+    let transform = match transform_of(layout_box) {
+        Some(transform) => transform,
+        None => return render_untransformed_box(list, layout_box, bounds, canvas_source, trace)
+    };
+    if bounds.is_some_and(|bounds| transform.map_rect(layout_box.subtree_bounds()).intersection(bounds).is_none()) {
+        return;
+    }
+    // a box scaled to nothing paints nothing
+    let inverse = match transform.inverse() {
+        Some(inverse) => inverse,
+        None => return
+    };
+    // the box paints as usual where the transform maps to `bounds`
+    list.push(DisplayCommand::PushTransform(transform));
+    render_untransformed_box(list, layout_box, bounds.map(|bounds| inverse.map_rect(bounds)), canvas_source, trace);
+    list.push(DisplayCommand::PopTransform);
+}
+
+/// The `transform` of a block-level or inline-block box, about its `transform-origin`.
+///
+/// https://www.w3.org/TR/css-transforms-1/#transform-rendering
+///
+/// Only translations and scales are supported. Transforms are purely visual: layout
+/// places the box as if it had none, and the box and its contents are then painted
+/// through the transform. Percentages in translations, and the origin, are of the
+/// border box, and the origin is its center by default.
+fn transform_of(layout_box: &LayoutBox) -> Option<Transform> {
+    let style = match layout_box.box_type {
+        BlockNode(style) | InlineBlockNode(style) => style,
+        _ => return None
+    };
+    let value = style.value("transform")?;
+    let functions = match value {
+        Value::List(ref functions) => functions.as_slice(),
+        Value::Function(..) => std::slice::from_ref(&value),
+        _ => return None
+    };
+    let border_box = layout_box.dimensions.border_box();
+    let mut transform = Transform::IDENTITY;
+    for function in functions {
+        transform = transform.after(transform_function(function, border_box)?);
+    }
+    let center = Value::Keyword("center".to_string());
+    let origin = style.value("transform-origin").unwrap_or(center);
+    let (x, y) = replaced::resolve_position(&origin, border_box.width, border_box.height);
+    let (x, y) = (border_box.x + x, border_box.y + y);
+    Some(Transform::translation(x, y).after(transform).after(Transform::translation(-x, -y)))
+}
+
+// one function of a `transform` list, or `None` for one that isn't supported
+fn transform_function(function: &Value, border_box: Rect) -> Option<Transform> {
+    let (name, args) = match function {
+        Value::Function(name, args) => (name.as_str(), args.as_slice()),
+        _ => return None
+    };
+    let factor = |value: &Value| match *value {
+        Value::Number(n) => Some(n),
+        Value::Length(p, Unit::Percent) => Some(p / 100.0),
+        _ => None
+    };
+    let (width, height) = (border_box.width, border_box.height);
+    Some(match (name, args) {
+        ("translate", [x]) => Transform::translation(x.to_px_of(width), 0.0),
+        ("translate", [x, y]) => Transform::translation(x.to_px_of(width), y.to_px_of(height)),
+        ("translatex", [x]) => Transform::translation(x.to_px_of(width), 0.0),
+        ("translatey", [y]) => Transform::translation(0.0, y.to_px_of(height)),
+        ("scale", [s]) => Transform::scale(factor(s)?, factor(s)?),
+        ("scale", [x, y]) => Transform::scale(factor(x)?, factor(y)?),
+        ("scalex", [x]) => Transform::scale(factor(x)?, 1.0),
+        ("scaley", [y]) => Transform::scale(1.0, factor(y)?),
+        _ => return None
+    })
 }
 
-fn render_layout_box(list: &mut DisplayList, layout_box: &LayoutBox) {
-    render_background(list, layout_box);
-    render_borders(list, layout_box);
-    // TODO: render text
+fn render_untransformed_box(list: &mut DisplayList, layout_box: &LayoutBox, bounds: Option<Rect>, canvas_source: Option<&LayoutBox>, trace: &mut Trace) {
+    if let Some(bounds) = bounds {
+        if layout_box.subtree_bounds().intersection(bounds).is_none() {
+            return;
+        }
+    }
+    let parent = trace.begin(layout_box, list.len());
+    render_box_and_contents(list, layout_box, bounds, canvas_source, trace);
+    trace.end(parent, list.len());
+}
+
+fn render_box_and_contents(list: &mut DisplayList, layout_box: &LayoutBox, bounds: Option<Rect>, canvas_source: Option<&LayoutBox>, trace: &mut Trace) {
+    // a hidden box still has visible descendants painted, if they set visibility back
+    let visible = layout_box.style_node().is_none_or(StyledNode::is_visible);
+    // inline elements are decorated along with the lines they're on
+    let start = list.len();
+    if visible && !matches!(layout_box.box_type, InlineNode(_)) {
+        // the canvas has taken the color, but not the images over it
+        let on_canvas = canvas_source.is_some_and(|source| std::ptr::eq(source, layout_box));
+        render_background(list, layout_box, !on_canvas, trace.viewport, bounds);
+        render_borders(list, layout_box);
+        render_image(list, layout_box);
+        render_control(list, layout_box);
+    }
+    trace.phase(PaintPhase::Decorations, start..list.len());
+    if !layout_box.contents_skipped {
+        render_contents(list, layout_box, bounds, canvas_source, trace);
+    }
+    let start = list.len();
+    if visible {
+        render_outline(list, layout_box);
+    }
+    trace.phase(PaintPhase::Outline, start..list.len());
+}
+
+// what's inside a box: its lines, then its children
+fn render_contents(list: &mut DisplayList, layout_box: &LayoutBox, bounds: Option<Rect>, canvas_source: Option<&LayoutBox>, trace: &mut Trace) {
+    let start = list.len();
+    if !layout_box.lines.is_empty() {
+        render_inline_decorations(list, &layout_box.children, trace.viewport, bounds);
+    }
+    trace.phase(PaintPhase::InlineDecorations, start..list.len());
+    let start = list.len();
+    render_text(list, layout_box, bounds);
+    trace.phase(PaintPhase::Text, start..list.len());
+    if layout_box.style_node().is_some_and(|style| style.display() == Display::Table) {
+        // the backgrounds of all row groups and rows go under the cells, so that a
+        // cell spanning rows isn't painted over by the rows below its first
+        let start = list.len();
+        for part in &layout_box.children {
+            render_row_decorations(list, part, trace.viewport, bounds);
+        }
+        trace.phase(PaintPhase::RowDecorations, start..list.len());
+        for part in &layout_box.children {
+            render_cells(list, part, bounds, canvas_source, trace);
+        }
+        let start = list.len();
+        for &(rect, color) in &layout_box.collapsed_borders {
+            list.push(DisplayCommand::SolidColor(color, rect));
+        }
+        trace.phase(PaintPhase::CollapsedBorders, start..list.len());
+        return;
+    }
     for child in &layout_box.children {
-        render_layout_box(list, child);
+        render_layout_box(list, child, bounds, canvas_source, trace);
+    }
+}
+
+/// Append translucent overlays showing the box model of every box, as browser
+/// developer tools do: margins in orange, padding in green, and each content box
+/// outlined in blue. Anonymous boxes are outlined in purple instead and labelled.
+///
+/// `bounds` limits the overlays to the boxes painted there, like
+/// `build_display_list_in`. Inline elements show only their padding and content,
+/// fragment by fragment, since their margins aren't kept.
+pub fn append_debug_overlay(list: &mut DisplayList, layout_box: &LayoutBox, bounds: Option<Rect>) {
+    if bounds.is_some_and(|bounds| layout_box.subtree_bounds().intersection(bounds).is_none()) {
+        return;
+    }
+    let d = layout_box.dimensions;
+    match layout_box.box_type {
+        InlineNode(style) if matches!(style.node.node_type, NodeType::Element(_)) => {
+            for fragment in &layout_box.inline_fragments {
+                let padding_box = fragment.border_box.shrunk_by(fragment.border);
+                let content = padding_box.shrunk_by(fragment.padding);
+                push_ring(list, OVERLAY_PADDING, padding_box, content);
+                push_outline(list, OVERLAY_CONTENT, content);
+            }
+        }
+        InlineNode(_) => {}
+        AnonymousBlock => {
+            push_outline(list, OVERLAY_ANONYMOUS, d.content);
+            let font = Font { size: 8.0, weight: 400, italic: false };
+            let label = "anonymous".to_string();
+            let width = StandardFontMeasurer.measure(&label, &font);
+            let height = StandardFontMeasurer.line_height(&font);
+            list.push(DisplayCommand::Text(label, font, OVERLAY_ANONYMOUS, Rect { x: d.content.x, y: d.content.y, width, height }));
+        }
+        BlockNode(_) | InlineBlockNode(_) => {
+            push_ring(list, OVERLAY_MARGIN, d.margin_box(), d.border_box());
+            push_ring(list, OVERLAY_PADDING, d.padding_box(), d.content);
+            push_outline(list, OVERLAY_CONTENT, d.content);
+        }
+    }
+    if !layout_box.contents_skipped {
+        for child in &layout_box.children {
+            append_debug_overlay(list, child, bounds);
+        }
+    }
+}
+
+// the colors of the debug overlay, after those of Chrome's developer tools
+const OVERLAY_MARGIN: Color = Color { r: 246, g: 178, b: 107, a: 168 };
+const OVERLAY_PADDING: Color = Color { r: 147, g: 196, b: 125, a: 140 };
+const OVERLAY_CONTENT: Color = Color { r: 111, g: 168, b: 220, a: 255 };
+const OVERLAY_ANONYMOUS: Color = Color { r: 160, g: 90, b: 200, a: 255 };
+
+// fill the area between `outer` and the `inner` rectangle inside it, a side at a time
+fn push_ring(list: &mut DisplayList, color: Color, outer: Rect, inner: Rect) {
+    let (outer_right, outer_bottom) = (outer.x + outer.width, outer.y + outer.height);
+    let (inner_right, inner_bottom) = (inner.x + inner.width, inner.y + inner.height);
+    let sides = [
+        Rect { x: outer.x, y: outer.y, width: outer.width, height: inner.y - outer.y },
+        Rect { x: outer.x, y: inner_bottom, width: outer.width, height: outer_bottom - inner_bottom },
+        Rect { x: outer.x, y: inner.y, width: inner.x - outer.x, height: inner.height },
+        Rect { x: inner_right, y: inner.y, width: outer_right - inner_right, height: inner.height },
+    ];
+    // negative margins leave nothing to fill on their side
+    for side in sides.into_iter().filter(|side| !side.is_empty()) {
+        list.push(DisplayCommand::SolidColor(color, side));
+    }
+}
+
+// a 1px line just inside the edges of `rect`
+fn push_outline(list: &mut DisplayList, color: Color, rect: Rect) {
+    let line = EdgeSizes { left: 1.0, right: 1.0, top: 1.0, bottom: 1.0 };
+    let inner = rect.shrunk_by(line);
+    if inner.is_empty() {
+        // too small to have an inside: fill it all
+        if rect.width > 0.0 || rect.height > 0.0 {
+            list.push(DisplayCommand::SolidColor(color, Rect { width: rect.width.max(1.0), height: rect.height.max(1.0), ..rect }));
+        }
+        return;
+    }
+    push_ring(list, color, rect, inner);
+}
+
+// a replaced element's image, fitted into its content box and clipped to it
+fn render_image(list: &mut DisplayList, layout_box: &LayoutBox) {
+    let (image, style) = match (&layout_box.image, layout_box.style_node()) {
+        (Some(image), Some(style)) => (image, style),
+        _ => return
+    };
+    let content = layout_box.dimensions.content;
+    let center = Value::Keyword("center".to_string());
+    let position = style.value("object-position").unwrap_or(center);
+    let intrinsic = (image.width as f32, image.height as f32);
+    let rect = replaced::fit_image(ObjectFit::of(style), &position, content, intrinsic);
+    list.push(DisplayCommand::Image(image.clone(), rect, content));
+}
+
+// what a form control shows besides text, backgrounds and borders: the mark of a
+// checked checkbox, the circle of a radio button and its dot when checked, and
+// the arrow of a select, in the right padding. Marks are in the control's color.
+fn render_control(list: &mut DisplayList, layout_box: &LayoutBox) {
+    let elem = match layout_box.box_type {
+        InlineBlockNode(style) | BlockNode(style) => match style.node.node_type {
+            NodeType::Element(ref elem) => elem,
+            _ => return
+        },
+        _ => return
+    };
+    let black = Color { r: 0, g: 0, b: 0, a: 255 };
+    let mark = get_color(layout_box, "color").unwrap_or(black);
+    let d = layout_box.dimensions;
+    let content = d.content;
+    match forms::control(elem) {
+        Some(Control::Checkbox) if forms::is_checked(elem) => {
+            let inset = (content.width.min(content.height) / 5.0).round();
+            let edge = EdgeSizes { left: inset, right: inset, top: inset, bottom: inset };
+            list.push(DisplayCommand::SolidColor(mark, content.shrunk_by(edge)));
+        }
+        Some(Control::Radio) => {
+            let ring = get_color(layout_box, "border-color").unwrap_or(black);
+            let white = Color { r: 255, g: 255, b: 255, a: 255 };
+            let radius = content.width.min(content.height) / 2.0;
+            let center = (content.x + content.width / 2.0, content.y + content.height / 2.0);
+            push_disc(list, ring, center, radius);
+            push_disc(list, white, center, radius - 1.0);
+            if forms::is_checked(elem) {
+                push_disc(list, mark, center, radius * 0.5);
+            }
+        }
+        Some(Control::Select) => {
+            // a triangle pointing down, half as wide as the padding it's centered in
+            let width = d.padding.right / 2.0;
+            let left = content.x + content.width + d.padding.right / 4.0;
+            let rows = (width / 2.0).round().max(1.0) as usize;
+            let top = content.y + (content.height - rows as f32) / 2.0;
+            for row in 0..rows {
+                let inset = row as f32 * width / (2.0 * rows as f32);
+                let rect = Rect { x: left + inset, y: top + row as f32, width: width - 2.0 * inset, height: 1.0 };
+                list.push(DisplayCommand::SolidColor(mark, rect));
+            }
+        }
+        _ => {}
+    }
+}
+
+// fill a circle a row of pixels at a time, each row as wide as the circle is
+// through the middle of it
+fn push_disc(list: &mut DisplayList, color: Color, (center_x, center_y): (f32, f32), radius: f32) {
+    if radius <= 0.0 {
+        return;
+    }
+    let mut y = (center_y - radius).floor();
+    while y < center_y + radius {
+        let top = y.max(center_y - radius);
+        let bottom = (y + 1.0).min(center_y + radius);
+        let dy = (top + bottom) / 2.0 - center_y;
+        let half = (radius * radius - dy * dy).max(0.0).sqrt();
+        list.push(DisplayCommand::SolidColor(color, Rect { x: center_x - half, y: top, width: 2.0 * half, height: bottom - top }));
+        y += 1.0;
+    }
+}
+
+// backgrounds and borders of a row, or of a row group and its rows
+fn render_row_decorations(list: &mut DisplayList, part: &LayoutBox, viewport: Rect, bounds: Option<Rect>) {
+    if part.style_node().is_some_and(StyledNode::is_visible) {
+        render_background(list, part, true, viewport, bounds);
+        render_borders(list, part);
+    }
+    if part.style_node().is_some_and(|style| style.display() == Display::TableRowGroup) {
+        for row in &part.children {
+            render_row_decorations(list, row, viewport, bounds);
+        }
+    }
+}
+
+// the cells of a row, or of the rows of a row group
+fn render_cells(list: &mut DisplayList, part: &LayoutBox, bounds: Option<Rect>, canvas_source: Option<&LayoutBox>, trace: &mut Trace) {
+    for child in &part.children {
+        if child.style_node().is_some_and(|style| style.display() == Display::TableRow) {
+            render_cells(list, child, bounds, canvas_source, trace);
+        } else {
+            render_layout_box(list, child, bounds, canvas_source, trace);
+        }
+    }
+}
+
+// the box's outline, around each of its decoration areas; outlines take up no room,
+// so they go over the contents, and can overlap whatever is next to the box
+fn render_outline(list: &mut DisplayList, layout_box: &LayoutBox) {
+    let outline = match layout_box.style_node().and_then(StyledNode::outline) {
+        Some(outline) => outline,
+        None => return
+    };
+    for area in decoration_areas(layout_box) {
+        let inner = outset(area.border_box, outline.offset);
+        let outer = outset(inner, outline.width);
+        match inner.is_empty() {
+            // a negative offset can leave nothing inside
+            true => list.push(DisplayCommand::SolidColor(outline.color, outer)),
+            false => push_ring(list, outline.color, outer, inner),
+        }
+    }
+}
+
+// `rect` grown by `by` on each side, or shrunk for a negative `by`
+fn outset(rect: Rect, by: f32) -> Rect {
+    let edges = EdgeSizes { left: by.abs(), right: by.abs(), top: by.abs(), bottom: by.abs() };
+    match by < 0.0 {
+        true => rect.shrunk_by(edges),
+        false => rect.expanded_by(edges),
+    }
+}
+
+// backgrounds and borders of the inline elements on some lines, in tree order, to
+// go under the lines' text; inline-blocks are painted whole, later
+fn render_inline_decorations(list: &mut DisplayList, boxes: &[LayoutBox], viewport: Rect, bounds: Option<Rect>) {
+    for layout_box in boxes {
+        if let InlineNode(style) = layout_box.box_type {
+            if style.is_visible() {
+                render_background(list, layout_box, true, viewport, bounds);
+                render_borders(list, layout_box);
+            }
+            render_inline_decorations(list, &layout_box.children, viewport, bounds);
+        }
+    }
+}
+
+// the border boxes a box paints its background and borders in, with the border
+// and padding widths of each: one per line for inline elements, otherwise just the one
+fn decoration_areas(layout_box: &LayoutBox) -> Vec<InlineFragment> {
+    match layout_box.box_type {
+        InlineNode(_) => layout_box.inline_fragments.clone(),
+        _ => vec![InlineFragment {
+            border_box: layout_box.dimensions.border_box(),
+            border: layout_box.dimensions.border,
+            padding: layout_box.dimensions.padding,
+        }]
+    }
+}
+
+// the background color, unless `color` is false, and then the background layers
+// from the bottom one up; fixed layers are positioned in `viewport`, and tiles
+// outside `bounds` are left out
+fn render_background(list: &mut DisplayList, layout_box: &LayoutBox, color: bool, viewport: Rect, bounds: Option<Rect>) {
+    let color = background_color(layout_box).filter(|_| color);
+    let layers = layout_box.style_node().map_or_else(Vec::new, background::layers);
+    if color.is_none() && layers.is_empty() {
+        return;
     }
+    let clip = background_clip(layout_box);
+    for area in decoration_areas(layout_box) {
+        // the background reaches under the borders unless clipped, so it shows
+        // through wherever they are transparent
+        let padding_box = area.border_box.shrunk_by(area.border);
+        let painted = match clip {
+            BackgroundClip::Border => area.border_box,
+            BackgroundClip::Padding => padding_box,
+            BackgroundClip::Content => padding_box.shrunk_by(area.padding),
+        };
+        if let Some(color) = color {
+            list.push(DisplayCommand::SolidColor(color, painted));
+        }
+        let visible = match bounds {
+            Some(bounds) => painted.intersection(bounds),
+            None => Some(painted),
+        };
+        for (index, layer) in layers.iter().rev().filter(|_| visible.is_some()) {
+            let origin = match layer.fixed {
+                true => viewport,
+                false => padding_box,
+            };
+            background::render_layer(list, layout_box, *index, layer, origin, painted, visible.unwrap_or(painted));
+        }
+    }
+}
+
+// https://www.w3.org/TR/css-backgrounds-3/#background-clip
+enum BackgroundClip {
+    Border,
+    Padding,
+    Content,
 }
 
-fn render_background(list: &mut DisplayList, layout_box: &LayoutBox) {
-    if let Some(color) = get_color(layout_box, "background") {
-        list.push(DisplayCommand::SolidColor(color, layout_box.dimensions.border_box()));
+fn background_clip(layout_box: &LayoutBox) -> BackgroundClip {
+    match layout_box.style_node().and_then(|style| style.value("background-clip")) {
+        Some(Value::Keyword(k)) if k == "padding-box" => BackgroundClip::Padding,
+        Some(Value::Keyword(k)) if k == "content-box" => BackgroundClip::Content,
+        _ => BackgroundClip::Border
     }
 }
 
 fn render_borders(list: &mut DisplayList, layout_box: &LayoutBox) {
+    // with collapsing borders, a table paints its own borders and its cells'
+    let collapsed = layout_box.style_node().is_some_and(|style| {
+        matches!(style.display(), Display::Table | Display::TableCell)
+            && style.value("border-collapse") == Some(Value::Keyword("collapse".to_string()))
+    });
+    if collapsed {
+        return;
+    }
     let color = match get_color(layout_box, "border-color") {
         Some(color) => color,
         _ => return
     };
+    for area in decoration_areas(layout_box) {
+        render_border(list, color, area.border_box, area.border);
+    }
+}
 
-    let d = &layout_box.dimensions;
-    let border_box = d.border_box();
-
+fn render_border(list: &mut DisplayList, color: Color, border_box: Rect, border: EdgeSizes) {
     // left border
     list.push(DisplayCommand::SolidColor(color, Rect {
         x: border_box.x,
         y: border_box.y,
-        width: d.border.left,
+        width: border.left,
         height: border_box.height,
     }));
 
     // right border
     list.push(DisplayCommand::SolidColor(color, Rect {
-        x: border_box.x + border_box.width - d.border.right,
+        x: border_box.x + border_box.width - border.right,
         y: border_box.y,
-        width: d.border.right,
+        width: border.right,
         height: border_box.height,
     }));
 
@@ -75,21 +1027,131 @@ fn render_borders(list: &mut DisplayList, layout_box: &LayoutBox) {
         x: border_box.x,
         y: border_box.y,
         width: border_box.width,
-        height: d.border.top,
+        height: border.top,
     }));
 
     // bottom border
     list.push(DisplayCommand::SolidColor(color, Rect {
         x: border_box.x,
-        y: border_box.y + border_box.height - d.border.bottom,
+        y: border_box.y + border_box.height - border.bottom,
         width: border_box.width,
-        height: d.border.bottom,
+        height: border.bottom,
     }));
 }
 
+// the lines outside `bounds` are left out; they're in order down the box, and each
+// line's text is within its line box, so they're found without looking at the rest
+fn render_text(list: &mut DisplayList, layout_box: &LayoutBox, bounds: Option<Rect>) {
+    let lines = match bounds {
+        Some(bounds) => {
+            let lines = &layout_box.lines;
+            let first = lines.partition_point(|line| line.rect.y + line.rect.height < bounds.y);
+            let end = first + lines[first..].partition_point(|line| line.rect.y <= bounds.y + bounds.height);
+            &lines[first..end]
+        }
+        None => &layout_box.lines[..]
+    };
+    for line in lines {
+        let visible: Vec<_> = line.fragments.iter().filter(|fragment| fragment.node.is_visible()).collect();
+        for (i, fragment) in visible.iter().enumerate() {
+            let color = match fragment.node.value("color") {
+                Some(Value::ColorValue(color)) => color,
+                _ => Color { r: 0, g: 0, b: 0, a: 255 }
+            };
+            let decorations = text_decorations(fragment.node);
+            // a decoration carries on across the space to the next run that has it too
+            let next = visible.get(i + 1).filter(|next| text_decorations(next.node) == decorations);
+            let right = next.map_or(fragment.rect.x + fragment.rect.width, |next| next.rect.x);
+            let line_rect = |offset: f32| {
+                let thickness = (fragment.font.size / 20.0).max(1.0);
+                let y = fragment.font.baseline(fragment.rect.y, fragment.rect.height) + offset * fragment.font.size;
+                DisplayCommand::SolidColor(color, Rect { x: fragment.rect.x, y, width: right - fragment.rect.x, height: thickness })
+            };
+            // underlines and overlines go under the text, line-throughs over it; the
+            // offsets below the baseline are from Helvetica's metrics
+            if decorations.underline {
+                list.push(line_rect(0.1));
+            }
+            if decorations.overline {
+                list.push(line_rect(-0.75));
+            }
+            list.push(DisplayCommand::Text(fragment.text.to_string(), fragment.font, color, fragment.rect));
+            if decorations.line_through {
+                list.push(line_rect(-0.3));
+            }
+        }
+    }
+}
+
+// the lines `text-decoration` draws on a run of text
+#[derive(Default, PartialEq)]
+struct TextDecorations {
+    underline: bool,
+    overline: bool,
+    line_through: bool,
+}
+
+// Decorations are inherited like a property rather than propagated, so text in an
+// element with its own `text-decoration` loses its ancestors' lines.
+fn text_decorations(node: &StyledNode) -> TextDecorations {
+    let mut decorations = TextDecorations::default();
+    let keywords = match node.value("text-decoration") {
+        Some(Value::List(values)) => values,
+        Some(value) => vec![value],
+        None => return decorations,
+    };
+    for keyword in keywords {
+        match keyword {
+            Value::Keyword(k) if k == "underline" => decorations.underline = true,
+            Value::Keyword(k) if k == "overline" => decorations.overline = true,
+            Value::Keyword(k) if k == "line-through" => decorations.line_through = true,
+            _ => {}
+        }
+    }
+    decorations
+}
+
+/// A word of laid-out text, with where it was painted and where it came from.
+pub struct TextFragment<'a> {
+    pub text: String,
+    pub rect: Rect, // bounding box in CSS px
+    pub node: &'a Node, // the DOM text node containing the word
+    pub index: usize, // byte offset of the word within the node's text
+    pub line: usize, // index of the line box, counting through the whole document
+}
+
+/// Every word in the layout tree, in reading order.
+///
+/// Fragments are listed line by line and left to right within a line, so joining
+/// their text with single spaces reconstructs the visible, whitespace-collapsed text.
+/// Right-to-left text is the exception: it's listed a character at a time, in the
+/// order the characters are painted.
+pub fn text_fragments<'a>(layout_root: &LayoutBox<'a>) -> Vec<TextFragment<'a>> {
+    fn walk<'a>(layout_box: &LayoutBox<'a>, fragments: &mut Vec<TextFragment<'a>>, line_count: &mut usize) {
+        for line in &layout_box.lines {
+            for fragment in &line.fragments {
+                fragments.push(TextFragment {
+                    text: fragment.text.to_string(),
+                    rect: fragment.rect,
+                    node: &fragment.node.node,
+                    index: fragment.index,
+                    line: *line_count,
+                });
+            }
+            *line_count += 1;
+        }
+        for child in &layout_box.children {
+            walk(child, fragments, line_count);
+        }
+    }
+    let mut fragments = Vec::new();
+    walk(layout_root, &mut fragments, &mut 0);
+    fragments
+}
+
 fn get_color(layout_box: &LayoutBox, name: &str) -> Option<Color> {
     match layout_box.box_type {
-        BlockNode(style) | InlineNode(style) => match style.value(name) {
+        BlockNode(style) | InlineNode(style) | InlineBlockNode(style) => match style.value(name) {
             Some(Value::ColorValue(color)) => Some(color),
             _ => None
         },
@@ -97,33 +1159,175 @@ fn get_color(layout_box: &LayoutBox, name: &str) -> Option<Color> {
     }
 }
 
+// background-color when it's set, otherwise the color of the background shorthand
+fn background_color(layout_box: &LayoutBox) -> Option<Color> {
+    let name = match layout_box.box_type {
+        BlockNode(style) | InlineNode(style) | InlineBlockNode(style) if style.value("background-color").is_some() => "background-color",
+        _ => "background"
+    };
+    get_color(layout_box, name)
+}
+
 impl Canvas {
-    // create a blank canvas
-    fn new(width: usize, height: usize) -> Canvas {
+    // create a blank canvas, unless it has more pixels than `limits` allow
+    pub fn new(width: usize, height: usize, limits: &Limits) -> Result<Canvas, EngineError> {
+        let max = limits.max_canvas_pixels;
+        match width.checked_mul(height) {
+            Some(pixels) if pixels <= max => Ok(Canvas::blank(width, height)),
+            _ => Err(EngineError::LimitExceeded { limit: Limit::CanvasPixels, max })
+        }
+    }
+
+    fn blank(width: usize, height: usize) -> Canvas {
         let white = Color { r: 255, g: 255, b: 255, a: 255 };
         Canvas {
             pixels: vec![white; width * height],
             width,
-            height
+            height,
+            blending: Blending::default()
+        }
+    }
+
+    // the canvas as a binary PPM image; PPM has no alpha channel, so translucent
+    // pixels are composited over white
+    pub fn to_ppm(&self) -> Vec<u8> {
+        let mut ppm = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        for pixel in &self.pixels {
+            let a = pixel.a as u32;
+            let over_white = |c: u8| ((c as u32 * a + 255 * (255 - a) + 127) / 255) as u8;
+            ppm.extend_from_slice(&[over_white(pixel.r), over_white(pixel.g), over_white(pixel.b)]);
+        }
+        ppm
+    }
+
+    // composite `color` over the pixel at `index` ("source over" in Porter-Duff terms)
+    fn blend_pixel(&mut self, index: usize, color: Color) {
+        match color.a {
+            0 => return,
+            255 => { self.pixels[index] = color; return; }
+            _ => {}
         }
+        let dst = self.pixels[index];
+        let src_a = color.a as f32 / 255.0;
+        let dst_a = dst.a as f32 / 255.0;
+        let out_a = src_a + dst_a * (1.0 - src_a);
+        let channel = |s: u8, d: u8| -> u8 {
+            match self.blending {
+                Blending::Srgb => {
+                    let c = (s as f32 * src_a + d as f32 * dst_a * (1.0 - src_a)) / out_a;
+                    c.round() as u8
+                }
+                Blending::Linear => {
+                    let lut = srgb_to_linear_table();
+                    let c = (lut[s as usize] * src_a + lut[d as usize] * dst_a * (1.0 - src_a)) / out_a;
+                    linear_to_srgb(c)
+                }
+            }
+        };
+        self.pixels[index] = Color {
+            r: channel(color.r, dst.r),
+            g: channel(color.g, dst.g),
+            b: channel(color.b, dst.b),
+            a: (out_a * 255.0).round() as u8
+        };
     }
 
-    fn paint_item(&mut self, item: &DisplayCommand) {
+    // draw `image` scaled to `rect`, sampling the nearest source pixel for each
+    // canvas pixel whose center is inside both `rect` and `clip`
+    fn paint_image(&mut self, image: &DecodedImage, rect: Rect, clip: Rect) {
+        let canvas = Rect { x: 0.0, y: 0.0, width: self.width as f32, height: self.height as f32 };
+        let painted = match rect.intersection(clip).and_then(|area| area.intersection(canvas)) {
+            Some(painted) if image.width > 0 && image.height > 0 => painted,
+            _ => return
+        };
+        let x0 = painted.x.round() as usize;
+        let y0 = painted.y.round() as usize;
+        let x1 = ((painted.x + painted.width).round() as usize).min(self.width);
+        let y1 = ((painted.y + painted.height).round() as usize).min(self.height);
+        let (width, height) = (image.width as usize, image.height as usize);
+        for y in y0..y1 {
+            let source_y = ((y as f32 + 0.5 - rect.y) / rect.height * height as f32) as usize;
+            for x in x0..x1 {
+                let source_x = ((x as f32 + 0.5 - rect.x) / rect.width * width as f32) as usize;
+                let i = (source_y.min(height - 1) * width + source_x.min(width - 1)) * 4;
+                let [r, g, b, a] = [image.rgba[i], image.rgba[i + 1], image.rgba[i + 2], image.rgba[i + 3]];
+                self.blend_pixel(y * self.width + x, Color { r, g, b, a });
+            }
+        }
+    }
+
+    pub fn paint_item(&mut self, item: &DisplayCommand) {
+        if item.is_transparent() {
+            return;
+        }
         match item {
             DisplayCommand::SolidColor(color, rect) => {
-                // clip rect to canvas boundaries
-                let x0 = rect.x.clamp(0.0, self.width as f32) as usize;
-                let y0 = rect.y.clamp(0.0, self.height as f32) as usize;
-                let x1 = (rect.x + rect.width).clamp(0.0, self.width as f32) as usize;
-                let y1 = (rect.y + rect.height).clamp(0.0, self.height as f32) as usize;
+                // clip rect to canvas boundaries while still in f32, so negative, huge
+                // or NaN coordinates never reach the index arithmetic
+                let canvas = Rect { x: 0.0, y: 0.0, width: self.width as f32, height: self.height as f32 };
+                let clipped = match rect.intersection(canvas) {
+                    Some(clipped) => clipped,
+                    None => return
+                };
+                let x0 = clipped.x as usize;
+                let y0 = clipped.y as usize;
+                let x1 = ((clipped.x + clipped.width) as usize).min(self.width);
+                let y1 = ((clipped.y + clipped.height) as usize).min(self.height);
 
                 for y in y0..y1 {
                     for x in x0..x1 {
-                        // TODO: alpha compositing with existing pixel
-                        self.pixels[y * self.width + x] = *color;
+                        self.blend_pixel(y * self.width + x, *color);
                     }
                 }
             }
+            // TODO: rasterize glyphs
+            DisplayCommand::Text(..) => {}
+            DisplayCommand::Image(image, rect, clip) => self.paint_image(image, *rect, *clip),
+            // lists are painted flattened, with `flatten_transforms`
+            DisplayCommand::PushTransform(_) | DisplayCommand::PopTransform => {}
+        }
+    }
+}
+// The sRGB transfer function, tabulated: decoding has one entry per 8-bit value,
+// and encoding samples linear light finely enough to round-trip every 8-bit value.
+const LINEAR_STEPS: usize = 4096;
+
+fn srgb_to_linear_table() -> &'static [f32; 256] {
+    static TABLE: OnceLock<[f32; 256]> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        let mut table = [0.0; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let c = i as f32 / 255.0;
+            *entry = if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) };
         }
+        table
+    })
+}
+
+fn linear_to_srgb(value: f32) -> u8 {
+    static TABLE: OnceLock<Vec<u8>> = OnceLock::new();
+    let table = TABLE.get_or_init(|| {
+        (0..LINEAR_STEPS).map(|i| {
+            let l = i as f32 / (LINEAR_STEPS - 1) as f32;
+            let c = if l <= 0.0031308 { l * 12.92 } else { 1.055 * l.powf(1.0 / 2.4) - 0.055 };
+            (c * 255.0).round() as u8
+        }).collect()
+    });
+    table[(value.clamp(0.0, 1.0) * (LINEAR_STEPS - 1) as f32).round() as usize]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canvas_over_the_pixel_limit_is_refused() {
+        let limits = Limits::default();
+        assert!(Canvas::new(800, 600, &limits).is_ok());
+        let too_many = Canvas::new(100_000, 100_000, &limits);
+        assert!(matches!(too_many, Err(EngineError::LimitExceeded { limit: Limit::CanvasPixels, .. })));
+        // a size whose product overflows is refused rather than wrapped
+        let overflowing = Canvas::new(usize::MAX, 2, &limits);
+        assert!(matches!(overflowing, Err(EngineError::LimitExceeded { limit: Limit::CanvasPixels, .. })));
     }
-}
\ No newline at end of file
+}
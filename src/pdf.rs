@@ -1,6 +1,30 @@
-use crate::layout::{LayoutBox, Rect};
-use crate::painting::{build_display_list, DisplayCommand};
+use crate::css::{Color, Stylesheet, Value};
+use crate::encoding;
+use crate::engine::{EngineError, Limit, Limits};
+use crate::font::Font;
+use crate::layout::{BlockNode, EdgeSizes, InlineBlockNode, LayoutBox, Rect};
+use crate::links::{self, Anchors};
+use crate::style::StyledNode;
+use crate::image_decode::DecodedImage;
+use crate::painting::{append_debug_overlay, build_display_list_in, DisplayCommand};
+use crate::report::{RenderReport, ReportEntry, Severity};
+use crate::stats::RenderStats;
+use std::fmt;
 use std::io::{self, Seek, Write};
+use std::sync::Arc;
+
+// Text is set in the standard Helvetica family, which every PDF reader provides,
+// so no font data needs to be embedded. Indexed by `base_font_index`.
+const BASE_FONTS: [&str; 4] = ["Helvetica", "Helvetica-Bold", "Helvetica-Oblique", "Helvetica-BoldOblique"];
+
+fn base_font_index(font: &Font) -> usize {
+    match (font.is_bold(), font.italic) {
+        (false, false) => 0,
+        (true, false) => 1,
+        (false, true) => 2,
+        (true, true) => 3,
+    }
+}
 
 fn px_to_pt(value: f32) -> f32 {
     // 96px = 1in = 72pt
@@ -8,46 +32,518 @@ fn px_to_pt(value: f32) -> f32 {
     value * 0.75
 }
 
+/// Page geometry for PDF output, in px, and what to draw on the pages.
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub page_width: f32,
+    pub page_height: f32,
+    pub margin: EdgeSizes,
+    // paint the margins, padding and content box of every box over the page
+    pub debug_overlay: bool,
+    // how far down the layout the first page starts, leaving out everything above
+    pub start: f32,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options { page_width: 800.0, page_height: 600.0, margin: EdgeSizes::default(), debug_overlay: false, start: 0.0 }
+    }
+}
+
+impl Options {
+    /// Override the defaults with the `size` and margin descriptors of the
+    /// stylesheets' `@page` rules, later rules taking precedence.
+    ///
+    /// https://www.w3.org/TR/css-page-3/#page-size-prop
+    ///
+    /// Rules for page selectors like `:first` are ignored.
+    pub fn from_stylesheets(stylesheets: &[Stylesheet]) -> Options {
+        let mut options = Options::default();
+        options.apply_stylesheets(stylesheets);
+        options
+    }
+
+    /// Like `from_stylesheets`, but overriding these options rather than the defaults.
+    pub fn apply_stylesheets(&mut self, stylesheets: &[Stylesheet]) {
+        let options = self;
+        let page_rules = stylesheets.iter()
+            .flat_map(|stylesheet| &stylesheet.at_rules)
+            .filter(|rule| rule.name == "page" && rule.prelude.is_empty());
+        for declaration in page_rules.flat_map(|rule| &rule.declarations) {
+            let value = &declaration.value;
+            match declaration.name.as_str() {
+                "size" => options.set_size(value),
+                "margin" => {
+                    // one to four lengths: top, right, bottom, left, with the missing ones mirrored
+                    let lengths: Vec<f32> = match value {
+                        Value::List(values) => values.iter().map(Value::to_px).collect(),
+                        value => vec![value.to_px()],
+                    };
+                    let (top, right, bottom, left) = match lengths[..] {
+                        [all] => (all, all, all, all),
+                        [vertical, horizontal] => (vertical, horizontal, vertical, horizontal),
+                        [top, horizontal, bottom] => (top, horizontal, bottom, horizontal),
+                        [top, right, bottom, left, ..] => (top, right, bottom, left),
+                        [] => continue,
+                    };
+                    options.margin = EdgeSizes { top, right, bottom, left };
+                }
+                "margin-top" => options.margin.top = value.to_px(),
+                "margin-right" => options.margin.right = value.to_px(),
+                "margin-bottom" => options.margin.bottom = value.to_px(),
+                "margin-left" => options.margin.left = value.to_px(),
+                _ => {}
+            }
+        }
+    }
+
+    // `size: <width> <height>`, `size: <length>`, or a paper size with an optional orientation
+    fn set_size(&mut self, value: &Value) {
+        let values = match value {
+            Value::List(values) => values.as_slice(),
+            value => std::slice::from_ref(value),
+        };
+        let mut landscape = None;
+        let mut lengths = Vec::new();
+        for value in values {
+            match value {
+                Value::Length(..) => lengths.push(value.to_px()),
+                Value::Keyword(k) => match &*k.to_ascii_lowercase() {
+                    "portrait" => landscape = Some(false),
+                    "landscape" => landscape = Some(true),
+                    // paper sizes at 96px per inch
+                    "a5" => lengths = vec![559.4, 793.7],
+                    "a4" => lengths = vec![793.7, 1122.5],
+                    "a3" => lengths = vec![1122.5, 1587.4],
+                    "letter" => lengths = vec![816.0, 1056.0],
+                    "legal" => lengths = vec![816.0, 1344.0],
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+        let (mut width, mut height) = match lengths[..] {
+            [side] => (side, side),
+            [width, height, ..] => (width, height),
+            [] => (self.page_width, self.page_height),
+        };
+        if landscape == Some(width < height) {
+            std::mem::swap(&mut width, &mut height);
+        }
+        self.page_width = width;
+        self.page_height = height;
+    }
+
+    /// The area of a page inside its margins, which is the layout viewport.
+    pub fn content_width(&self) -> f32 {
+        (self.page_width - self.margin.left - self.margin.right).max(0.0)
+    }
+
+    pub fn content_height(&self) -> f32 {
+        (self.page_height - self.margin.top - self.margin.bottom).max(0.0)
+    }
+}
+
+/// What was put on the pages of a rendered document.
+#[derive(Debug, Clone)]
+pub struct RenderOutput {
+    pub pages: Vec<(f32, f32)>, // the slice of the layout on each page, from `page_breaks`
+    pub anchors: Anchors,
+    // a warning for each fragment that links point to but no element has the name of
+    pub report: RenderReport,
+    // the render's size; the caller, which owns the DOM and stylesheets, adds their counts
+    pub stats: RenderStats,
+}
+
+impl RenderOutput {
+    /// The page the element `fragment` names starts on, counting from 0, and how far
+    /// its top is below the top of the page's content area, in px.
+    pub fn anchor_position(&self, fragment: &str) -> Option<(usize, f32)> {
+        let y = self.anchors.position(fragment)?;
+        let page = self.pages.iter().rposition(|&(top, _)| top <= y).unwrap_or(0);
+        Some((page, y - self.pages[page].0))
+    }
+}
+
+/// Why the layout couldn't be written as a PDF.
+#[derive(Debug)]
+pub enum Error {
+    Engine(EngineError), // the document needs more pages than the limits allow
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Engine(error) => write!(f, "{}", error),
+            Error::Io(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Engine(error) => Some(error),
+            Error::Io(error) => Some(error),
+        }
+    }
+}
+
+impl From<EngineError> for Error {
+    fn from(error: EngineError) -> Error {
+        Error::Engine(error)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::Io(error)
+    }
+}
+
+/// Render the layout as pages the size of `bounds`, one after another.
 pub fn render<W: Write + Seek>(
     layout_root: &LayoutBox,
     bounds: Rect,
+    limits: &Limits,
     file: &mut W,
-) -> io::Result<()> {
-    let display_list = build_display_list(layout_root);
+) -> Result<RenderOutput, Error> {
+    let options = Options { page_width: bounds.width, page_height: bounds.height, ..Options::default() };
+    render_with_options(layout_root, &options, limits, file)
+}
+
+/// Render the layout, laid out at `options.content_width()`, split into pages.
+///
+/// Links to fragments of the document become link annotations that go to where the
+/// element they name starts.
+pub fn render_with_options<W: Write + Seek>(
+    layout_root: &LayoutBox,
+    options: &Options,
+    limits: &Limits,
+    file: &mut W,
+) -> Result<RenderOutput, Error> {
+    let pages = page_breaks_from(layout_root, options.content_height(), options.start, limits)?;
+    let mut stats = RenderStats::default();
+    if let Some(style_root) = layout_root.style_node() {
+        stats.count_styled_nodes(style_root);
+    }
+    stats.count_layout_boxes(layout_root);
+    let mut rendered = RenderOutput { pages: pages.clone(), anchors: Anchors::collect(layout_root), report: RenderReport::default(), stats };
+    let links = links::fragment_links(layout_root);
+    let page_height = px_to_pt(options.page_height);
     let mut pdf = Pdf::new(file)?;
-    // We map CSS pt to Poscript points (which is the default length unit in PDF).
-    pdf.render_page(px_to_pt(bounds.width), px_to_pt(bounds.height), |output| {
-        for item in display_list {
-            render_item(&item, output)?;
+    for link in &links {
+        if pdf.destinations.iter().any(|(name, ..)| *name == link.fragment) {
+            continue;
         }
-        Ok(())
-    })?;
-    pdf.finish()
+        match rendered.anchor_position(&link.fragment) {
+            Some((page, y)) => {
+                let top = page_height - px_to_pt(options.margin.top + y);
+                pdf.destinations.push((link.fragment.clone(), page, top));
+            }
+            None => {
+                let message = format!("no element is named by the link to #{}", link.fragment);
+                if !rendered.report.entries.iter().any(|entry| entry.message == message) {
+                    rendered.report.push(ReportEntry::new(Severity::Warning, "paint", message));
+                }
+            }
+        }
+    }
+    pdf.write_fonts()?;
+    for (top, bottom) in pages {
+        // the slice of the layout on this page, and a little beyond it horizontally
+        let slice = Rect { x: -options.margin.left, y: top, width: options.page_width, height: bottom - top };
+        let mut display_list = build_display_list_in(layout_root, slice);
+        if options.debug_overlay {
+            append_debug_overlay(&mut display_list, layout_root, Some(slice));
+        }
+        rendered.stats.count_display_list(&display_list);
+        let alphas = translucent_alphas(&display_list);
+        let images = pdf.write_images(&display_list)?;
+        // the parts of links on this page that go somewhere, as PDF rectangles
+        let annotations: Vec<([f32; 4], &str)> = links.iter()
+            .filter(|link| pdf.destinations.iter().any(|(name, ..)| *name == link.fragment))
+            .flat_map(|link| link.rects.iter().filter_map(|rect| Some((rect.intersection(slice)?, link.fragment.as_str()))))
+            .map(|(rect, fragment)| {
+                // from the layout's px, y down, to the page's pt, y up
+                let left = px_to_pt(options.margin.left + rect.x);
+                let top_edge = page_height - px_to_pt(options.margin.top + rect.y - top);
+                ([left, top_edge - px_to_pt(rect.height), left + px_to_pt(rect.width), top_edge], fragment)
+            })
+            .collect();
+        // We map CSS pt to Poscript points (which is the default length unit in PDF).
+        pdf.render_page(px_to_pt(options.page_width), page_height, &alphas, &images, &annotations, |output| {
+            // move the slice into the page's content area, and cut off what belongs to other pages
+            writeln!(output, "q 1 0 0 1 {} {} cm", options.margin.left, options.margin.top - top)?;
+            writeln!(output, "{} {} {} {} re W n", slice.x, slice.y, slice.width, slice.height)?;
+            for item in &display_list {
+                render_item(item, &images, output)?;
+            }
+            writeln!(output, "Q")
+        })?;
+    }
+    pdf.finish()?;
+    Ok(rendered)
+}
+
+/// Split the document into page-sized slices of layout, as `(top, bottom)` pairs.
+///
+/// https://www.w3.org/TR/css-break-3/#breaking-rules
+///
+/// Each page holds up to `page_height` of the document. A page ends early at a
+/// forced break (`break-before`/`break-after: page`, or the legacy `page-break-*:
+/// always`) that follows some content on the page, or to avoid splitting a line box
+/// or a box with `break-inside: avoid`, if that box would fit on a page of its own.
+pub fn page_breaks(layout_root: &LayoutBox, page_height: f32, limits: &Limits) -> Result<Vec<(f32, f32)>, EngineError> {
+    page_breaks_from(layout_root, page_height, 0.0, limits)
+}
+
+// same as `page_breaks`, with the first page starting `start` px down the document
+pub fn page_breaks_from(layout_root: &LayoutBox, page_height: f32, start: f32, limits: &Limits) -> Result<Vec<(f32, f32)>, EngineError> {
+    let page_height = page_height.max(1.0);
+    let mut hints = BreakHints::default();
+    hints.collect(layout_root);
+    hints.sort();
+    let root_bounds = layout_root.subtree_bounds();
+    let document_bottom = root_bounds.y + root_bounds.height;
+
+    // Pages only go down the document, so each list of hints is walked once, with
+    // a cursor past the hints above the current page.
+    let (mut next_content, mut next_forced, mut next_unbreakable) = (0, 0, 0);
+    let mut pages = Vec::new();
+    let mut top = start;
+    loop {
+        let mut bottom = top + page_height;
+        while hints.content_tops.get(next_content).is_some_and(|&t| t < top) {
+            next_content += 1;
+        }
+        while hints.forced.get(next_forced).is_some_and(|&y| y <= top) {
+            next_forced += 1;
+        }
+        while hints.unbreakable.get(next_unbreakable).is_some_and(|&(t, _)| t <= top) {
+            next_unbreakable += 1;
+        }
+        // a forced break only ends the page if some content comes before it
+        if let Some(&content_top) = hints.content_tops.get(next_content) {
+            if let Some(&forced) = hints.forced[next_forced..].iter()
+                .take_while(|&&y| y < bottom)
+                .find(|&&y| y > content_top) {
+                bottom = forced;
+            }
+        }
+        if let Some(&(avoid, _)) = hints.unbreakable[next_unbreakable..].iter()
+            .take_while(|&&(t, _)| t < bottom)
+            .find(|&&(t, b)| b > bottom && b - t <= page_height) {
+            bottom = avoid;
+        }
+        // at sizes where adding a page's height to `top` doesn't change it, the
+        // document can't be split any further
+        if bottom <= top || bottom.is_nan() {
+            break;
+        }
+        pages.push((top, bottom));
+        Limits::check(Limit::Pages, pages.len(), limits.max_pages)?;
+        if bottom >= document_bottom {
+            break;
+        }
+        top = bottom;
+    }
+    if pages.is_empty() {
+        pages.push((top, top + page_height));
+    }
+    Ok(pages)
 }
 
-fn render_item<W: Write>(item: &DisplayCommand, output: &mut W) -> io::Result<()> {
+// where the layout may, must, or shouldn't break between pages
+#[derive(Default)]
+struct BreakHints {
+    forced: Vec<f32>,
+    unbreakable: Vec<(f32, f32)>,
+    content_tops: Vec<f32>, // tops of lines and inline-blocks
+}
+
+impl BreakHints {
+    // in order down the document, as `page_breaks_from` walks them
+    fn sort(&mut self) {
+        self.forced.sort_by(f32::total_cmp);
+        self.unbreakable.sort_by(|a, b| a.0.total_cmp(&b.0));
+        self.content_tops.sort_by(f32::total_cmp);
+    }
+
+    fn collect(&mut self, layout_box: &LayoutBox) {
+        let border_box = layout_box.dimensions.border_box();
+        let margin_box = layout_box.dimensions.margin_box();
+        match layout_box.box_type {
+            BlockNode(style) => {
+                if forces_break(style, "break-before", "page-break-before") {
+                    self.forced.push(border_box.y);
+                }
+                if forces_break(style, "break-after", "page-break-after") {
+                    self.forced.push(margin_box.y + margin_box.height);
+                }
+                let auto = Value::Keyword("auto".to_string());
+                if let Value::Keyword(k) = style.lookup("break-inside", "page-break-inside", &auto) {
+                    if k == "avoid" || k == "avoid-page" {
+                        self.unbreakable.push((border_box.y, border_box.y + border_box.height));
+                    }
+                }
+            }
+            InlineBlockNode(_) => {
+                self.unbreakable.push((margin_box.y, margin_box.y + margin_box.height));
+                self.content_tops.push(margin_box.y);
+            }
+            _ => {}
+        }
+        for line in &layout_box.lines {
+            self.unbreakable.push((line.rect.y, line.rect.y + line.rect.height));
+            self.content_tops.push(line.rect.y);
+        }
+        for child in &layout_box.children {
+            self.collect(child);
+        }
+    }
+}
+
+fn forces_break(style: &StyledNode, name: &str, legacy_name: &str) -> bool {
+    let auto = Value::Keyword("auto".to_string());
+    matches!(style.lookup(name, legacy_name, &auto), Value::Keyword(k) if k == "page" || k == "always")
+}
+
+// the distinct alpha values of the page's translucent items, each of which needs
+// an ExtGState in the page's resources
+fn translucent_alphas(display_list: &[DisplayCommand]) -> Vec<u8> {
+    let mut alphas: Vec<u8> = display_list.iter()
+        .map(|item| item.color().a)
+        .filter(|&a| a != 0 && a != 255)
+        .collect();
+    alphas.sort_unstable();
+    alphas.dedup();
+    alphas
+}
+
+// the images a page draws, each with the ID of its image XObject
+type PageImages = [(Arc<DecodedImage>, usize)];
+
+fn render_item<W: Write>(item: &DisplayCommand, images: &PageImages, output: &mut W) -> io::Result<()> {
+    let alpha = item.color().a;
+    match alpha {
+        0 => return Ok(()),
+        255 => {}
+        // PDF content has no alpha in its colors; the opacity comes from a graphics state
+        _ => write!(output, "q /GA{} gs ", alpha)?,
+    }
+    render_opaque_item(item, images, output)?;
+    if alpha != 255 {
+        writeln!(output, "Q")?;
+    }
+    Ok(())
+}
+
+fn render_opaque_item<W: Write>(item: &DisplayCommand, images: &PageImages, output: &mut W) -> io::Result<()> {
     match item {
         DisplayCommand::SolidColor(color, rect) => {
             writeln!(
                 output,
-                "{} {} {} sc {} {} {} {} re f",
-                // FIMXE: alpha transparency
-                color.r,
-                color.g,
-                color.b,
+                "{} {} {} {} {} re f",
+                fill_color(color),
                 rect.x,
                 rect.y,
                 rect.width,
                 rect.height
             )
         }
+        DisplayCommand::Text(text, font, color, rect) => {
+            // The page is flipped to y-down, so the text matrix flips glyphs back upright.
+            let baseline = font.baseline(rect.y, rect.height);
+            write!(
+                output,
+                "{} BT /F{} {} Tf 1 0 0 -1 {} {} Tm ",
+                fill_color(color),
+                base_font_index(font) + 1,
+                font.size,
+                rect.x,
+                baseline
+            )?;
+            output.write_all(&pdf_string(text))?;
+            writeln!(output, " Tj ET")
+        }
+        DisplayCommand::Image(image, rect, clip) => {
+            let id = match images.iter().find(|(written, _)| Arc::ptr_eq(written, image)) {
+                Some(&(_, id)) => id,
+                None => return Ok(())
+            };
+            // An image fills the unit square with its first row at the top, so with the
+            // page flipped to y-down it's drawn upside down from (x, y + height).
+            writeln!(
+                output,
+                "q {} {} {} {} re W n {} 0 0 {} {} {} cm /Im{} Do Q",
+                clip.x, clip.y, clip.width, clip.height,
+                rect.width, -rect.height, rect.x, rect.y + rect.height,
+                id
+            )
+        }
+        // the items up to the matching `Q` are drawn through the transform
+        DisplayCommand::PushTransform(transform) => writeln!(
+            output,
+            "q {} 0 0 {} {} {} cm",
+            transform.scale_x, transform.scale_y, transform.translate_x, transform.translate_y
+        ),
+        DisplayCommand::PopTransform => writeln!(output, "Q"),
     }
 }
 
+/// The operator setting the non-stroking color, whose components range over 0 to 1.
+fn fill_color(color: &Color) -> String {
+    format!(
+        "{} {} {} sc",
+        color.r as f32 / 255.0,
+        color.g as f32 / 255.0,
+        color.b as f32 / 255.0
+    )
+}
+
+/// Encode text as a PDF literal string in WinAnsiEncoding.
+///
+/// Delimiters and backslashes are escaped, and bytes above ASCII written in octal.
+/// Characters outside the encoding, which is Latin-1 but for the punctuation,
+/// symbols and letters such as `—`, `“`, `€` and `™` that windows-1252 has in place
+/// of the C1 controls, are replaced by `?`, as are control characters.
+fn pdf_string(text: &str) -> Vec<u8> {
+    let mut bytes = vec![b'('];
+    for c in text.chars() {
+        let byte = encoding::windows_1252_byte(c).filter(|&byte| byte >= 0x20 && byte != 0x7F).unwrap_or(b'?');
+        match byte {
+            b'(' | b')' | b'\\' => bytes.extend_from_slice(&[b'\\', byte]),
+            0x80.. => bytes.extend_from_slice(format!("\\{:03o}", byte).as_bytes()),
+            _ => bytes.push(byte),
+        }
+    }
+    bytes.push(b')');
+    bytes
+}
+
+/// Encode a name as a PDF name object, escaping delimiters, `#` and bytes outside
+/// the printable ASCII range as `#XX`.
+fn pdf_name(name: &str) -> String {
+    let mut encoded = String::from("/");
+    for byte in name.bytes() {
+        match byte {
+            b'!'..=b'~' if !b"#%()/<>[]{}".contains(&byte) => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("#{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
 struct Pdf<'a, W: 'a + Write + Seek> {
     output: &'a mut W,
     object_offsets: Vec<i64>,
     page_objects_ids: Vec<usize>,
+    font_object_ids: Vec<usize>,
+    image_object_ids: Vec<(Arc<DecodedImage>, usize)>, // each image is written once, for all pages
+    // named destinations for links: the name, the index of the page, and the y on it in pt
+    destinations: Vec<(String, usize, f32)>,
 }
 
 const ROOT_OBJECT_ID: usize = 1;
@@ -63,6 +559,84 @@ impl<'a, W: Write + Seek> Pdf<'a, W> {
             // We reserve IDs 1 and 2 for the catalog and page tree.
             object_offsets: vec![-1, -1, -1],
             page_objects_ids: vec![],
+            font_object_ids: vec![],
+            image_object_ids: vec![],
+            destinations: vec![],
+        })
+    }
+
+    /// Write a font dictionary for each of the `BASE_FONTS`, for pages to refer to.
+    fn write_fonts(&mut self) -> io::Result<()> {
+        for base_font in BASE_FONTS {
+            let id = self.write_new_object(|font_object_id, pdf| {
+                writeln!(pdf.output, "<<  /Type /Font")?;
+                writeln!(pdf.output, "    /Subtype /Type1")?;
+                writeln!(pdf.output, "    /BaseFont /{}", base_font)?;
+                writeln!(pdf.output, "    /Encoding /WinAnsiEncoding")?;
+                writeln!(pdf.output, ">>")?;
+                Ok(font_object_id)
+            })?;
+            self.font_object_ids.push(id);
+        }
+        Ok(())
+    }
+
+    /// Write an image XObject for each image in the display list that doesn't have
+    /// one yet, and return the images the list draws with their object IDs.
+    fn write_images(&mut self, display_list: &[DisplayCommand]) -> io::Result<Vec<(Arc<DecodedImage>, usize)>> {
+        let mut page_images: Vec<(Arc<DecodedImage>, usize)> = Vec::new();
+        for item in display_list {
+            let image = match item {
+                DisplayCommand::Image(image, ..) => image,
+                _ => continue
+            };
+            if page_images.iter().any(|(written, _)| Arc::ptr_eq(written, image)) {
+                continue;
+            }
+            let id = match self.image_object_ids.iter().find(|(written, _)| Arc::ptr_eq(written, image)) {
+                Some(&(_, id)) => id,
+                None => {
+                    let id = self.write_image(image)?;
+                    self.image_object_ids.push((image.clone(), id));
+                    id
+                }
+            };
+            page_images.push((image.clone(), id));
+        }
+        Ok(page_images)
+    }
+
+    // an uncompressed RGB image, with its alpha channel as a soft mask if it isn't opaque
+    fn write_image(&mut self, image: &DecodedImage) -> io::Result<usize> {
+        let opaque = image.rgba.chunks_exact(4).all(|pixel| pixel[3] == 255);
+        let mask_id = match opaque {
+            true => None,
+            false => {
+                let alpha: Vec<u8> = image.rgba.chunks_exact(4).map(|pixel| pixel[3]).collect();
+                Some(self.write_image_stream(image.width, image.height, "/DeviceGray", None, &alpha)?)
+            }
+        };
+        let rgb: Vec<u8> = image.rgba.chunks_exact(4).flat_map(|pixel| [pixel[0], pixel[1], pixel[2]]).collect();
+        self.write_image_stream(image.width, image.height, "/DeviceRGB", mask_id, &rgb)
+    }
+
+    fn write_image_stream(&mut self, width: u32, height: u32, color_space: &str, mask_id: Option<usize>, samples: &[u8]) -> io::Result<usize> {
+        self.write_new_object(|id, pdf| {
+            writeln!(pdf.output, "<<  /Type /XObject")?;
+            writeln!(pdf.output, "    /Subtype /Image")?;
+            writeln!(pdf.output, "    /Width {}", width)?;
+            writeln!(pdf.output, "    /Height {}", height)?;
+            writeln!(pdf.output, "    /ColorSpace {}", color_space)?;
+            writeln!(pdf.output, "    /BitsPerComponent 8")?;
+            if let Some(mask_id) = mask_id {
+                writeln!(pdf.output, "    /SMask {} 0 R", mask_id)?;
+            }
+            writeln!(pdf.output, "    /Length {}", samples.len())?;
+            writeln!(pdf.output, ">>")?;
+            writeln!(pdf.output, "stream")?;
+            pdf.output.write_all(samples)?;
+            writeln!(pdf.output, "\nendstream")?;
+            Ok(id)
         })
     }
 
@@ -71,7 +645,10 @@ impl<'a, W: Write + Seek> Pdf<'a, W> {
         self.output.stream_position()
     }
 
-    fn render_page<F>(&mut self, width: f32, height: f32, render_contents: F) -> io::Result<()>
+    // `alphas` are the opacities the contents select with `/GA<alpha> gs`, `images`
+    // the images they draw with `/Im<id> Do`, and `links` the areas that go to one of
+    // the `destinations`
+    fn render_page<F>(&mut self, width: f32, height: f32, alphas: &[u8], images: &PageImages, links: &[([f32; 4], &str)], render_contents: F) -> io::Result<()>
     where
         F: FnOnce(&mut W) -> io::Result<()>,
     {
@@ -98,9 +675,40 @@ impl<'a, W: Write + Seek> Pdf<'a, W> {
         let page_object_id = self.write_new_object(|page_object_id, pdf| {
             writeln!(pdf.output, "<<  /Type /Page")?;
             writeln!(pdf.output, "    /Parent {} 0 R", PAGES_OBJECT_ID)?;
-            writeln!(pdf.output, "    /Resources << >>")?;
+            write!(pdf.output, "    /Resources << /Font << ")?;
+            for (i, font_object_id) in pdf.font_object_ids.iter().enumerate() {
+                write!(pdf.output, "/F{} {} 0 R ", i + 1, font_object_id)?;
+            }
+            write!(pdf.output, ">> ")?;
+            if !alphas.is_empty() {
+                write!(pdf.output, "/ExtGState << ")?;
+                for alpha in alphas {
+                    let opacity = *alpha as f32 / 255.0;
+                    write!(pdf.output, "/GA{} << /ca {} /CA {} >> ", alpha, opacity, opacity)?;
+                }
+                write!(pdf.output, ">> ")?;
+            }
+            if !images.is_empty() {
+                write!(pdf.output, "/XObject << ")?;
+                for (_, id) in images {
+                    write!(pdf.output, "/Im{} {} 0 R ", id, id)?;
+                }
+                write!(pdf.output, ">> ")?;
+            }
+            writeln!(pdf.output, ">>")?;
             writeln!(pdf.output, "    /MediaBox [ 0 0 {} {} ]", width, height)?;
             writeln!(pdf.output, "    /Contents {} 0 R", contents_object_id)?;
+            if !links.is_empty() {
+                writeln!(pdf.output, "    /Annots [")?;
+                for ([x0, y0, x1, y1], name) in links {
+                    writeln!(
+                        pdf.output,
+                        "        << /Type /Annot /Subtype /Link /Rect [ {} {} {} {} ] /Border [ 0 0 0 ] /Dest {} >>",
+                        x0, y0, x1, y1, pdf_name(name)
+                    )?;
+                }
+                writeln!(pdf.output, "    ]")?;
+            }
             writeln!(pdf.output, ">>")?;
             Ok(page_object_id)
         })?;
@@ -156,9 +764,25 @@ impl<'a, W: Write + Seek> Pdf<'a, W> {
             writeln!(pdf.output, ">>")?;
             Ok(())
         })?;
+        let dests_object_id = match self.destinations.is_empty() {
+            true => None,
+            false => Some(self.write_new_object(|dests_object_id, pdf| {
+                writeln!(pdf.output, "<<")?;
+                for (name, page, y) in &pdf.destinations {
+                    // keep the horizontal position and zoom, and scroll to `y`
+                    let page_object_id = pdf.page_objects_ids[*page];
+                    writeln!(pdf.output, "    {} [ {} 0 R /XYZ null {} null ]", pdf_name(name), page_object_id, y)?;
+                }
+                writeln!(pdf.output, ">>")?;
+                Ok(dests_object_id)
+            })?),
+        };
         self.write_object_with_id(ROOT_OBJECT_ID, |pdf| {
             writeln!(pdf.output, "<<  /Type /Catalog")?;
             writeln!(pdf.output, "    /Pages {} 0 R", PAGES_OBJECT_ID)?;
+            if let Some(dests_object_id) = dests_object_id {
+                writeln!(pdf.output, "    /Dests {} 0 R", dests_object_id)?;
+            }
             writeln!(pdf.output, ">>")?;
             Ok(())
         })?;
@@ -181,4 +805,68 @@ impl<'a, W: Write + Seek> Pdf<'a, W> {
         writeln!(self.output, "%%EOF")?;
         Ok(())
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::css::{self, Origin};
+    use crate::engine::{self, RenderOptions};
+    use crate::{html, style};
+
+    // lay out `source` styled by `author_css` in an 800px viewport, and hand the layout to `f`
+    fn with_layout(source: &str, author_css: &str, f: impl FnOnce(&LayoutBox)) {
+        let root = html::parse(source.to_string());
+        let stylesheets = [css::default_user_agent_stylesheet(), css::parse(author_css.to_string(), Origin::Author)];
+        let style_root = style::style_tree(&root, &stylesheets);
+        let layout_root = engine::layout(&style_root, &RenderOptions::default()).unwrap();
+        f(&layout_root);
+    }
+
+    #[test]
+    fn pages_end_at_forced_and_avoided_breaks() {
+        let css = "body { margin: 0 } div { height: 300px } .after { break-before: page } .whole { break-inside: avoid }";
+        let source = "<div></div><p>a</p><div class=after></div><div class=whole></div><div></div>";
+        with_layout(source, css, |layout_root| {
+            let pages = page_breaks(layout_root, 500.0, &Limits::default()).unwrap();
+            assert_eq!(pages[0].0, 0.0);
+            // the forced break ends the first page before the page is full, and the
+            // box that avoids breaks starts a page of its own
+            assert!(pages[0].1 < 500.0, "{:?}", pages);
+            assert_eq!(pages.len(), 4, "{:?}", pages);
+            assert!(pages.windows(2).all(|pair| pair[0].1 == pair[1].0));
+        });
+    }
+
+    #[test]
+    fn documents_too_long_to_page_are_refused() {
+        with_layout("<div></div>", "div { height: 100000000000000000000000000px }", |layout_root| {
+            // past where adding a page to the top of one changes it, no page may be
+            // empty, so splitting stops at one limit or the other instead of looping
+            let pages = page_breaks(layout_root, 500.0, &Limits::default());
+            assert!(matches!(pages, Err(EngineError::LimitExceeded { limit: Limit::Pages, .. })));
+            let unlimited = Limits { max_pages: usize::MAX, ..Limits::default() };
+            assert!(page_breaks(layout_root, 500.0, &unlimited).is_ok());
+        });
+    }
+
+    #[test]
+    fn text_is_shown_in_escaped_strings() {
+        assert_eq!(pdf_string(r"a (b) c\d"), br"(a \(b\) c\\d)");
+        // punctuation and symbols in WinAnsiEncoding's 0x80 to 0x9F, written in octal
+        assert_eq!(pdf_string("“Hi” — ‘a’–b • 5€™…"), br"(\223Hi\224 \227 \221a\222\226b \225 5\200\231\205)");
+        assert_eq!(pdf_string("Œuvre, Ÿ, é"), br"(\214uvre, \237, \351)");
+        // a C1 control, though windows-1252 decodes an unassigned byte to it, and CJK
+        assert_eq!(pdf_string("\u{81}\u{9d}漢"), b"(???)");
+        with_layout(r"<p>Total (net) C:\temp</p>", "", |layout_root| {
+            let mut file = io::Cursor::new(Vec::new());
+            render_with_options(layout_root, &Options::default(), &Limits::default(), &mut file).unwrap();
+            let pdf = String::from_utf8_lossy(file.get_ref()).into_owned();
+            // each word is shown on its own, in Helvetica at the paragraph's font size
+            let shown: Vec<&str> = pdf.lines()
+                .filter(|line| line.contains(" BT /F1 16 Tf 1 0 0 -1 ") && line.ends_with(" Tj ET"))
+                .filter_map(|line| line.split_once(" Tm ").map(|(_, shown)| shown))
+                .collect();
+            assert_eq!(shown, [r"(Total) Tj ET", r"(\(net\)) Tj ET", r"(C:\\temp) Tj ET"]);
+        });
+    }
+}
@@ -0,0 +1,186 @@
+use crate::image_decode::{adler32, crc32, PNG_SIGNATURE};
+use crate::painting::Canvas;
+use std::io::{self, Write};
+
+// A PNG encoder for raster output. The image is 8-bit RGBA with no filtering, in a
+// single zlib stream of fixed-Huffman DEFLATE blocks whose only matches repeat the
+// pixel before, which is what most of a rendered page is: runs of one color. That
+// keeps the encoder short, at the cost of hardly compressing text and gradients.
+//
+// https://www.w3.org/TR/png-3/
+// https://www.rfc-editor.org/rfc/rfc1951
+
+/// Write `canvas` to `out` as a PNG image.
+pub fn write(canvas: &Canvas, out: &mut impl Write) -> io::Result<()> {
+    if canvas.width == 0 || canvas.height == 0 || canvas.width > u32::MAX as usize || canvas.height > u32::MAX as usize {
+        let message = format!("a PNG can't be {}x{} pixels", canvas.width, canvas.height);
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+    }
+    out.write_all(&PNG_SIGNATURE)?;
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(canvas.width as u32).to_be_bytes());
+    header.extend_from_slice(&(canvas.height as u32).to_be_bytes());
+    // bit depth 8, color type RGBA, deflate, adaptive filtering, not interlaced
+    header.extend_from_slice(&[8, 6, 0, 0, 0]);
+    write_chunk(out, b"IHDR", &header)?;
+
+    // each row is a filter type byte, 0 for none, then its pixels
+    let mut scanlines = Vec::with_capacity(canvas.height * (1 + canvas.width * 4));
+    for row in canvas.pixels.chunks(canvas.width) {
+        scanlines.push(0);
+        for pixel in row {
+            scanlines.extend_from_slice(&[pixel.r, pixel.g, pixel.b, pixel.a]);
+        }
+    }
+    write_chunk(out, b"IDAT", &zlib(&scanlines))?;
+    write_chunk(out, b"IEND", &[])
+}
+
+// a chunk's length, type, data, and the CRC of its type and data
+fn write_chunk(out: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    let mut checked = Vec::with_capacity(4 + data.len());
+    checked.extend_from_slice(kind);
+    checked.extend_from_slice(data);
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(&checked)?;
+    out.write_all(&crc32(&checked).to_be_bytes())
+}
+
+// the bytes of a pixel, which a match of the pixel before skips back
+const PIXEL: usize = 4;
+
+// `data` as a zlib stream: a header for DEFLATE with a 32 KiB window, the blocks,
+// and the Adler-32 of `data`
+fn zlib(data: &[u8]) -> Vec<u8> {
+    let mut bits = BitWriter { out: vec![0x78, 0x01], buffer: 0, count: 0 };
+    // a single final block with the fixed codes
+    bits.write(1, 1);
+    bits.write(1, 2);
+    let mut i = 0;
+    while i < data.len() {
+        let run = match i >= PIXEL {
+            true => (i..data.len().min(i + MAX_MATCH)).take_while(|&j| data[j] == data[j - PIXEL]).count(),
+            false => 0
+        };
+        if run >= MIN_MATCH {
+            write_length(&mut bits, run);
+            // distance codes 0 to 3 are the distances 1 to 4, with no extra bits
+            bits.write_code(PIXEL as u32 - 1, 5);
+            i += run;
+        } else {
+            write_literal(&mut bits, data[i] as u32);
+            i += 1;
+        }
+    }
+    write_literal(&mut bits, 256); // end of block
+    bits.flush();
+    let mut out = bits.out;
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+
+// the first length of each length code from 257, and how many extra bits follow it
+const LENGTH_BASES: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83,
+    99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA_BITS: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+
+fn write_length(bits: &mut BitWriter, length: usize) {
+    let index = LENGTH_BASES.partition_point(|&base| base as usize <= length) - 1;
+    write_literal(bits, 257 + index as u32);
+    bits.write((length - LENGTH_BASES[index] as usize) as u32, LENGTH_EXTRA_BITS[index] as u32);
+}
+
+// a literal byte, the end of the block or a length, in the fixed literal/length code
+fn write_literal(bits: &mut BitWriter, symbol: u32) {
+    match symbol {
+        0..=143 => bits.write_code(0x30 + symbol, 8),
+        144..=255 => bits.write_code(0x190 + symbol - 144, 9),
+        256..=279 => bits.write_code(symbol - 256, 7),
+        _ => bits.write_code(0xC0 + symbol - 280, 8)
+    }
+}
+
+// bits packed into bytes least significant first, as DEFLATE stores them
+struct BitWriter {
+    out: Vec<u8>,
+    buffer: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    // `count` bits of `value`, least significant first, as extra bits and headers are
+    fn write(&mut self, value: u32, count: u32) {
+        self.buffer |= (value as u64) << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    // a Huffman code of `length` bits, which goes most significant bit first
+    fn write_code(&mut self, code: u32, length: u32) {
+        self.write(code.reverse_bits() >> (32 - length), length);
+    }
+
+    // pad the last byte with zeros
+    fn flush(&mut self) {
+        if self.count > 0 {
+            self.out.push(self.buffer as u8);
+            self.buffer = 0;
+            self.count = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::css::{self, Origin};
+    use crate::engine::{self, RenderOptions};
+    use crate::image_decode::{self, ImageSource};
+    use crate::layout::{InitialContainingBlock, Rect};
+    use crate::{html, painting, style};
+
+    #[test]
+    fn rendered_pages_round_trip_through_a_decoder() {
+        let root = html::parse("<div><p></p><p class=\"blue\"></p></div>".to_string());
+        let css = "div { background-color: #ffffff } p { width: 6px; height: 2px; margin: 0; background-color: #ff0000 } .blue { background-color: #0000ff }";
+        let stylesheets = [css::default_user_agent_stylesheet(), css::parse(css.to_string(), Origin::Author)];
+        let style_root = style::style_tree(&root, &stylesheets);
+        let options = RenderOptions { width: 10.0, height: 4.0, ..RenderOptions::default() };
+        let layout_root = engine::layout(&style_root, &options).unwrap();
+        let bounds = InitialContainingBlock::from_viewport(options.viewport()).canvas_rect(&layout_root);
+        assert_eq!(bounds, Rect { x: 0.0, y: 0.0, width: 10.0, height: 4.0 });
+        let canvas = painting::paint(&layout_root, bounds, &options.limits).unwrap();
+        let mut encoded = Vec::new();
+        write(&canvas, &mut encoded).unwrap();
+
+        // the signature, then IHDR, IDAT and IEND, each with the CRC of its type and data
+        assert_eq!(encoded[..8], PNG_SIGNATURE);
+        let mut chunks = Vec::new();
+        let mut rest = &encoded[8..];
+        while !rest.is_empty() {
+            let length = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            let checked = &rest[4..8 + length];
+            assert_eq!(rest[8 + length..12 + length], crc32(checked).to_be_bytes());
+            chunks.push(String::from_utf8(checked[..4].to_vec()).unwrap());
+            rest = &rest[12 + length..];
+        }
+        assert_eq!(chunks, ["IHDR", "IDAT", "IEND"]);
+
+        let decoded = image_decode::decode(ImageSource::Bytes(&encoded)).unwrap();
+        assert_eq!((decoded.width, decoded.height), (10, 4));
+        let pixel = |x: usize, y: usize| &decoded.rgba[(y * 10 + x) * 4..][..4];
+        assert_eq!(pixel(0, 0), [255, 0, 0, 255]);
+        assert_eq!(pixel(5, 3), [0, 0, 255, 255]);
+        assert_eq!(pixel(6, 0), [255, 255, 255, 255]);
+        let pixels: Vec<u8> = canvas.pixels.iter().flat_map(|color| [color.r, color.g, color.b, color.a]).collect();
+        assert_eq!(decoded.rgba, pixels);
+    }
+}
@@ -0,0 +1,151 @@
+use crate::background;
+use crate::css::Unit::{Percent, Px};
+use crate::css::Value::{self, Keyword, Length, List};
+use crate::dom::NodeType;
+use crate::image_decode::{ImageCache, ImageError};
+use crate::layout::{BlockNode, InlineBlockNode, LayoutBox, Rect};
+use crate::resource::{ResolvedUrl, ResourceLoader};
+use crate::style::StyledNode;
+
+// Replaced elements: `<img>` boxes, whose content is an image rather than children
+// laid out by CSS. Layout sizes them from their width and height and the image's
+// intrinsic size, and painting maps the image into the content box as
+// `object-fit` and `object-position` say.
+
+/// Is the element one whose content is replaced by an image?
+pub fn is_replaced(style: &StyledNode) -> bool {
+    matches!(style.node.node_type, NodeType::Element(ref elem) if elem.tag_name.eq_ignore_ascii_case("img"))
+}
+
+/// Decode the image of every `<img>` in the tree, and every background image,
+/// resolving `src` against the document's URL, `base`, and fetching it with `loader`.
+///
+/// An image that can't be loaded or decoded is left out, and its element laid out as
+/// if it had no image: at its specified size, or none. Returns a warning for each.
+pub fn load_images(root: &mut LayoutBox, base: &ResolvedUrl, loader: &dyn ResourceLoader, cache: &mut ImageCache) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if let Some(style) = root.style_node() {
+        root.background_images = background::load_images(style, base, loader, cache, &mut warnings);
+    }
+    if let Some(src) = root.style_node().filter(|style| is_replaced(style)).and_then(image_source) {
+        let image = base.join(&src).map_err(ImageError::from).and_then(|url| cache.get_or_decode(&url, loader));
+        match image {
+            Ok(image) => root.image = Some(image),
+            Err(error) => warnings.push(format!("can't load image {}: {}", src, error)),
+        }
+    }
+    for child in &mut root.children {
+        warnings.extend(load_images(child, base, loader, cache));
+    }
+    warnings
+}
+
+fn image_source(style: &StyledNode) -> Option<String> {
+    match style.node.node_type {
+        NodeType::Element(ref elem) => elem.attrs.get("src").map(|src| src.trim().to_string()).filter(|src| !src.is_empty()),
+        _ => None
+    }
+}
+
+/// The size of a replaced box's content area, or `None` for other boxes.
+///
+/// https://www.w3.org/TR/CSS2/visudet.html#inline-replaced-width
+///
+/// A px width or height is used as given. An auto one follows from the other and
+/// the image's aspect ratio, or is the image's own size if both are auto. Without
+/// an image, auto sizes are zero.
+pub(crate) fn used_size(layout_box: &LayoutBox) -> Option<(f32, f32)> {
+    let style = match layout_box.box_type {
+        BlockNode(style) | InlineBlockNode(style) if is_replaced(style) => style,
+        _ => return None
+    };
+    let px = |name| match style.value(name) {
+        Some(Length(value, Px)) => Some(value),
+        _ => None
+    };
+    let intrinsic = layout_box.image.as_ref().map(|image| (image.width as f32, image.height as f32));
+    let ratio = intrinsic.filter(|&(w, h)| w > 0.0 && h > 0.0).map(|(w, h)| w / h);
+    Some(match (px("width"), px("height")) {
+        (Some(width), Some(height)) => (width, height),
+        (Some(width), None) => (width, ratio.map_or(0.0, |ratio| width / ratio)),
+        (None, Some(height)) => (ratio.map_or(0.0, |ratio| height * ratio), height),
+        (None, None) => intrinsic.unwrap_or((0.0, 0.0)),
+    })
+}
+
+/// How a replaced element's image is fitted into its content box.
+///
+/// https://www.w3.org/TR/css-images-3/#the-object-fit
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ObjectFit {
+    #[default]
+    Fill, // stretched to the box
+    Contain, // scaled to fit inside the box, keeping its aspect ratio
+    Cover, // scaled to cover the box, keeping its aspect ratio
+    None, // at its intrinsic size
+    ScaleDown, // like `none` or `contain`, whichever is smaller
+}
+
+impl ObjectFit {
+    pub fn of(style: &StyledNode) -> ObjectFit {
+        match style.value("object-fit") {
+            Some(Keyword(k)) if k == "contain" => ObjectFit::Contain,
+            Some(Keyword(k)) if k == "cover" => ObjectFit::Cover,
+            Some(Keyword(k)) if k == "none" => ObjectFit::None,
+            Some(Keyword(k)) if k == "scale-down" => ObjectFit::ScaleDown,
+            _ => ObjectFit::Fill
+        }
+    }
+}
+
+/// Where an image of `intrinsic` size is drawn for a replaced element with
+/// `content` as its content box. The result can reach outside `content`, under
+/// `cover` or `none`, and is clipped to it when painted.
+pub fn fit_image(fit: ObjectFit, position: &Value, content: Rect, intrinsic: (f32, f32)) -> Rect {
+    let (width, height) = intrinsic;
+    if width <= 0.0 || height <= 0.0 {
+        return content;
+    }
+    let contain = (content.width / width).min(content.height / height);
+    let (width, height) = match fit {
+        ObjectFit::Fill => return content,
+        ObjectFit::Contain => (width * contain, height * contain),
+        ObjectFit::Cover => {
+            let cover = (content.width / width).max(content.height / height);
+            (width * cover, height * cover)
+        }
+        ObjectFit::None => (width, height),
+        ObjectFit::ScaleDown => (width * contain.min(1.0), height * contain.min(1.0)),
+    };
+    let (x, y) = resolve_position(position, content.width - width, content.height - height);
+    Rect { x: content.x + x, y: content.y + y, width, height }
+}
+
+// the offset of a point placed by a `<position>` from the top left corner of an
+// area `width` by `height`: the offset of the image from the content box given how
+// much narrower and shorter it is, or a transform origin within the border box
+//
+// https://www.w3.org/TR/css-backgrounds-3/#background-position
+pub(crate) fn resolve_position(position: &Value, width: f32, height: f32) -> (f32, f32) {
+    let center = Keyword("center".to_string());
+    let (mut horizontal, mut vertical) = match position {
+        List(values) if values.len() == 2 => (&values[0], &values[1]),
+        Keyword(k) if k == "top" || k == "bottom" => (&center, position),
+        value => (value, &center),
+    };
+    // keywords may come in either order, as in `top left`
+    let is_vertical = |value: &Value| matches!(value, Keyword(k) if k == "top" || k == "bottom");
+    let is_horizontal = |value: &Value| matches!(value, Keyword(k) if k == "left" || k == "right");
+    if is_vertical(horizontal) || is_horizontal(vertical) {
+        std::mem::swap(&mut horizontal, &mut vertical);
+    }
+    let offset = |value: &Value, size: f32| match value {
+        Keyword(k) if k == "left" || k == "top" => 0.0,
+        Keyword(k) if k == "right" || k == "bottom" => size,
+        Length(p, Percent) => size * p / 100.0,
+        Length(px, Px) => *px,
+        Value::Number(zero) => *zero,
+        _ => size / 2.0
+    };
+    (offset(horizontal, width), offset(vertical, height))
+}
@@ -0,0 +1,185 @@
+use crate::a11y::json_string;
+use crate::coverage::CssCoverage;
+use crate::css::SourceLocation;
+use crate::dom::{Node, NodeType};
+use crate::stats::RenderStats;
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::time::Duration;
+
+// Everything one render had to say, as a single artifact: the warnings and notes
+// each stage raised, how long the stages took, how much they handled and how much
+// of the stylesheets the engine understood, as `--report` writes it. A CI job can
+// fail a build on a new warning or a slower stage by reading this rather than
+// scraping what was printed.
+
+/// How much a report entry matters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Note, // worth knowing, like a box overflowing, but often intended
+    Warning, // something in the input the engine skipped or couldn't load
+    Error,
+}
+
+impl Severity {
+    pub fn name(self) -> &'static str {
+        match self {
+            Severity::Note => "note",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        }
+    }
+}
+
+/// One thing a stage of the render reported.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReportEntry {
+    pub severity: Severity,
+    pub stage: &'static str, // "parse", "style", "layout" or "paint", as stages are timed
+    pub message: String,
+    pub location: Option<SourceLocation>, // where in a stylesheet it's about
+    pub dom_path: Option<String>, // the element it's about, as `dom_paths` writes it
+}
+
+impl ReportEntry {
+    pub fn new(severity: Severity, stage: &'static str, message: impl Into<String>) -> ReportEntry {
+        ReportEntry { severity, stage, message: message.into(), location: None, dom_path: None }
+    }
+
+    pub fn at(self, location: SourceLocation) -> ReportEntry {
+        ReportEntry { location: Some(location), ..self }
+    }
+
+    pub fn on(self, dom_path: Option<String>) -> ReportEntry {
+        ReportEntry { dom_path, ..self }
+    }
+
+    fn write_json(&self, out: &mut String) {
+        let location = match self.location {
+            Some(ref location) => format!("{{\"source\":{},\"line\":{},\"column\":{}}}",
+                                          location.source_name.as_deref().map_or("null".to_string(), json_string),
+                                          location.line, location.column),
+            None => "null".to_string(),
+        };
+        let dom_path = self.dom_path.as_deref().map_or("null".to_string(), json_string);
+        let _ = write!(out, "{{\"severity\":\"{}\",\"stage\":\"{}\",\"message\":{},\"location\":{},\"domPath\":{}}}",
+                       self.severity.name(), self.stage, json_string(&self.message), location, dom_path);
+    }
+}
+
+// as printed while rendering, like `Warning: no element matches --scroll-to #top`
+impl fmt::Display for ReportEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Note => "Note",
+            Severity::Warning => "Warning",
+            Severity::Error => "Error",
+        };
+        write!(f, "{}: {}", severity, self.message)?;
+        if let Some(ref location) = self.location {
+            write!(f, " at {}", location)?;
+        }
+        if let Some(ref dom_path) = self.dom_path {
+            write!(f, " ({})", dom_path)?;
+        }
+        Ok(())
+    }
+}
+
+/// What a render reported, stage by stage, with what it took and handled.
+#[derive(Debug, Clone, Default)]
+pub struct RenderReport {
+    pub entries: Vec<ReportEntry>, // in the order they were reported
+    pub timings: Vec<(&'static str, Duration)>, // each stage, in order
+    pub stats: Option<RenderStats>,
+    pub css_coverage: CssCoverage,
+}
+
+impl RenderReport {
+    pub fn push(&mut self, entry: ReportEntry) {
+        self.entries.push(entry);
+    }
+
+    /// How many entries are as severe as `severity`.
+    pub fn count(&self, severity: Severity) -> usize {
+        self.entries.iter().filter(|entry| entry.severity == severity).count()
+    }
+
+    /// Render the report as a JSON object, with times in milliseconds.
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{\"entries\":[");
+        for (i, entry) in self.entries.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            entry.write_json(&mut out);
+        }
+        out.push_str("],\"timings\":{");
+        for (i, (stage, time)) in self.timings.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let _ = write!(out, "\"{}\":{:.3}", stage, time.as_secs_f64() * 1000.0);
+        }
+        let stats = self.stats.as_ref().map_or("null".to_string(), RenderStats::to_json);
+        let _ = write!(out, "}},\"stats\":{},\"stylesheets\":{}}}", stats, self.css_coverage.to_json());
+        out
+    }
+}
+
+impl fmt::Display for RenderReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} errors, {} warnings, {} notes",
+                 self.count(Severity::Error), self.count(Severity::Warning), self.count(Severity::Note))?;
+        for entry in &self.entries {
+            writeln!(f, "  [{}] {}", entry.stage, entry)?;
+        }
+        for (stage, time) in &self.timings {
+            writeln!(f, "Time ({}): {:.2?}", stage, time)?;
+        }
+        if let Some(ref stats) = self.stats {
+            writeln!(f, "{}", stats)?;
+        }
+        write!(f, "{}", self.css_coverage)
+    }
+}
+
+/// Where each of `targets` is in the document under `root`, as the elements from
+/// the root down to it, like `html > body > div#main.note > p`, or `None` for a
+/// node that isn't in the document. A text node is named by its parent's path.
+pub fn dom_paths(root: &Node, targets: &[&Node]) -> Vec<Option<String>> {
+    fn walk(node: &Node, ancestors: &mut Vec<String>, wanted: &HashMap<*const Node, Vec<usize>>, paths: &mut [Option<String>]) {
+        let elem = match node.node_type {
+            NodeType::Element(ref elem) => Some(elem),
+            _ => None,
+        };
+        if let Some(elem) = elem {
+            let mut step = elem.tag_name.clone();
+            if let Some(id) = elem.id() {
+                let _ = write!(step, "#{}", id);
+            }
+            let mut classes: Vec<&str> = elem.classes().into_iter().filter(|class| !class.is_empty()).collect();
+            classes.sort_unstable();
+            for class in classes {
+                let _ = write!(step, ".{}", class);
+            }
+            ancestors.push(step);
+        }
+        for &i in wanted.get(&(node as *const Node)).into_iter().flatten() {
+            paths[i] = Some(ancestors.join(" > "));
+        }
+        for child in &node.children {
+            walk(child, ancestors, wanted, paths);
+        }
+        if elem.is_some() {
+            ancestors.pop();
+        }
+    }
+    let mut wanted: HashMap<*const Node, Vec<usize>> = HashMap::new();
+    for (i, &target) in targets.iter().enumerate() {
+        wanted.entry(target as *const Node).or_default().push(i);
+    }
+    let mut paths = vec![None; targets.len()];
+    walk(root, &mut Vec::new(), &wanted, &mut paths);
+    paths
+}
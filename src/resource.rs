@@ -0,0 +1,307 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Where documents get the resources they refer to, such as images and imported
+// stylesheets. References are resolved to a `ResolvedUrl` first, and then loaded by
+// whichever `ResourceLoader` the render was given: from a directory, from memory,
+// or not at all. Nothing else in the engine reads files for a document.
+
+/// A reference from a document resolved against the URL of what it appears in.
+///
+/// Local resources are paths relative to the loader's root, with `/` between
+/// segments, and `.` and `..` segments resolved: `images/logo.png`. A path that
+/// climbs above the root keeps its leading `..` segments so loaders can refuse it.
+/// Remote ones are absolute `http:` or `https:` URLs.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct ResolvedUrl(String);
+
+impl ResolvedUrl {
+    /// The URL of a local resource, at `path` under the loader's root.
+    pub fn local(path: &str) -> ResolvedUrl {
+        ResolvedUrl(resolve_path(&[], path, false).join("/"))
+    }
+
+    /// Resolve `reference`, as written in the resource at this URL.
+    ///
+    /// https://url.spec.whatwg.org/#concept-basic-url-parser
+    ///
+    /// Only relative references and `http:` and `https:` URLs are supported. Query
+    /// strings are kept for remote resources, and fragments are dropped.
+    pub fn join(&self, reference: &str) -> Result<ResolvedUrl, LoadError> {
+        let reference = reference.trim();
+        let reference = reference.split('#').next().unwrap_or_default();
+        if let Some(scheme) = scheme(reference) {
+            let scheme = scheme.to_ascii_lowercase();
+            if scheme != "http" && scheme != "https" {
+                return Err(LoadError::UnsupportedScheme(scheme));
+            }
+            let (host, path) = split_host(&reference[scheme.len() + 1..]);
+            return Ok(ResolvedUrl::remote(&scheme, host, &[], path));
+        }
+        match self.0.split_once("://") {
+            Some((scheme, rest)) => {
+                let (host, base_path) = split_host(rest);
+                if let Some(rest) = reference.strip_prefix("//") {
+                    let (host, path) = split_host(rest);
+                    return Ok(ResolvedUrl::remote(scheme, host, &[], path));
+                }
+                let base_path = base_path.split('?').next().unwrap_or_default();
+                Ok(ResolvedUrl::remote(scheme, host, &directory(base_path), reference))
+            }
+            None if reference.starts_with("//") => Err(LoadError::UnsupportedScheme(String::new())),
+            None => {
+                // query strings mean nothing to a file
+                let path = reference.split('?').next().unwrap_or_default();
+                Ok(ResolvedUrl(resolve_path(&directory(&self.0), path, false).join("/")))
+            }
+        }
+    }
+
+    // `reference` is a path and query followed from `directory` on `host`
+    fn remote(scheme: &str, host: &str, directory: &[&str], reference: &str) -> ResolvedUrl {
+        let (path, query) = match reference.split_once('?') {
+            Some((path, query)) => (path, format!("?{}", query)),
+            None => (reference, String::new()),
+        };
+        let path = resolve_path(directory, path, true).join("/");
+        ResolvedUrl(format!("{}://{}/{}{}", scheme, host.to_ascii_lowercase(), path, query))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Is this an `http:` or `https:` URL, rather than a local path?
+    pub fn is_remote(&self) -> bool {
+        self.0.contains("://")
+    }
+}
+
+impl fmt::Display for ResolvedUrl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// the scheme of an absolute URL, which comes before the first `:` and is made of
+// letters, digits, `+`, `-` and `.`
+fn scheme(reference: &str) -> Option<&str> {
+    let (scheme, _) = reference.split_once(':')?;
+    let mut chars = scheme.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+    valid.then_some(scheme)
+}
+
+// split what follows `scheme:` into the host, without the leading `//`, and the path
+fn split_host(rest: &str) -> (&str, &str) {
+    let rest = rest.trim_start_matches('/');
+    rest.split_once('/').unwrap_or((rest, ""))
+}
+
+// the segments of the directory holding the resource at `path`
+fn directory(path: &str) -> Vec<&str> {
+    let mut segments: Vec<&str> = path.split('/').collect();
+    segments.pop();
+    segments
+}
+
+// the segments of `path` followed from `directory`, or from the root if it starts
+// with `/`; a `..` above the root is dropped for URLs, as browsers do, and kept for
+// local paths
+fn resolve_path(directory: &[&str], path: &str, clamp: bool) -> Vec<String> {
+    let mut segments: Vec<String> = match path.starts_with('/') {
+        true => Vec::new(),
+        false => directory.iter().filter(|s| !s.is_empty()).map(|s| s.to_string()).collect(),
+    };
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => match segments.last().map(String::as_str) {
+                Some(last) if last != ".." => {
+                    segments.pop();
+                }
+                _ if clamp => {}
+                _ => segments.push("..".to_string()),
+            },
+            part => segments.push(part.to_string()),
+        }
+    }
+    segments
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    NotFound,
+    // a path that leads out of the directory an `FsLoader` serves
+    OutsideRoot,
+    // the loader doesn't load resources like this one, or any at all
+    Blocked,
+    UnsupportedScheme(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::NotFound => write!(f, "not found"),
+            LoadError::OutsideRoot => write!(f, "the path leads outside the resource directory"),
+            LoadError::Blocked => write!(f, "loading resources is blocked"),
+            LoadError::UnsupportedScheme(scheme) if scheme.is_empty() => write!(f, "a URL without a scheme needs a remote base"),
+            LoadError::UnsupportedScheme(scheme) => write!(f, "unsupported URL scheme {}:", scheme),
+            LoadError::Io(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(error: io::Error) -> LoadError {
+        match error.kind() {
+            io::ErrorKind::NotFound => LoadError::NotFound,
+            _ => LoadError::Io(error),
+        }
+    }
+}
+
+/// Something resources can be fetched from.
+pub trait ResourceLoader: fmt::Debug + Send + Sync {
+    fn load(&self, url: &ResolvedUrl) -> Result<Vec<u8>, LoadError>;
+}
+
+/// Loads local resources from the files under a directory, and nothing from outside
+/// it, whether reached by `..` or through a symbolic link. An empty path is the
+/// current directory.
+#[derive(Debug, Clone)]
+pub struct FsLoader {
+    root: PathBuf,
+}
+
+impl FsLoader {
+    pub fn new(root: impl Into<PathBuf>) -> FsLoader {
+        FsLoader { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+}
+
+impl ResourceLoader for FsLoader {
+    fn load(&self, url: &ResolvedUrl) -> Result<Vec<u8>, LoadError> {
+        if url.is_remote() {
+            return Err(LoadError::Blocked);
+        }
+        if url.as_str().split('/').next() == Some("..") {
+            return Err(LoadError::OutsideRoot);
+        }
+        let root = match self.root.as_os_str().is_empty() {
+            true => fs::canonicalize(".")?,
+            false => fs::canonicalize(&self.root)?,
+        };
+        let path = fs::canonicalize(root.join(url.as_str()))?;
+        if !path.starts_with(&root) {
+            return Err(LoadError::OutsideRoot);
+        }
+        Ok(fs::read(path)?)
+    }
+}
+
+/// Loads resources from memory, by their `ResolvedUrl`, as an embedder serving a
+/// document and what it refers to without touching the filesystem would.
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryLoader {
+    resources: HashMap<String, Vec<u8>>,
+}
+
+impl InMemoryLoader {
+    pub fn new() -> InMemoryLoader {
+        Default::default()
+    }
+
+    // `url` is resolved as a local path, unless it's an absolute URL
+    pub fn insert(&mut self, url: &str, contents: impl Into<Vec<u8>>) {
+        let url = ResolvedUrl::default().join(url).unwrap_or_else(|_| ResolvedUrl::local(url));
+        self.resources.insert(url.0, contents.into());
+    }
+}
+
+impl ResourceLoader for InMemoryLoader {
+    fn load(&self, url: &ResolvedUrl) -> Result<Vec<u8>, LoadError> {
+        self.resources.get(url.as_str()).cloned().ok_or(LoadError::NotFound)
+    }
+}
+
+/// Loads nothing, so a document renders without any of the resources it refers to.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DenyAllLoader;
+
+impl ResourceLoader for DenyAllLoader {
+    fn load(&self, _url: &ResolvedUrl) -> Result<Vec<u8>, LoadError> {
+        Err(LoadError::Blocked)
+    }
+}
+
+/// Fetches `http:` URLs over the network, with the `network` feature. There is no
+/// TLS implementation, so `https:` URLs are refused, as are local paths.
+#[cfg(feature = "network")]
+#[derive(Debug, Clone)]
+pub struct HttpLoader {
+    pub timeout: std::time::Duration,
+    pub max_bytes: usize,
+}
+
+#[cfg(feature = "network")]
+impl Default for HttpLoader {
+    fn default() -> HttpLoader {
+        HttpLoader { timeout: std::time::Duration::from_secs(30), max_bytes: 64 << 20 }
+    }
+}
+
+#[cfg(feature = "network")]
+impl ResourceLoader for HttpLoader {
+    // an HTTP/1.0 GET, so the response ends when the connection closes and is never chunked
+    fn load(&self, url: &ResolvedUrl) -> Result<Vec<u8>, LoadError> {
+        use std::io::{Read, Write};
+        use std::net::{TcpStream, ToSocketAddrs};
+
+        let rest = match url.as_str().split_once("://") {
+            Some(("http", rest)) => rest,
+            Some((scheme, _)) => return Err(LoadError::UnsupportedScheme(scheme.to_string())),
+            None => return Err(LoadError::Blocked),
+        };
+        let (host, path) = rest.split_once('/').unwrap_or((rest, ""));
+        let address = match host.contains(':') {
+            true => host.to_string(),
+            false => format!("{}:80", host),
+        };
+        let address = address.to_socket_addrs()?.next().ok_or(LoadError::NotFound)?;
+        let mut stream = TcpStream::connect_timeout(&address, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        write!(stream, "GET /{} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n", path, host)?;
+        let mut response = Vec::new();
+        stream.take(self.max_bytes as u64 + 1).read_to_end(&mut response)?;
+        if response.len() > self.max_bytes {
+            return Err(LoadError::Io(io::Error::new(io::ErrorKind::InvalidData, "the response is too large")));
+        }
+        let header_end = response.windows(4).position(|w| w == b"\r\n\r\n")
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed HTTP response"))?;
+        let status = String::from_utf8_lossy(&response[..header_end]);
+        match status.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(response[header_end + 4..].to_vec()),
+            Some("404") | Some("410") => Err(LoadError::NotFound),
+            _ => Err(LoadError::Io(io::Error::other(format!("HTTP status {}", status.lines().next().unwrap_or_default())))),
+        }
+    }
+}
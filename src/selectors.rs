@@ -0,0 +1,395 @@
+use crate::css::{Combinator, Rule, Selector, SimpleSelector, Specificity};
+use crate::dom::{ElementData, Node};
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+// Selector matching, shared by the cascade and by DOM queries. Matching an element
+// needs more than the element itself once selectors can refer to its surroundings,
+// so callers describe where the element sits in the tree with a `MatchingContext`.
+
+/// Dynamic state of an element that pseudo-classes can match.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ElementState {
+    pub hover: bool,
+    pub active: bool,
+    pub focus: bool,
+    pub visited: bool,
+}
+
+/// A pseudo-class that matches on an element's dynamic state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PseudoClass {
+    Hover,
+    Active,
+    Focus,
+    Visited,
+}
+
+impl PseudoClass {
+    /// The pseudo-class with this (lowercase) name, without the colon.
+    pub fn from_name(name: &str) -> Option<PseudoClass> {
+        match name {
+            "hover" => Some(PseudoClass::Hover),
+            "active" => Some(PseudoClass::Active),
+            "focus" => Some(PseudoClass::Focus),
+            "visited" => Some(PseudoClass::Visited),
+            _ => None
+        }
+    }
+}
+
+impl ElementState {
+    pub fn has(&self, pseudo_class: PseudoClass) -> bool {
+        match pseudo_class {
+            PseudoClass::Hover => self.hover,
+            PseudoClass::Active => self.active,
+            PseudoClass::Focus => self.focus,
+            PseudoClass::Visited => self.visited,
+        }
+    }
+
+    fn set(&mut self, pseudo_class: PseudoClass) {
+        match pseudo_class {
+            PseudoClass::Hover => self.hover = true,
+            PseudoClass::Active => self.active = true,
+            PseudoClass::Focus => self.focus = true,
+            PseudoClass::Visited => self.visited = true,
+        }
+    }
+}
+
+/// Which elements of a document are hovered, active, focused or visited, as
+/// supplied by whoever embeds the engine; there's no event loop to work it out.
+///
+/// Elements are identified by address, so the state only applies to the document
+/// it was built for, and only until that document is changed. Nothing is implied:
+/// to have the ancestors of a hovered element match `:hover` as well, as browsers
+/// do, mark them too.
+#[derive(Debug, Clone, Default)]
+pub struct PseudoClassState {
+    states: HashMap<*const Node, ElementState>,
+}
+
+impl PseudoClassState {
+    pub fn insert(&mut self, node: &Node, pseudo_class: PseudoClass) {
+        self.states.entry(node as *const Node).or_default().set(pseudo_class);
+    }
+
+    /// The state of the node, with nothing set if the caller didn't mention it.
+    pub fn state_of(&self, node: &Node) -> ElementState {
+        self.states.get(&(node as *const Node)).copied().unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty()
+    }
+}
+
+/// Where the element being matched sits in the document.
+#[derive(Clone, Copy, Default)]
+pub struct MatchingContext<'a> {
+    pub ancestors: &'a [&'a ElementData], // from the root down to the parent
+    // the dynamic state of each of the ancestors; those left out have nothing set
+    pub ancestor_states: &'a [ElementState],
+    pub state: ElementState,
+    // the pseudo-element of the element being styled, such as `before`, or `None`
+    // for the element itself
+    pub pseudo_element: Option<&'a str>,
+    // the element's language from the nearest lang attribute, for `:lang()`
+    pub language: Option<&'a str>,
+    // the names, ids and classes of the ancestors, for rejecting a selector that needs
+    // one none of them has without walking them; `None` always walks them
+    pub ancestor_filter: Option<&'a AncestorFilter>,
+}
+
+/// A counting Bloom filter of the tag names, ids and classes of the ancestors of the
+/// element being matched, kept as a tree is walked: each element is pushed before
+/// its children are matched and popped after. It may say that an ancestor has a
+/// name, id or class when none does, but never the reverse, so it can only rule
+/// matches out; the walk along the ancestors still decides the rest.
+pub struct AncestorFilter {
+    counts: Vec<u32>,
+}
+
+// buckets in the filter, a power of two, each name, id or class setting two of them
+const FILTER_BUCKETS: usize = 4096;
+
+impl Default for AncestorFilter {
+    fn default() -> AncestorFilter {
+        AncestorFilter { counts: vec![0; FILTER_BUCKETS] }
+    }
+}
+
+impl AncestorFilter {
+    pub fn push(&mut self, elem: &ElementData) {
+        for hash in element_hashes(elem) {
+            for bucket in buckets(hash) {
+                self.counts[bucket] += 1;
+            }
+        }
+    }
+
+    /// Take out an element pushed before, once its children are matched.
+    pub fn pop(&mut self, elem: &ElementData) {
+        for hash in element_hashes(elem) {
+            for bucket in buckets(hash) {
+                self.counts[bucket] -= 1;
+            }
+        }
+    }
+
+    // might the ancestors have every name, id and class hashed in `hashes`?
+    fn may_contain_all(&self, hashes: &[u64]) -> bool {
+        hashes.iter().all(|&hash| buckets(hash).iter().all(|&bucket| self.counts[bucket] > 0))
+    }
+}
+
+// the two buckets a hash sets, from its low and high bits
+fn buckets(hash: u64) -> [usize; 2] {
+    [hash as usize % FILTER_BUCKETS, (hash >> 32) as usize % FILTER_BUCKETS]
+}
+
+// a tag name, id or class, hashed with what it is so that `div` the class and
+// `div` the tag name don't collide
+fn component_hash(kind: u8, name: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (kind, name).hash(&mut hasher);
+    hasher.finish()
+}
+
+fn element_hashes(elem: &ElementData) -> Vec<u64> {
+    let mut hashes = vec![component_hash(b't', &elem.tag_name)];
+    hashes.extend(elem.id().map(|id| component_hash(b'i', id)));
+    hashes.extend(elem.classes().into_iter().map(|class| component_hash(b'c', class)));
+    hashes
+}
+
+// what an element must have to match the selector
+fn selector_hashes(selector: &SimpleSelector) -> impl Iterator<Item = u64> + '_ {
+    let tag_name = selector.tag_name.iter().map(|name| component_hash(b't', name));
+    let id = selector.id.iter().map(|id| component_hash(b'i', id));
+    tag_name.chain(id).chain(selector.class.iter().map(|class| component_hash(b'c', class)))
+}
+
+/// A selector split into compound selectors, ordered from the subject leftwards so
+/// that matching can start at the element and stop at the first part that fails.
+#[derive(Debug, Clone)]
+pub struct CompiledSelector {
+    pub compounds: Vec<SimpleSelector>,
+    // what joins each compound selector to the next one along, to its left
+    pub combinators: Vec<Combinator>,
+    pub specificity: Specificity,
+    // the names, ids and classes the compounds left of the subject need, each in an
+    // ancestor of the element, as `AncestorFilter` hashes them
+    ancestor_hashes: Vec<u64>,
+}
+
+impl CompiledSelector {
+    pub fn new(selector: &Selector) -> CompiledSelector {
+        let (compounds, combinators) = match selector {
+            Selector::Simple(simple) => (vec![simple.clone()], Vec::new()),
+            Selector::Compound(first, rest) => {
+                let lefts = std::iter::once(first).chain(rest.iter().map(|(_, simple)| simple));
+                (lefts.rev().cloned().collect(), rest.iter().rev().map(|&(combinator, _)| combinator).collect())
+            }
+        };
+        let ancestor_hashes = compounds.iter().skip(1).flat_map(selector_hashes).collect();
+        CompiledSelector { compounds, combinators, specificity: selector.specificity(), ancestor_hashes }
+    }
+
+    pub fn matches(&self, elem: &ElementData, ctx: &MatchingContext) -> bool {
+        let subject = match self.compounds.first() {
+            Some(subject) => subject,
+            None => return false
+        };
+        // a selector ending in a pseudo-element only applies to that pseudo-element
+        subject.pseudo_element.as_deref() == ctx.pseudo_element
+            && matches_simple_selector(elem, subject)
+            && matches_pseudo_classes(subject, &ctx.state, ctx.language)
+            && ctx.ancestor_filter.is_none_or(|filter| filter.may_contain_all(&self.ancestor_hashes))
+            && self.matches_ancestors(1, ctx.ancestors.len(), ctx) == AncestorMatch::Matched
+    }
+
+    // do the compound selectors from `index` on match ancestors of the element the
+    // one before matched, which is `ctx.ancestors[end]`, or the element itself if
+    // that's past the end? Descendant combinators try the nearest ancestor first, and
+    // the ones further out if the rest of the selector then fails, unless it failed
+    // in a way no ancestor further out can help with.
+    //
+    // https://github.com/servo/servo/blob/master/components/selectors/matching.rs
+    fn matches_ancestors(&self, index: usize, end: usize, ctx: &MatchingContext) -> AncestorMatch {
+        let compound = match self.compounds.get(index) {
+            Some(compound) => compound,
+            None => return AncestorMatch::Matched
+        };
+        let matches_compound = |i: usize| {
+            let state = ctx.ancestor_states.get(i).copied().unwrap_or_default();
+            let language = ctx.ancestors[..=i].iter().rev().find_map(|ancestor| ancestor.attrs.get("lang"));
+            compound.pseudo_element.is_none()
+                && matches_simple_selector(ctx.ancestors[i], compound)
+                && matches_pseudo_classes(compound, &state, language.map(String::as_str))
+        };
+        match self.combinators[index - 1] {
+            // with no parent, no ancestor further out has one either
+            Combinator::Child if end == 0 => AncestorMatch::NotMatchedGlobally,
+            Combinator::Child if !matches_compound(end - 1) => AncestorMatch::NotMatchedAndRestartFromClosestDescendant,
+            Combinator::Child => self.matches_ancestors(index + 1, end - 1, ctx),
+            Combinator::Descendant => {
+                for i in (0..end).rev().filter(|&i| matches_compound(i)) {
+                    match self.matches_ancestors(index + 1, i, ctx) {
+                        AncestorMatch::NotMatchedAndRestartFromClosestDescendant => continue,
+                        result => return result
+                    }
+                }
+                // the rest of the selector failed on every ancestor from here out, and
+                // an element matched further in would only leave it fewer
+                AncestorMatch::NotMatchedGlobally
+            }
+        }
+    }
+}
+
+// How matching the compound selectors from some index on against the ancestors came
+// out. A failure is either one only a child combinator's parent caused, which the
+// closest descendant combinator to its right may get past by trying an ancestor
+// further out, or one no ancestor further out can fix, which ends the whole match.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AncestorMatch {
+    Matched,
+    NotMatchedAndRestartFromClosestDescendant,
+    NotMatchedGlobally,
+}
+
+/// Does the element match the selector?
+pub fn matches(elem: &ElementData, selector: &Selector, ctx: &MatchingContext) -> bool {
+    CompiledSelector::new(selector).matches(elem, ctx)
+}
+
+/// If the element matches any of the rule's selectors, return the specificity of the
+/// most specific one that does.
+pub fn match_rule(elem: &ElementData, rule: &Rule, ctx: &MatchingContext) -> Option<Specificity> {
+    // the parser sorts each rule's selectors by specificity, highest first
+    rule.selectors.iter()
+        .find(|selector| matches(elem, selector, ctx))
+        .map(|selector| selector.specificity())
+}
+
+/// Same as `match_rule`, with the rule's selectors compiled already, in order, for
+/// matching many elements against.
+pub fn match_compiled_rule(elem: &ElementData, selectors: &[CompiledSelector], ctx: &MatchingContext) -> Option<Specificity> {
+    selectors.iter()
+        .find(|selector| selector.matches(elem, ctx))
+        .map(|selector| selector.specificity)
+}
+
+// Do the element's state and language match the selector's pseudo-classes? Those we
+// don't support never match, so rules that use them are left out rather than
+// misapplied.
+fn matches_pseudo_classes(selector: &SimpleSelector, state: &ElementState, language: Option<&str>) -> bool {
+    selector.pseudo_classes.iter().all(|name| match name.strip_prefix("lang(").and_then(|name| name.strip_suffix(')')) {
+        Some(range) => language.is_some_and(|language| matches_language(language, range)),
+        None => PseudoClass::from_name(name).is_some_and(|pseudo_class| state.has(pseudo_class)),
+    })
+}
+
+// Is `language` the `range` of `:lang()` or a subtag of it, as "en-GB" is of "en"?
+// The range is lowercase; an empty language, from `lang=""`, is unknown and matches
+// none.
+//
+// https://www.w3.org/TR/selectors-4/#the-lang-pseudo
+fn matches_language(language: &str, range: &str) -> bool {
+    let range = range.trim_matches(|c| c == '"' || c == '\'');
+    !range.is_empty() && language.get(..range.len()).is_some_and(|prefix| prefix.eq_ignore_ascii_case(range))
+        && matches!(language.as_bytes().get(range.len()), None | Some(b'-'))
+}
+
+/// Do the element's name, id, and classes match the selector?
+pub fn matches_simple_selector(elem: &ElementData, selector: &SimpleSelector) -> bool {
+    // check type selector
+    if selector.tag_name.iter().any(|name| elem.tag_name != *name) {
+        return false;
+    }
+
+    // check ID selector
+    if selector.id.iter().any(|id| elem.id() != Some(id)) {
+        return false;
+    }
+
+    // check class selectors
+    if selector.class.iter().any(|class| !elem.classes().contains(class.as_str())) {
+        return false;
+    }
+
+    // we didn't find any non-matching selector components
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::css::parse_selector;
+
+    fn element(tag_name: &str, class: Option<&str>) -> ElementData {
+        let attrs = class.map(|class| ("class".to_string(), class.to_string())).into_iter().collect();
+        ElementData { tag_name: tag_name.to_string(), attrs }
+    }
+
+    fn matches_with_ancestors(selector: &str, elem: &ElementData, ancestors: &[ElementData]) -> bool {
+        let ancestors: Vec<&ElementData> = ancestors.iter().collect();
+        let ctx = MatchingContext { ancestors: &ancestors, ..Default::default() };
+        CompiledSelector::new(&parse_selector(selector).unwrap()).matches(elem, &ctx)
+    }
+
+    #[test]
+    fn descendant_and_child_combinators() {
+        let ancestors = [element("div", Some("a")), element("section", None), element("div", Some("b"))];
+        let p = element("p", None);
+        assert!(matches_with_ancestors(".a p", &p, &ancestors));
+        assert!(matches_with_ancestors(".b > p", &p, &ancestors));
+        assert!(!matches_with_ancestors(".a > p", &p, &ancestors));
+        assert!(matches_with_ancestors(".a > section .b > p", &p, &ancestors));
+        assert!(matches_with_ancestors(".a div p", &p, &ancestors));
+        assert!(!matches_with_ancestors(".b div p", &p, &ancestors));
+        // the first `div` tried for `div > section` fails, and the one further out
+        // can't be tried for `.a > div`, which needs a parent
+        assert!(!matches_with_ancestors("div > section div > p", &p, &ancestors[1..]));
+        assert!(matches_with_ancestors("section div > p", &p, &ancestors));
+    }
+
+    // Without giving up on a descendant combinator once the rest of the selector has
+    // failed on every ancestor out to the root, each way of choosing five of the divs
+    // would be tried, which for this many takes hours.
+    #[test]
+    fn deep_nesting_without_a_match_is_linear() {
+        let mut ancestors: Vec<ElementData> = (0..1000).map(|_| element("div", None)).collect();
+        let p = element("p", None);
+        assert!(!matches_with_ancestors(".x div div div div div p", &p, &ancestors));
+        // with the `.x` too close for the divs to fit between it and the paragraph
+        ancestors[997] = element("div", Some("x"));
+        assert!(!matches_with_ancestors(".x div div div div div p", &p, &ancestors));
+        ancestors[0] = element("div", Some("x"));
+        assert!(matches_with_ancestors(".x div div div div div p", &p, &ancestors));
+        assert!(!matches_with_ancestors(".x > div > div .y div p", &p, &ancestors));
+    }
+
+    #[test]
+    fn lang_matches_the_language_and_its_subtags() {
+        let matches = |selector: &str, language: Option<&str>| {
+            let ctx = MatchingContext { language, ..Default::default() };
+            CompiledSelector::new(&parse_selector(selector).unwrap()).matches(&element("p", None), &ctx)
+        };
+        assert!(matches("p:lang(en)", Some("en")));
+        assert!(matches(":lang(EN)", Some("en-GB")));
+        assert!(!matches(":lang(en)", Some("eng")));
+        assert!(!matches(":lang(en-gb)", Some("en")));
+        assert!(!matches(":lang(en)", Some("")));
+        assert!(!matches(":lang(en)", None));
+        // an ancestor's language is the nearest lang attribute from it outwards
+        let mut french = element("div", None);
+        french.attrs.insert("lang".to_string(), "fr".to_string());
+        let ancestors = [french, element("section", None)];
+        assert!(matches_with_ancestors("section:lang(fr) p", &element("p", None), &ancestors));
+        assert!(!matches_with_ancestors("section:lang(en) p", &element("p", None), &ancestors));
+    }
+}
@@ -0,0 +1,210 @@
+use crate::css::{Origin, Stylesheet};
+use crate::dom::{Node, NodeType};
+use crate::layout::{AnonymousBlock, BlockNode, InlineBlockNode, InlineNode, LayoutBox};
+use crate::painting::DisplayCommand;
+use crate::style::{Display, StyledNode};
+use std::fmt::{self, Write};
+
+// How much a render handled at each stage, for capacity planning and for telling
+// pathological documents apart, as `--profile` prints. The counts are taken by
+// walking each stage's output once it's done, as `Limits` checks are, so rendering
+// itself does no extra work.
+
+/// The size of everything one render produced, stage by stage.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RenderStats {
+    pub elements: usize, // DOM nodes, by type
+    pub text_nodes: usize,
+    pub comments: usize,
+    pub stylesheets: Vec<(Origin, RuleCounts)>, // per origin, in cascade order
+    pub styled_nodes: usize, // including generated content
+    pub hidden_nodes: usize, // styled nodes in `display: none` subtrees, which generate no boxes
+    pub block_boxes: usize, // layout boxes, by `BoxType`
+    pub inline_boxes: usize,
+    pub inline_block_boxes: usize,
+    pub anonymous_boxes: usize,
+    pub fills: usize, // display commands, by kind, over all pages
+    pub texts: usize,
+    pub images: usize,
+    pub transforms: usize, // `PushTransform`s; each has a matching `PopTransform`
+    pub peak_canvas_bytes: usize, // the largest raster canvas painted; 0 for PDF output
+    // heap allocations made during each stage, with the `count-allocations` feature
+    pub allocations: Vec<(&'static str, usize)>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RuleCounts {
+    pub rules: usize,
+    pub declarations: usize,
+}
+
+impl RenderStats {
+    /// Count the DOM nodes of the document.
+    pub fn count_document(&mut self, node: &Node) {
+        match node.node_type {
+            NodeType::Element(_) => self.elements += 1,
+            NodeType::Text(_) => self.text_nodes += 1,
+            NodeType::Comment(_) => self.comments += 1,
+        }
+        for child in &node.children {
+            self.count_document(child);
+        }
+    }
+
+    /// Count the rules and declarations of each origin's stylesheets.
+    pub fn count_stylesheets(&mut self, stylesheets: &[Stylesheet]) {
+        for stylesheet in stylesheets {
+            let counts = match self.stylesheets.iter_mut().find(|(origin, _)| *origin == stylesheet.origin) {
+                Some((_, counts)) => counts,
+                None => {
+                    self.stylesheets.push((stylesheet.origin, RuleCounts::default()));
+                    &mut self.stylesheets.last_mut().unwrap().1
+                }
+            };
+            counts.rules += stylesheet.rules.len();
+            counts.declarations += stylesheet.rules.iter().map(|rule| rule.declarations.len()).sum::<usize>();
+        }
+    }
+
+    pub fn count_styled_nodes(&mut self, node: &StyledNode) {
+        self.count_styled_subtree(node, false);
+    }
+
+    fn count_styled_subtree(&mut self, node: &StyledNode, hidden: bool) {
+        let hidden = hidden || (matches!(node.node.node_type, NodeType::Element(_)) && node.display() == Display::None);
+        self.styled_nodes += 1;
+        self.hidden_nodes += hidden as usize;
+        for child in &node.children {
+            self.count_styled_subtree(child, hidden);
+        }
+    }
+
+    pub fn count_layout_boxes(&mut self, layout_box: &LayoutBox) {
+        match layout_box.box_type {
+            BlockNode(_) => self.block_boxes += 1,
+            InlineNode(_) => self.inline_boxes += 1,
+            InlineBlockNode(_) => self.inline_block_boxes += 1,
+            AnonymousBlock => self.anonymous_boxes += 1,
+        }
+        for child in &layout_box.children {
+            self.count_layout_boxes(child);
+        }
+    }
+
+    pub fn count_display_list(&mut self, display_list: &[DisplayCommand]) {
+        for item in display_list {
+            match item {
+                DisplayCommand::SolidColor(..) => self.fills += 1,
+                DisplayCommand::Text(..) => self.texts += 1,
+                DisplayCommand::Image(..) => self.images += 1,
+                DisplayCommand::PushTransform(_) => self.transforms += 1,
+                DisplayCommand::PopTransform => {}
+            }
+        }
+    }
+
+    pub fn layout_boxes(&self) -> usize {
+        self.block_boxes + self.inline_boxes + self.inline_block_boxes + self.anonymous_boxes
+    }
+
+    /// Render the counts as a JSON object.
+    pub fn to_json(&self) -> String {
+        let mut out = format!("{{\"elements\":{},\"textNodes\":{},\"comments\":{},\"stylesheets\":[",
+                              self.elements, self.text_nodes, self.comments);
+        for (i, (origin, counts)) in self.stylesheets.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let _ = write!(out, "{{\"origin\":\"{}\",\"rules\":{},\"declarations\":{}}}",
+                           origin_name(*origin), counts.rules, counts.declarations);
+        }
+        let _ = write!(out, "],\"styledNodes\":{},\"hiddenNodes\":{},\"blockBoxes\":{},\"inlineBoxes\":{},\
+                             \"inlineBlockBoxes\":{},\"anonymousBoxes\":{},\"fills\":{},\"texts\":{},\"images\":{},\
+                             \"transforms\":{},\"peakCanvasBytes\":{},\"allocations\":{{",
+                       self.styled_nodes, self.hidden_nodes, self.block_boxes, self.inline_boxes, self.inline_block_boxes,
+                       self.anonymous_boxes, self.fills, self.texts, self.images, self.transforms, self.peak_canvas_bytes);
+        for (i, (stage, allocations)) in self.allocations.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let _ = write!(out, "\"{}\":{}", stage, allocations);
+        }
+        out.push_str("}}");
+        out
+    }
+}
+
+fn origin_name(origin: Origin) -> &'static str {
+    match origin {
+        Origin::UserAgent => "user agent",
+        Origin::User => "user",
+        Origin::Author => "author",
+    }
+}
+
+impl fmt::Display for RenderStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "DOM nodes: {} ({} elements, {} text, {} comments)",
+                 self.elements + self.text_nodes + self.comments, self.elements, self.text_nodes, self.comments)?;
+        for (origin, counts) in &self.stylesheets {
+            writeln!(f, "Rules ({}): {} with {} declarations", origin_name(*origin), counts.rules, counts.declarations)?;
+        }
+        writeln!(f, "Styled nodes: {} ({} visible, {} hidden by display: none)",
+                 self.styled_nodes, self.styled_nodes - self.hidden_nodes, self.hidden_nodes)?;
+        writeln!(f, "Layout boxes: {} ({} block, {} inline, {} inline-block, {} anonymous)",
+                 self.layout_boxes(), self.block_boxes, self.inline_boxes, self.inline_block_boxes, self.anonymous_boxes)?;
+        writeln!(f, "Display commands: {} fills, {} text runs, {} images, {} transforms",
+                 self.fills, self.texts, self.images, self.transforms)?;
+        write!(f, "Peak canvas: {} bytes", self.peak_canvas_bytes)?;
+        for (stage, allocations) in &self.allocations {
+            write!(f, "\nAllocations ({}): {}", stage, allocations)?;
+        }
+        Ok(())
+    }
+}
+
+/// The number of heap allocations made so far, or `None` unless built with the
+/// `count-allocations` feature, which counts them with a wrapper around the system
+/// allocator.
+pub fn allocations() -> Option<usize> {
+    #[cfg(feature = "count-allocations")]
+    return Some(counting::ALLOCATIONS.load(std::sync::atomic::Ordering::Relaxed));
+    #[cfg(not(feature = "count-allocations"))]
+    None
+}
+
+/// The number of bytes heap allocations have asked for so far, counting each
+/// reallocation as a new allocation of its full size, or `None` unless built with
+/// the `count-allocations` feature.
+pub fn allocated_bytes() -> Option<usize> {
+    #[cfg(feature = "count-allocations")]
+    return Some(counting::ALLOCATED_BYTES.load(std::sync::atomic::Ordering::Relaxed));
+    #[cfg(not(feature = "count-allocations"))]
+    None
+}
+
+#[cfg(feature = "count-allocations")]
+mod counting {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    pub(super) static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+    pub(super) static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+    struct CountingAllocator;
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+}
@@ -0,0 +1,135 @@
+use crate::css::{self, Origin};
+use crate::dom::NodeType;
+use crate::engine::{self, EngineError, RenderOptions};
+use crate::html::{self, ParserOptions};
+use crate::layout::LayoutBox;
+use crate::painting::{self, DisplayCommand};
+use crate::style;
+use std::collections::HashSet;
+
+// Fixtures for what of a document's structure reaches the display list. The UA stylesheet hides the head, and the elements
+// the HTML rendering rules hide wherever they appear, like `title`, `style`,
+// `script` and `meta`; none of their text may be painted, and none of them may
+// generate a box. An author rule displaying one renders it like any other element,
+// as long as the elements around it are displayed too.
+
+/// A document, its author stylesheet, and what rendering it must come to.
+pub struct StructureCase {
+    pub name: &'static str,
+    pub html: &'static str,
+    pub css: &'static str,
+    pub painted: &'static [&'static str], // words that must be painted
+    pub unpainted: &'static [&'static str], // words that mustn't be
+    pub boxless: &'static [&'static str], // elements that mustn't generate a box
+}
+
+/// The fixtures: metadata inside and outside the head, metadata
+/// an author stylesheet displays, and comments at the top level of the document.
+pub fn cases() -> Vec<StructureCase> {
+    vec![
+        StructureCase {
+            name: "metadata in the head",
+            html: "<html><head><title>HeadTitle</title><style>/* HeadStyle */ p { margin: 0 }</style>\
+                <script>HeadScript()</script><meta charset=\"utf-8\"><link rel=\"icon\" href=\"icon.png\">\
+                </head><body><p>BodyText</p></body></html>",
+            css: "",
+            painted: &["BodyText"],
+            unpainted: &["HeadTitle", "HeadStyle", "HeadScript()"],
+            boxless: &["head", "title", "style", "script", "meta", "link"],
+        },
+        StructureCase {
+            name: "metadata in the body",
+            html: "<html><body><title>BodyTitle</title><p>BodyText</p><style>/* BodyStyle */ p { margin: 0 }</style>\
+                <script>BodyScript()</script><meta name=\"x\" content=\"y\"><link rel=\"icon\" href=\"icon.png\">\
+                </body></html>",
+            css: "",
+            painted: &["BodyText"],
+            unpainted: &["BodyTitle", "BodyStyle", "BodyScript()"],
+            boxless: &["title", "style", "script", "meta", "link"],
+        },
+        StructureCase {
+            name: "metadata outside the html element",
+            html: "<title>TopTitle</title><html><body><p>BodyText</p></body></html><script>TopScript()</script>",
+            css: "",
+            painted: &["BodyText"],
+            unpainted: &["TopTitle", "TopScript()"],
+            boxless: &["title", "script"],
+        },
+        StructureCase {
+            name: "head displayed by the author",
+            html: "<html><head><title>HeadTitle</title><style>/* HeadStyle */ p { margin: 0 }</style>\
+                <script>HeadScript()</script><meta charset=\"utf-8\"></head><body><p>BodyText</p></body></html>",
+            css: "head, title, style, script { display: block; }",
+            painted: &["HeadTitle", "HeadStyle", "HeadScript()", "BodyText"],
+            unpainted: &[],
+            boxless: &["meta"],
+        },
+        StructureCase {
+            name: "title displayed in a hidden head",
+            html: "<html><head><title>HeadTitle</title></head><body><p>BodyText</p></body></html>",
+            css: "title { display: block; }",
+            painted: &["BodyText"],
+            unpainted: &["HeadTitle"],
+            boxless: &["head", "title"],
+        },
+        StructureCase {
+            name: "comments at the top level",
+            html: "<!DOCTYPE html><!-- BeforeComment --><html><!-- HtmlComment --><body><p>BodyText</p></body></html>\
+                <!-- AfterComment -->",
+            css: "",
+            painted: &["BodyText"],
+            unpainted: &["BeforeComment", "HtmlComment", "AfterComment"],
+            boxless: &[],
+        },
+    ]
+}
+
+/// Render `case` as far as its display list, and say what came out other than it
+/// should, if anything.
+pub fn check_case(case: &StructureCase, options: &RenderOptions) -> Result<Vec<String>, EngineError> {
+    let parser_options = ParserOptions { limits: options.limits.clone(), ..Default::default() };
+    let root = html::parse_with_options(case.html, parser_options)?;
+    let stylesheets = [css::default_user_agent_stylesheet(), css::parse_with_limits(case.css.to_string(), Origin::Author, &options.limits)?];
+    let style_root = style::style_tree_with_context(&root, &stylesheets, &options.style_context());
+    let layout_root = engine::layout(&style_root, options)?;
+    let painted: HashSet<String> = painting::build_display_list(&layout_root).into_iter()
+        .filter_map(|command| match command {
+            DisplayCommand::Text(text, ..) => Some(text),
+            _ => None
+        })
+        .flat_map(|text| text.split_whitespace().map(str::to_string).collect::<Vec<_>>())
+        .collect();
+    let mut boxed = HashSet::new();
+    collect_boxed_elements(&layout_root, &mut boxed);
+
+    let mut problems = Vec::new();
+    for word in case.painted.iter().filter(|word| !painted.contains(**word)) {
+        problems.push(format!("{:?} isn't painted", word));
+    }
+    for word in case.unpainted.iter().filter(|word| painted.contains(**word)) {
+        problems.push(format!("{:?} is painted", word));
+    }
+    for tag_name in case.boxless.iter().filter(|tag_name| boxed.contains(**tag_name)) {
+        problems.push(format!("a {} element generates a box", tag_name));
+    }
+    Ok(problems)
+}
+
+// the lowercased names of the elements that generated boxes
+fn collect_boxed_elements(layout_box: &LayoutBox, boxed: &mut HashSet<String>) {
+    if let Some(NodeType::Element(ref elem)) = layout_box.style_node().map(|node| &node.node.node_type) {
+        boxed.insert(elem.tag_name.to_ascii_lowercase());
+    }
+    for child in &layout_box.children {
+        collect_boxed_elements(child, boxed);
+    }
+}
+
+#[test]
+fn documents_render_their_structure_as_expected() {
+    let options = RenderOptions::default();
+    for case in &cases() {
+        let problems = check_case(case, &options).unwrap();
+        assert!(problems.is_empty(), "{}: {}", case.name, problems.join(", "));
+    }
+}
@@ -1,23 +1,62 @@
-use crate::dom::{Node, NodeType, ElementData};
-use crate::css::{Stylesheet, Rule, Selector, SimpleSelector, Value, Specificity, Origin};
-use std::collections::HashMap;
+use crate::dom::{self, Node, NodeType, ElementData, QuirksMode};
+use crate::css::{self, Rule, Selector, Stylesheet, Unit, Value, Specificity, Origin};
+use crate::media::{ColorScheme, MediaContext, MediaQueryList, MediaType};
+use crate::forms::{self, Control, ShownText};
+use crate::selectors::{self, AncestorFilter, CompiledSelector, ElementState, MatchingContext, PseudoClassState};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::ops::Deref;
 
 type PropertyMap = HashMap<String, Value>;
 
+/// The DOM node a styled node belongs to. Generated content such as `::before` has
+/// no node in the document, so the styled tree owns a node made up for it.
+pub enum NodeRef<'a> {
+    Dom(&'a Node),
+    Generated(Box<Node>),
+}
+
+impl Deref for NodeRef<'_> {
+    type Target = Node;
+
+    fn deref(&self) -> &Node {
+        match self {
+            NodeRef::Dom(node) => node,
+            NodeRef::Generated(node) => node,
+        }
+    }
+}
+
 pub struct StyledNode<'a> {
-    pub node: &'a Node, // pointer to a DOM tree node
+    pub node: NodeRef<'a>, // pointer to a DOM tree node
     pub specified_values: PropertyMap, // hashmap (property name, value)
-    pub children: Vec<StyledNode<'a>>
+    pub language: Option<String>, // from the nearest lang attribute, inherited like a property
+    pub children: Vec<StyledNode<'a>>,
+    // for text at the start of a block with `::first-line` or `::first-letter` rules,
+    // the text as styled where it falls on the block's first line, and the text as
+    // styled as its first letter; which part that is isn't known until layout
+    pub first_line: Option<Box<StyledNode<'a>>>,
+    pub first_letter: Option<Box<StyledNode<'a>>>,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Display {
     Inline,
     Block,
+    InlineBlock,
+    Table,
+    TableRowGroup, // also for header and footer groups, which aren't moved
+    TableRow,
+    TableCell,
+    Grid,
     None
 }
 
-// Cascade order: (origin_importance, specificity, source_order)
-type CascadeKey = (u8, Specificity, usize);
+// Cascade order: (origin_importance, layer, specificity, source_order), with
+// source order counted across stylesheets, as (stylesheet index, rule index)
+//
+// https://www.w3.org/TR/css-cascade-5/#cascade-sort
+type CascadeKey = (u8, i32, Specificity, (usize, usize));
 
 #[derive(Clone)]
 struct CascadedDeclaration<'a> {
@@ -29,23 +68,35 @@ struct CascadedDeclaration<'a> {
 const INHERITED_PROPERTIES: &[&str] = &[
     "color", "font-family", "font-size", "font-style", "font-weight", 
     "line-height", "text-align", "text-decoration", "text-indent",
-    "visibility", "white-space", "word-spacing", "letter-spacing"
+    "visibility", "white-space", "word-break", "overflow-wrap", "word-wrap",
+    "word-spacing", "letter-spacing", "tab-size",
+    "direction", "border-collapse", "border-spacing"
+];
+
+// The properties `::first-line` and `::first-letter` can set here; backgrounds,
+// boxes and floats for drop caps aren't supported
+const FIRST_LINE_PROPERTIES: &[&str] = &[
+    "color", "font-family", "font-size", "font-style", "font-weight", "line-height",
+    "text-decoration", "word-spacing", "letter-spacing"
 ];
 
-// Initial values for properties
-fn get_initial_value(property: &str) -> Value {
-    match property {
+// Initial values for properties; `None` for those whose absence from the
+// specified values already means the initial value to layout and painting
+fn get_initial_value(property: &str) -> Option<Value> {
+    Some(match property {
         "display" => Value::Keyword("inline".to_string()),
         "color" => Value::ColorValue(crate::css::Color { r: 0, g: 0, b: 0, a: 255 }),
-        "font-size" => Value::Length(16.0, crate::css::Unit::Px),
+        "font-size" => Value::Keyword("medium".to_string()),
         "font-weight" => Value::Keyword("normal".to_string()),
         "font-style" => Value::Keyword("normal".to_string()),
         "text-align" => Value::Keyword("left".to_string()),
         "text-decoration" => Value::Keyword("none".to_string()),
         "visibility" => Value::Keyword("visible".to_string()),
         "white-space" => Value::Keyword("normal".to_string()),
-        _ => Value::Keyword("initial".to_string()),
-    }
+        "tab-size" => Value::Number(8.0),
+        "direction" => Value::Keyword("ltr".to_string()),
+        _ => return None,
+    })
 }
 
 fn is_inherited_property(property: &str) -> bool {
@@ -64,57 +115,853 @@ impl<'a> StyledNode<'a> {
             .unwrap_or_else(|| default.clone())
     }
 
+    // the language of the node's content as a BCP 47 tag such as "en-GB", or `None`
+    // when unknown because no ancestor has a lang attribute, or the nearest is empty
+    pub fn language(&self) -> Option<&str> {
+        self.language.as_deref().filter(|language| !language.is_empty())
+    }
+
+    // false for visibility: hidden or collapse, which is laid out but not painted
+    // is `other` this node, or this text as styled for its block's first line or
+    // first letter?
+    pub fn is_same_text(&self, other: &StyledNode) -> bool {
+        std::ptr::eq(self, other) || [&self.first_line, &self.first_letter].into_iter()
+            .any(|version| version.as_deref().is_some_and(|version| std::ptr::eq(version, other)))
+    }
+
+    pub fn is_visible(&self) -> bool {
+        !matches!(self.value("visibility"), Some(Value::Keyword(v)) if v == "hidden" || v == "collapse")
+    }
+
     // return value of display property with default inline
     pub fn display(&self) -> Display {
         match self.value("display") {
             Some(Value::Keyword(s)) => match &*s {
                 "block" => Display::Block,
+                "inline-block" => Display::InlineBlock,
+                "table" => Display::Table,
+                "table-row-group" | "table-header-group" | "table-footer-group" => Display::TableRowGroup,
+                "table-row" => Display::TableRow,
+                "table-cell" => Display::TableCell,
+                "grid" => Display::Grid,
                 "none" => Display::None,
                 _ => Display::Inline
             },
             _ => Display::Inline
         }
     }
+
+    /// The outline drawn around the node's box, or `None` if its `outline-style` is
+    /// `none` or its width is 0.
+    ///
+    /// https://www.w3.org/TR/css-ui-4/#outline-props
+    ///
+    /// Every style other than `none` is drawn solid, as borders are.
+    pub fn outline(&self) -> Option<Outline> {
+        match self.value("outline-style") {
+            Some(Value::Keyword(style)) if style != "none" => {}
+            _ => return None
+        }
+        let width = match self.value("outline-width") {
+            Some(Value::Keyword(k)) if k == "thin" => 1.0,
+            Some(Value::Keyword(k)) if k == "thick" => 5.0,
+            Some(Value::Keyword(_)) | None => 3.0, // medium
+            Some(length) => length.to_px()
+        };
+        // the initial color is the text's
+        let color = match (self.value("outline-color"), self.value("color")) {
+            (Some(Value::ColorValue(color)), _) | (_, Some(Value::ColorValue(color))) => color,
+            _ => css::Color { r: 0, g: 0, b: 0, a: 255 }
+        };
+        let offset = self.value("outline-offset").map_or(0.0, |offset| offset.to_px());
+        (width > 0.0).then_some(Outline { width, offset, color })
+    }
+}
+
+/// An outline: a line drawn around a box, outside its border edge and `offset` px
+/// away from it, that takes up no space in layout.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Outline {
+    pub width: f32,
+    pub offset: f32,
+    pub color: css::Color,
+}
+
+/// The environment a document is styled in.
+///
+/// With a `zoom` other than 1, every length computes to `zoom` times its size, and
+/// the viewport is `viewport_width / zoom` document px wide, so the document lays
+/// out as if zoomed in a browser.
+#[derive(Debug, Clone, Copy)]
+pub struct StyleContext {
+    pub viewport_width: f32, // in px of output, for resolving vw, vh, vmin and vmax
+    pub viewport_height: f32,
+    pub default_font_size: f32, // the size of `medium`, which is the root's initial size
+    pub minimum_font_size: f32, // smaller font sizes compute to this
+    pub zoom: f32,
+    pub color_scheme: ColorScheme, // for `prefers-color-scheme` media queries
+    pub media_type: MediaType, // print for paged output, where the viewport is a page's content box
+    // whether selectors that need ancestors are first checked against a Bloom filter
+    // of them, a fast path that never changes what matches
+    pub ancestor_filter: bool,
+    pub quirks_mode: QuirksMode, // the document's, from parsing it
+}
+
+impl Default for StyleContext {
+    fn default() -> StyleContext {
+        StyleContext {
+            viewport_width: 800.0,
+            viewport_height: 600.0,
+            default_font_size: 16.0,
+            minimum_font_size: 0.0,
+            zoom: 1.0,
+            color_scheme: ColorScheme::Light,
+            media_type: MediaType::Screen,
+            ancestor_filter: true,
+            quirks_mode: QuirksMode::NoQuirks,
+        }
+    }
+}
+
+impl StyleContext {
+    /// What `@media` rules are evaluated against: the viewport in document px.
+    pub fn media(&self) -> MediaContext {
+        let (width, height) = (self.viewport_width / self.zoom, self.viewport_height / self.zoom);
+        MediaContext { media_type: self.media_type, width, height, color_scheme: self.color_scheme }
+    }
+
+    // replace viewport-relative and absolute lengths by px, which is what they
+    // compute to, and zoom px lengths; a zoomed viewport unit, a fraction of a
+    // viewport `zoom` times smaller, comes to the same size as without the zoom
+    fn resolve_lengths(&self, value: &mut Value) {
+        let (w, h) = (self.viewport_width, self.viewport_height);
+        match value {
+            Value::Length(n, unit) => {
+                let per_unit = match unit {
+                    Unit::Px => return *n *= self.zoom,
+                    Unit::In | Unit::Cm | Unit::Mm | Unit::Q | Unit::Pt | Unit::Pc => {
+                        return *value = Value::Length(unit.absolute_px(*n).unwrap_or(0.0) * self.zoom, Unit::Px);
+                    }
+                    Unit::Vw => w,
+                    Unit::Vh => h,
+                    Unit::Vmin => w.min(h),
+                    Unit::Vmax => w.max(h),
+                    _ => return
+                };
+                *value = Value::Length(*n * per_unit / 100.0, Unit::Px);
+            }
+            Value::List(values) | Value::CommaList(values) | Value::Function(_, values) => {
+                for value in values {
+                    self.resolve_lengths(value);
+                }
+            }
+            _ => {}
+        }
+        compute_math_function(value);
+    }
+
+    // Font size keywords compute to px: the absolute sizes to the CSS scale around a
+    // medium of the default font size, and `larger` and `smaller` to a step of 1.2
+    // from the parent's size. Lengths in em and percentages are of the parent's size,
+    // and in rem of the root element's, `root`, or for the root itself of medium. No
+    // font size computes to less than the minimum.
+    //
+    // Em and rem lengths in the other properties then compute to px, against the
+    // element's own font size, which is what they're relative to.
+    //
+    // https://www.w3.org/TR/css-fonts-4/#absolute-size-mapping
+    // https://www.w3.org/TR/css-values-4/#font-relative-lengths
+    fn compute_font_size(&self, values: &mut PropertyMap, parent_values: Option<&PropertyMap>, root: Option<f32>) {
+        let medium = self.default_font_size * self.zoom;
+        let parent = match parent_values.and_then(|parent| parent.get("font-size")) {
+            Some(&Value::Length(size, Unit::Px)) => size,
+            _ => medium
+        };
+        let rem = root.unwrap_or(medium);
+        let size = match values.get("font-size") {
+            Some(&Value::Length(size, Unit::Px)) => Some(size),
+            Some(value @ (Value::Length(..) | Value::Function(..))) => {
+                let mut value = value.clone();
+                resolve_font_relative(&mut value, parent, rem);
+                match value {
+                    Value::Length(size, Unit::Px) => Some(size),
+                    Value::Length(percent, Unit::Percent) => Some(parent * percent / 100.0),
+                    value => value.math_function_px(parent)
+                }
+            }
+            Some(Value::Keyword(keyword)) => {
+                match &**keyword {
+                    "xx-small" => Some(medium * 9.0 / 16.0),
+                    "x-small" => Some(medium * 10.0 / 16.0),
+                    "small" => Some(medium * 13.0 / 16.0),
+                    "medium" => Some(medium),
+                    "large" => Some(medium * 18.0 / 16.0),
+                    "x-large" => Some(medium * 24.0 / 16.0),
+                    "xx-large" => Some(medium * 32.0 / 16.0),
+                    "xxx-large" => Some(medium * 48.0 / 16.0),
+                    "larger" => Some(parent * 1.2),
+                    "smaller" => Some(parent / 1.2),
+                    _ => None
+                }
+            }
+            _ => None
+        };
+        // without a font size of its own, as for `::first-line`, the parent's applies
+        let em = match size {
+            Some(size) => {
+                let size = size.max(self.minimum_font_size * self.zoom);
+                values.insert("font-size".to_string(), Value::Length(size, Unit::Px));
+                size
+            }
+            None => parent
+        };
+        for (property, value) in values.iter_mut() {
+            if property != "font-size" {
+                resolve_font_relative(value, em, rem);
+            }
+        }
+    }
+}
+
+// replace em and rem lengths by px, where an em is `em` px and a rem `rem` px
+fn resolve_font_relative(value: &mut Value, em: f32, rem: f32) {
+    match value {
+        Value::Length(n, Unit::Em) => *value = Value::Length(*n * em, Unit::Px),
+        Value::Length(n, Unit::Rem) => *value = Value::Length(*n * rem, Unit::Px),
+        Value::List(values) | Value::CommaList(values) | Value::Function(_, values) => {
+            for value in values {
+                resolve_font_relative(value, em, rem);
+            }
+        }
+        _ => {}
+    }
+    compute_math_function(value);
+}
+
+// `min()`, `max()` and `clamp()` of lengths that are all in px compute to a length;
+// those with percentages or font-relative lengths wait until what they're of is known
+fn compute_math_function(value: &mut Value) {
+    if let Value::Function(_, arguments) = value {
+        let absolute = arguments.iter().all(|argument| matches!(argument, Value::Length(_, Unit::Px)));
+        if let Some(px) = value.math_function_px(0.0).filter(|_| absolute) {
+            *value = Value::Length(px, Unit::Px);
+        }
+    }
 }
 
 // Enhanced style_tree that supports multiple stylesheets and parent context
 pub fn style_tree<'a>(root: &'a Node, stylesheets: &'a [Stylesheet]) -> StyledNode<'a> {
-    style_tree_with_parent(root, stylesheets, None)
+    style_tree_with_context(root, stylesheets, &StyleContext::default())
+}
+
+// style a document for the environment described by `context`
+pub fn style_tree_with_context<'a>(root: &'a Node, stylesheets: &'a [Stylesheet], context: &StyleContext) -> StyledNode<'a> {
+    style_tree_with_state(root, stylesheets, context, &PseudoClassState::default())
 }
 
-fn style_tree_with_parent<'a>(
-    node: &'a Node, 
-    stylesheets: &'a [Stylesheet], 
-    parent_values: Option<&PropertyMap>
+/// Style a document with some of its elements hovered, focused and so on, so that
+/// rules like `a:hover` apply to them.
+pub fn style_tree_with_state<'a>(
+    root: &'a Node,
+    stylesheets: &'a [Stylesheet],
+    context: &StyleContext,
+    state: &PseudoClassState
 ) -> StyledNode<'a> {
-    let specified_values = match node.node_type {
-        NodeType::Element(ref elem) => {
-            let mut values = specified_values(elem, stylesheets);
-            apply_inheritance(&mut values, parent_values);
-            apply_initial_values(&mut values);
-            values
-        },
-        NodeType::Text(_) => {
-            let mut values = HashMap::new();
-            apply_inheritance(&mut values, parent_values);
-            values
-        },
-        NodeType::Comment(_) => HashMap::new()
-    };
+    StyleWalk::new(stylesheets, context, state).style_node(root, None, false)
+}
 
-    let children = node.children.iter()
-        .map(|child| style_tree_with_parent(child, stylesheets, Some(&specified_values)))
-        .collect();
+/// Style a document as `style_tree_with_context` does, keeping what each element
+/// and pseudo-element cascaded to, for `restyle_tree` to start from.
+pub fn style_tree_keeping_cascade<'a>(root: &'a Node, stylesheets: &'a [Stylesheet], context: &StyleContext) -> (StyledNode<'a>, CascadeCache) {
+    let state = PseudoClassState::default();
+    let mut walk = StyleWalk::new(stylesheets, context, &state);
+    walk.cascades = RefCell::new(Some(CascadeCache::default()));
+    let styled = walk.style_node(root, None, false);
+    (styled, walk.cascades.into_inner().unwrap_or_default())
+}
 
-    StyledNode {
-        node,
-        specified_values,
-        children
+/// Style a document again after one of its stylesheets changed by `changes`,
+/// cascading again only the elements and pseudo-elements a changed rule matches.
+///
+/// `previous` is the cascade of the last styling of the same document, with the
+/// same context and the stylesheets as they were before the change. Everything
+/// after the cascade, like inheritance and computing lengths, is done for every
+/// element as usual. Returns the styled tree, its cascade for the next change, and
+/// how many elements were cascaded again.
+pub fn restyle_tree<'a>(
+    root: &'a Node,
+    stylesheets: &'a [Stylesheet],
+    context: &StyleContext,
+    previous: &CascadeCache,
+    changes: &RuleChanges
+) -> (StyledNode<'a>, CascadeCache, usize) {
+    let state = PseudoClassState::default();
+    let mut walk = StyleWalk::new(stylesheets, context, &state);
+    walk.cascades = RefCell::new(Some(CascadeCache::default()));
+    walk.restyle = Some((previous, changes));
+    let styled = walk.style_node(root, None, false);
+    let restyled = walk.restyled.into_inner().len();
+    (styled, walk.cascades.into_inner().unwrap_or_default(), restyled)
+}
+
+/// What each element and pseudo-element of a styled document cascaded to, before
+/// inheritance and computed values. The elements are kept by address, so the cache
+/// only means anything for the document it was made from.
+#[derive(Default)]
+pub struct CascadeCache {
+    values: HashMap<(*const Node, Option<String>), PropertyMap>,
+}
+
+/// The rules that differ between two versions of a stylesheet: those between the
+/// rules both begin with and the rules both end with, in either version.
+///
+/// The rules outside that run keep their order, so an element none of the changed
+/// rules match cascades to the same values from either version.
+pub struct RuleChanges {
+    rules: Vec<ChangedRule>,
+}
+
+struct ChangedRule {
+    media: Vec<MediaQueryList>,
+    selectors: Vec<CompiledSelector>,
+    // for each selector, what its subject must have, to rule most elements out
+    // without matching them
+    buckets: Vec<RuleBucket>,
+}
+
+// the id, else a class, else the tag name a selector's subject needs
+enum RuleBucket {
+    Id(String),
+    Class(String),
+    TagName(String),
+    Universal,
+}
+
+impl RuleBucket {
+    fn of(selector: &Selector) -> RuleBucket {
+        let subject = selector.subject();
+        if let Some(ref id) = subject.id {
+            RuleBucket::Id(id.clone())
+        } else if let Some(class) = subject.class.first() {
+            RuleBucket::Class(class.clone())
+        } else if let Some(ref tag_name) = subject.tag_name {
+            RuleBucket::TagName(tag_name.clone())
+        } else {
+            RuleBucket::Universal
+        }
+    }
+
+    fn holds(&self, elem: &ElementData) -> bool {
+        match self {
+            RuleBucket::Id(id) => elem.id() == Some(id),
+            RuleBucket::Class(class) => elem.classes().contains(class.as_str()),
+            RuleBucket::TagName(tag_name) => elem.tag_name == *tag_name,
+            RuleBucket::Universal => true,
+        }
     }
 }
 
+impl RuleChanges {
+    /// The rules changed from `old` to `new`. If the two differ in origin or layer,
+    /// every rule of each has changed its place in the cascade.
+    pub fn between(old: &Stylesheet, new: &Stylesheet) -> RuleChanges {
+        let (old_rules, new_rules) = match old.origin == new.origin && old.layer == new.layer {
+            true => {
+                let prefix = old.rules.iter().zip(&new.rules).take_while(|(a, b)| same_rule(a, b)).count();
+                let suffix = old.rules[prefix..].iter().rev().zip(new.rules[prefix..].iter().rev())
+                    .take_while(|(a, b)| same_rule(a, b))
+                    .count();
+                (&old.rules[prefix..old.rules.len() - suffix], &new.rules[prefix..new.rules.len() - suffix])
+            }
+            false => (&old.rules[..], &new.rules[..]),
+        };
+        let rules = old_rules.iter().chain(new_rules)
+            .map(|rule| ChangedRule {
+                media: rule.media.clone(),
+                selectors: rule.selectors.iter().map(CompiledSelector::new).collect(),
+                buckets: rule.selectors.iter().map(RuleBucket::of).collect(),
+            })
+            .collect();
+        RuleChanges { rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    // does a changed rule apply to the element, or its pseudo-element, in `ctx`?
+    fn affect(&self, elem: &ElementData, ctx: &MatchingContext, media: &MediaContext) -> bool {
+        self.rules.iter().any(|rule| {
+            rule.buckets.iter().any(|bucket| bucket.holds(elem))
+                && rule.media.iter().all(|condition| condition.matches(media))
+                && selectors::match_compiled_rule(elem, &rule.selectors, ctx).is_some()
+        })
+    }
+}
+
+// do the rules select the same elements with the same declarations? Where they
+// were written doesn't matter.
+fn same_rule(a: &Rule, b: &Rule) -> bool {
+    a.selectors == b.selectors
+        && a.media == b.media
+        && a.declarations.len() == b.declarations.len()
+        && a.declarations.iter().zip(&b.declarations).all(|(a, b)| {
+            a.name == b.name && a.value == b.value && a.important == b.important
+        })
+}
+
+// state carried through the document while styling it
+struct StyleWalk<'a, 's> {
+    stylesheets: &'a [Stylesheet],
+    context: StyleContext,
+    state: &'s PseudoClassState,
+    ancestors: Vec<&'a ElementData>,
+    ancestor_states: Vec<ElementState>, // of each of the ancestors
+    ancestor_filter: Option<AncestorFilter>, // of the ancestors, unless the context turns it off
+    compiled_rules: CompiledRules,
+    counters: Counters,
+    language: Option<String>, // the language inherited by the node being styled
+    has_first_line_rules: bool, // whether any rule is for `::first-line` or `::first-letter`
+    root_font_size: Option<f32>, // once the root element is styled, what a rem is
+    // the last styling's cascade and what changed since, to reuse the cascade of
+    // elements the change doesn't touch
+    restyle: Option<(&'s CascadeCache, &'s RuleChanges)>,
+    cascades: RefCell<Option<CascadeCache>>, // what each cascade came to, when kept
+    restyled: RefCell<HashSet<*const Node>>, // the elements cascaded again
+}
+
+impl<'a, 's> StyleWalk<'a, 's> {
+    fn new(stylesheets: &'a [Stylesheet], context: &StyleContext, state: &'s PseudoClassState) -> StyleWalk<'a, 's> {
+        StyleWalk {
+            stylesheets,
+            context: *context,
+            state,
+            ancestors: Vec::new(),
+            ancestor_states: Vec::new(),
+            ancestor_filter: context.ancestor_filter.then(AncestorFilter::default),
+            compiled_rules: compile_rules(stylesheets),
+            counters: Counters::default(),
+            language: None,
+            has_first_line_rules: stylesheets.iter()
+                .flat_map(|stylesheet| &stylesheet.rules)
+                .flat_map(|rule| &rule.selectors)
+                .any(|selector| {
+                    matches!(selector.subject().pseudo_element.as_deref(), Some("first-line" | "first-letter"))
+                }),
+            root_font_size: None,
+            restyle: None,
+            cascades: RefCell::new(None),
+            restyled: RefCell::new(HashSet::new()),
+        }
+    }
+
+    // the cascaded values of an element, or of its pseudo-element in `ctx`, reused
+    // from the last styling when restyling and no changed rule applies to it
+    fn cascade(&self, node: &'a Node, elem: &'a ElementData, ctx: &MatchingContext) -> PropertyMap {
+        let key = (node as *const Node, ctx.pseudo_element.map(str::to_string));
+        let media = self.context.media();
+        let reused = self.restyle.and_then(|(previous, changes)| {
+            previous.values.get(&key).filter(|_| !changes.affect(elem, ctx, &media)).cloned()
+        });
+        let values = reused.unwrap_or_else(|| {
+            if self.restyle.is_some() {
+                self.restyled.borrow_mut().insert(key.0);
+            }
+            cascaded_values(elem, self.stylesheets, &self.compiled_rules, ctx, &media, self.context.quirks_mode)
+        });
+        if let Some(ref mut cascades) = *self.cascades.borrow_mut() {
+            cascades.values.insert(key, values.clone());
+        }
+        values
+    }
+
+    // `hidden` is set inside display: none subtrees, where counters don't change
+    fn style_node(
+        &mut self,
+        node: &'a Node,
+        parent_values: Option<&PropertyMap>,
+        mut hidden: bool
+    ) -> StyledNode<'a> {
+        let elem = match node.node_type {
+            NodeType::Element(ref elem) => elem,
+            NodeType::Text(_) => {
+                let mut values = HashMap::new();
+                apply_inheritance(&mut values, parent_values);
+                return StyledNode {
+                    node: NodeRef::Dom(node),
+                    specified_values: values,
+                    language: self.language.clone(),
+                    children: Vec::new(),
+                    first_line: None,
+                    first_letter: None
+                };
+            }
+            NodeType::Comment(_) => return StyledNode {
+                node: NodeRef::Dom(node),
+                specified_values: HashMap::new(),
+                language: self.language.clone(),
+                children: Vec::new(),
+                first_line: None,
+                first_letter: None
+            }
+        };
+
+        // an element's own lang attribute overrides the one it inherits
+        let language = elem.attrs.get("lang").cloned().or_else(|| self.language.clone());
+        let ctx = MatchingContext {
+            ancestors: &self.ancestors,
+            ancestor_states: &self.ancestor_states,
+            state: self.state.state_of(node),
+            language: language.as_deref(),
+            ancestor_filter: self.ancestor_filter.as_ref(),
+            ..Default::default()
+        };
+        let mut values = self.cascade(node, elem, &ctx);
+        values.values_mut().for_each(|value| self.context.resolve_lengths(value));
+        apply_inheritance(&mut values, parent_values);
+        apply_initial_values(&mut values);
+        resolve_current_color(&mut values, parent_values);
+        self.context.compute_font_size(&mut values, parent_values, self.root_font_size);
+        if self.root_font_size.is_none() {
+            self.root_font_size = match values.get("font-size") {
+                Some(&Value::Length(size, Unit::Px)) => Some(size),
+                _ => None
+            };
+        }
+
+        hidden = hidden || values.get("display") == Some(&Value::Keyword("none".to_string()));
+        if !hidden {
+            self.counters.update(&values, self.ancestors.len());
+        }
+
+        let inherited_language = std::mem::replace(&mut self.language, language.clone());
+        let mut children = Vec::new();
+        children.extend(self.style_pseudo_element(node, elem, "before", &values));
+
+        // counters created by the children are in scope until the end of this element
+        let counters_in_scope = self.counters.instances.len();
+        self.ancestors.push(elem);
+        self.ancestor_states.push(self.state.state_of(node));
+        if let Some(ref mut filter) = self.ancestor_filter {
+            filter.push(elem);
+        }
+        for child in &node.children {
+            children.push(self.style_node(child, Some(&values), hidden));
+        }
+        self.ancestors.pop();
+        self.ancestor_states.pop();
+        if let Some(ref mut filter) = self.ancestor_filter {
+            filter.pop(elem);
+        }
+        self.counters.instances.truncate(counters_in_scope);
+
+        children.extend(self.style_pseudo_element(node, elem, "after", &values));
+        // a form control shows its value, label or placeholder in place of its children
+        match forms::shown_text(node) {
+            Some(ShownText::Value(text)) => children = vec![self.generated_text(text, &values)],
+            Some(ShownText::Placeholder(text)) => {
+                let placeholder_values = self.pseudo_element_values(node, elem, "placeholder");
+                children = vec![self.generated_element("placeholder", placeholder_values, text, &values)];
+            }
+            None => {}
+        }
+        if self.has_first_line_rules && !hidden {
+            self.style_first_line(node, elem, &values, &mut children);
+        }
+        self.language = inherited_language;
+        StyledNode { node: NodeRef::Dom(node), specified_values: values, language, children, first_line: None, first_letter: None }
+    }
+
+    /// Style the `::before` or `::after` pseudo-element of an element, if it has content.
+    ///
+    /// https://www.w3.org/TR/css-pseudo-4/#generated-content
+    ///
+    /// The pseudo-element becomes a child of the element holding a single text node.
+    fn style_pseudo_element(
+        &self,
+        node: &'a Node,
+        elem: &'a ElementData,
+        pseudo_element: &str,
+        elem_values: &PropertyMap
+    ) -> Option<StyledNode<'a>> {
+        let values = self.pseudo_element_values(node, elem, pseudo_element);
+        let content = self.counters.generated_content(values.get("content"))?;
+        Some(self.generated_element(pseudo_element, values, content, elem_values))
+    }
+
+    // the specified values of a pseudo-element of an element
+    fn pseudo_element_values(
+        &self,
+        node: &'a Node,
+        elem: &'a ElementData,
+        pseudo_element: &str
+    ) -> PropertyMap {
+        let ctx = MatchingContext {
+            ancestors: &self.ancestors,
+            ancestor_states: &self.ancestor_states,
+            state: self.state.state_of(node),
+            pseudo_element: Some(pseudo_element),
+            language: self.language.as_deref(),
+            ancestor_filter: self.ancestor_filter.as_ref()
+        };
+        self.cascade(node, elem, &ctx)
+    }
+
+    // a pseudo-element with specified `values`, a child of an element with
+    // `elem_values`, holding the text `content`
+    fn generated_element(&self, pseudo_element: &str, mut values: PropertyMap, content: String, elem_values: &PropertyMap) -> StyledNode<'a> {
+        values.values_mut().for_each(|value| self.context.resolve_lengths(value));
+        apply_inheritance(&mut values, Some(elem_values));
+        apply_initial_values(&mut values);
+        resolve_current_color(&mut values, Some(elem_values));
+        self.context.compute_font_size(&mut values, Some(elem_values), self.root_font_size);
+        let text = self.generated_text(content, &values);
+        StyledNode {
+            node: NodeRef::Generated(Box::new(dom::elem(format!("::{}", pseudo_element), HashMap::new(), Vec::new()))),
+            specified_values: values,
+            language: self.language.clone(),
+            children: vec![text],
+            first_line: None,
+            first_letter: None
+        }
+    }
+
+    // a text node that isn't in the document, in an element with `parent_values`
+    fn generated_text(&self, content: String, parent_values: &PropertyMap) -> StyledNode<'a> {
+        let mut text_values = HashMap::new();
+        apply_inheritance(&mut text_values, Some(parent_values));
+        StyledNode {
+            node: NodeRef::Generated(Box::new(dom::text(content))),
+            specified_values: text_values,
+            language: self.language.clone(),
+            children: Vec::new(),
+            first_line: None,
+            first_letter: None
+        }
+    }
+
+    /// Style the text a block's `::first-line` and `::first-letter` apply to.
+    ///
+    /// https://www.w3.org/TR/css-pseudo-4/#first-text-line
+    ///
+    /// Only the block's own first line counts, not the first line of a block nested
+    /// at its start. The pseudo-elements set only font, color and text properties,
+    /// and of those only what the text inherits from the block unchanged, so an
+    /// inline element with its own color keeps it.
+    fn style_first_line(
+        &self,
+        node: &'a Node,
+        elem: &'a ElementData,
+        elem_values: &PropertyMap,
+        children: &mut [StyledNode<'a>]
+    ) {
+        if matches!(elem_values.get("display"), Some(Value::Keyword(k)) if k == "inline" || k == "none") {
+            return;
+        }
+        let pseudo_values = |pseudo_element| {
+            let ctx = MatchingContext {
+                ancestors: &self.ancestors,
+                ancestor_states: &self.ancestor_states,
+                state: self.state.state_of(node),
+                pseudo_element: Some(pseudo_element),
+                language: self.language.as_deref(),
+                ancestor_filter: self.ancestor_filter.as_ref()
+            };
+            let values = self.cascade(node, elem, &ctx);
+            // these properties all inherit, so `unset` means `inherit`
+            let mut values: PropertyMap = values.into_iter()
+                .filter(|(property, _)| FIRST_LINE_PROPERTIES.contains(&property.as_str()))
+                .filter_map(|(property, value)| {
+                    let value = match value {
+                        Value::Inherit => elem_values.get(&property)?.clone(),
+                        Value::Keyword(k) if k == "unset" => elem_values.get(&property)?.clone(),
+                        Value::Keyword(k) if k == "initial" => get_initial_value(&property)?,
+                        value => value
+                    };
+                    Some((property, value))
+                })
+                .collect();
+            values.values_mut().for_each(|value| self.context.resolve_lengths(value));
+            self.context.compute_font_size(&mut values, Some(elem_values), self.root_font_size);
+            Some(values).filter(|values| !values.is_empty())
+        };
+        let first_line = pseudo_values("first-line");
+        let mut first_letter = pseudo_values("first-letter");
+        if first_line.is_some() || first_letter.is_some() {
+            style_first_line_text(children, elem_values, first_line.as_ref(), &mut first_letter);
+        }
+    }
+}
+
+// Give the text among `children`, the content of a block, its first-line and
+// first-letter versions, up to the first block-level child, where the block's
+// first line ends. Returns false once that child is found.
+fn style_first_line_text<'a>(
+    children: &mut [StyledNode<'a>],
+    block_values: &PropertyMap,
+    first_line: Option<&PropertyMap>,
+    first_letter: &mut Option<PropertyMap>
+) -> bool {
+    for child in children {
+        match child.node.node_type {
+            NodeType::Text(ref text) => {
+                // what the pseudo-element sets, where the text has the block's own value
+                let styled = |values: &PropertyMap, overrides: &PropertyMap| {
+                    let mut values = values.clone();
+                    for (property, value) in overrides {
+                        if child.specified_values.get(property) == block_values.get(property) {
+                            values.insert(property.clone(), value.clone());
+                        }
+                    }
+                    values
+                };
+                let line_values = first_line.map(|overrides| styled(&child.specified_values, overrides));
+                // the first letter is in the first text that isn't all whitespace
+                let letter_values = match text.trim().is_empty() {
+                    true => None,
+                    false => first_letter.take().map(|overrides| {
+                        styled(line_values.as_ref().unwrap_or(&child.specified_values), &overrides)
+                    })
+                };
+                let version = |values: PropertyMap| Box::new(StyledNode {
+                    node: match child.node {
+                        NodeRef::Dom(node) => NodeRef::Dom(node),
+                        NodeRef::Generated(_) => NodeRef::Generated(Box::new(dom::text(text.clone()))),
+                    },
+                    specified_values: values,
+                    language: child.language.clone(),
+                    children: Vec::new(),
+                    first_line: None,
+                    first_letter: None
+                });
+                child.first_line = line_values.map(version);
+                child.first_letter = letter_values.map(version);
+            }
+            NodeType::Element(_) => match child.display() {
+                Display::Inline => {
+                    if !style_first_line_text(&mut child.children, block_values, first_line, first_letter) {
+                        return false;
+                    }
+                }
+                // an inline-block is a single unit on the line, whose text is its own
+                Display::InlineBlock | Display::None => {}
+                _ => return false
+            },
+            NodeType::Comment(_) => {}
+        }
+    }
+    true
+}
+
+/// CSS counters in scope at the current point of the styled-tree walk.
+///
+/// https://www.w3.org/TR/css-lists-3/#auto-numbering
+///
+/// An element's `counter-reset` creates a new instance of a counter, which is
+/// visible to the element, its following siblings and all their descendants. It
+/// nests inside any instance of the same name already in scope, except one created
+/// by a preceding sibling, which it replaces.
+#[derive(Default)]
+struct Counters {
+    instances: Vec<CounterInstance>, // outermost first
+}
+
+struct CounterInstance {
+    name: String,
+    value: i32,
+    depth: usize, // tree depth of the element that created it
+}
+
+impl Counters {
+    // apply the `counter-reset`, then the `counter-increment` of an element at `depth`
+    fn update(&mut self, values: &PropertyMap, depth: usize) {
+        for (name, value) in counter_changes(values.get("counter-reset"), 0) {
+            self.reset(name, value, depth);
+        }
+        for (name, by) in counter_changes(values.get("counter-increment"), 1) {
+            match self.instances.iter_mut().rev().find(|c| c.name == name) {
+                Some(counter) => counter.value += by,
+                // incrementing a counter that isn't in scope creates it on the element
+                None => self.reset(name, by, depth)
+            }
+        }
+    }
+
+    fn reset(&mut self, name: String, value: i32, depth: usize) {
+        // instances as deep as the element that are still in scope come from its siblings
+        if let Some(i) = self.instances.iter().rposition(|c| c.name == name && c.depth == depth) {
+            self.instances.remove(i);
+        }
+        self.instances.push(CounterInstance { name, value, depth });
+    }
+
+    // the values of every instance of the counter, outermost first
+    fn values(&self, name: &str) -> Vec<i32> {
+        let values: Vec<i32> = self.instances.iter().filter(|c| c.name == name).map(|c| c.value).collect();
+        if values.is_empty() { vec![0] } else { values }
+    }
+
+    // evaluate a `content` value, or `None` if it generates no pseudo-element
+    fn generated_content(&self, content: Option<&Value>) -> Option<String> {
+        let items = match content {
+            None | Some(Value::Inherit) => return None,
+            Some(Value::Keyword(k)) if k == "none" || k == "normal" => return None,
+            Some(Value::List(items)) => items.as_slice(),
+            Some(item) => std::slice::from_ref(item)
+        };
+        let mut text = String::new();
+        for item in items {
+            match item {
+                Value::Str(s) => text.push_str(s),
+                Value::Function(f, args) => match (f.as_str(), args.as_slice()) {
+                    ("counter", [Value::Keyword(name), ..]) => {
+                        text.push_str(&self.values(name).last().unwrap().to_string());
+                    }
+                    ("counters", [Value::Keyword(name), Value::Str(separator), ..]) => {
+                        let values: Vec<String> = self.values(name).iter().map(i32::to_string).collect();
+                        text.push_str(&values.join(separator));
+                    }
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+        Some(text)
+    }
+}
+
+// the counters named by `counter-reset` or `counter-increment`, each with the
+// integer following it or `default`
+fn counter_changes(value: Option<&Value>, default: i32) -> Vec<(String, i32)> {
+    let items = match value {
+        Some(Value::List(items)) => items.as_slice(),
+        Some(item) => std::slice::from_ref(item),
+        None => return Vec::new()
+    };
+    let mut changes: Vec<(String, i32)> = Vec::new();
+    for item in items {
+        match item {
+            Value::Keyword(name) if name != "none" => changes.push((name.clone(), default)),
+            Value::Number(n) => if let Some(last) = changes.last_mut() {
+                last.1 = *n as i32;
+            },
+            _ => {}
+        }
+    }
+    changes
+}
+
 // Apply inheritance rules
 fn apply_inheritance(values: &mut PropertyMap, parent_values: Option<&PropertyMap>) {
+    // `unset` means `inherit` for inherited properties and `initial` for the rest
+    for (property, value) in values.iter_mut() {
+        if *value == Value::Keyword("unset".to_string()) {
+            *value = match is_inherited_property(property) {
+                true => Value::Inherit,
+                false => Value::Keyword("initial".to_string())
+            };
+        }
+    }
     if let Some(parent) = parent_values {
         for (property, value) in parent {
             // Inherit if property is explicitly set to inherit OR if it's an inherited property and not set
@@ -128,130 +975,494 @@ fn apply_inheritance(values: &mut PropertyMap, parent_values: Option<&PropertyMa
 
 // Apply initial values for unset properties
 fn apply_initial_values(values: &mut PropertyMap) {
+    // `initial`, and `inherit` with nothing to inherit from, give the initial value
+    let initial = Value::Keyword("initial".to_string());
+    let explicitly_initial: Vec<String> = values.iter()
+        .filter(|(_, value)| **value == initial || **value == Value::Inherit)
+        .map(|(property, _)| property.clone())
+        .collect();
+    for property in explicitly_initial {
+        match get_initial_value(&property) {
+            Some(value) => values.insert(property, value),
+            None => values.remove(&property)
+        };
+    }
+
     // For commonly used properties, set initial values if not specified
     let important_properties = ["display", "color", "font-size"];
     for &property in &important_properties {
         if !values.contains_key(property) {
-            values.insert(property.to_string(), get_initial_value(property));
+            values.insert(property.to_string(), get_initial_value(property).unwrap());
+        }
+    }
+}
+
+// Replace `currentcolor` with the element's color, which for `color` itself is the
+// parent's color
+//
+// https://www.w3.org/TR/css-color-4/#currentcolor-color
+fn resolve_current_color(values: &mut PropertyMap, parent_values: Option<&PropertyMap>) {
+    fn replace(value: &mut Value, color: &Value) {
+        match value {
+            Value::Keyword(keyword) if keyword.eq_ignore_ascii_case("currentcolor") => *value = color.clone(),
+            Value::Function(_, values) | Value::List(values) | Value::CommaList(values) => {
+                values.iter_mut().for_each(|value| replace(value, color));
+            }
+            _ => {}
         }
     }
+    if let Some(color) = values.get_mut("color") {
+        let inherited = parent_values.and_then(|parent| parent.get("color")).cloned();
+        replace(color, &inherited.unwrap_or_else(|| get_initial_value("color").unwrap()));
+    }
+    let color = values["color"].clone();
+    values.values_mut().for_each(|value| replace(value, &color));
 }
 
 // Enhanced specified_values function with cascading support
 pub fn specified_values(elem: &ElementData, stylesheets: &[Stylesheet]) -> PropertyMap {
+    specified_values_in_context(elem, stylesheets, &MatchingContext::default(), &MediaContext::default())
+}
+
+// specified values of an element at the position in the tree described by `ctx`,
+// from the rules whose `@media` conditions `media` matches, in a document in no
+// quirks mode
+pub fn specified_values_in_context(elem: &ElementData, stylesheets: &[Stylesheet], ctx: &MatchingContext, media: &MediaContext) -> PropertyMap {
+    cascaded_values(elem, stylesheets, &compile_rules(stylesheets), ctx, media, QuirksMode::NoQuirks)
+}
+
+// the selectors of each rule of each stylesheet, compiled once for styling many elements
+type CompiledRules = Vec<Vec<Vec<CompiledSelector>>>;
+
+fn compile_rules(stylesheets: &[Stylesheet]) -> CompiledRules {
+    stylesheets.iter()
+        .map(|stylesheet| stylesheet.rules.iter().map(|rule| rule.selectors.iter().map(CompiledSelector::new).collect()).collect())
+        .collect()
+}
+
+// `specified_values_in_context`, with `compiled` the compiled selectors of `stylesheets`,
+// in a document in `quirks_mode`
+fn cascaded_values(
+    elem: &ElementData,
+    stylesheets: &[Stylesheet],
+    compiled: &CompiledRules,
+    ctx: &MatchingContext,
+    media: &MediaContext,
+    quirks_mode: QuirksMode
+) -> PropertyMap {
     let mut cascaded_declarations: Vec<CascadedDeclaration> = Vec::new();
     
     // Collect declarations from all stylesheets
-    for stylesheet in stylesheets {
+    for (stylesheet_index, stylesheet) in stylesheets.iter().enumerate() {
         for (rule_index, rule) in stylesheet.rules.iter().enumerate() {
-            if let Some((specificity, _)) = match_rule(elem, rule) {
+            if !rule.media.iter().all(|condition| condition.matches(media)) {
+                continue;
+            }
+            if let Some(specificity) = selectors::match_compiled_rule(elem, &compiled[stylesheet_index][rule_index], ctx) {
                 for declaration in &rule.declarations {
+                    // presentational hints from attributes slot in at 2, below author rules
                     let origin_importance = match (&stylesheet.origin, declaration.important) {
                         (Origin::UserAgent, false) => 0,
                         (Origin::User, false) => 1,
-                        (Origin::Author, false) => 2,
-                        (Origin::UserAgent, true) => 3,
-                        (Origin::User, true) => 4,
-                        (Origin::Author, true) => 5,
+                        (Origin::Author, false) => 3,
+                        (Origin::UserAgent, true) => 4,
+                        (Origin::User, true) => 5,
+                        (Origin::Author, true) => 6,
                     };
                     
+                    // important declarations reverse the order of layers
+                    let layer = if declaration.important { -stylesheet.layer } else { stylesheet.layer };
                     cascaded_declarations.push(CascadedDeclaration {
                         declaration,
-                        cascade_key: (origin_importance, specificity, rule_index),
+                        cascade_key: (origin_importance, layer, specificity, (stylesheet_index, rule_index)),
                     });
                 }
             }
         }
     }
     
-    // Check for style attribute
-    let mut style_declarations = Vec::new();
-    if let Some(style_attr) = elem.attrs.get("style") {
-        // Parse style attribute as CSS declarations
-        if let Some(parsed_declarations) = parse_style_attribute(style_attr) {
-            style_declarations = parsed_declarations;
-        }
+    // Attributes that map to CSS properties, which only style the element itself
+    let hints = match ctx.pseudo_element {
+        None => presentational_hints(elem, ctx.ancestors),
+        Some(_) => Vec::new()
+    };
+    for declaration in &hints {
+        cascaded_declarations.push(CascadedDeclaration {
+            declaration,
+            cascade_key: (2, 0, (0, 0, 0), (0, 0)),
+        });
+    }
+
+    // user agent rules for type selectors, after the user agent stylesheet's own
+    let quirks = match (quirks_mode, ctx.pseudo_element) {
+        (QuirksMode::Quirks, None) => quirks_mode_declarations(elem),
+        _ => Vec::new()
+    };
+    for declaration in &quirks {
+        cascaded_declarations.push(CascadedDeclaration {
+            declaration,
+            cascade_key: (0, 0, (0, 0, 1), (usize::MAX, 0)),
+        });
     }
+
+    // Check for style attribute, which only styles the element itself
+    let style_declarations = match elem.attrs.get("style").filter(|_| ctx.pseudo_element.is_none()) {
+        Some(style_attr) => crate::css::parse_declaration_list(style_attr),
+        None => Vec::new(),
+    };
     
     // Add style declarations to cascaded declarations
     for declaration in &style_declarations {
-        let origin_importance = if declaration.important { 5 } else { 4 };
+        let origin_importance = if declaration.important { 6 } else { 5 };
         cascaded_declarations.push(CascadedDeclaration {
             declaration,
-            cascade_key: (origin_importance, (1, 0, 0), 999999), // High specificity
+            cascade_key: (origin_importance, 0, (1, 0, 0), (usize::MAX, 0)), // High specificity
         });
     }
     
     // Sort by cascade order
-    cascaded_declarations.sort_by(|a, b| a.cascade_key.cmp(&b.cascade_key));
+    cascaded_declarations.sort_by_key(|a| a.cascade_key);
     
     // Apply declarations in order, later ones override earlier ones
     let mut values = HashMap::new();
     for cascaded in cascaded_declarations {
-        values.insert(
-            cascaded.declaration.name.clone(), 
-            cascaded.declaration.value.clone()
-        );
+        let declaration = cascaded.declaration;
+        if declaration.name == "all" {
+            // `all` sets every other property, at its own place in the cascade, except
+            // the ones that belong to the document's markup rather than its style
+            let excluded = ["all", "direction", "unicode-bidi"];
+            for property in css::known_properties().filter(|p| !excluded.contains(p)) {
+                values.insert(property.to_string(), declaration.value.clone());
+            }
+        } else {
+            // a shorthand sets its longhands too, in its own place in the cascade
+            for (longhand, value) in css::expand_shorthand(&declaration.name, &declaration.value).into_iter().flatten() {
+                values.insert(longhand.to_string(), value);
+            }
+            values.insert(declaration.name.clone(), declaration.value.clone());
+        }
+    }
+    for (property, value) in values.iter_mut() {
+        if css::takes_colors(property) {
+            css::resolve_named_colors(value);
+        }
     }
     
     values
 }
 
-// Parse style attribute (simplified - reuses CSS parser)
-fn parse_style_attribute(style: &str) -> Option<Vec<crate::css::Declaration>> {
-    // Wrap in braces to make it a valid CSS rule body
-    let wrapped = format!("dummy {{ {} }}", style);
-    
-    // Try to parse it - if it fails, return None
-    if let Ok(stylesheet) = std::panic::catch_unwind(|| {
-        crate::css::parse(wrapped, Origin::Author)
-    }) {
-        if let Some(rule) = stylesheet.rules.first() {
-            return Some(rule.declarations.clone());
+// standing in for the rules the user agent stylesheet has for documents in quirks
+// mode only: a table doesn't inherit the font and the text alignment around it, and
+// a form has a margin below it
+//
+// https://html.spec.whatwg.org/multipage/rendering.html#tables-2
+fn quirks_mode_declarations(elem: &ElementData) -> Vec<crate::css::Declaration> {
+    let declarations = match &*elem.tag_name.to_ascii_lowercase() {
+        "table" => "font-weight: initial; font-style: initial; font-variant: initial; font-size: initial; \
+            line-height: initial; white-space: initial; text-align: initial",
+        "form" => "margin-bottom: 1em",
+        _ => return Vec::new()
+    };
+    crate::css::parse_declaration_list(declarations)
+}
+
+// The declarations an element's presentational attributes stand for.
+//
+// https://html.spec.whatwg.org/multipage/rendering.html#presentational-hints
+fn presentational_hints(elem: &ElementData, ancestors: &[&ElementData]) -> Vec<crate::css::Declaration> {
+    let mut hints = Vec::new();
+    let mut hint = |name: &str, value: Value| hints.push(crate::css::Declaration {
+        name: name.to_string(),
+        value,
+        important: false,
+        span: Default::default() // from attributes, not any stylesheet
+    });
+
+    // standing in for the UA rule `[hidden] { display: none }`
+    if elem.attrs.contains_key("hidden") {
+        hint("display", Value::Keyword("none".to_string()));
+    }
+
+    // dir="auto" needs the element's text to decide, which waits on bidi support
+    let dir = elem.attrs.get("dir").map(|dir| dir.to_ascii_lowercase());
+    if let Some(dir @ ("ltr" | "rtl")) = dir.as_deref() {
+        hint("direction", Value::Keyword(dir.to_string()));
+    }
+
+    let tag = elem.tag_name.to_ascii_lowercase();
+    let attr = |name: &str| elem.attrs.get(name).map(|value| value.trim());
+    let sized = ["img", "table", "td", "th", "col", "hr", "iframe", "video", "canvas", "object", "embed"];
+    if sized.contains(&tag.as_str()) {
+        if let Some(width) = attr("width").and_then(parse_dimension) {
+            hint("width", width);
         }
     }
-    None
+    if sized.contains(&tag.as_str()) || tag == "tr" {
+        if let Some(height) = attr("height").and_then(parse_dimension) {
+            hint("height", height);
+        }
+    }
+    if matches!(&*tag, "body" | "table" | "tr" | "td" | "th") {
+        if let Some(color) = attr("bgcolor").and_then(parse_legacy_color) {
+            hint("background-color", color);
+        }
+    }
+
+    // a table's border attribute borders the table, and its cells with 1px; its
+    // cellspacing and cellpadding space the cells out and pad them
+    let gray = || Value::ColorValue(crate::css::Color { r: 128, g: 128, b: 128, a: 255 });
+    if tag == "table" {
+        if let Some(width) = attr("border").map(parse_border_attribute).filter(|&width| width > 0.0) {
+            hint("border-width", Value::Length(width, Unit::Px));
+            hint("border-color", gray());
+        }
+        if let Some(Value::Length(spacing, Unit::Px)) = attr("cellspacing").and_then(parse_dimension) {
+            hint("border-spacing", Value::Length(spacing, Unit::Px));
+        }
+    }
+    if matches!(&*tag, "td" | "th") {
+        let table = ancestors.iter().rev().find(|ancestor| ancestor.tag_name.eq_ignore_ascii_case("table"));
+        let table_attr = |name: &str| table.and_then(|table| table.attrs.get(name)).map(|value| value.trim());
+        if table_attr("border").map(parse_border_attribute).is_some_and(|width| width > 0.0) {
+            hint("border-width", Value::Length(1.0, Unit::Px));
+            hint("border-color", gray());
+        }
+        if let Some(Value::Length(padding, Unit::Px)) = table_attr("cellpadding").and_then(parse_dimension) {
+            hint("padding", Value::Length(padding, Unit::Px));
+        }
+    }
+
+    if tag == "font" {
+        if let Some(color) = attr("color").and_then(parse_legacy_color) {
+            hint("color", color);
+        }
+        if let Some(size) = attr("size").and_then(parse_legacy_font_size) {
+            hint("font-size", Value::Keyword(size.to_string()));
+        }
+        if let Some(face) = attr("face").filter(|face| !face.is_empty()) {
+            let mut families: Vec<Value> = face.split(',').map(|family| Value::Keyword(family.trim().to_string())).collect();
+            hint("font-family", if families.len() == 1 { families.remove(0) } else { Value::CommaList(families) });
+        }
+    }
+
+    // form controls sized by their type and attributes: a text field `size`
+    // characters wide and a line tall, and a text area `cols` characters wide and
+    // `rows` lines tall, at an average advance of half an em; checkboxes and radio
+    // buttons are small boxes, and a radio button's circle is painted in place of
+    // its borders and background
+    let count = |name: &str, default: f32| attr(name).and_then(|n| n.parse::<u32>().ok()).filter(|&n| n > 0).map_or(default, |n| n as f32);
+    let px = |n: f32| Value::Length(n, Unit::Px);
+    match forms::control(elem) {
+        Some(Control::Hidden) => hint("display", Value::Keyword("none".to_string())),
+        Some(Control::TextField | Control::Password) => {
+            hint("width", Value::Length(count("size", 20.0) * 0.5, Unit::Em));
+            hint("height", Value::Length(1.2, Unit::Em));
+        }
+        Some(Control::TextArea) => {
+            hint("width", Value::Length(count("cols", 20.0) * 0.5, Unit::Em));
+            hint("height", Value::Length(count("rows", 2.0) * 1.2, Unit::Em));
+        }
+        Some(Control::Button) if tag == "input" => {
+            hint("padding", Value::List(vec![px(1.0), px(6.0)]));
+            hint("background-color", Value::ColorValue(crate::css::Color { r: 239, g: 239, b: 239, a: 255 }));
+        }
+        Some(control @ (Control::Checkbox | Control::Radio)) => {
+            hint("width", px(13.0));
+            hint("height", px(13.0));
+            hint("padding", px(0.0));
+            hint("margin", Value::List(vec![px(3.0), px(3.0), px(3.0), px(4.0)]));
+            if control == Control::Radio {
+                hint("border-width", px(0.0));
+                hint("background-color", Value::ColorValue(crate::css::Color { r: 0, g: 0, b: 0, a: 0 }));
+            }
+        }
+        _ => {}
+    }
+
+    // tables and rules are aligned as boxes, everything else aligns its content
+    let align = attr("align").map(|align| align.to_ascii_lowercase());
+    let auto = || Value::Keyword("auto".to_string());
+    match (&*tag, align.as_deref()) {
+        ("table" | "hr", Some("center")) => {
+            hint("margin-left", auto());
+            hint("margin-right", auto());
+        }
+        ("table" | "hr", Some("left")) => hint("margin-right", auto()),
+        ("table" | "hr", Some("right")) => hint("margin-left", auto()),
+        ("div" | "p" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "caption" | "thead" | "tbody" | "tfoot" | "tr" | "td" | "th",
+         Some(align @ ("left" | "right" | "center" | "justify"))) => {
+            hint("text-align", Value::Keyword(align.to_string()));
+        }
+        _ => {}
+    }
+    hints
 }
 
-type MatchedNode<'a> = (Specificity, &'a Rule);
+// A width or height attribute: a number of px, or a percentage. Trailing junk
+// after the number is ignored as browsers do, and zero means no hint.
+fn parse_dimension(attr: &str) -> Option<Value> {
+    let number_len = attr.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(attr.len());
+    let number: f32 = attr[..number_len].parse().ok().filter(|&n: &f32| n > 0.0)?;
+    let unit = if attr[number_len..].starts_with('%') { Unit::Percent } else { Unit::Px };
+    Some(Value::Length(number, unit))
+}
 
-// go through rules in stylesheet and filter which rules match the element
-fn matching_rules<'a>(elem: &ElementData, stylesheet: &'a Stylesheet) -> Vec<MatchedNode<'a>> {
-    // linear scan of rules for now; for larger DOM trees, store rules in Hashmap based on tag_name, id, class
-    stylesheet.rules.iter().filter_map(|rule| match_rule(elem, rule)).collect()
+// The border attribute of <table>, in px: a number, with a value that isn't one
+// (like a bare `border`) meaning 1.
+fn parse_border_attribute(attr: &str) -> f32 {
+    let digits = &attr[..attr.find(|c: char| !c.is_ascii_digit()).unwrap_or(attr.len())];
+    digits.parse().unwrap_or(1.0)
 }
 
-// if the element matches the rule, return a MatchedNode (specificity of selector, rule)
-fn match_rule<'a>(elem: &ElementData, rule: &'a Rule) -> Option<MatchedNode<'a>> {
-    rule.selectors.iter()
-        .find(|selector| matches(elem, selector))
-        .map(|selector| (selector.specificity(), rule))
+// The size attribute of <font>: 1 to 7, or relative to the default of 3 as +n or
+// -n, clamped to that range, and given as the matching font-size keyword.
+//
+// https://html.spec.whatwg.org/multipage/rendering.html#phrasing-content-3
+fn parse_legacy_font_size(attr: &str) -> Option<&'static str> {
+    let (base, digits): (i64, &str) = match attr.as_bytes().first()? {
+        b'+' | b'-' => (3, &attr[1..]),
+        _ => (0, attr)
+    };
+    let digits = &digits[..digits.find(|c: char| !c.is_ascii_digit()).unwrap_or(digits.len())];
+    if digits.is_empty() {
+        return None;
+    }
+    // any number too long to parse is past the largest size anyway
+    let n: i64 = digits.parse().unwrap_or(i64::MAX);
+    let size = if attr.starts_with('-') { base.saturating_sub(n) } else { base.saturating_add(n) };
+    let keywords = ["x-small", "small", "medium", "large", "x-large", "xx-large", "xxx-large"];
+    Some(keywords[size.clamp(1, 7) as usize - 1])
 }
 
-// if the element matches the selector, return true
-fn matches(elem: &ElementData, selector: &Selector) -> bool {
-    match selector {
-        Selector::Simple(s) => matches_simple_selector(elem, s)
+// A color attribute such as bgcolor or <font color>: one of the named colors CSS
+// knows, or #rgb or #rrggbb with the # optional.
+fn parse_legacy_color(attr: &str) -> Option<Value> {
+    // `transparent` is a named color in CSS, but not in these attributes
+    if let Some(color) = crate::css::named_color(attr).filter(|_| !attr.eq_ignore_ascii_case("transparent")) {
+        return Some(Value::ColorValue(color));
+    }
+    let hex = attr.strip_prefix('#').unwrap_or(attr);
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
     }
+    let channel = |digits: &str| u8::from_str_radix(digits, 16).ok();
+    let (r, g, b) = match hex.len() {
+        3 => (channel(&hex[0..1])? * 17, channel(&hex[1..2])? * 17, channel(&hex[2..3])? * 17),
+        6 => (channel(&hex[0..2])?, channel(&hex[2..4])?, channel(&hex[4..6])?),
+        _ => return None
+    };
+    Some(Value::ColorValue(crate::css::Color { r, g, b, a: 255 }))
 }
 
-// if the elem name, id, or classes match selector, return true
-fn matches_simple_selector(elem: &ElementData, selector: &SimpleSelector) -> bool {
-    // check type selector
-    if selector.tag_name.iter().any(|name| elem.tag_name != *name) {
-        return false;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn named_colors_are_colors_only_where_colors_go() {
+        let root = crate::dom::elem("p".to_string(), Default::default(), Vec::new());
+        let css = "p { counter-reset: teal; color: teal; border: 1px solid navy }";
+        let stylesheets = [crate::css::parse(css.to_string(), crate::css::Origin::Author)];
+        let p = style_tree(&root, &stylesheets);
+        let teal = Value::ColorValue(crate::css::Color { r: 0, g: 128, b: 128, a: 255 });
+        assert_eq!(p.value("counter-reset"), Some(Value::Keyword("teal".to_string())));
+        assert_eq!(p.value("color"), Some(teal));
+        assert_eq!(p.value("border-color"), Some(Value::ColorValue(crate::css::Color { r: 0, g: 0, b: 128, a: 255 })));
     }
 
-    // check ID selector
-    if selector.id.iter().any(|id| elem.id() != Some(id)) {
-        return false;
+    #[test]
+    fn hover_rules_apply_only_to_the_elements_the_state_marks() {
+        let root = crate::html::parse("<ul><li><a>one</a><b class=\"menu\">m</b></li><li><a>two</a><b class=\"menu\">m</b></li></ul>".to_string());
+        let css = "a { color: blue } a:hover { color: red } .menu { display: none } li:hover > .menu { display: block }";
+        let stylesheets = [crate::css::parse(css.to_string(), crate::css::Origin::Author)];
+        let mut state = PseudoClassState::default();
+        state.insert(&root.children[0].children[0], crate::selectors::PseudoClass::Hover);
+        state.insert(&root.children[1], crate::selectors::PseudoClass::Hover);
+        let ul = style_tree_with_state(&root, &stylesheets, &StyleContext::default(), &state);
+        let color = |item: &StyledNode| item.children[0].value("color");
+        let display = |item: &StyledNode| item.children[1].value("display");
+        let red = Value::ColorValue(crate::css::Color { r: 255, g: 0, b: 0, a: 255 });
+        let blue = Value::ColorValue(crate::css::Color { r: 0, g: 0, b: 255, a: 255 });
+        assert_eq!((color(&ul.children[0]), color(&ul.children[1])), (Some(red), Some(blue)));
+        let (none, block) = (Value::Keyword("none".to_string()), Value::Keyword("block".to_string()));
+        assert_eq!((display(&ul.children[0]), display(&ul.children[1])), (Some(none), Some(block)));
     }
 
-    // check class selectors
-    if selector.class.iter().any(|class| !elem.classes().contains(class.as_str())) {
-        return false;
+    #[test]
+    fn tables_in_quirks_mode_inherit_no_font() {
+        let root = crate::html::parse("<div><table><tr><td>cell</td></tr></table><form></form></div>".to_string());
+        let css = "div { font-size: 30px; font-weight: bold; text-align: right } td { font-style: italic }";
+        let stylesheets = [crate::css::default_user_agent_stylesheet(), crate::css::parse(css.to_string(), crate::css::Origin::Author)];
+        for (quirks_mode, font_size, text_align, form_margin) in [
+            (QuirksMode::NoQuirks, 30.0, "right", None),
+            (QuirksMode::Quirks, 16.0, "left", Some(Value::Length(30.0, Unit::Px))),
+        ] {
+            let context = StyleContext { quirks_mode, ..StyleContext::default() };
+            let div = style_tree_with_context(&root, &stylesheets, &context);
+            let (table, form) = (&div.children[0], &div.children[1]);
+            assert_eq!(table.value("font-size"), Some(Value::Length(font_size, Unit::Px)), "{:?}", quirks_mode);
+            assert_eq!(table.value("text-align"), Some(Value::Keyword(text_align.to_string())), "{:?}", quirks_mode);
+            assert_eq!(form.value("margin-bottom"), form_margin, "{:?}", quirks_mode);
+            // the cell inherits from the table, and its own rules still apply
+            let cell = &table.children[0].children[0];
+            assert_eq!(cell.value("font-size"), Some(Value::Length(font_size, Unit::Px)), "{:?}", quirks_mode);
+            assert_eq!(cell.value("font-style"), Some(Value::Keyword("italic".to_string())), "{:?}", quirks_mode);
+        }
     }
 
-    // we didn't find any non-matching selector components
-    return true;
-}
\ No newline at end of file
+    #[test]
+    fn shorthands_and_longhands_cascade_in_order() {
+        let margin_left = |css: &str| {
+            let root = crate::html::parse("<p class=\"x\">text</p>".to_string());
+            let stylesheets = [crate::css::parse(css.to_string(), crate::css::Origin::Author)];
+            style_tree(&root, &stylesheets).value("margin-left")
+        };
+        let px = |n| Some(Value::Length(n, Unit::Px));
+        assert_eq!(margin_left("p { margin: 10px; margin-left: 0px }"), px(0.0));
+        assert_eq!(margin_left("p { margin-left: 0px; margin: 10px }"), px(10.0));
+        assert_eq!(margin_left("p { margin: 10px 20px 30px }"), px(20.0));
+        // an important shorthand sets important longhands
+        assert_eq!(margin_left("p { margin: 10px !important } .x { margin-left: 0px }"), px(10.0));
+        assert_eq!(margin_left("p.x { margin-left: 5px } p { margin: 10px }"), px(5.0));
+    }
+
+    #[test]
+    fn the_ancestor_filter_leaves_styles_as_they_were() {
+        fn same_styles(a: &StyledNode, b: &StyledNode) -> bool {
+            a.specified_values == b.specified_values
+                && a.children.len() == b.children.len()
+                && a.children.iter().zip(&b.children).all(|(a, b)| same_styles(a, b))
+        }
+        // nested elements and mostly rules needing an ancestor class none has, as
+        // `cargo bench --bench selectors` times at a larger size
+        let mut source = String::new();
+        for i in 0..40 {
+            source.push_str(&format!("<div class=\"a{} b{}\">", i % 10, i % 7));
+        }
+        source.push_str(&"</div>".repeat(40));
+        let css: String = (0..40).map(|i| match i % 10 {
+            0 => format!(".a{} .b{} div {{ color: #{:06x}; }}\n", i % 10, i % 7, i * 4099),
+            _ => format!(".absent{} .a{} div {{ color: #{:06x}; }}\n", i, i % 10, i * 4099),
+        }).collect();
+        let root = crate::html::parse(source);
+        let stylesheets = [crate::css::default_user_agent_stylesheet(), crate::css::parse(css, crate::css::Origin::Author)];
+        let filtered = style_tree_with_context(&root, &stylesheets, &StyleContext { ancestor_filter: true, ..Default::default() });
+        let unfiltered = style_tree_with_context(&root, &stylesheets, &StyleContext { ancestor_filter: false, ..Default::default() });
+        assert!(same_styles(&filtered, &unfiltered));
+    }
+
+    #[test]
+    fn legacy_font_sizes() {
+        assert_eq!(parse_legacy_font_size("3"), Some("medium"));
+        assert_eq!(parse_legacy_font_size("+2"), Some("x-large"));
+        assert_eq!(parse_legacy_font_size("-1"), Some("small"));
+        assert_eq!(parse_legacy_font_size("0"), Some("x-small"));
+        assert_eq!(parse_legacy_font_size("5px"), Some("x-large"));
+        assert_eq!(parse_legacy_font_size("big"), None);
+        assert_eq!(parse_legacy_font_size("+"), None);
+        // sizes far out of range, even past what an i64 holds, are clamped
+        assert_eq!(parse_legacy_font_size("+9223372036854775807"), Some("xxx-large"));
+        assert_eq!(parse_legacy_font_size("-9223372036854775807"), Some("x-small"));
+        assert_eq!(parse_legacy_font_size("99999999999999999999999"), Some("xxx-large"));
+    }
+}
@@ -0,0 +1,551 @@
+use crate::css::{Color, Value, Value::{Keyword, Length}, Unit::{Percent, Px}};
+use crate::dom::NodeType;
+use crate::layout::{Dimensions, EdgeSizes, LayoutBox, LayoutContext, Rect};
+use crate::style::{Display, StyledNode};
+
+// Table layout, for boxes with `display: table` and the rows and cells in them.
+//
+// https://www.w3.org/TR/css-tables-3/
+//
+// Cells are placed into a grid of slots the way HTML does it, following `colspan`
+// and `rowspan`, so a cell covers the union of the slots it spans. Columns are as
+// wide as their widest cell, a spanning cell spreading what it needs over its
+// columns, and rows as tall as their tallest cell. Cells are spaced out by
+// `border-spacing`, or share their borders with `border-collapse: collapse`.
+// Captions, columns and vertical-align aren't supported, and no anonymous table
+// boxes are generated: content of a table, row group or row outside of its rows
+// and cells is left out of the layout tree.
+
+// the largest spans HTML allows; larger values are clamped to these
+const MAX_COLSPAN: usize = 1000;
+const MAX_ROWSPAN: usize = 65534;
+
+/// Where a row is in the layout tree: the index of a table child, and of the row
+/// within it if that child is a row group.
+#[derive(Clone, Copy, Debug)]
+pub struct RowRef(usize, Option<usize>);
+
+/// A cell placed in the grid, in slots `row..row + rowspan` and
+/// `column..column + colspan`.
+#[derive(Clone, Copy, Debug)]
+pub struct GridCell {
+    pub row: usize,
+    pub column: usize,
+    pub rowspan: usize,
+    pub colspan: usize,
+    index: usize, // of the cell among its row's children
+}
+
+/// The grid of slots of a table, with the cells placed in it.
+///
+/// https://html.spec.whatwg.org/multipage/tables.html#forming-a-table
+#[derive(Debug, Default)]
+pub struct TableGrid {
+    pub rows: Vec<RowRef>,
+    pub columns: usize,
+    pub cells: Vec<GridCell>, // in tree order
+    // spans that were malformed and had to be clamped, or cells that would overlap
+    pub warnings: Vec<String>,
+}
+
+impl TableGrid {
+    /// Place the cells of a table box into slots.
+    pub fn build(table: &LayoutBox) -> TableGrid {
+        let mut grid = TableGrid::default();
+        // the row group of each row; rows directly in the table form a group with
+        // the rows next to them
+        let mut groups = Vec::new();
+        let mut group = 0;
+        for (i, child) in table.children.iter().enumerate() {
+            if is_display(child, Display::TableRowGroup) {
+                group += 1;
+                grid.rows.extend((0..child.children.len()).map(|j| RowRef(i, Some(j))));
+                groups.resize(grid.rows.len(), group);
+                group += 1;
+            } else {
+                grid.rows.push(RowRef(i, None));
+                groups.push(group);
+            }
+        }
+        // the row after the last of each row's group, which its cells can't span past
+        let mut group_ends = vec![grid.rows.len(); grid.rows.len()];
+        for row in (0..grid.rows.len().saturating_sub(1)).rev() {
+            group_ends[row] = if groups[row] == groups[row + 1] { group_ends[row + 1] } else { row + 1 };
+        }
+
+        // which slots are taken, by row
+        let mut occupied: Vec<Vec<bool>> = vec![Vec::new(); grid.rows.len()];
+        for (row, &row_ref) in grid.rows.iter().enumerate() {
+            let mut column = 0;
+            for (index, cell) in row_box(table, row_ref).children.iter().enumerate() {
+                while occupied[row].get(column).copied().unwrap_or(false) {
+                    column += 1;
+                }
+                let mut colspan = span_attribute(cell, "colspan", 1, MAX_COLSPAN, &mut grid.warnings);
+                // a cell never covers a slot taken by a cell spanning down from above
+                let free = (column..column + colspan)
+                    .take_while(|&c| !occupied[row].get(c).copied().unwrap_or(false))
+                    .count();
+                if free < colspan {
+                    grid.warnings.push(format!(
+                        "{} in row {} overlaps a cell spanning rows, its colspan is cut to {}",
+                        describe(cell), row + 1, free));
+                    colspan = free;
+                }
+                let rows_left = group_ends[row] - row;
+                // rowspan="0" spans the rest of the row group
+                let rowspan = match span_attribute(cell, "rowspan", 1, MAX_ROWSPAN, &mut grid.warnings) {
+                    0 => rows_left,
+                    n if n > rows_left => {
+                        grid.warnings.push(format!(
+                            "{} in row {} spans {} rows but only {} are left, using {}",
+                            describe(cell), row + 1, n, rows_left, rows_left));
+                        rows_left
+                    }
+                    n => n
+                };
+                for slots in &mut occupied[row..row + rowspan] {
+                    if slots.len() < column + colspan {
+                        slots.resize(column + colspan, false);
+                    }
+                    slots[column..column + colspan].fill(true);
+                }
+                grid.columns = grid.columns.max(column + colspan);
+                grid.cells.push(GridCell { row, column, rowspan, colspan, index });
+                column += colspan;
+            }
+        }
+        grid
+    }
+}
+
+/// The warnings from building the grid of every table in a layout tree.
+pub fn span_warnings(root: &LayoutBox) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if is_display(root, Display::Table) {
+        let grid = TableGrid::build(root);
+        warnings.extend(grid.warnings.iter().cloned());
+        for cell in &grid.cells {
+            warnings.extend(span_warnings(cell_box(root, &grid, cell)));
+        }
+    } else {
+        for child in &root.children {
+            warnings.extend(span_warnings(child));
+        }
+    }
+    warnings
+}
+
+// Lay out a table box: the table is sized and placed like a block, then its
+// columns and rows are sized to fit their cells, and the cells are stretched
+// over the slots they span.
+pub(crate) fn layout_table(table: &mut LayoutBox, containing_block: Dimensions, containing_height: Option<f32>, ctx: &LayoutContext) {
+    table.calculate_block_width(containing_block, ctx);
+    table.calculate_block_position(containing_block);
+    let grid = TableGrid::build(table);
+    let style = table.get_style_node();
+
+    // with collapsing borders there's no spacing and no table padding, and the
+    // table and its cells each take half of the grid lines around them as borders
+    let collapsed = (style.value("border-collapse") == Some(Keyword("collapse".to_string())))
+        .then(|| CollapsedBorders::resolve(table, &grid));
+    let (h_spacing, v_spacing) = match collapsed {
+        Some(_) => (0.0, 0.0),
+        None if grid.cells.is_empty() => (0.0, 0.0),
+        None => border_spacing(style)
+    };
+    if let Some(ref borders) = collapsed {
+        let d = &mut table.dimensions;
+        let border_box = d.border_box();
+        d.padding = EdgeSizes::default();
+        d.border = borders.edges(0, grid.rows.len(), 0, grid.columns);
+        d.content.x = border_box.x + d.border.left;
+        d.content.y = border_box.y + d.border.top;
+        d.content.width = (border_box.width - d.border.left - d.border.right).max(0.0);
+    }
+    let cell_borders = |cell: &GridCell| collapsed.as_ref()
+        .map(|borders| borders.edges(cell.row, cell.row + cell.rowspan, cell.column, cell.column + cell.colspan));
+
+    // columns are as wide as their cells' max-content widths; spanning cells are
+    // considered narrowest span first, each adding what it lacks to its columns
+    // in proportion to their widths so far
+    let mut widths = vec![0.0; grid.columns];
+    let mut by_colspan: Vec<&GridCell> = grid.cells.iter().collect();
+    by_colspan.sort_by_key(|cell| cell.colspan);
+    for cell in by_colspan {
+        let cell_box = cell_box(table, &grid, cell);
+        let mut needed = cell_box.max_content_contribution(ctx) - (cell.colspan - 1) as f32 * h_spacing;
+        if let Some(border) = cell_borders(cell) {
+            let own = border_widths(cell_box.get_style_node());
+            needed += border.left + border.right - own.left - own.right;
+        }
+        distribute(&mut widths[cell.column..cell.column + cell.colspan], needed);
+    }
+
+    // an auto width table shrinks to its columns, but no wider than it could be;
+    // a table with a width stretches its columns to fill it
+    let available = table.dimensions.content.width;
+    let specified = match table.get_style_node().value("width") {
+        Some(Length(w, Px)) => Some(w),
+        Some(Length(p, Percent)) => Some(p * containing_block.content.width / 100.0),
+        _ => None
+    };
+    let spacing_width = (grid.columns + 1) as f32 * h_spacing;
+    let columns_width: f32 = widths.iter().sum::<f32>() + spacing_width;
+    let width = specified.unwrap_or(columns_width.min(available)).max(0.0);
+    if specified.is_none() {
+        let auto = Keyword("auto".to_string());
+        let style = table.get_style_node();
+        if style.value("margin-left") == Some(auto.clone()) && style.value("margin-right") == Some(auto) {
+            let centering = (available - width) / 2.0;
+            table.dimensions.margin.left += centering;
+            table.dimensions.margin.right += centering;
+            table.dimensions.content.x += centering;
+        }
+    }
+    table.dimensions.content.width = width;
+    scale_to(&mut widths, (width - spacing_width).max(0.0));
+    // the left edge of each column, and the right edge of the last one plus spacing
+    let mut column_x = vec![table.dimensions.content.x + h_spacing];
+    for width in &widths {
+        column_x.push(column_x.last().unwrap() + width + h_spacing);
+    }
+
+    // lay out each cell at the top of the table in the columns it spans, then size
+    // the rows to fit, with cells spanning rows considered like spanning columns
+    let mut heights: Vec<f32> = grid.rows.iter()
+        .map(|&row| row_box(table, row).definite_height(None).unwrap_or(0.0))
+        .collect();
+    let mut by_rowspan: Vec<&GridCell> = grid.cells.iter().collect();
+    by_rowspan.sort_by_key(|cell| cell.rowspan);
+    let top = table.dimensions.content.y + v_spacing;
+    for cell in by_rowspan {
+        let block = Dimensions {
+            content: Rect {
+                x: column_x[cell.column],
+                y: top,
+                width: column_x[cell.column + cell.colspan] - column_x[cell.column] - h_spacing,
+                height: 0.0,
+            },
+            ..Default::default()
+        };
+        let borders = cell_borders(cell);
+        let cell_box = cell_box_mut(table, &grid, cell);
+        layout_cell(cell_box, block, borders, ctx);
+        let needed = cell_box.dimensions.margin_box().height - (cell.rowspan - 1) as f32 * v_spacing;
+        distribute(&mut heights[cell.row..cell.row + cell.rowspan], needed);
+    }
+    // the top edge of each row, and the bottom edge of the last one plus spacing
+    let mut row_y = vec![top];
+    for height in &heights {
+        row_y.push(row_y.last().unwrap() + height + v_spacing);
+    }
+
+    // move the cells down to their rows, and stretch them over the rows they span
+    for cell in &grid.cells {
+        let cell_box = cell_box_mut(table, &grid, cell);
+        cell_box.translate(0.0, row_y[cell.row] - top);
+        let span_height = row_y[cell.row + cell.rowspan] - row_y[cell.row] - v_spacing;
+        cell_box.dimensions.content.height += (span_height - cell_box.dimensions.margin_box().height).max(0.0);
+    }
+
+    // rows cover the cells of the table, and row groups their rows
+    let x = column_x[0];
+    let rows_width = (column_x[grid.columns] - h_spacing - x).max(0.0);
+    for (row, &row_ref) in grid.rows.iter().enumerate() {
+        row_box_mut(table, row_ref).dimensions = Dimensions {
+            content: Rect { x, y: row_y[row], width: rows_width, height: heights[row] },
+            ..Default::default()
+        };
+    }
+    let mut y = top;
+    for child in table.children.iter_mut() {
+        if is_display(child, Display::TableRowGroup) {
+            let rows = child.children.first().zip(child.children.last());
+            let (group_y, bottom) = rows.map_or((y, y), |(first, last)| {
+                let last = last.dimensions.content;
+                (first.dimensions.content.y, last.y + last.height)
+            });
+            child.dimensions = Dimensions {
+                content: Rect { x, y: group_y, width: rows_width, height: bottom - group_y },
+                ..Default::default()
+            };
+        }
+        let content = child.dimensions.content;
+        y = y.max(content.y + content.height);
+    }
+
+    if let Some(ref borders) = collapsed {
+        table.collapsed_borders = borders.segments(&grid, &column_x, &row_y);
+    }
+    let rows_height = row_y.last().unwrap() - table.dimensions.content.y;
+    table.dimensions.content.height = table.definite_height(containing_height).unwrap_or(0.0).max(rows_height);
+}
+
+// lay out a cell in the area of the columns it spans; a cell fills them whatever
+// its width, its height is a minimum, margins don't apply to cells, and
+// percentage heights act as auto. `borders` replaces the cell's own with
+// collapsing borders.
+fn layout_cell(cell: &mut LayoutBox, block: Dimensions, borders: Option<EdgeSizes>, ctx: &LayoutContext) {
+    cell.calculate_block_width(block, ctx);
+    cell.calculate_block_position(block);
+    let d = &mut cell.dimensions;
+    if let Some(borders) = borders {
+        d.border = borders;
+    }
+    d.margin = EdgeSizes::default();
+    d.content.x = block.content.x + d.border.left + d.padding.left;
+    d.content.y = block.content.y + d.border.top + d.padding.top;
+    d.content.width = (block.content.width - d.padding.left - d.padding.right - d.border.left - d.border.right).max(0.0);
+    cell.layout_block_children(None, ctx);
+    let min_height = cell.definite_height(None).unwrap_or(0.0);
+    cell.dimensions.content.height = cell.dimensions.content.height.max(min_height);
+}
+
+// `border-spacing` as (horizontal, vertical) px; one length sets both
+fn border_spacing(style: &StyledNode) -> (f32, f32) {
+    match style.value("border-spacing") {
+        Some(Value::List(values)) => match &values[..] {
+            [h, v] => (h.to_px().max(0.0), v.to_px().max(0.0)),
+            [h, ..] => (h.to_px().max(0.0), h.to_px().max(0.0)),
+            [] => (0.0, 0.0)
+        },
+        Some(value) => (value.to_px().max(0.0), value.to_px().max(0.0)),
+        None => (0.0, 0.0)
+    }
+}
+
+// the border widths a box's style gives it, in px
+fn border_widths(style: &StyledNode) -> EdgeSizes {
+    let zero = Length(0.0, Px);
+    let width = |side| style.lookup(side, "border-width", &zero).to_px().max(0.0);
+    EdgeSizes {
+        left: width("border-left-width"),
+        right: width("border-right-width"),
+        top: width("border-top-width"),
+        bottom: width("border-bottom-width"),
+    }
+}
+
+fn border_color(style: &StyledNode) -> Option<Color> {
+    match style.value("border-color") {
+        Some(Value::ColorValue(color)) => Some(color),
+        _ => None
+    }
+}
+
+/// The borders of a table in the collapsing border model.
+///
+/// https://www.w3.org/TR/CSS2/tables.html#collapsing-borders
+///
+/// Where two cells, or a cell and the edge of the table, meet, the wider of their
+/// borders is drawn, once, centered on the grid line; on a tie the cell's border
+/// beats the table's, and the cell to the left or above beats the other. Each
+/// grid line is as wide as the widest border along it. Border styles aren't
+/// supported, and the borders of rows and row groups are ignored.
+struct CollapsedBorders {
+    vertical: Vec<f32>, // the width of each vertical grid line, left to right
+    horizontal: Vec<f32>, // and of each horizontal one, top to bottom
+    owners: Vec<Vec<Option<usize>>>, // the cell covering each slot, by row
+    cells: Vec<(EdgeSizes, Option<Color>)>, // each cell's own borders, as in the grid
+    table: (EdgeSizes, Option<Color>),
+}
+
+impl CollapsedBorders {
+    fn resolve(table: &LayoutBox, grid: &TableGrid) -> CollapsedBorders {
+        let mut owners = vec![vec![None; grid.columns]; grid.rows.len()];
+        for (i, cell) in grid.cells.iter().enumerate() {
+            for row in &mut owners[cell.row..cell.row + cell.rowspan] {
+                row[cell.column..cell.column + cell.colspan].fill(Some(i));
+            }
+        }
+        let own_borders = |style: &StyledNode| (border_widths(style), border_color(style));
+        let mut borders = CollapsedBorders {
+            vertical: vec![0.0; grid.columns + 1],
+            horizontal: vec![0.0; grid.rows.len() + 1],
+            owners,
+            cells: grid.cells.iter().map(|cell| own_borders(cell_box(table, grid, cell).get_style_node())).collect(),
+            table: own_borders(table.get_style_node()),
+        };
+        for row in 0..grid.rows.len() {
+            for line in 0..=grid.columns {
+                let width = borders.vertical_segment(row, line).0;
+                borders.vertical[line] = borders.vertical[line].max(width);
+            }
+        }
+        for line in 0..=grid.rows.len() {
+            for column in 0..grid.columns {
+                let width = borders.horizontal_segment(line, column).0;
+                borders.horizontal[line] = borders.horizontal[line].max(width);
+            }
+        }
+        borders
+    }
+
+    // the border on vertical grid line `line` in row `row`, as its width and color
+    fn vertical_segment(&self, row: usize, line: usize) -> (f32, Option<Color>) {
+        let slots = &self.owners[row];
+        let left = line.checked_sub(1).and_then(|column| slots[column]);
+        let right = slots.get(line).copied().flatten();
+        if left.is_some() && left == right {
+            return (0.0, None); // inside a cell spanning columns
+        }
+        let last = slots.len();
+        winner([
+            left.map(|cell| (self.cells[cell].0.right, self.cells[cell].1)),
+            right.map(|cell| (self.cells[cell].0.left, self.cells[cell].1)),
+            (line == 0).then_some((self.table.0.left, self.table.1)),
+            (line == last).then_some((self.table.0.right, self.table.1)),
+        ])
+    }
+
+    // the border on horizontal grid line `line` in column `column`
+    fn horizontal_segment(&self, line: usize, column: usize) -> (f32, Option<Color>) {
+        let above = line.checked_sub(1).and_then(|row| self.owners[row][column]);
+        let below = self.owners.get(line).and_then(|slots| slots[column]);
+        if above.is_some() && above == below {
+            return (0.0, None); // inside a cell spanning rows
+        }
+        let last = self.owners.len();
+        winner([
+            above.map(|cell| (self.cells[cell].0.bottom, self.cells[cell].1)),
+            below.map(|cell| (self.cells[cell].0.top, self.cells[cell].1)),
+            (line == 0).then_some((self.table.0.top, self.table.1)),
+            (line == last).then_some((self.table.0.bottom, self.table.1)),
+        ])
+    }
+
+    // the borders of the area between the grid lines `top` and `bottom`, and
+    // `left` and `right`: half of each line
+    fn edges(&self, top: usize, bottom: usize, left: usize, right: usize) -> EdgeSizes {
+        EdgeSizes {
+            left: self.vertical[left] / 2.0,
+            right: self.vertical[right] / 2.0,
+            top: self.horizontal[top] / 2.0,
+            bottom: self.horizontal[bottom] / 2.0,
+        }
+    }
+
+    // the rectangles to paint the borders in, given where the grid lines are
+    fn segments(&self, grid: &TableGrid, column_x: &[f32], row_y: &[f32]) -> Vec<(Rect, Color)> {
+        let mut segments = Vec::new();
+        // vertical segments reach across the horizontal lines at their ends, so the
+        // corners where borders meet are filled
+        for row in 0..grid.rows.len() {
+            for (line, &x) in column_x.iter().enumerate() {
+                if let (width, Some(color)) = self.vertical_segment(row, line) {
+                    let top = row_y[row] - self.horizontal[row] / 2.0;
+                    let bottom = row_y[row + 1] + self.horizontal[row + 1] / 2.0;
+                    let rect = Rect { x: x - width / 2.0, y: top, width, height: bottom - top };
+                    segments.push((rect, color));
+                }
+            }
+        }
+        for (line, &y) in row_y.iter().enumerate() {
+            for column in 0..grid.columns {
+                if let (width, Some(color)) = self.horizontal_segment(line, column) {
+                    let rect = Rect {
+                        x: column_x[column],
+                        y: y - width / 2.0,
+                        width: column_x[column + 1] - column_x[column],
+                        height: width,
+                    };
+                    segments.push((rect, color));
+                }
+            }
+        }
+        segments.retain(|(rect, _)| !rect.is_empty());
+        segments
+    }
+}
+
+// the widest of the borders that meet, the first of them on a tie
+fn winner(candidates: [Option<(f32, Option<Color>)>; 4]) -> (f32, Option<Color>) {
+    candidates.into_iter().flatten().fold((0.0, None), |best, candidate| {
+        if candidate.0 > best.0 { candidate } else { best }
+    })
+}
+
+// grow `sizes` so that together they're at least `needed`, in proportion to their
+// sizes, or evenly if they're all zero
+pub(crate) fn distribute(sizes: &mut [f32], needed: f32) {
+    let total: f32 = sizes.iter().sum();
+    if needed <= total || sizes.is_empty() {
+        return;
+    }
+    let count = sizes.len() as f32;
+    for size in sizes.iter_mut() {
+        *size += if total > 0.0 { (needed - total) * *size / total } else { (needed - total) / count };
+    }
+}
+
+// stretch or shrink `sizes` to add up to `target`, keeping their proportions
+fn scale_to(sizes: &mut [f32], target: f32) {
+    let total: f32 = sizes.iter().sum();
+    if total > 0.0 {
+        sizes.iter_mut().for_each(|size| *size *= target / total);
+    } else if !sizes.is_empty() {
+        let count = sizes.len() as f32;
+        sizes.iter_mut().for_each(|size| *size = target / count);
+    }
+}
+
+// the value of a colspan or rowspan attribute: a non-negative integer, clamped to
+// `max`, with `default` when it's missing; values HTML doesn't allow are reported
+fn span_attribute(cell: &LayoutBox, name: &str, default: usize, max: usize, warnings: &mut Vec<String>) -> usize {
+    let value = match cell.style_node().map(|style| &style.node.node_type) {
+        Some(NodeType::Element(elem)) => match elem.attrs.get(name) {
+            Some(value) => value.trim(),
+            None => return default,
+        },
+        _ => return default,
+    };
+    let digits = &value[..value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len())];
+    let (span, problem) = match digits.parse::<usize>() {
+        // only rowspan can be zero
+        Ok(0) if name == "colspan" => (default, format!("using {}", default)),
+        Ok(n) if n > max => (max, format!("using the maximum of {}", max)),
+        Ok(n) => return n,
+        // all digits and still no number: too many of them
+        Err(_) if !digits.is_empty() => (max, format!("using the maximum of {}", max)),
+        Err(_) => (default, format!("using {}", default)),
+    };
+    warnings.push(format!("{} has {}=\"{}\", {}", describe(cell), name, value, problem));
+    span
+}
+
+// `<td>` or `<th>`, for warnings
+fn describe(cell: &LayoutBox) -> String {
+    match cell.style_node().map(|style| &style.node.node_type) {
+        Some(NodeType::Element(elem)) => format!("<{}>", elem.tag_name),
+        _ => "a cell".to_string(),
+    }
+}
+
+fn is_display(layout_box: &LayoutBox, display: Display) -> bool {
+    layout_box.style_node().is_some_and(|style| style.display() == display)
+}
+
+fn row_box<'b, 'a>(table: &'b LayoutBox<'a>, RowRef(i, j): RowRef) -> &'b LayoutBox<'a> {
+    let child = &table.children[i];
+    match j {
+        Some(j) => &child.children[j],
+        None => child,
+    }
+}
+
+fn row_box_mut<'b, 'a>(table: &'b mut LayoutBox<'a>, RowRef(i, j): RowRef) -> &'b mut LayoutBox<'a> {
+    let child = &mut table.children[i];
+    match j {
+        Some(j) => &mut child.children[j],
+        None => child,
+    }
+}
+
+fn cell_box<'b, 'a>(table: &'b LayoutBox<'a>, grid: &TableGrid, cell: &GridCell) -> &'b LayoutBox<'a> {
+    &row_box(table, grid.rows[cell.row]).children[cell.index]
+}
+
+fn cell_box_mut<'b, 'a>(table: &'b mut LayoutBox<'a>, grid: &TableGrid, cell: &GridCell) -> &'b mut LayoutBox<'a> {
+    &mut row_box_mut(table, grid.rows[cell.row]).children[cell.index]
+}
@@ -0,0 +1,303 @@
+use crate::dom::{Node, NodeType};
+use crate::html::ParseError;
+
+// Filling an HTML template with data, for rendering the same document with
+// different contents (reports, invoices, ...). `{{key}}` placeholders in text and
+// attribute values are replaced with values from a JSON object, and elements with
+// `data-if="key"` are dropped when the key's value is falsy. Keys may be dotted
+// paths into nested objects, like `{{customer.name}}`.
+//
+// Substitution runs on the parsed DOM, so a value always ends up as the text of a
+// single node: markup in it is shown as written, never parsed, and can't add
+// elements or attributes. A document without placeholders or `data-if`
+// attributes comes out exactly as it went in.
+
+/// A JSON value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>), // in source order
+}
+
+impl Json {
+    /// Parse a JSON document.
+    pub fn parse(source: &str) -> Result<Json, ParseError> {
+        let mut parser = JsonParser { input: source, pos: 0 };
+        let value = parser.parse_value()?;
+        parser.consume_whitespace();
+        if !parser.eof() {
+            return Err(parser.error("unexpected data after the JSON value"));
+        }
+        Ok(value)
+    }
+
+    /// Look up a dotted path like `customer.name` through nested objects.
+    pub fn get(&self, path: &str) -> Option<&Json> {
+        path.split('.').try_fold(self, |value, key| match value {
+            Json::Object(members) => members.iter().rev().find(|(name, _)| name == key).map(|(_, v)| v),
+            _ => None,
+        })
+    }
+
+    /// `false`, `null`, `0`, `""` and `[]` are falsy, like in JavaScript (except
+    /// for the empty array); everything else is truthy.
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Json::Null => false,
+            Json::Bool(b) => *b,
+            Json::Number(n) => *n != 0.0 && !n.is_nan(),
+            Json::Str(s) => !s.is_empty(),
+            Json::Array(items) => !items.is_empty(),
+            Json::Object(_) => true,
+        }
+    }
+
+    // the text a placeholder is replaced with, if the value is a scalar
+    fn to_text(&self) -> Option<String> {
+        match self {
+            Json::Null => Some(String::new()),
+            Json::Bool(b) => Some(b.to_string()),
+            Json::Number(n) => Some(n.to_string()),
+            Json::Str(s) => Some(s.clone()),
+            Json::Array(_) | Json::Object(_) => None,
+        }
+    }
+}
+
+/// What to do with a placeholder whose key isn't in the data.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum MissingKeys {
+    #[default]
+    Keep,  // leave `{{key}}` in place, so the gap is visible in the output
+    Empty, // replace it with nothing
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    pub missing_keys: MissingKeys,
+}
+
+/// Substitute `data` into the template rooted at `root`.
+pub fn apply(root: &mut Node, data: &Json, options: &Options) {
+    if let NodeType::Element(ref mut elem) = root.node_type {
+        for value in elem.attrs.values_mut() {
+            if let Some(substituted) = substitute(value, data, options) {
+                *value = substituted;
+            }
+        }
+    }
+    if let NodeType::Text(ref mut text) = root.node_type {
+        if let Some(substituted) = substitute(text, data, options) {
+            *text = substituted;
+        }
+    }
+    root.children.retain(|child| is_included(child, data));
+    for child in &mut root.children {
+        apply(child, data, options);
+    }
+}
+
+// false for an element whose `data-if` key is missing or falsy
+fn is_included(node: &Node, data: &Json) -> bool {
+    match node.node_type {
+        NodeType::Element(ref elem) => match elem.attrs.get("data-if") {
+            Some(key) => data.get(key.trim()).is_some_and(Json::is_truthy),
+            None => true,
+        },
+        _ => true,
+    }
+}
+
+// `text` with its placeholders replaced, or `None` if it has none
+fn substitute(text: &str, data: &Json, options: &Options) -> Option<String> {
+    if !text.contains("{{") {
+        return None;
+    }
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start + 2..].find("}}") {
+            Some(end) => start + 2 + end,
+            None => break,
+        };
+        result.push_str(&rest[..start]);
+        let placeholder = &rest[start..end + 2];
+        match data.get(rest[start + 2..end].trim()).and_then(Json::to_text) {
+            Some(value) => result.push_str(&value),
+            None if options.missing_keys == MissingKeys::Keep => result.push_str(placeholder),
+            None => {}
+        }
+        rest = &rest[end + 2..];
+    }
+    result.push_str(rest);
+    Some(result)
+}
+
+struct JsonParser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl JsonParser<'_> {
+    fn parse_value(&mut self) -> Result<Json, ParseError> {
+        self.consume_whitespace();
+        match self.next_char() {
+            Some('{') => self.parse_object(),
+            Some('[') => self.parse_array(),
+            Some('"') => self.parse_string().map(Json::Str),
+            Some('-' | '0'..='9') => self.parse_number(),
+            Some(_) if self.consume_literal("true") => Ok(Json::Bool(true)),
+            Some(_) if self.consume_literal("false") => Ok(Json::Bool(false)),
+            Some(_) if self.consume_literal("null") => Ok(Json::Null),
+            Some(_) => Err(self.error("expected a JSON value")),
+            None => Err(self.error("unexpected end of JSON")),
+        }
+    }
+
+    fn parse_object(&mut self) -> Result<Json, ParseError> {
+        self.expect('{')?;
+        let mut members = Vec::new();
+        self.consume_whitespace();
+        if self.consume_literal("}") {
+            return Ok(Json::Object(members));
+        }
+        loop {
+            self.consume_whitespace();
+            if self.next_char() != Some('"') {
+                return Err(self.error("expected a string key"));
+            }
+            let key = self.parse_string()?;
+            self.consume_whitespace();
+            self.expect(':')?;
+            members.push((key, self.parse_value()?));
+            self.consume_whitespace();
+            if !self.consume_literal(",") {
+                self.expect('}')?;
+                return Ok(Json::Object(members));
+            }
+        }
+    }
+
+    fn parse_array(&mut self) -> Result<Json, ParseError> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        self.consume_whitespace();
+        if self.consume_literal("]") {
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.parse_value()?);
+            self.consume_whitespace();
+            if !self.consume_literal(",") {
+                self.expect(']')?;
+                return Ok(Json::Array(items));
+            }
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String, ParseError> {
+        self.expect('"')?;
+        let mut value = String::new();
+        loop {
+            match self.consume_char() {
+                Some('"') => return Ok(value),
+                Some('\\') => match self.consume_char() {
+                    Some('"') => value.push('"'),
+                    Some('\\') => value.push('\\'),
+                    Some('/') => value.push('/'),
+                    Some('b') => value.push('\u{8}'),
+                    Some('f') => value.push('\u{c}'),
+                    Some('n') => value.push('\n'),
+                    Some('r') => value.push('\r'),
+                    Some('t') => value.push('\t'),
+                    Some('u') => value.push(self.parse_unicode_escape()?),
+                    _ => return Err(self.error("invalid escape in string")),
+                },
+                Some(c) => value.push(c),
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    // the code point of `\uXXXX`, combining a surrogate pair written as two escapes
+    fn parse_unicode_escape(&mut self) -> Result<char, ParseError> {
+        let high = self.parse_hex4()?;
+        if !(0xd800..0xdc00).contains(&high) {
+            return char::from_u32(high).ok_or_else(|| self.error("invalid \\u escape"));
+        }
+        if !self.consume_literal("\\u") {
+            return Err(self.error("unpaired surrogate in \\u escape"));
+        }
+        let low = self.parse_hex4()?;
+        if !(0xdc00..0xe000).contains(&low) {
+            return Err(self.error("unpaired surrogate in \\u escape"));
+        }
+        let code_point = 0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00);
+        char::from_u32(code_point).ok_or_else(|| self.error("invalid \\u escape"))
+    }
+
+    fn parse_hex4(&mut self) -> Result<u32, ParseError> {
+        let digits = self.input.get(self.pos..self.pos + 4)
+            .filter(|digits| digits.chars().all(|c| c.is_ascii_hexdigit()))
+            .ok_or_else(|| self.error("expected four hex digits"))?;
+        self.pos += 4;
+        Ok(u32::from_str_radix(digits, 16).unwrap())
+    }
+
+    fn parse_number(&mut self) -> Result<Json, ParseError> {
+        let start = self.pos;
+        while let Some('-' | '+' | '.' | 'e' | 'E' | '0'..='9') = self.next_char() {
+            self.pos += 1;
+        }
+        self.input[start..self.pos].parse().map(Json::Number).map_err(|_| ParseError {
+            message: "invalid number".to_string(),
+            position: start,
+        })
+    }
+
+    fn consume_whitespace(&mut self) {
+        while let Some(' ' | '\t' | '\n' | '\r') = self.next_char() {
+            self.pos += 1;
+        }
+    }
+
+    fn consume_literal(&mut self, literal: &str) -> bool {
+        let matched = self.input[self.pos..].starts_with(literal);
+        if matched {
+            self.pos += literal.len();
+        }
+        matched
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), ParseError> {
+        match self.next_char() {
+            Some(next) if next == c => {
+                self.pos += c.len_utf8();
+                Ok(())
+            }
+            _ => Err(self.error(&format!("expected '{}'", c))),
+        }
+    }
+
+    fn consume_char(&mut self) -> Option<char> {
+        let c = self.next_char()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn next_char(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
+    }
+
+    fn eof(&self) -> bool {
+        self.pos >= self.input.len()
+    }
+
+    fn error(&self, message: &str) -> ParseError {
+        ParseError { message: format!("JSON: {}", message), position: self.pos }
+    }
+}
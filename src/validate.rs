@@ -0,0 +1,137 @@
+use crate::dom::NodeType;
+use crate::layout::{AnonymousBlock, BlockNode, InlineBlockNode, LayoutBox, Rect};
+use crate::style::Display;
+use std::fmt;
+
+// Structural checks on a laid-out box tree, so that a layout bug shows up as a named
+// broken invariant at the box that broke it, rather than as something painted wrong.
+// Debug builds, and builds with the `check-layout` feature, run them after every layout.
+
+/// A box in the layout tree that breaks one of the invariants layout keeps.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvariantViolation {
+    pub kind: ViolationKind,
+    pub path: String, // the boxes from the root down to the offending one, like `html > body > p`
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ViolationKind {
+    // a coordinate or size that is NaN or infinite, such as "content width"
+    NonFinite(&'static str),
+    // padding or border, such as "padding-left", with a negative width; only
+    // margins may be negative
+    NegativeEdge(&'static str, f32),
+    // a block-level box starting this far above the bottom of the previous one,
+    // with no negative margin between them to explain it
+    Overlap(f32),
+    // an anonymous block holding a block-level box, where it should hold only the
+    // inline-level boxes of a run of inline content
+    BlockInAnonymousBlock,
+}
+
+impl fmt::Display for InvariantViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ViolationKind::NonFinite(what) => write!(f, "non-finite {} at {}", what, self.path),
+            ViolationKind::NegativeEdge(edge, value) => write!(f, "negative {} of {}px at {}", edge, value, self.path),
+            ViolationKind::Overlap(overlap) => write!(f, "overlaps the previous block by {}px at {}", overlap, self.path),
+            ViolationKind::BlockInAnonymousBlock => write!(f, "block-level box in an anonymous block at {}", self.path),
+        }
+    }
+}
+
+// how far layout may be off before it counts, since positions are sums of f32s
+const TOLERANCE: f32 = 0.01;
+
+/// Check the layout tree under `root`, returning every violation found, in tree order.
+pub fn check_invariants(root: &LayoutBox) -> Vec<InvariantViolation> {
+    let mut violations = Vec::new();
+    check_box(root, &describe(root), &mut violations);
+    violations
+}
+
+fn check_box(layout_box: &LayoutBox, path: &str, violations: &mut Vec<InvariantViolation>) {
+    let mut report = |kind| violations.push(InvariantViolation { kind, path: path.to_string() });
+    let d = layout_box.dimensions;
+    let margins = [("margin-top", d.margin.top), ("margin-right", d.margin.right),
+                   ("margin-bottom", d.margin.bottom), ("margin-left", d.margin.left)];
+    let edges = [("padding-top", d.padding.top), ("padding-right", d.padding.right),
+                 ("padding-bottom", d.padding.bottom), ("padding-left", d.padding.left),
+                 ("border-top-width", d.border.top), ("border-right-width", d.border.right),
+                 ("border-bottom-width", d.border.bottom), ("border-left-width", d.border.left)];
+    let content = [("content x", d.content.x), ("content y", d.content.y),
+                   ("content width", d.content.width), ("content height", d.content.height)];
+    for (what, value) in content.into_iter().chain(margins).chain(edges) {
+        if !value.is_finite() {
+            report(ViolationKind::NonFinite(what));
+        }
+    }
+    for (edge, value) in edges {
+        if value < 0.0 {
+            report(ViolationKind::NegativeEdge(edge, value));
+        }
+    }
+    let is_finite = |rect: Rect| [rect.x, rect.y, rect.width, rect.height].iter().all(|v| v.is_finite());
+    if !layout_box.lines.iter().all(|line| is_finite(line.rect) && line.baseline.is_finite()) {
+        report(ViolationKind::NonFinite("line box"));
+    }
+    if !layout_box.lines.iter().flat_map(|line| &line.fragments).all(|fragment| is_finite(fragment.rect)) {
+        report(ViolationKind::NonFinite("text fragment"));
+    }
+    if !layout_box.inline_fragments.iter().all(|fragment| is_finite(fragment.border_box)) {
+        report(ViolationKind::NonFinite("inline fragment"));
+    }
+    // the children of a skipped box were never laid out
+    if layout_box.contents_skipped {
+        return;
+    }
+
+    let stacks_blocks = match layout_box.box_type {
+        // tables, row groups, rows and grids place their children in rows and columns
+        BlockNode(style) => !matches!(style.display(), Display::Table | Display::TableRowGroup | Display::TableRow | Display::Grid),
+        InlineBlockNode(_) => true,
+        _ => false,
+    };
+    let mut previous: Option<&LayoutBox> = None;
+    for child in &layout_box.children {
+        let child_path = format!("{} > {}", path, describe(child));
+        if is_block_level(child) {
+            if matches!(layout_box.box_type, AnonymousBlock) {
+                violations.push(InvariantViolation { kind: ViolationKind::BlockInAnonymousBlock, path: child_path.clone() });
+            }
+            if let Some(previous) = previous.filter(|_| stacks_blocks) {
+                let previous_box = previous.dimensions.margin_box();
+                let overlap = previous_box.y + previous_box.height - child.dimensions.margin_box().y;
+                // negative margins pull boxes over the ones before them
+                let explained = previous.dimensions.margin.bottom < 0.0 || child.dimensions.margin.top < 0.0;
+                if overlap > TOLERANCE && !explained {
+                    violations.push(InvariantViolation { kind: ViolationKind::Overlap(overlap), path: child_path.clone() });
+                }
+            }
+            previous = Some(child);
+        }
+        check_box(child, &child_path, violations);
+    }
+}
+
+// block-level boxes stack in a block formatting context; inline and inline-block
+// boxes go on lines
+fn is_block_level(layout_box: &LayoutBox) -> bool {
+    matches!(layout_box.box_type, BlockNode(_) | AnonymousBlock)
+}
+
+// a box as it appears in a violation's path: its element's tag and id, or what
+// kind of box it is
+pub(crate) fn describe(layout_box: &LayoutBox) -> String {
+    let style = match layout_box.style_node() {
+        Some(style) => style,
+        None => return "(anonymous)".to_string(),
+    };
+    match style.node.node_type {
+        NodeType::Element(ref elem) => match elem.id() {
+            Some(id) => format!("{}#{}", elem.tag_name, id),
+            None => elem.tag_name.clone(),
+        },
+        _ => "(text)".to_string(),
+    }
+}